@@ -0,0 +1,190 @@
+use std::str::FromStr;
+
+use crate::config::Config;
+
+/// Mapping between the brightness percentage and the raw characteristic value (0 to 255), which
+/// is linear to the emitted light while perceived brightness isn't
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BrightnessCurve {
+    #[default]
+    Linear,
+    /// Gamma 2, a cheap approximation of the perceived brightness
+    Square,
+    /// CIE 1976 lightness (L*), the percentage being L*
+    CieLightness,
+}
+
+impl BrightnessCurve {
+    /// Curve selected with the RUSTBEE_BRIGHTNESS_CURVE env var, linear by default
+    pub fn current() -> Self {
+        Config::current().brightness_curve
+    }
+
+    /// From a percentage (0. to 100.) to the raw characteristic value
+    pub fn to_raw(&self, percent: f32) -> u8 {
+        let percent = percent.clamp(0., 100.) / 100.;
+
+        let value = match self {
+            Self::Linear => percent,
+            Self::Square => percent.powi(2),
+            Self::CieLightness => {
+                let lightness = percent * 100.;
+                if lightness > 8. {
+                    ((lightness + 16.) / 116.).powi(3)
+                } else {
+                    lightness / 903.3
+                }
+            }
+        };
+
+        (value * 255.).round() as _
+    }
+
+    /// From the raw characteristic value to a percentage (0. to 100.), inverse of to_raw
+    pub fn to_percent(&self, raw: u8) -> f32 {
+        let value = raw as f32 / 255.;
+
+        let percent = match self {
+            Self::Linear => value,
+            Self::Square => value.sqrt(),
+            Self::CieLightness => {
+                let lightness = if value > 0.008856 {
+                    116. * value.cbrt() - 16.
+                } else {
+                    903.3 * value
+                };
+                lightness / 100.
+            }
+        };
+
+        percent * 100.
+    }
+}
+
+impl FromStr for BrightnessCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "linear" => Ok(Self::Linear),
+            "square" => Ok(Self::Square),
+            "cie" | "cie-lightness" => Ok(Self::CieLightness),
+            _ => Err(format!(
+                "Unknown brightness curve \"{s}\", expected one of: linear, square, cie-lightness"
+            )),
+        }
+    }
+}
+
+/// How the brightness of several devices is summed up into one, e.g. for the all devices slider
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BrightnessAggregate {
+    #[default]
+    Average,
+    /// Most common value, the highest one on ties
+    Mode,
+    Min,
+    Max,
+}
+
+impl BrightnessAggregate {
+    /// Aggregate selected with the RUSTBEE_BRIGHTNESS_AGGREGATE env var, the average by default
+    pub fn current() -> Self {
+        Config::current().brightness_aggregate
+    }
+
+    /// Full brightness when there's no value
+    pub fn aggregate(&self, values: impl IntoIterator<Item = u8>) -> u8 {
+        let values = values.into_iter().collect::<Vec<_>>();
+        if values.is_empty() {
+            return 100;
+        }
+
+        match self {
+            Self::Average => {
+                let sum = values.iter().map(|v| *v as u32).sum::<u32>();
+                (sum as f32 / values.len() as f32).round() as _
+            }
+            Self::Mode => {
+                let mut counts = [0usize; u8::MAX as usize + 1];
+                for value in &values {
+                    counts[*value as usize] += 1;
+                }
+
+                // max_by_key returns the last max so the highest value wins ties
+                counts
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, count)| **count)
+                    .map_or(100, |(value, _)| value as _)
+            }
+            Self::Min => *values.iter().min().unwrap(),
+            Self::Max => *values.iter().max().unwrap(),
+        }
+    }
+}
+
+impl FromStr for BrightnessAggregate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "average" | "avg" => Ok(Self::Average),
+            "mode" => Ok(Self::Mode),
+            "min" => Ok(Self::Min),
+            "max" => Ok(Self::Max),
+            _ => Err(format!(
+                "Unknown brightness aggregate \"{s}\", expected one of: average, mode, min, max"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod brightness_tests {
+    use super::*;
+
+    const CURVES: [BrightnessCurve; 3] = [
+        BrightnessCurve::Linear,
+        BrightnessCurve::Square,
+        BrightnessCurve::CieLightness,
+    ];
+
+    #[test]
+    fn curve_bounds() {
+        for curve in CURVES {
+            assert_eq!(curve.to_raw(0.), 0);
+            assert_eq!(curve.to_raw(100.), 255);
+            assert_eq!(curve.to_percent(0), 0.);
+            assert!((curve.to_percent(255) - 100.).abs() < 0.01);
+        }
+
+        assert_eq!(BrightnessCurve::Linear.to_raw(50.), 128);
+        assert_eq!(BrightnessCurve::Square.to_raw(50.), 64);
+        assert_eq!(BrightnessCurve::CieLightness.to_raw(50.), 47);
+    }
+
+    #[test]
+    fn curve_round_trip() {
+        for curve in CURVES {
+            for raw in 0..=u8::MAX {
+                assert_eq!(curve.to_raw(curve.to_percent(raw)), raw, "{curve:?} {raw}");
+            }
+        }
+    }
+
+    #[test]
+    fn aggregate_mixed_brightnesses() {
+        let values = [0, 100, 100, 40];
+
+        // A device at 0% doesn't drag the others down anymore
+        assert_eq!(BrightnessAggregate::Average.aggregate(values), 60);
+        assert_eq!(BrightnessAggregate::Mode.aggregate(values), 100);
+        assert_eq!(BrightnessAggregate::Min.aggregate(values), 0);
+        assert_eq!(BrightnessAggregate::Max.aggregate(values), 100);
+
+        assert_eq!(BrightnessAggregate::Mode.aggregate([20, 80]), 80);
+        assert_eq!(BrightnessAggregate::Average.aggregate([33, 34]), 34);
+        assert_eq!(BrightnessAggregate::default().aggregate([]), 100);
+    }
+}
@@ -1,13 +1,301 @@
+use std::str::FromStr;
 use std::sync::LazyLock;
 
 use color_space::Rgb;
 use log::*;
 
-// Limits for Hue Play lights
+use crate::config::Config;
+use crate::constants::{MAX_COLOR_TEMP_KELVIN, MIN_COLOR_TEMP_KELVIN};
+
+// Limits for Hue Play lights, see Gamut::C
 // https://developers.meethue.com/develop/application-design-guidance/color-conversion-formulas-rgb-to-xy-and-back/#Gamut
 static RED: LazyLock<Xy> = LazyLock::new(|| Xy::new(0.6915, 0.3038));
 static GREEN: LazyLock<Xy> = LazyLock::new(|| Xy::new(0.17, 0.7));
 static BLUE: LazyLock<Xy> = LazyLock::new(|| Xy::new(0.1532, 0.0475));
+/// D65
+static WHITE: LazyLock<Xy> = LazyLock::new(|| Xy::new(0.3127, 0.3290));
+
+/// Color gamuts of the Hue lights, which one a device has depends on its model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Gamut {
+    /// LivingColors lamps and the first LightStrips
+    A,
+    /// First generations of the color bulbs
+    B,
+    /// Every recent light like the Hue Play
+    #[default]
+    C,
+}
+
+impl Gamut {
+    /// From the model number of the device information (see `rustbee info`), unknown models
+    /// being recent they fall back to gamut C
+    pub fn from_model(model: &str) -> Self {
+        match model.trim().to_ascii_uppercase().as_str() {
+            "LST001" | "LLC005" | "LLC006" | "LLC007" | "LLC010" | "LLC011" | "LLC012"
+            | "LLC013" | "LLC014" => Self::A,
+            "LCT001" | "LCT002" | "LCT003" | "LCT007" | "LLM001" => Self::B,
+            _ => Self::C,
+        }
+    }
+
+    /// Red, green and blue corners of the triangle
+    fn corners(&self) -> [Xy; 3] {
+        match self {
+            Self::A => [
+                Xy::new(0.704, 0.296),
+                Xy::new(0.2151, 0.7106),
+                Xy::new(0.138, 0.08),
+            ],
+            Self::B => [
+                Xy::new(0.675, 0.322),
+                Xy::new(0.409, 0.518),
+                Xy::new(0.167, 0.04),
+            ],
+            Self::C => [*RED, *GREEN, *BLUE],
+        }
+    }
+}
+
+/// How colors out of the light gamut are brought back into it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GamutClamp {
+    /// Closest point of the gamut edges, close to the requested color but the hue can shift
+    #[default]
+    NearestEdge,
+    /// Moves toward the white point until it's in gamut, keeps the hue but desaturates
+    Desaturate,
+}
+
+impl GamutClamp {
+    /// Strategy selected with the RUSTBEE_GAMUT_CLAMP env var, nearest edge by default
+    pub fn current() -> Self {
+        Config::current().gamut_clamp
+    }
+}
+
+impl FromStr for GamutClamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nearest-edge" | "edge" => Ok(Self::NearestEdge),
+            "desaturate" | "white" => Ok(Self::Desaturate),
+            _ => Err(format!(
+                "Unknown gamut clamping \"{s}\", expected one of: nearest-edge, desaturate"
+            )),
+        }
+    }
+}
+
+/// Clamps the temperature to MIN_COLOR_TEMP_KELVIN..=MAX_COLOR_TEMP_KELVIN
+pub fn kelvin_to_mireds(kelvin: u16) -> u16 {
+    let kelvin = kelvin.clamp(MIN_COLOR_TEMP_KELVIN, MAX_COLOR_TEMP_KELVIN) as f64;
+
+    (1_000_000. / kelvin).round() as _
+}
+
+pub fn mireds_to_kelvin(mireds: u16) -> u16 {
+    (1_000_000. / mireds.max(1) as f64).round() as _
+}
+
+/// Hue in degrees, saturation and value from 0. to 1., values out of range are clamped
+// https://en.wikipedia.org/wiki/HSL_and_HSV#HSV_to_RGB
+pub fn hsv_to_rgb(hue: f64, saturation: f64, value: f64) -> Rgb {
+    let hue = hue.clamp(0., 360.) % 360.;
+    let (saturation, value) = (saturation.clamp(0., 1.), value.clamp(0., 1.));
+
+    let chroma = value * saturation;
+    let sector = hue / 60.;
+    let x = chroma * (1. - (sector % 2. - 1.).abs());
+    let (r, g, b) = match sector as u8 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let m = value - chroma;
+
+    Rgb::new((r + m) * 255., (g + m) * 255., (b + m) * 255.)
+}
+
+/// Lowercase rrggbb without the leading #, the channels are rounded
+pub fn rgb_to_hex(rgb: Rgb) -> String {
+    let channel = |value: f64| value.round().clamp(0., 255.) as u8;
+
+    format!(
+        "{:02x}{:02x}{:02x}",
+        channel(rgb.r),
+        channel(rgb.g),
+        channel(rgb.b)
+    )
+}
+
+/// Case insensitive rrggbb, the leading # is optional
+pub fn hex_to_rgb(hex: &str) -> Result<Rgb, String> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{hex} is not a hex color"));
+    }
+    if digits.len() != 6 {
+        return Err(format!("Hex length must be 6 like so: ffFF00, got {hex}"));
+    }
+
+    // Only ASCII hex digits are left so it can't fail
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).unwrap() as f64;
+
+    Ok(Rgb::new(channel(0), channel(2), channel(4)))
+}
+
+/// CSS colors along whites close to the Hue app scenes, black is left out since it's not a color
+/// a light can show
+const NAMED_COLORS: [(&str, [u8; 3]); 31] = [
+    ("red", [255, 0, 0]),
+    ("crimson", [220, 20, 60]),
+    ("coral", [255, 127, 80]),
+    ("salmon", [250, 128, 114]),
+    ("orange", [255, 165, 0]),
+    ("gold", [255, 215, 0]),
+    ("yellow", [255, 255, 0]),
+    ("lime", [0, 255, 0]),
+    ("green", [0, 128, 0]),
+    ("spring-green", [0, 255, 127]),
+    ("teal", [0, 128, 128]),
+    ("turquoise", [64, 224, 208]),
+    ("cyan", [0, 255, 255]),
+    ("sky-blue", [135, 206, 235]),
+    ("blue", [0, 0, 255]),
+    ("navy", [0, 0, 128]),
+    ("indigo", [75, 0, 130]),
+    ("purple", [128, 0, 128]),
+    ("violet", [238, 130, 238]),
+    ("lavender", [230, 230, 250]),
+    ("magenta", [255, 0, 255]),
+    ("pink", [255, 192, 203]),
+    ("hot-pink", [255, 105, 180]),
+    ("white", [255, 255, 255]),
+    ("warm-white", [255, 214, 170]),
+    ("cool-white", [235, 242, 255]),
+    // Hue scenes, from the warmest
+    ("candle", [255, 147, 41]),
+    ("relax", [255, 183, 107]),
+    ("read", [255, 214, 170]),
+    ("concentrate", [255, 236, 224]),
+    ("energize", [245, 243, 255]),
+];
+
+/// Case insensitive, spaces and underscores are read as hyphens
+pub fn named_color(name: &str) -> Option<Rgb> {
+    let name = normalized_color_name(name);
+
+    NAMED_COLORS
+        .iter()
+        .find(|(color, _)| *color == name)
+        .map(|(_, [r, g, b])| Rgb::new(*r as _, *g as _, *b as _))
+}
+
+/// Known color names the closest to the given one, at most 3 and the closest first
+pub fn similar_color_names(name: &str) -> Vec<&'static str> {
+    let name = normalized_color_name(name);
+    // Beyond that, the names have little in common
+    let max_distance = usize::max(name.len() / 2, 2);
+
+    let mut names = NAMED_COLORS
+        .iter()
+        .map(|(color, _)| (edit_distance(&name, color), *color))
+        .filter(|(distance, color)| *distance <= max_distance || color.contains(name.as_str()))
+        .collect::<Vec<_>>();
+    names.sort();
+
+    names.into_iter().take(3).map(|(_, color)| color).collect()
+}
+
+fn normalized_color_name(name: &str) -> String {
+    name.trim().to_lowercase().replace([' ', '_'], "-")
+}
+
+/// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a_char != *b_char);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// RGB color spaces (D65 white point) a xy color can be read as
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    #[default]
+    Srgb,
+    DisplayP3,
+    AdobeRgb,
+}
+
+impl ColorSpace {
+    // http://www.brucelindbloom.com/index.html?Eqn_RGB_XYZ_Matrix.html
+    fn xyz_to_rgb_matrix(&self) -> [[f64; 3]; 3] {
+        match self {
+            Self::Srgb => [
+                [3.2404542, -1.5371385, -0.4985314],
+                [-0.9692660, 1.8760108, 0.0415560],
+                [0.0556434, -0.2040259, 1.0572252],
+            ],
+            Self::DisplayP3 => [
+                [2.4934969, -0.9313836, -0.4027108],
+                [-0.8294890, 1.7626641, 0.0236247],
+                [0.0358458, -0.0761724, 0.9568845],
+            ],
+            Self::AdobeRgb => [
+                [2.0413690, -0.5649464, -0.3446944],
+                [-0.9692660, 1.8760108, 0.0415560],
+                [0.0134474, -0.1183897, 1.0154096],
+            ],
+        }
+    }
+
+    /// Gamma encodes a linear value from 0. to 1.
+    fn encode(&self, value: f64) -> f64 {
+        match self {
+            // Display P3 uses the sRGB transfer function
+            Self::Srgb | Self::DisplayP3 => {
+                if value <= 0.0031308 {
+                    12.92 * value
+                } else {
+                    (1.0 + 0.055) * value.powf(1.0 / 2.4) - 0.055
+                }
+            }
+            Self::AdobeRgb => value.powf(256. / 563.),
+        }
+    }
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "srgb" => Ok(Self::Srgb),
+            "display-p3" | "p3" => Ok(Self::DisplayP3),
+            "adobe-rgb" | "adobe" => Ok(Self::AdobeRgb),
+            _ => Err(format!(
+                "Unknown color space \"{s}\", expected one of: srgb, display-p3, adobe-rgb"
+            )),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct Xy {
@@ -31,10 +319,27 @@ impl Xy {
         }
     }
 
+    /// Decodes the 4 color bytes of a device (x then y as little endian u16 scaled to 0xFFFF)
+    pub fn from_le_bytes([x0, x1, y0, y1]: [u8; 4]) -> Self {
+        Self::new(
+            u16::from_le_bytes([x0, x1]) as f64 / 0xFFFF as f64,
+            u16::from_le_bytes([y0, y1]) as f64 / 0xFFFF as f64,
+        )
+    }
+
+    /// Brightness aware conversion (brightness from 0. to 1.), to display the color itself (e.g. a
+    /// swatch next to a separate brightness) use Rgb::from(xy) which is at full brightness. Out of
+    /// gamut colors are clamped first, see clamp_to_gamut
     // https://developers.meethue.com/develop/application-design-guidance/color-conversion-formulas-rgb-to-xy-and-back/#xy-to-rgb-color
-    pub fn to_rgb(mut self, brightness: f64) -> Rgb {
-        if !self.is_within_color_gamut() {
-            self = self.closest_point_in_triangle(&RED, &GREEN, &BLUE);
+    pub fn to_rgb(self, brightness: f64) -> Rgb {
+        self.to_rgb_with_gamut(brightness, Gamut::default())
+    }
+
+    /// Same as to_rgb but out of gamut colors are clamped to the given gamut instead of the
+    /// default one
+    pub fn to_rgb_with_gamut(mut self, brightness: f64, gamut: Gamut) -> Rgb {
+        if !self.is_within_gamut(gamut) {
+            self = self.clamp_to_gamut_with(GamutClamp::current(), gamut);
         }
 
         // To XYZ
@@ -127,14 +432,157 @@ impl Xy {
         );
         */
 
-        Rgb::new(r * 255., g * 255., b * 255.)
+        // Corners of the gamut can end up slightly negative on the smallest channel
+        Rgb::new(r.max(0.) * 255., g.max(0.) * 255., b.max(0.) * 255.)
     }
 
+    /// Converts to RGB values (0. to 255.) of the given color space, out of gamut colors are
+    /// scaled to keep their hue. Unlike to_rgb, the xy isn't clamped to the Hue gamut first
+    /// since colors read from a device are already within it
+    pub fn to_rgb_in(&self, brightness: f64, color_space: ColorSpace) -> Rgb {
+        if self.y == 0. {
+            return Rgb::new(0., 0., 0.);
+        }
+
+        // To XYZ
+        let y = brightness;
+        let x = (y / self.y) * self.x;
+        let z = (y / self.y) * (1. - self.x - self.y);
+
+        let mut rgb = color_space
+            .xyz_to_rgb_matrix()
+            .map(|row| (row[0] * x + row[1] * y + row[2] * z).max(0.));
+
+        let max = rgb.into_iter().fold(0., f64::max);
+        if max > 1. {
+            rgb = rgb.map(|v| v / max);
+        }
+
+        let [r, g, b] = rgb.map(|v| color_space.encode(v) * 255.);
+
+        Rgb::new(r, g, b)
+    }
+
+    /// Approximates the Planckian locus, valid from 1667K to 25000K
+    // https://en.wikipedia.org/wiki/Planckian_locus#Approximation
+    pub fn from_kelvin(kelvin: f64) -> Self {
+        let t = kelvin.clamp(1667., 25000.);
+
+        let x = if t <= 4000. {
+            -0.2661239e9 / t.powi(3) - 0.2343589e6 / t.powi(2) + 0.8776956e3 / t + 0.179910
+        } else {
+            -3.0258469e9 / t.powi(3) + 2.1070379e6 / t.powi(2) + 0.2226347e3 / t + 0.240390
+        };
+
+        let y = if t <= 2222. {
+            -1.1063814 * x.powi(3) - 1.34811020 * x.powi(2) + 2.18555832 * x - 0.20219683
+        } else if t <= 4000. {
+            -0.9549476 * x.powi(3) - 1.37418593 * x.powi(2) + 2.09137015 * x - 0.16748867
+        } else {
+            3.0817580 * x.powi(3) - 5.87338670 * x.powi(2) + 3.75112997 * x - 0.37001483
+        };
+
+        Self::new(x, y)
+    }
+
+    /// Out of gamut colors are clamped to the given gamut, see GamutClamp
+    pub fn from_rgb_with_gamut(rgb: Rgb, gamut: Gamut) -> Self {
+        let (mut r, mut g, mut b) = (rgb.r, rgb.g, rgb.b);
+        r /= 255.;
+        g /= 255.;
+        b /= 255.;
+
+        // Gamma correction
+        let red = if r > 0.04045 {
+            ((r + 0.055) / (1.0 + 0.055)).powf(2.4)
+        } else {
+            r / 12.92
+        };
+        let green = if g > 0.04045 {
+            ((g + 0.055) / (1.0 + 0.055)).powf(2.4)
+        } else {
+            g / 12.92
+        };
+        let blue = if b > 0.04045 {
+            ((b + 0.055) / (1.0 + 0.055)).powf(2.4)
+        } else {
+            b / 12.92
+        };
+
+        // RGB to XYZ Wide RGB D65 conversion formula
+        let x = red * 0.4124 + green * 0.3576 + blue * 0.1805;
+        let y = red * 0.2126 + green * 0.7152 + blue * 0.0722;
+        let z = red * 0.0193 + green * 0.1192 + blue * 0.9505;
+
+        // Xy from XYZ
+        let brightness = y;
+        let sum = x + y + z;
+        // Black has no chromaticity
+        if sum == 0. {
+            return Self {
+                brightness: Some(0.),
+                ..*WHITE
+            };
+        }
+
+        let x = x / sum;
+        let y = y / sum;
+
+        let xy = Self {
+            x,
+            y,
+            brightness: Some(brightness),
+        };
+
+        // The luminance doesn't depend on the chromaticity so it's kept
+        if !xy.is_within_gamut(gamut) {
+            return Self {
+                brightness: xy.brightness,
+                ..xy.clamp_to_gamut_with(GamutClamp::current(), gamut)
+            };
+        }
+
+        xy
+    }
+
+    /// See hsv_to_rgb, the value only matters for black since the chromaticity doesn't depend on it
+    pub fn from_hsv(hue: f64, saturation: f64, value: f64) -> Self {
+        Self::from(hsv_to_rgb(hue, saturation, value))
+    }
+
+    /// Color temperature as a xy color within the gamut, for devices driven with set_colors
+    /// only. Temperatures out of 1000K..=40000K saturate
+    pub fn from_cct(kelvin: u16) -> Self {
+        let xy = Self::from_kelvin(kelvin.clamp(1000, 40000) as _);
+
+        if xy.is_within_color_gamut() {
+            xy
+        } else {
+            xy.closest_point_in_triangle(&RED, &GREEN, &BLUE)
+        }
+    }
+
+    /// Neutral white, it's inside every gamut
+    pub fn d65() -> Self {
+        *WHITE
+    }
+
+    /// Incandescent like white around 2700K, within the gamut
+    pub fn warm_white() -> Self {
+        Self::from_cct(2700)
+    }
+
+    /// Within the default gamut, see is_within_gamut
     pub fn is_within_color_gamut(&self) -> bool {
+        self.is_within_gamut(Gamut::default())
+    }
+
+    pub fn is_within_gamut(&self, gamut: Gamut) -> bool {
+        let [red, green, blue] = gamut.corners();
         let (x, y) = (self.x, self.y);
-        let (x1, y1) = (RED.x, RED.y);
-        let (x2, y2) = (GREEN.x, GREEN.y);
-        let (x3, y3) = (BLUE.x, BLUE.y);
+        let (x1, y1) = (red.x, red.y);
+        let (x2, y2) = (green.x, green.y);
+        let (x3, y3) = (blue.x, blue.y);
 
         let denominator = (y2 - y3) * (x1 - x3) + (x3 - x2) * (y1 - y3);
 
@@ -147,6 +595,57 @@ impl Xy {
             && (0. ..=1.).contains(&lambda3)
     }
 
+    /// Brings the color back into the default gamut with the strategy of GamutClamp::current(),
+    /// colors already within it are left as is. There's no need to call it before sending a
+    /// color since From<Rgb> and to_rgb already clamp
+    pub fn clamp_to_gamut(&self) -> Self {
+        if self.is_within_color_gamut() {
+            return *self;
+        }
+
+        self.clamp_to_gamut_with(GamutClamp::current(), Gamut::default())
+    }
+
+    /// Clamps even if the color is within the gamut, it lands on an edge then
+    pub fn clamp_to_gamut_with(&self, strategy: GamutClamp, gamut: Gamut) -> Self {
+        match strategy {
+            GamutClamp::NearestEdge => self.closest_point_in_gamut(gamut),
+            GamutClamp::Desaturate => {
+                let [red, green, blue] = gamut.corners();
+                self.intersect_toward(&WHITE, [(&red, &green), (&green, &blue), (&blue, &red)])
+                    .unwrap_or_else(|| self.closest_point_in_gamut(gamut))
+            }
+        }
+    }
+
+    fn closest_point_in_gamut(&self, gamut: Gamut) -> Self {
+        let [red, green, blue] = gamut.corners();
+        self.closest_point_in_triangle(&red, &green, &blue)
+    }
+
+    /// First intersection of the segment from self to target with the edges
+    fn intersect_toward(&self, target: &Self, edges: [(&Self, &Self); 3]) -> Option<Self> {
+        let (dx, dy) = (target.x - self.x, target.y - self.y);
+
+        edges
+            .into_iter()
+            .filter_map(|(a, b)| {
+                let (ex, ey) = (b.x - a.x, b.y - a.y);
+                let denominator = dx * ey - dy * ex;
+                if denominator.abs() < f64::EPSILON {
+                    return None;
+                }
+
+                // self + t * d = a + s * e
+                let t = ((a.x - self.x) * ey - (a.y - self.y) * ex) / denominator;
+                let s = ((a.x - self.x) * dy - (a.y - self.y) * dx) / denominator;
+
+                ((0. ..=1.).contains(&t) && (0. ..=1.).contains(&s)).then_some(t)
+            })
+            .min_by(f64::total_cmp)
+            .map(|t| Self::new(self.x + t * dx, self.y + t * dy))
+    }
+
     fn closest_point_in_triangle(&self, x1: &Self, x2: &Self, x3: &Self) -> Self {
         let euclidean_distance =
             |a: &Self, b: &Self| ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).powf(0.5);
@@ -159,9 +658,10 @@ impl Xy {
         let d2 = euclidean_distance(&p2_closest, self);
         let d3 = euclidean_distance(&p3_closest, self);
 
-        if d1 < d2 && d1 < d3 {
+        // Two edges are as close when the point projects on their shared corner
+        if d1 <= d2 && d1 <= d3 {
             p1_closest
-        } else if d2 < d1 && d2 < d3 {
+        } else if d2 <= d3 {
             p2_closest
         } else {
             p3_closest
@@ -190,51 +690,17 @@ impl Xy {
     }
 }
 
+/// Clamped to the default gamut, see Xy::from_rgb_with_gamut
 impl From<Rgb> for Xy {
     fn from(rgb: Rgb) -> Self {
-        let (mut r, mut g, mut b) = (rgb.r, rgb.g, rgb.b);
-        r /= 255.;
-        g /= 255.;
-        b /= 255.;
-
-        // Gamma correction
-        let red = if r > 0.04045 {
-            ((r + 0.055) / (1.0 + 0.055)).powf(2.4)
-        } else {
-            r / 12.92
-        };
-        let green = if g > 0.04045 {
-            ((g + 0.055) / (1.0 + 0.055)).powf(2.4)
-        } else {
-            g / 12.92
-        };
-        let blue = if b > 0.04045 {
-            ((b + 0.055) / (1.0 + 0.055)).powf(2.4)
-        } else {
-            b / 12.92
-        };
-
-        // RGB to XYZ Wide RGB D65 conversion formula
-        let x = red * 0.4124 + green * 0.3576 + blue * 0.1805;
-        let y = red * 0.2126 + green * 0.7152 + blue * 0.0722;
-        let z = red * 0.0193 + green * 0.1192 + blue * 0.9505;
-
-        // Xy from XYZ
-        let brightness = y;
-        let x = x / (x + y + z);
-        let y = y / (x + y + z);
-
-        let xy = Self {
-            x,
-            y,
-            brightness: Some(brightness),
-        };
-
-        if !xy.is_within_color_gamut() {
-            return xy.closest_point_in_triangle(&RED, &GREEN, &BLUE);
-        }
+        Self::from_rgb_with_gamut(rgb, Gamut::default())
+    }
+}
 
-        xy
+/// Representative color at full brightness, same as xy.to_rgb(1.)
+impl From<Xy> for Rgb {
+    fn from(xy: Xy) -> Self {
+        xy.to_rgb(1.)
     }
 }
 
@@ -261,4 +727,382 @@ mod color_tests {
         assert_eq!(red.g, rgb.g, "Red G isn't equal to RGB G");
         assert_eq!(red.b, rgb.b, "Red B isn't equal to RGB B");
     }
+
+    fn rounded(rgb: Rgb) -> (u8, u8, u8) {
+        (rgb.r.round() as _, rgb.g.round() as _, rgb.b.round() as _)
+    }
+
+    #[test]
+    fn xy_to_color_spaces() {
+        let white = Xy::new(0.3127, 0.3290);
+        let srgb_red = Xy::new(0.64, 0.33);
+        let p3_red = Xy::new(0.68, 0.32);
+        let adobe_green = Xy::new(0.21, 0.71);
+
+        for color_space in [ColorSpace::Srgb, ColorSpace::DisplayP3, ColorSpace::AdobeRgb] {
+            assert_eq!(rounded(white.to_rgb_in(1., color_space)), (255, 255, 255));
+        }
+
+        assert_eq!(rounded(srgb_red.to_rgb_in(1., ColorSpace::Srgb)), (255, 0, 0));
+        assert_eq!(rounded(srgb_red.to_rgb_in(1., ColorSpace::AdobeRgb)), (255, 0, 0));
+        assert_eq!(rounded(srgb_red.to_rgb_in(1., ColorSpace::DisplayP3)), (255, 57, 40));
+
+        assert_eq!(rounded(p3_red.to_rgb_in(1., ColorSpace::DisplayP3)), (255, 0, 0));
+        // Out of sRGB gamut so it's scaled
+        assert_eq!(rounded(p3_red.to_rgb_in(1., ColorSpace::Srgb)), (255, 0, 0));
+
+        assert_eq!(rounded(adobe_green.to_rgb_in(1., ColorSpace::AdobeRgb)), (0, 255, 0));
+        assert_eq!(rounded(adobe_green.to_rgb_in(1., ColorSpace::DisplayP3)), (0, 255, 46));
+    }
+
+    #[test]
+    fn rgb_from_xy() {
+        for xy in [
+            *RED,
+            *GREEN,
+            *BLUE,
+            Xy::new(0.3127, 0.3290),
+            Xy::new(0.45, 0.41),
+            // Out of gamut
+            Xy::new(0.8, 0.25),
+        ] {
+            assert_eq!(rounded(Rgb::from(xy)), rounded(xy.to_rgb(1.)), "{xy:?}");
+        }
+    }
+
+    #[test]
+    fn color_temp_mireds() {
+        assert_eq!(kelvin_to_mireds(2000), 500);
+        assert_eq!(kelvin_to_mireds(6500), 154);
+        assert_eq!(kelvin_to_mireds(4000), 250);
+
+        // Out of range values are clamped
+        assert_eq!(kelvin_to_mireds(1000), 500);
+        assert_eq!(kelvin_to_mireds(10000), 154);
+
+        assert_eq!(mireds_to_kelvin(250), 4000);
+        assert_eq!(mireds_to_kelvin(500), 2000);
+        // Doesn't divide by 0, the kelvin saturate
+        assert_eq!(mireds_to_kelvin(0), u16::MAX);
+    }
+
+    #[test]
+    fn color_temp_round_trip() {
+        for kelvin in (MIN_COLOR_TEMP_KELVIN..=MAX_COLOR_TEMP_KELVIN).step_by(100) {
+            let back = mireds_to_kelvin(kelvin_to_mireds(kelvin));
+            // A mired step is wider in Kelvin as the temperature rises
+            let tolerance = (kelvin as u32).pow(2) / 2_000_000 + 1;
+            assert!(
+                back.abs_diff(kelvin) as u32 <= tolerance,
+                "{kelvin}K -> {back}K"
+            );
+        }
+
+        let (min, max) = (
+            kelvin_to_mireds(MAX_COLOR_TEMP_KELVIN),
+            kelvin_to_mireds(MIN_COLOR_TEMP_KELVIN),
+        );
+        for mireds in min..=max {
+            assert_eq!(kelvin_to_mireds(mireds_to_kelvin(mireds)), mireds);
+        }
+    }
+
+    #[test]
+    fn xy_from_kelvin() {
+        let close = |a: Xy, (x, y): (f64, f64)| (a.x - x).abs() < 1e-3 && (a.y - y).abs() < 1e-3;
+
+        assert!(close(Xy::from_kelvin(2000.), (0.5269, 0.4133)));
+        assert!(close(Xy::from_kelvin(4000.), (0.3805, 0.3767)));
+        // D65
+        assert!(close(Xy::from_kelvin(6500.), (0.3135, 0.3237)));
+        assert!(close(Xy::from_kelvin(100.), (0.5646, 0.4029)));
+    }
+
+    #[test]
+    fn xy_from_cct() {
+        let close = |a: Xy, (x, y): (f64, f64)| (a.x - x).abs() < 1e-2 && (a.y - y).abs() < 1e-2;
+
+        assert!(close(Xy::from_cct(2700), (0.454, 0.408)));
+        assert!(close(Xy::from_cct(6500), (0.313, 0.329)));
+
+        for kelvin in [0, 500, 1000, 40000, u16::MAX] {
+            let xy = Xy::from_cct(kelvin);
+            assert!(!xy.x.is_nan() && !xy.y.is_nan());
+        }
+        assert_eq!(Xy::from_cct(0), Xy::from_cct(1000));
+        assert_eq!(Xy::from_cct(u16::MAX), Xy::from_cct(40000));
+    }
+
+    #[test]
+    fn named_colors() {
+        assert_eq!(named_color("red"), Some(Rgb::new(255., 0., 0.)));
+        assert_eq!(
+            Xy::from(named_color("red").unwrap()),
+            Xy::from(Rgb::new(255., 0., 0.))
+        );
+        assert_eq!(named_color("Warm White"), named_color("warm-white"));
+        assert_eq!(named_color("warm_white"), named_color("warm-white"));
+        assert!(named_color("candle").is_some());
+        assert!(named_color("infrared").is_none());
+    }
+
+    #[test]
+    fn similar_colors() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "red"), 3);
+        assert_eq!(edit_distance("red", "red"), 0);
+
+        assert_eq!(similar_color_names("rde")[0], "red");
+        assert_eq!(similar_color_names("purpel")[0], "purple");
+        assert!(similar_color_names("white").contains(&"warm-white"));
+        assert!(similar_color_names("xxxxxxxxxxxx").is_empty());
+    }
+
+    #[test]
+    fn hsv_primaries() {
+        assert_eq!(hsv_to_rgb(0., 1., 1.), Rgb::new(255., 0., 0.));
+        assert_eq!(hsv_to_rgb(120., 1., 1.), Rgb::new(0., 255., 0.));
+        assert_eq!(hsv_to_rgb(240., 1., 1.), Rgb::new(0., 0., 255.));
+        assert_eq!(hsv_to_rgb(360., 1., 1.), Rgb::new(255., 0., 0.));
+        assert_eq!(hsv_to_rgb(60., 0.5, 1.), Rgb::new(255., 255., 127.5));
+
+        // Out of range values are clamped
+        assert_eq!(hsv_to_rgb(400., 2., 1.), hsv_to_rgb(0., 1., 1.));
+        assert_eq!(hsv_to_rgb(-10., -1., 0.5), Rgb::new(127.5, 127.5, 127.5));
+
+        let close = |a: Xy, b: Xy| (a.x - b.x).abs() < 1e-9 && (a.y - b.y).abs() < 1e-9;
+        let (red, green) = (Rgb::new(255., 0., 0.), Rgb::new(0., 255., 0.));
+        assert!(close(Xy::from_hsv(0., 1., 1.), Xy::from(red)));
+        // The value doesn't change the chromaticity
+        assert!(close(Xy::from_hsv(120., 1., 0.2), Xy::from(green)));
+    }
+
+    #[test]
+    fn white_presets() {
+        let (d65, warm) = (Xy::d65(), Xy::warm_white());
+
+        assert_eq!((d65.x, d65.y), (0.3127, 0.3290));
+        assert!(d65.is_within_color_gamut());
+        assert!(warm.is_within_color_gamut());
+        // Warmer whites are redder, further along x
+        assert!(warm.x > d65.x);
+        assert!((warm.x - 0.46).abs() < 0.01 && (warm.y - 0.41).abs() < 0.01);
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let magenta = Rgb::new(255., 0., 255.);
+
+        assert_eq!(hex_to_rgb("ff00ff"), Ok(magenta));
+        assert_eq!(rgb_to_hex(hex_to_rgb("ff00ff").unwrap()), "ff00ff");
+        assert_eq!(hex_to_rgb("#FF00ff"), Ok(magenta));
+        assert_eq!(rgb_to_hex(Rgb::new(18., 52.4, 85.6)), "123456");
+
+        assert!(hex_to_rgb("ff00f").is_err());
+        assert!(hex_to_rgb("ff00fg").is_err());
+        assert!(hex_to_rgb("+f+f+f").is_err());
+    }
+
+    #[test]
+    fn color_space_from_str() {
+        assert_eq!("sRGB".parse(), Ok(ColorSpace::Srgb));
+        assert_eq!("display-p3".parse(), Ok(ColorSpace::DisplayP3));
+        assert_eq!("adobe-rgb".parse(), Ok(ColorSpace::AdobeRgb));
+        assert!("cmyk".parse::<ColorSpace>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod color_pipeline_tests {
+    use super::*;
+
+    /// RGB to xy uses the sRGB matrix so sRGB references are matched closely, sRGB blue is
+    /// slightly out of the Hue gamut and gets clamped to its edge (~0.0035 away)
+    const XY_TOLERANCE: f64 = 0.005;
+    /// to_rgb uses the Wide RGB D65 matrix from the Hue docs while From<Rgb> uses the sRGB one
+    /// so a round trip drifts, mostly on saturated red and green
+    const ROUND_TRIP_TOLERANCE: f64 = 0.08;
+
+    fn distance(a: &Xy, b: &Xy) -> f64 {
+        ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt()
+    }
+
+    fn assert_close(actual: &Xy, expected: &Xy, tolerance: f64) {
+        assert!(
+            distance(actual, expected) <= tolerance,
+            "{actual:?} isn't within {tolerance} of {expected:?}"
+        );
+    }
+
+    fn assert_dominant(rgb: Rgb, channel: usize) {
+        let channels = [rgb.r, rgb.g, rgb.b];
+
+        for value in channels {
+            assert!((0. ..=255.).contains(&value), "{rgb:?} is out of range");
+        }
+        assert_eq!(channels[channel].round(), 255., "{rgb:?}");
+        for (i, value) in channels.into_iter().enumerate() {
+            if i != channel {
+                assert!(value < channels[channel], "{rgb:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn rgb_to_xy_references() {
+        // sRGB primaries and D65 white point, (rgb, xy, luminance)
+        let references = [
+            (Rgb::new(255., 0., 0.), Xy::new(0.64, 0.33), 0.2126),
+            (Rgb::new(0., 255., 0.), Xy::new(0.30, 0.60), 0.7152),
+            (Rgb::new(0., 0., 255.), Xy::new(0.15, 0.06), 0.0722),
+            (Rgb::new(255., 255., 255.), Xy::new(0.3127, 0.3290), 1.),
+        ];
+
+        for (rgb, expected, luminance) in references {
+            let xy = Xy::from(rgb);
+
+            assert_close(&xy, &expected, XY_TOLERANCE);
+            assert!(xy.is_within_color_gamut(), "{rgb:?}");
+            assert!((xy.brightness.unwrap() - luminance).abs() < 1e-4);
+        }
+
+        for rgb in [
+            Rgb::new(255., 255., 0.),
+            Rgb::new(0., 255., 255.),
+            Rgb::new(255., 0., 255.),
+            Rgb::new(255., 128., 0.),
+            Rgb::new(128., 128., 128.),
+        ] {
+            assert!(Xy::from(rgb).is_within_color_gamut(), "{rgb:?}");
+        }
+    }
+
+    #[test]
+    fn rgb_black_and_green() {
+        let black = Xy::from(Rgb::new(0., 0., 0.));
+        assert!(!black.x.is_nan() && !black.y.is_nan());
+        assert_close(&black, &WHITE, 1e-9);
+        assert_eq!(black.brightness, Some(0.));
+
+        // Pure green is the sRGB primary, well within the gamut so it's not clamped to GREEN
+        let green = Xy::from(Rgb::new(0., 255., 0.));
+        assert_close(&green, &Xy::new(0.30, 0.60), XY_TOLERANCE);
+        assert!(green.is_within_color_gamut());
+    }
+
+    #[test]
+    fn rgb_round_trip() {
+        for rgb in [
+            Rgb::new(255., 0., 0.),
+            Rgb::new(0., 255., 0.),
+            Rgb::new(0., 0., 255.),
+            Rgb::new(255., 255., 255.),
+            Rgb::new(255., 255., 0.),
+            Rgb::new(0., 255., 255.),
+            Rgb::new(255., 0., 255.),
+            Rgb::new(255., 128., 0.),
+        ] {
+            let xy = Xy::from(rgb);
+            let back = Xy::from(xy.to_rgb(1.));
+
+            assert_close(&back, &xy, ROUND_TRIP_TOLERANCE);
+        }
+
+        assert_dominant(Xy::from(Rgb::new(255., 0., 0.)).to_rgb(1.), 0);
+        assert_dominant(Xy::from(Rgb::new(0., 255., 0.)).to_rgb(1.), 1);
+        assert_dominant(Xy::from(Rgb::new(0., 0., 255.)).to_rgb(1.), 2);
+    }
+
+    #[test]
+    fn gamut_corners() {
+        assert_dominant(RED.to_rgb(1.), 0);
+        assert_dominant(GREEN.to_rgb(1.), 1);
+        assert_dominant(BLUE.to_rgb(1.), 2);
+
+        for corner in [&*RED, &*GREEN, &*BLUE] {
+            assert!(corner.is_within_color_gamut());
+        }
+
+        // Points beyond a corner are clamped to it
+        let clamp = |xy: Xy| xy.closest_point_in_triangle(&RED, &GREEN, &BLUE);
+        assert_close(&clamp(Xy::new(0.8, 0.25)), &RED, 1e-9);
+        assert_close(&clamp(Xy::new(0.1, 0.9)), &GREEN, 1e-9);
+        assert_close(&clamp(Xy::new(0., 0.)), &BLUE, 1e-9);
+        assert!(!Xy::new(0.8, 0.25).is_within_color_gamut());
+    }
+
+    #[test]
+    fn gamut_clamp_strategies() {
+        // Saturated cyan, beyond the green-blue edge
+        let cyan = Xy::new(0.03, 0.45);
+        assert!(!cyan.is_within_color_gamut());
+
+        let clamp = |xy: &Xy, strategy| xy.clamp_to_gamut_with(strategy, Gamut::C);
+        let edge = clamp(&cyan, GamutClamp::NearestEdge);
+        let desaturated = clamp(&cyan, GamutClamp::Desaturate);
+
+        // Both land on the gamut edge
+        for xy in [&edge, &desaturated] {
+            assert_close(&clamp(xy, GamutClamp::NearestEdge), xy, 1e-9);
+        }
+
+        // The nearest edge is closer to the requested color and desaturating stays on its line
+        // to the white point, which keeps the hue
+        assert!(distance(&edge, &cyan) < distance(&desaturated, &cyan));
+        let cross = (desaturated.x - cyan.x) * (WHITE.y - cyan.y)
+            - (desaturated.y - cyan.y) * (WHITE.x - cyan.x);
+        assert!(cross.abs() < 1e-9);
+        assert!(distance(&desaturated, &WHITE) < distance(&cyan, &WHITE));
+
+        // Beyond the red corner, the nearest edge snaps to it while desaturating doesn't
+        let red = Xy::new(0.8, 0.25);
+        assert_close(&clamp(&red, GamutClamp::NearestEdge), &RED, 1e-9);
+        assert!(distance(&clamp(&red, GamutClamp::Desaturate), &RED) > 0.01);
+
+        assert_eq!("desaturate".parse(), Ok(GamutClamp::Desaturate));
+        assert_eq!("Nearest-Edge".parse(), Ok(GamutClamp::NearestEdge));
+        assert!("closest".parse::<GamutClamp>().is_err());
+    }
+
+    #[test]
+    fn clamp_out_of_gamut() {
+        let far = Xy::new(0.9, 0.9);
+        assert!(!far.is_within_color_gamut());
+
+        // Beyond the red-green edge, it lands on it
+        let clamped = far.clamp_to_gamut();
+        let cross =
+            (GREEN.x - RED.x) * (clamped.y - RED.y) - (GREEN.y - RED.y) * (clamped.x - RED.x);
+        assert!(cross.abs() < 1e-9);
+        assert!((GREEN.x..=RED.x).contains(&clamped.x));
+        assert!(distance(&clamped, &far) > 0.1);
+
+        // Colors within the gamut aren't moved to an edge
+        assert_close(&WHITE.clamp_to_gamut(), &WHITE, 0.);
+    }
+
+    #[test]
+    fn gamut_per_model() {
+        assert_eq!(Gamut::from_model("lst001"), Gamut::A);
+        assert_eq!(Gamut::from_model("LCT001"), Gamut::B);
+        assert_eq!(Gamut::from_model("LCA006"), Gamut::C);
+        assert_eq!(Gamut::from_model(""), Gamut::default());
+
+        // Beyond the green corners, gamut A reaches further than gamut C
+        let green = Xy::new(0.1, 0.8);
+        let [_, green_a, _] = Gamut::A.corners();
+        let a = green.clamp_to_gamut_with(GamutClamp::NearestEdge, Gamut::A);
+        let c = green.clamp_to_gamut_with(GamutClamp::NearestEdge, Gamut::C);
+        assert_close(&a, &green_a, 1e-9);
+        assert_close(&c, &GREEN, 1e-9);
+        assert!(distance(&a, &c) > 0.04);
+
+        // Within gamut C's green but out of the narrower gamut B
+        assert!(GREEN.is_within_gamut(Gamut::C));
+        assert!(!GREEN.is_within_gamut(Gamut::B));
+        assert_ne!(
+            GREEN.to_rgb_with_gamut(1., Gamut::B),
+            GREEN.to_rgb_with_gamut(1., Gamut::C)
+        );
+    }
 }
@@ -0,0 +1,211 @@
+use std::env;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+use log::*;
+
+use crate::brightness::{BrightnessAggregate, BrightnessCurve};
+use crate::colors::GamutClamp;
+use crate::constants::{
+    ADAPTER_RESET_ENV, ADDR_LEN, ALERT_DEVICE_ENV, BRIGHTNESS_AGGREGATE_ENV, BRIGHTNESS_CURVE_ENV,
+    CMD_DELAY_ENV, CMD_DELAY_RANGE_MS, DAEMON_BIN_ENV, DAEMON_TIMEOUT_ENV,
+    DAEMON_TIMEOUT_RANGE_SECS, DEFAULT_CMD_DELAY_MS, DEFAULT_DAEMON_TIMEOUT_SECS,
+    DEFAULT_MAX_CONNECTIONS, GAMUT_CLAMP_ENV, LOG_FORMAT_ENV, LOG_LEVEL, LOG_LEVEL_ENV,
+    MAX_CONNECTIONS_ENV, SOCKET_ENV, SOCKET_PATH, TRACE_ENV,
+};
+use crate::logger::parse_log_level;
+use crate::utils::{addr_to_str, parse_hex_address};
+
+static CURRENT: LazyLock<Config> = LazyLock::new(Config::from_env);
+
+/// Every setting read from the env vars, invalid values fall back to the default with a warning
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub daemon_bin: Option<PathBuf>,
+    pub socket_path: PathBuf,
+    pub max_connections: usize,
+    pub daemon_timeout_secs: u64,
+    pub cmd_delay_ms: u64,
+    pub brightness_curve: BrightnessCurve,
+    pub brightness_aggregate: BrightnessAggregate,
+    pub gamut_clamp: GamutClamp,
+    pub alert_device: Option<[u8; ADDR_LEN]>,
+    pub adapter_reset: bool,
+    pub trace_packets: bool,
+    pub log_level: LevelFilter,
+    pub json_logs: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            daemon_bin: None,
+            socket_path: SOCKET_PATH.into(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            daemon_timeout_secs: DEFAULT_DAEMON_TIMEOUT_SECS,
+            cmd_delay_ms: DEFAULT_CMD_DELAY_MS,
+            brightness_curve: BrightnessCurve::default(),
+            brightness_aggregate: BrightnessAggregate::default(),
+            gamut_clamp: GamutClamp::default(),
+            alert_device: None,
+            adapter_reset: false,
+            trace_packets: false,
+            log_level: LOG_LEVEL.to_level_filter(),
+            json_logs: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loaded once from the env vars and flags of the process
+    pub fn current() -> &'static Self {
+        &CURRENT
+    }
+
+    /// The flags of the command line that are the same as their env var are applied too
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var(name).ok()).with_flags(env::args())
+    }
+
+    /// Applies --trace-packets and --adapter-reset, see TRACE_ENV and ADAPTER_RESET_ENV
+    pub fn with_flags<I: IntoIterator<Item = String>>(mut self, args: I) -> Self {
+        for arg in args {
+            match arg.as_str() {
+                "--trace-packets" => self.trace_packets = true,
+                "--adapter-reset" => self.adapter_reset = true,
+                _ => (),
+            }
+        }
+
+        self
+    }
+
+    /// Builds the config from a lookup of the env var values, empty values are ignored
+    pub fn from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Self {
+        let var = |name: &str| var(name).filter(|value| !value.is_empty());
+        let default = Self::default();
+
+        Self {
+            daemon_bin: var(DAEMON_BIN_ENV).map(PathBuf::from),
+            socket_path: var(SOCKET_ENV).map_or(default.socket_path, PathBuf::from),
+            max_connections: var(MAX_CONNECTIONS_ENV).map_or(default.max_connections, |value| {
+                match value.parse::<usize>() {
+                    Ok(max) if max > 0 => max,
+                    _ => {
+                        warn!(
+                            "Invalid {MAX_CONNECTIONS_ENV} value \"{value}\", using the default {}",
+                            default.max_connections
+                        );
+                        default.max_connections
+                    }
+                }
+            }),
+            daemon_timeout_secs: clamped_var(
+                DAEMON_TIMEOUT_ENV,
+                var(DAEMON_TIMEOUT_ENV),
+                default.daemon_timeout_secs,
+                DAEMON_TIMEOUT_RANGE_SECS,
+            ),
+            cmd_delay_ms: clamped_var(
+                CMD_DELAY_ENV,
+                var(CMD_DELAY_ENV),
+                default.cmd_delay_ms,
+                CMD_DELAY_RANGE_MS,
+            ),
+            brightness_curve: var(BRIGHTNESS_CURVE_ENV).map_or(default.brightness_curve, |value| {
+                value.parse().unwrap_or_else(|err| {
+                    warn!("{err}, falling back to the linear brightness curve");
+                    default.brightness_curve
+                })
+            }),
+            brightness_aggregate: var(BRIGHTNESS_AGGREGATE_ENV).map_or(
+                default.brightness_aggregate,
+                |value| {
+                    value.parse().unwrap_or_else(|err| {
+                        warn!("{err}, falling back to the average brightness");
+                        default.brightness_aggregate
+                    })
+                },
+            ),
+            gamut_clamp: var(GAMUT_CLAMP_ENV).map_or(default.gamut_clamp, |value| {
+                value.parse().unwrap_or_else(|err| {
+                    warn!("{err}, falling back to the nearest edge gamut clamping");
+                    default.gamut_clamp
+                })
+            }),
+            alert_device: var(ALERT_DEVICE_ENV).and_then(|value| match parse_hex_address(&value) {
+                Ok(addr) => Some(addr),
+                Err(err) => {
+                    warn!(
+                        "Invalid {ALERT_DEVICE_ENV} address \"{value}\": {err}, expected xx:xx:xx:xx:xx:xx"
+                    );
+                    None
+                }
+            }),
+            adapter_reset: var(ADAPTER_RESET_ENV).is_some_and(|value| value == "1"),
+            trace_packets: var(TRACE_ENV).is_some_and(|value| value == "packets"),
+            log_level: var(LOG_LEVEL_ENV).map_or(default.log_level, |value| {
+                parse_log_level(&value).unwrap_or_else(|| {
+                    warn!("Invalid {LOG_LEVEL_ENV} value \"{value}\", using {}", default.log_level);
+                    default.log_level
+                })
+            }),
+            json_logs: var(LOG_FORMAT_ENV).is_some_and(|value| value == "json"),
+        }
+    }
+
+    /// Env var names along their effective value, for display
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        vec![
+            (
+                DAEMON_BIN_ENV,
+                self.daemon_bin
+                    .as_ref()
+                    .map_or_else(|| "none".into(), |path| path.display().to_string()),
+            ),
+            (SOCKET_ENV, self.socket_path.display().to_string()),
+            (MAX_CONNECTIONS_ENV, self.max_connections.to_string()),
+            (DAEMON_TIMEOUT_ENV, self.daemon_timeout_secs.to_string()),
+            (CMD_DELAY_ENV, self.cmd_delay_ms.to_string()),
+            (BRIGHTNESS_CURVE_ENV, format!("{:?}", self.brightness_curve)),
+            (
+                BRIGHTNESS_AGGREGATE_ENV,
+                format!("{:?}", self.brightness_aggregate),
+            ),
+            (GAMUT_CLAMP_ENV, format!("{:?}", self.gamut_clamp)),
+            (
+                ALERT_DEVICE_ENV,
+                self.alert_device
+                    .as_ref()
+                    .map_or_else(|| "none".into(), addr_to_str),
+            ),
+            (ADAPTER_RESET_ENV, self.adapter_reset.to_string()),
+            (TRACE_ENV, self.trace_packets.to_string()),
+            (LOG_LEVEL_ENV, self.log_level.to_string()),
+            (LOG_FORMAT_ENV, if self.json_logs { "json" } else { "plain" }.into()),
+        ]
+    }
+}
+
+/// Parses the number, values out of the range are clamped and invalid ones fall back to the default
+fn clamped_var(name: &str, value: Option<String>, default: u64, range: RangeInclusive<u64>) -> u64 {
+    let Some(value) = value else {
+        return default;
+    };
+
+    match value.parse::<u64>() {
+        Ok(number) => {
+            let clamped = number.clamp(*range.start(), *range.end());
+            if clamped != number {
+                warn!("{name} value {number} is out of {range:?}, clamped to {clamped}");
+            }
+
+            clamped
+        }
+        Err(_) => {
+            warn!("Invalid {name} value \"{value}\", using the default {default}");
+            default
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::ops::RangeInclusive;
+
 use uuid::{uuid, Uuid};
 
 pub type MaskT = u16;
@@ -5,6 +7,14 @@ pub type MaskT = u16;
 pub const APP_ID: &str = "Rustbee";
 pub const HUE_BAR_1_ADDR: [u8; ADDR_LEN] = [0xE8, 0xD4, 0xEA, 0xC4, 0x62, 0x00];
 pub const HUE_BAR_2_ADDR: [u8; ADDR_LEN] = [0xEC, 0x27, 0xA7, 0xD6, 0x5A, 0x9C];
+/// Known Philips/Signify address prefixes (OUI) of Hue devices
+pub const HUE_OUIS: [[u8; 3]; 5] = [
+    [0x00, 0x17, 0x88],
+    [0xC4, 0x29, 0x96],
+    [0xE8, 0xD4, 0xEA],
+    [0xEC, 0x27, 0xA7],
+    [0xEC, 0xB5, 0xFA],
+];
 
 // Thanks to https://gist.github.com/shinyquagsire23/f7907fdf6b470200702e75a30135caf3 for the UUIDs
 pub const LIGHT_SERVICES_UUID: Uuid = uuid!("932c32bd-0000-47a2-835a-a8d455b859dd");
@@ -12,28 +22,87 @@ pub const POWER_UUID: Uuid = uuid!("932c32bd-0002-47a2-835a-a8d455b859dd");
 pub const BRIGHTNESS_UUID: Uuid = uuid!("932c32bd-0003-47a2-835a-a8d455b859dd");
 pub const TEMPERATURE_UUID: Uuid = uuid!("932c32bd-0004-47a2-835a-a8d455b859dd");
 pub const COLOR_UUID: Uuid = uuid!("932c32bd-0005-47a2-835a-a8d455b859dd");
+pub const CONFIG_SERVICES_UUID: Uuid = uuid!("0000fe0f-0000-1000-8000-00805f9b34fb");
+/// Writable unlike the GAP device name, at most MAX_NAME_LEN bytes
+pub const DEVICE_NAME_UUID: Uuid = uuid!("97fe6561-0003-4f62-86e9-b71ee2da3d22");
 pub const MISC_SERVICES_UUID: Uuid = uuid!("0000180a-0000-1000-8000-00805f9b34fb");
 pub const MODEL_UUID: Uuid = uuid!("00002a24-0000-1000-8000-00805f9b34fb");
 pub const MANUFACTURER_UUID: Uuid = uuid!("00002a29-0000-1000-8000-00805f9b34fb");
+/// Software revision string, Hue lights report their firmware version there
+pub const FIRMWARE_UUID: Uuid = uuid!("00002a28-0000-1000-8000-00805f9b34fb");
 
 #[cfg(target_os = "windows")]
 pub const SOCKET_PATH: &str = r#"\\.\pipe\rustbee-daemon.sock"#;
 #[cfg(target_os = "windows")]
 pub const LOG_PATH: &str = "./rustbee.log"; // TODO: Use APPDATA
 
+#[cfg(target_os = "windows")]
+pub const DAEMON_BIN: &str = "rustbee-daemon.exe";
+
 #[cfg(not(target_os = "windows"))]
 pub const SOCKET_PATH: &str = "/var/run/rustbee-daemon.sock";
 #[cfg(not(target_os = "windows"))]
 pub const LOG_PATH: &str = "/var/log/rustbee.log";
+#[cfg(not(target_os = "windows"))]
+pub const DAEMON_BIN: &str = "rustbee-daemon";
+
+/// Env var to specify the full path of the daemon executable
+pub const DAEMON_BIN_ENV: &str = "RUSTBEE_DAEMON_BIN";
+/// Env var to override SOCKET_PATH, e.g. for a location writable without root. The client and the
+/// daemon must use the same one
+pub const SOCKET_ENV: &str = "RUSTBEE_SOCKET";
+/// Env var to override the max connections the daemon processes at the same time
+pub const MAX_CONNECTIONS_ENV: &str = "RUSTBEE_MAX_CONNECTIONS";
+/// Excess connections wait for a slot so a misbehaving client cannot flood the BLE adapter
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+/// Env var of the seconds the daemon stays up without any connection before exiting, clamped to
+/// DAEMON_TIMEOUT_RANGE_SECS
+pub const DAEMON_TIMEOUT_ENV: &str = "RUSTBEE_DAEMON_TIMEOUT";
+pub const DEFAULT_DAEMON_TIMEOUT_SECS: u64 = 60 * 10;
+pub const DAEMON_TIMEOUT_RANGE_SECS: RangeInclusive<u64> = 10..=60 * 60 * 24 * 7;
+/// Env var of the milliseconds to wait between two commands to a device, clamped to
+/// CMD_DELAY_RANGE_MS. Below 50ms the writes risk hitting the Hue GATT limit and being dropped
+pub const CMD_DELAY_ENV: &str = "RUSTBEE_CMD_DELAY_MS";
+/// https://developers.meethue.com/develop/get-started-2/core-concepts/#limitations
+pub const DEFAULT_CMD_DELAY_MS: u64 = 100;
+pub const CMD_DELAY_RANGE_MS: RangeInclusive<u64> = 10..=5000;
+/// Interval between the writes of a fade, the command delay is used instead if it's longer
+pub const FADE_STEP_MS: u64 = 100;
+/// Env var to select the brightness curve: linear (default), square or cie-lightness
+pub const BRIGHTNESS_CURVE_ENV: &str = "RUSTBEE_BRIGHTNESS_CURVE";
+/// Env var to select how the GUI sums up the devices brightness: average (default), mode, min, max
+pub const BRIGHTNESS_AGGREGATE_ENV: &str = "RUSTBEE_BRIGHTNESS_AGGREGATE";
+/// Env var to select how out of gamut colors are clamped: nearest-edge (default) or desaturate
+pub const GAMUT_CLAMP_ENV: &str = "RUSTBEE_GAMUT_CLAMP";
+/// Env var of the device address (xx:xx:xx:xx:xx:xx) the daemon flashes red when commands to a
+/// device keep failing, for unattended setups
+pub const ALERT_DEVICE_ENV: &str = "RUSTBEE_ALERT_DEVICE";
+/// Env var (set to 1) to power cycle the adapter before each discovery, Linux only. Same as
+/// starting the daemon with --adapter-reset
+pub const ADAPTER_RESET_ENV: &str = "RUSTBEE_ADAPTER_RESET";
+/// Env var, set to "packets" to log the raw requests and responses of the client and daemon at
+/// trace level. Same as the --trace-packets flag of the CLI and daemon
+pub const TRACE_ENV: &str = "RUSTBEE_TRACE";
+/// Env var, level from which the logs are written (error, warn, info, debug, trace or off),
+/// LOG_LEVEL by default
+pub const LOG_LEVEL_ENV: &str = "RUSTBEE_LOG";
+/// Env var, set to "json" to write the logs as one JSON object per line instead of plain text
+pub const LOG_FORMAT_ENV: &str = "RUSTBEE_LOG_FORMAT";
 
 // Levels ERROR < WARN < INFO < DEBUG < TRACE
 pub const LOG_LEVEL: log::Level = log::Level::Debug;
+/// Size from which the log file is rotated to LOG_PATH.1, LOG_PATH.2... up to LOG_KEEP files
+pub const LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+pub const LOG_KEEP: usize = 3;
 
 /// Buffer input
 /// Sent by the client
 /// Received by the server
-pub const BUFFER_LEN: usize = ADDR_LEN + 2 + 1 + DATA_LEN; // ADDR_LEN bytes BLE UUID length + 2 for the flags (u16 divided by 2 u8)
-                                                           // + 1 for the SET/GET flag + DATA_LEN for values when SET
+pub const BUFFER_LEN: usize = 1 + ADDR_LEN + 2 + 1 + DATA_LEN; // 1 for PROTOCOL_VERSION + ADDR_LEN bytes BLE UUID length + 2 for the flags (u16 divided by 2 u8)
+                                                               // + 1 for the SET/GET flag + DATA_LEN for values when SET
+/// First byte of every request, to be bumped when the layout of the requests or the outputs
+/// changes so a daemon left running from another version rejects them (OutputCode::VersionMismatch)
+pub const PROTOCOL_VERSION: u8 = 1;
 
 /// Buffer output
 /// Sent by the server
@@ -41,17 +110,49 @@ pub const BUFFER_LEN: usize = ADDR_LEN + 2 + 1 + DATA_LEN; // ADDR_LEN bytes BLE
 pub const OUTPUT_LEN: usize = 1 + 19; // 1 for output status code + 20 bytes output data (mostly because of strings)
 
 pub const DATA_LEN: usize = 10;
+/// Longer device names are truncated, it's the max length of a BLE device name. Names longer than
+/// an output are streamed in several packets
+pub const MAX_NAME_LEN: usize = 248;
 pub const ADDR_LEN: usize = 6;
+/// Max number of devices of a BATCH request since their count is a u8
+pub const MAX_BATCH_LEN: usize = u8::MAX as _;
+
+/// Index of the request options byte on the data (set/get flag excluded). See options
+pub const OPTIONS_IDX: usize = DATA_LEN - 1;
+
+/// Color temperature range accepted when setting it, values out of it are clamped
+pub const MIN_COLOR_TEMP_KELVIN: u16 = 2000;
+pub const MAX_COLOR_TEMP_KELVIN: u16 = 6500;
 
 pub const GUI_SAVE_INTERVAL_SECS: u64 = 60;
+/// Max time to wait for the daemon process to exit after asking it to shutdown
+pub const DAEMON_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+/// Max time for the daemon to answer a shutdown request before falling back to signals (Linux) or
+/// terminating the process (Windows)
+pub const SHUTDOWN_REQUEST_TIMEOUT_SECS: u64 = 2;
+/// Max time for a newly launched daemon to answer a ping
+pub const DAEMON_READY_TIMEOUT_SECS: u64 = 5;
+/// Max time for the daemon to answer a ping, an older daemon not knowing it may never answer
+pub const PING_TIMEOUT_MS: u64 = 500;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OutputCode {
     Success,
+    /// The data may hold a short UTF-8 reason, null terminated when it's shorter than the data
+    /// (see device::failure_reason)
     Failure,
     DeviceNotFound,
     Streaming,
     StreamEOF,
+    /// The command was sent too soon after the previous one (see options::NO_WAIT), the data
+    /// holds the milliseconds to wait before retrying (u16 little-endian)
+    RateLimited,
+    /// The device isn't paired, on Windows pairing is managed in the Bluetooth settings
+    NotPaired,
+    /// The device cannot be connected or dropped the connection (out of range, powered off)
+    NotConnected,
+    /// The request PROTOCOL_VERSION isn't the daemon's, the first data byte holds the daemon's
+    VersionMismatch,
 }
 
 impl OutputCode {
@@ -68,6 +169,10 @@ impl From<u8> for OutputCode {
             2 => OutputCode::DeviceNotFound,
             3 => OutputCode::Streaming,
             4 => OutputCode::StreamEOF,
+            5 => OutputCode::RateLimited,
+            6 => OutputCode::NotPaired,
+            7 => OutputCode::NotConnected,
+            8 => OutputCode::VersionMismatch,
             x => panic!("Output code is {x} which is not handled"),
         }
     }
@@ -81,6 +186,10 @@ impl From<OutputCode> for u8 {
             OutputCode::DeviceNotFound => 2,
             OutputCode::Streaming => 3,
             OutputCode::StreamEOF => 4,
+            OutputCode::RateLimited => 5,
+            OutputCode::NotPaired => 6,
+            OutputCode::NotConnected => 7,
+            OutputCode::VersionMismatch => 8,
         }
     }
 }
@@ -100,6 +209,13 @@ pub mod flags {
     pub const BRIGHTNESS: MaskT = 7;
     pub const NAME: MaskT = 8;
     pub const SEARCH_NAME: MaskT = 9;
+    pub const BENCH_DISCOVERY: MaskT = 10;
+    pub const SUBSCRIBE: MaskT = 11;
+    pub const COLOR_TEMP: MaskT = 12;
+    pub const BATCH: MaskT = 13;
+    pub const LIST: MaskT = 14;
+    pub const SHUTDOWN: MaskT = 15;
+    pub const PING: MaskT = 16;
 }
 
 pub mod masks {
@@ -112,6 +228,65 @@ pub mod masks {
     pub const COLOR_HEX: MaskT = 1 << 4;
     pub const COLOR_XY: MaskT = 1 << 5;
     pub const BRIGHTNESS: MaskT = 1 << 6;
+    /// When setting, the first data byte is the length of the new name and its UTF-8 bytes follow
+    /// the request. When reading, options::MODEL and the like read another string instead
     pub const NAME: MaskT = 1 << 7;
     pub const SEARCH_NAME: MaskT = 1 << 8;
+    /// Times the discovery of the device without using the daemon cache
+    pub const BENCH_DISCOVERY: MaskT = 1 << 9;
+    /// Streams the power, brightness and color changes notified by the device
+    pub const SUBSCRIBE: MaskT = 1 << 10;
+    /// Mireds (u16 little endian) of the temperature characteristic, read as 0 when the device
+    /// doesn't support color temperature
+    pub const COLOR_TEMP: MaskT = 1 << 11;
+    /// Runs the commands on several devices. The address of the request is ignored, it's followed
+    /// by the number of devices (u8) and their addresses (ADDR_LEN bytes each). The daemon answers
+    /// one output per device in the same order. The commands streaming their output (name,
+    /// search, bench discovery and subscribe) can't be batched and fail for every device
+    pub const BATCH: MaskT = 1 << 12;
+    /// Streams the address and connection state (1 byte) of every device cached by the daemon,
+    /// the address of the request is ignored
+    pub const LIST: MaskT = 1 << 13;
+    /// Stops the daemon once the requests in progress are done, it's answered before stopping
+    pub const SHUTDOWN: MaskT = 1 << 14;
+    /// Answered Success right away without touching any device, to check the daemon is ready
+    pub const PING: MaskT = 1 << 15;
+}
+
+/// Bits of the data byte at OPTIONS_IDX, only used when setting colors/brightness unless stated
+/// otherwise
+pub mod options {
+    /// Powers the device on (if it's off) before applying the values since some firmwares
+    /// ignore them while being off
+    pub const ENSURE_ON: u8 = 1 << 0;
+    /// Used along ENSURE_ON, powers the device back off after applying the values if it was off
+    pub const RESTORE_OFF: u8 = 1 << 1;
+    /// Instead of waiting for the Hue rate limit, the daemon answers OutputCode::RateLimited if
+    /// the previous command to the device was too recent so the client can back off
+    pub const NO_WAIT: u8 = 1 << 2;
+    /// Used when searching, only streams the devices whose address starts with one of HUE_OUIS
+    pub const HUE_ONLY: u8 = 1 << 3;
+    /// Used when reading the name, reads the model number of the device information instead.
+    /// Only one of MODEL, MANUFACTURER and FIRMWARE is used, in that order
+    pub const MODEL: u8 = 1 << 4;
+    /// Same as MODEL for the manufacturer name
+    pub const MANUFACTURER: u8 = 1 << 5;
+    /// Same as MODEL for the firmware version
+    pub const FIRMWARE: u8 = 1 << 6;
+    /// Used when reading the color temperature, reads the range the device supports instead:
+    /// coolest then warmest mireds (u16 little endian each), 0s when the device doesn't report it
+    pub const TEMP_RANGE: u8 = 1 << 7;
+}
+
+/// Indexes of the output data (code excluded) when a read has more than one command returning
+/// data, each one has its own region so they don't overwrite each other. The name is streamed
+/// entirely before the final output
+pub mod regions {
+    pub const POWER_IDX: usize = 0;
+    pub const BRIGHTNESS_IDX: usize = 1;
+    /// 4 bytes, see Xy::from_le_bytes
+    pub const COLOR_IDX: usize = 2;
+    pub const CONNECTED_IDX: usize = 6;
+    /// 2 bytes, mireds as little endian u16
+    pub const COLOR_TEMP_IDX: usize = 7;
 }
@@ -1,24 +1,50 @@
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::{future, stream, StreamExt};
 use interprocess::local_socket::{
     tokio::Stream as TokioStream, traits::tokio::Stream as _, GenericFilePath, ToFsName as _,
 };
 use log::*;
-use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
 use tokio::sync::Mutex;
 
-#[cfg(feature = "ffi")]
 use interprocess::local_socket::{traits::Stream as _, Stream as SyncStream};
 
+use crate::brightness::BrightnessCurve;
+use crate::colors::{kelvin_to_mireds, mireds_to_kelvin, Xy};
+use crate::config::Config;
 use crate::constants::{masks::*, *};
+use crate::logger::trace_packet;
+use crate::utils::{addr_to_str, truncate_name};
 use crate::InnerDevice;
 
 pub const EMPTY_BUFFER: [u8; DATA_LEN + 1] = [0; DATA_LEN + 1];
 
+/// Set when the daemon answered OutputCode::VersionMismatch, see daemon_version_mismatched
+static VERSION_MISMATCH: AtomicBool = AtomicBool::new(false);
+
+/// Whether a request was rejected because the running daemon is from another version of rustbee
+pub fn daemon_version_mismatched() -> bool {
+    VERSION_MISMATCH.load(Ordering::Relaxed)
+}
+
+/// Set by enable_dry_run, see HueDevice::<Client>::write_request
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// The requests setting a value are logged instead of sent to the daemon, the reads still are
+pub fn enable_dry_run() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+pub fn dry_run_enabled() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
 #[derive(Debug)]
 pub struct Error(pub String);
 
@@ -30,12 +56,64 @@ impl std::fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
+/// Reads the 4 color bytes of the color characteristic payload, errors (and logs the payload) if
+/// it's shorter since some firmwares may use another encoding
+pub(crate) fn color_from_payload(addr: &[u8; ADDR_LEN], bytes: &[u8]) -> Result<[u8; 4], Error> {
+    let mut buf = [0u8; 4];
+    let len = buf.len();
+
+    if bytes.len() < len {
+        let msg = format!(
+            "Color payload of device {} is {} byte(s) long instead of {len}: {bytes:?}",
+            addr_to_str(addr),
+            bytes.len()
+        );
+        error!("{msg}");
+
+        return Err(Error(msg));
+    }
+
+    buf.copy_from_slice(&bytes[..len]);
+
+    Ok(buf)
+}
+
+/// Device information strings can be null terminated or padded depending on the firmware
+pub(crate) fn info_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(name_bytes(bytes)).trim().into()
+}
+
+/// Coolest and warmest mireds following the value of the temperature characteristic payload,
+/// None when the payload is only the value or the range is empty
+pub(crate) fn temp_range_from_payload(bytes: &[u8]) -> Option<(u16, u16)> {
+    let [_, _, cool_low, cool_high, warm_low, warm_high, ..] = bytes else {
+        return None;
+    };
+    let cool = u16::from_le_bytes([*cool_low, *cool_high]);
+    let warm = u16::from_le_bytes([*warm_low, *warm_high]);
+
+    (cool != 0 && cool < warm).then_some((cool, warm))
+}
+
 #[derive(Debug, Default, Hash)]
 pub struct FoundDevice {
     pub address: [u8; ADDR_LEN],
     pub name: String,
 }
 
+/// Serialized with the formatted address along the raw bytes so it can be displayed as is
+impl serde::Serialize for FoundDevice {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct as _;
+
+        let mut state = serializer.serialize_struct("FoundDevice", 3)?;
+        state.serialize_field("address", &addr_to_str(&self.address))?;
+        state.serialize_field("address_bytes", &self.address)?;
+        state.serialize_field("name", &self.name)?;
+        state.end()
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Client;
 #[derive(Clone, Debug, Default)]
@@ -48,6 +126,9 @@ pub struct FFI;
 pub struct HueDevice<Type> {
     pub addr: [u8; ADDR_LEN],
     pub device: Option<InnerDevice>,
+    /// Client only, omits the CONNECT flag so the daemon doesn't ensure the connection before
+    /// each command. Only use it after explicitly connecting to the device
+    pub skip_connect: bool,
     _type: PhantomData<Type>,
 }
 
@@ -56,6 +137,7 @@ impl Default for HueDevice<Server> {
         Self {
             addr: Default::default(),
             device: Default::default(),
+            skip_connect: false,
             _type: Default::default(),
         }
     }
@@ -65,6 +147,7 @@ impl Default for HueDevice<Client> {
         Self {
             addr: Default::default(),
             device: Default::default(),
+            skip_connect: false,
             _type: Default::default(),
         }
     }
@@ -75,6 +158,7 @@ impl Default for HueDevice<FFI> {
         Self {
             addr: Default::default(),
             device: Default::default(),
+            skip_connect: false,
             _type: Default::default(),
         }
     }
@@ -121,46 +205,349 @@ where
 
 pub type CmdOutput = (OutputCode, [u8; OUTPUT_LEN - 1]);
 
+/// Typed data of a daemon output, see Response::decode
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Power(bool),
+    /// Percentage from 0 to 100, see BrightnessCurve
+    Brightness(u8),
+    ColorXy(Xy),
+    /// Kelvin
+    ColorTemp(u16),
+    Name(String),
+    Connected(bool),
+    /// The command doesn't return data
+    None,
+}
+
+/// Every readable value of a device, see HueDevice::<Client>::get_all_state
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceState {
+    pub connected: bool,
+    pub power: bool,
+    /// Percentage from 0 to 100, see BrightnessCurve
+    pub brightness: u8,
+    pub color: Xy,
+    pub name: String,
+}
+
+impl DeviceState {
+    /// Decodes the final output of a read of every command, the name being streamed before it.
+    /// See constants::regions
+    pub(crate) fn decode(name: String, (code, data): CmdOutput) -> Result<Self, OutputCode> {
+        if !code.is_success() {
+            return Err(code);
+        }
+
+        let color = &data[regions::COLOR_IDX..regions::COLOR_IDX + 4];
+
+        Ok(Self {
+            connected: data[regions::CONNECTED_IDX] == 1,
+            power: data[regions::POWER_IDX] == 1,
+            brightness: BrightnessCurve::current()
+                .to_percent(data[regions::BRIGHTNESS_IDX])
+                .round() as _,
+            color: Xy::from_le_bytes([color[0], color[1], color[2], color[3]]),
+            name,
+        })
+    }
+}
+
+/// Mask of the command reading the characteristic, for the ones notifying state changes
+pub(crate) fn notification_mask(uuid: &uuid::Uuid) -> Option<MaskT> {
+    match *uuid {
+        POWER_UUID => Some(POWER),
+        BRIGHTNESS_UUID => Some(BRIGHTNESS),
+        COLOR_UUID => Some(COLOR_XY),
+        _ => None,
+    }
+}
+
+/// Reason of a Failure output sent by the daemon, None for other codes or when it's not given
+pub fn failure_reason((code, data): &CmdOutput) -> Option<&str> {
+    if *code != OutputCode::Failure {
+        return None;
+    }
+
+    std::str::from_utf8(name_bytes(data))
+        .ok()
+        .filter(|reason| !reason.is_empty())
+}
+
+/// Bytes of a name output, up to the first null byte
+fn name_bytes(data: &[u8]) -> &[u8] {
+    let len = data.iter().position(|b| *b == b'\0').unwrap_or(data.len());
+    &data[..len]
+}
+
+/// What a request setting a value would do e.g. "set brightness to 60% on aa:bb:cc:dd:ee:ff",
+/// None for the other requests
+fn set_action(request: &[u8]) -> Option<String> {
+    let flags_idx = 1 + ADDR_LEN;
+    let flags = MaskT::from_le_bytes([request[flags_idx], request[flags_idx + 1]]);
+    let data = &request[flags_idx + 2..BUFFER_LEN];
+    if data[0] != SET {
+        return None;
+    }
+
+    let value = &data[1..];
+    let mut actions = Vec::new();
+    if flags & POWER != 0 {
+        let state = if value[0] == 1 { "on" } else { "off" };
+        actions.push(format!("power {state}"));
+    }
+    if flags & BRIGHTNESS != 0 {
+        let percent = BrightnessCurve::current().to_percent(value[0]);
+        actions.push(format!("brightness to {percent:.0}%"));
+    }
+    if flags & COLOR_TEMP != 0 {
+        let kelvin = mireds_to_kelvin(u16::from_le_bytes([value[0], value[1]]));
+        actions.push(format!("color temperature to {kelvin}K"));
+    }
+    if flags & (COLOR_XY | COLOR_RGB | COLOR_HEX) != 0 {
+        let xy = Xy::from_le_bytes([value[0], value[1], value[2], value[3]]);
+        actions.push(format!("color to x {:.4} y {:.4}", xy.x, xy.y));
+    }
+    if flags & NAME != 0 {
+        let name = request.get(BUFFER_LEN..BUFFER_LEN + value[0] as usize)?;
+        actions.push(format!("name to \"{}\"", String::from_utf8_lossy(name)));
+    }
+    if actions.is_empty() {
+        return None;
+    }
+
+    let target = if flags & BATCH != 0 {
+        request[BUFFER_LEN + 1..]
+            .chunks_exact(ADDR_LEN)
+            .map(|addr| addr_to_str(addr.try_into().unwrap()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    } else {
+        addr_to_str(request[1..=ADDR_LEN].try_into().unwrap())
+    };
+
+    Some(format!("set {} on {target}", actions.join(", ")))
+}
+
+/// Evenly spaced values from `from` to `to` (both included) for a fade lasting the duration with
+/// a step every interval
+pub fn fade_steps(from: f64, to: f64, duration: Duration, interval: Duration) -> Vec<f64> {
+    let steps = (duration.as_millis() / interval.as_millis().max(1)).max(1) as usize;
+
+    (0..=steps)
+        .map(|step| from + (to - from) * step as f64 / steps as f64)
+        .collect()
+}
+
+/// Writes faster than the command delay would be delayed by the daemon anyway
+fn fade_interval() -> Duration {
+    Duration::from_millis(u64::max(FADE_STEP_MS, Config::current().cmd_delay_ms))
+}
+
+impl Response {
+    /// Decodes the output of a command that reads data given the mask it was sent with. Errors
+    /// with the output code if it's not a success. Since only one command of the mask can return
+    /// data (see the daemon), the first one of NAME, COLOR_*, COLOR_TEMP, BRIGHTNESS, POWER is
+    /// decoded and CONNECT only if it's alone
+    pub fn decode(mask: MaskT, (code, data): CmdOutput) -> Result<Self, OutputCode> {
+        if !code.is_success() {
+            return Err(code);
+        }
+
+        let response = if mask & NAME != 0 {
+            Self::Name(String::from_utf8_lossy(name_bytes(&data)).into_owned())
+        } else if mask & (COLOR_XY | COLOR_RGB | COLOR_HEX) != 0 {
+            Self::ColorXy(Xy::from_le_bytes([data[0], data[1], data[2], data[3]]))
+        } else if mask & COLOR_TEMP != 0 {
+            Self::ColorTemp(mireds_to_kelvin(u16::from_le_bytes([data[0], data[1]])))
+        } else if mask & BRIGHTNESS != 0 {
+            Self::Brightness(BrightnessCurve::current().to_percent(data[0]).round() as _)
+        } else if mask & POWER != 0 {
+            Self::Power(data[0] == 1)
+        } else if mask == CONNECT {
+            Self::Connected(data[0] == 1)
+        } else {
+            Self::None
+        };
+
+        Ok(response)
+    }
+
+    /// Decodes a streamed output of a subscription: the mask of the changed value (u16 little
+    /// endian) followed by its data like a read of it
+    pub fn decode_notification(data: [u8; OUTPUT_LEN - 1]) -> Self {
+        let mask = MaskT::from_le_bytes([data[0], data[1]]);
+        let mut value = [0; OUTPUT_LEN - 1];
+        value[..data.len() - 2].copy_from_slice(&data[2..]);
+
+        Self::decode(mask, (OutputCode::Success, value)).unwrap_or(Self::None)
+    }
+}
+
 impl HueDevice<Client>
 where
     HueDevice<Client>: Default + std::fmt::Debug,
 {
+    fn connect_mask(&self) -> MaskT {
+        if self.skip_connect {
+            0
+        } else {
+            CONNECT
+        }
+    }
+
     pub async fn set_power(&self, state: bool) -> OutputCode {
         let mut buf = EMPTY_BUFFER;
         buf[0] = SET;
         buf[1] = state as _;
 
-        self.send_packet_to_daemon(CONNECT | POWER, buf).await.0
+        self.send_packet_to_daemon(self.connect_mask() | POWER, buf).await.0
     }
 
     pub async fn get_power(&self) -> CmdOutput {
-        self.send_packet_to_daemon(CONNECT | POWER, EMPTY_BUFFER)
+        self.send_packet_to_daemon(self.connect_mask() | POWER, EMPTY_BUFFER)
+            .await
+    }
+
+    /// Sets the power state of every device in batched requests, the output codes are in the same
+    /// order as the addresses
+    pub async fn set_power_many(addrs: &[[u8; ADDR_LEN]], state: bool) -> Vec<OutputCode> {
+        let mut buf = EMPTY_BUFFER;
+        buf[0] = SET;
+        buf[1] = state as _;
+
+        Self::send_batch_to_daemon(addrs, CONNECT | POWER, buf)
             .await
+            .into_iter()
+            .map(|(code, _)| code)
+            .collect()
     }
 
     pub async fn set_brightness(&self, value: u8) -> OutputCode {
+        self.set_brightness_with_options(value, 0).await
+    }
+
+    /// See constants::options for the options
+    pub async fn set_brightness_with_options(&self, value: u8, options: u8) -> OutputCode {
         let mut buf = EMPTY_BUFFER;
         buf[0] = SET;
-        buf[1] = (((value as f32) / 100.) * 0xff as f32) as _;
+        buf[1] = BrightnessCurve::current().to_raw(value as _);
+        buf[OPTIONS_IDX + 1] = options;
 
-        self.send_packet_to_daemon(CONNECT | BRIGHTNESS, buf)
+        self.send_packet_to_daemon(self.connect_mask() | BRIGHTNESS, buf)
             .await
             .0
     }
 
+    /// Same as set_brightness but if the previous command to the device was too recent, the
+    /// daemon doesn't wait and it returns the delay to retry after, see options::NO_WAIT
+    pub async fn try_set_brightness(&self, value: u8) -> Result<OutputCode, Duration> {
+        let mut buf = EMPTY_BUFFER;
+        buf[0] = SET;
+        buf[1] = BrightnessCurve::current().to_raw(value as _);
+        buf[OPTIONS_IDX + 1] = options::NO_WAIT;
+
+        let (code, data) = self
+            .send_packet_to_daemon(self.connect_mask() | BRIGHTNESS, buf)
+            .await;
+
+        if code == OutputCode::RateLimited {
+            return Err(Duration::from_millis(
+                u16::from_le_bytes([data[0], data[1]]) as _
+            ));
+        }
+
+        Ok(code)
+    }
+
     pub async fn get_brightness(&self) -> CmdOutput {
-        self.send_packet_to_daemon(CONNECT | BRIGHTNESS, EMPTY_BUFFER)
+        self.send_packet_to_daemon(self.connect_mask() | BRIGHTNESS, EMPTY_BUFFER)
             .await
     }
 
     pub async fn get_colors(&self, color_mask: MaskT) -> CmdOutput {
         assert!([COLOR_XY, COLOR_RGB, COLOR_HEX].contains(&color_mask));
 
-        self.send_packet_to_daemon(CONNECT | color_mask, EMPTY_BUFFER)
+        self.send_packet_to_daemon(self.connect_mask() | color_mask, EMPTY_BUFFER)
             .await
     }
 
+    /// Reads only the chromaticity of the device, the brightness is a separate read
+    pub async fn get_color_xy(&self) -> Result<Xy, OutputCode> {
+        match Response::decode(COLOR_XY, self.get_colors(COLOR_XY).await)? {
+            Response::ColorXy(xy) => Ok(xy),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Kelvin, clamped to MIN_COLOR_TEMP_KELVIN..=MAX_COLOR_TEMP_KELVIN
+    pub async fn set_color_temp(&self, kelvin: u16) -> OutputCode {
+        self.set_color_temp_with_options(kelvin, 0).await
+    }
+
+    /// See constants::options for the options
+    pub async fn set_color_temp_with_options(&self, kelvin: u16, options: u8) -> OutputCode {
+        let mut buf = EMPTY_BUFFER;
+        buf[0] = SET;
+        buf[1..3].copy_from_slice(&kelvin_to_mireds(kelvin).to_le_bytes());
+        buf[OPTIONS_IDX + 1] = options;
+
+        self.send_packet_to_daemon(self.connect_mask() | COLOR_TEMP, buf)
+            .await
+            .0
+    }
+
+    /// Kelvin, None if the device doesn't support color temperature. See masks::COLOR_TEMP
+    pub async fn get_color_temp(&self) -> Result<Option<u16>, OutputCode> {
+        let output = self
+            .send_packet_to_daemon(self.connect_mask() | COLOR_TEMP, EMPTY_BUFFER)
+            .await;
+
+        if output.0.is_success() && output.1[..2] == [0, 0] {
+            return Ok(None);
+        }
+
+        match Response::decode(COLOR_TEMP, output)? {
+            Response::ColorTemp(kelvin) => Ok(Some(kelvin)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Kelvin range (warmest, coolest) supported by the device, None if it isn't reported (or the
+    /// daemon ignores options::TEMP_RANGE) so MIN_COLOR_TEMP_KELVIN..=MAX_COLOR_TEMP_KELVIN applies
+    pub async fn get_color_temp_range(&self) -> Result<Option<(u16, u16)>, OutputCode> {
+        let mut buf = EMPTY_BUFFER;
+        buf[OPTIONS_IDX + 1] = options::TEMP_RANGE;
+
+        let (code, data) = self
+            .send_packet_to_daemon(self.connect_mask() | COLOR_TEMP, buf)
+            .await;
+        if !code.is_success() {
+            return Err(code);
+        }
+
+        let cool = u16::from_le_bytes([data[0], data[1]]);
+        let warm = u16::from_le_bytes([data[2], data[3]]);
+        if cool == 0 || warm == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some((mireds_to_kelvin(warm), mireds_to_kelvin(cool))))
+    }
+
     pub async fn set_colors(&self, scaled_x: u16, scaled_y: u16, color_mask: MaskT) -> OutputCode {
+        self.set_colors_with_options(scaled_x, scaled_y, color_mask, 0)
+            .await
+    }
+
+    /// See constants::options for the options
+    pub async fn set_colors_with_options(
+        &self,
+        scaled_x: u16,
+        scaled_y: u16,
+        color_mask: MaskT,
+        options: u8,
+    ) -> OutputCode {
         assert!([COLOR_XY, COLOR_RGB, COLOR_HEX].contains(&color_mask));
 
         let mut buf = EMPTY_BUFFER;
@@ -169,31 +556,298 @@ where
         buf[2] = (scaled_x >> 8) as _;
         buf[3] = (scaled_y & 0xFF) as _;
         buf[4] = (scaled_y >> 8) as _;
+        buf[OPTIONS_IDX + 1] = options;
 
         debug!("{scaled_x} {scaled_y} {buf:?}");
 
-        self.send_packet_to_daemon(CONNECT | color_mask, buf)
+        self.send_packet_to_daemon(self.connect_mask() | color_mask, buf)
             .await
             .0
     }
 
-    pub async fn get_name(&self) -> CmdOutput {
-        self.send_packet_to_daemon(NAME, EMPTY_BUFFER).await
+    /// Steps from the current brightness to the value over the duration, see fade_steps. The
+    /// options only apply to the first step, RESTORE_OFF isn't supported
+    pub async fn set_brightness_fade(
+        &self,
+        value: u8,
+        duration: Duration,
+        options: u8,
+    ) -> OutputCode {
+        let (code, data) = self.get_brightness().await;
+        if !code.is_success() {
+            return code;
+        }
+
+        let current = BrightnessCurve::current().to_percent(data[0]) as f64;
+        let steps = fade_steps(current, value as _, duration, fade_interval());
+
+        Self::fade(steps, options, move |value, options| {
+            self.set_brightness_with_options(value.round() as _, options)
+        })
+        .await
+    }
+
+    /// Same as set_brightness_fade for the color, x and y are stepped separately
+    pub async fn set_colors_fade(
+        &self,
+        scaled_x: u16,
+        scaled_y: u16,
+        color_mask: MaskT,
+        duration: Duration,
+        options: u8,
+    ) -> OutputCode {
+        let current = match self.get_color_xy().await {
+            Ok(xy) => xy,
+            Err(code) => return code,
+        };
+
+        let scale = |value: f64| value * 0xFFFF as f64;
+        let interval = fade_interval();
+        let xs = fade_steps(scale(current.x), scaled_x as _, duration, interval);
+        let ys = fade_steps(scale(current.y), scaled_y as _, duration, interval);
+        let steps = xs.into_iter().zip(ys).collect();
+
+        Self::fade(steps, options, move |(x, y), options| {
+            self.set_colors_with_options(x.round() as _, y.round() as _, color_mask, options)
+        })
+        .await
+    }
+
+    /// Same as set_brightness_fade for the color temperature, set at once when the device doesn't
+    /// report it
+    pub async fn set_color_temp_fade(
+        &self,
+        kelvin: u16,
+        duration: Duration,
+        options: u8,
+    ) -> OutputCode {
+        let current = match self.get_color_temp().await {
+            Ok(Some(current)) => current,
+            Ok(None) => return self.set_color_temp_with_options(kelvin, options).await,
+            Err(code) => return code,
+        };
+
+        let steps = fade_steps(current as _, kelvin as _, duration, fade_interval());
+
+        Self::fade(steps, options, move |kelvin, options| {
+            self.set_color_temp_with_options(kelvin.round() as _, options)
+        })
+        .await
+    }
+
+    /// Writes every step but the first one (the current value) each fade_interval, stopping at
+    /// the first failure
+    async fn fade<T, F, Fut>(steps: Vec<T>, options: u8, mut set: F) -> OutputCode
+    where
+        F: FnMut(T, u8) -> Fut,
+        Fut: std::future::Future<Output = OutputCode>,
+    {
+        let interval = fade_interval();
+        let start = tokio::time::Instant::now();
+
+        for (i, step) in steps.into_iter().enumerate().skip(1) {
+            tokio::time::sleep_until(start + interval * (i - 1) as u32).await;
+
+            let code = set(step, if i == 1 { options } else { 0 }).await;
+            if !code.is_success() {
+                return code;
+            }
+        }
+
+        OutputCode::Success
+    }
+
+    /// Names that don't fit an output are streamed in several packets by the daemon, they're
+    /// reassembled here
+    pub async fn get_name(&self) -> Result<String, OutputCode> {
+        self.get_string(0).await
+    }
+
+    /// Empty if the device doesn't expose it, same for the manufacturer and the firmware
+    pub async fn get_model(&self) -> Result<String, OutputCode> {
+        self.get_string(options::MODEL).await
+    }
+
+    pub async fn get_manufacturer(&self) -> Result<String, OutputCode> {
+        self.get_string(options::MANUFACTURER).await
+    }
+
+    pub async fn get_firmware(&self) -> Result<String, OutputCode> {
+        self.get_string(options::FIRMWARE).await
+    }
+
+    /// Reads the name or the device information string selected by the option, see masks::NAME
+    async fn get_string(&self, option: u8) -> Result<String, OutputCode> {
+        let mut buf = EMPTY_BUFFER;
+        buf[OPTIONS_IDX + 1] = option;
+
+        let mut stream = Self::get_file_socket().await;
+        let (mut code, mut data) =
+            Self::_send_packet_to_daemon(&mut stream, Some(self.addr), NAME, buf).await;
+
+        let mut bytes = Vec::new();
+        while code == OutputCode::Streaming {
+            bytes.extend(name_bytes(&data));
+            (code, data) = Self::receive_packet_from_daemon(&mut stream).await;
+        }
+
+        if !code.is_success() {
+            return Err(code);
+        }
+
+        bytes.extend(name_bytes(&data));
+
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Names longer than MAX_NAME_LEN bytes are truncated
+    pub async fn set_name(&self, name: &str) -> OutputCode {
+        if truncate_name(name).len() < name.len() {
+            warn!("The name is longer than {MAX_NAME_LEN} bytes, it's truncated");
+        }
+
+        let mut stream = Self::get_file_socket().await;
+        let request = Self::name_request(self.addr, self.connect_mask() | NAME, name);
+
+        if !Self::write_request(&mut stream, &request, dry_run_enabled()).await {
+            return OutputCode::Success;
+        }
+
+        let output = Self::receive_packet_from_daemon(&mut stream).await;
+        if let Some(reason) = failure_reason(&output) {
+            error!("Device {} error: {reason}", addr_to_str(&self.addr));
+        }
+
+        output.0
+    }
+
+    /// Request buffer followed by the name truncated to MAX_NAME_LEN bytes, see masks::NAME
+    pub fn name_request(addr: [u8; ADDR_LEN], flags: MaskT, name: &str) -> Vec<u8> {
+        let name = truncate_name(name);
+        let mut buf = EMPTY_BUFFER;
+        buf[0] = SET;
+        buf[1] = name.len() as _;
+
+        let mut request = Self::request_buffer(Some(addr), flags | NAME, buf).to_vec();
+        request.extend_from_slice(name.as_bytes());
+
+        request
+    }
+
+    /// Reads the power, brightness, color, name and connection state with a single request
+    pub async fn get_all_state(&self) -> Result<DeviceState, OutputCode> {
+        self.get_all_state_on(&mut Self::get_file_socket().await)
+            .await
+    }
+
+    /// Same as get_all_state on a connection that stays open, see connect_daemon
+    pub async fn get_all_state_on(
+        &self,
+        stream: &mut TokioStream,
+    ) -> Result<DeviceState, OutputCode> {
+        let (mut code, mut data) = Self::_send_packet_to_daemon(
+            stream,
+            Some(self.addr),
+            self.connect_mask() | POWER | BRIGHTNESS | COLOR_XY | NAME,
+            EMPTY_BUFFER,
+        )
+        .await;
+
+        let mut bytes = Vec::new();
+        while code == OutputCode::Streaming {
+            bytes.extend(name_bytes(&data));
+            (code, data) = Self::receive_packet_from_daemon(stream).await;
+        }
+
+        DeviceState::decode(String::from_utf8_lossy(&bytes).into_owned(), (code, data))
     }
 
     pub async fn is_connected(&self) -> CmdOutput {
         self.send_packet_to_daemon(CONNECT, EMPTY_BUFFER).await
     }
 
+    /// Streams the state changes notified by the device until it disconnects, dropping the
+    /// stream ends the subscription
+    pub async fn subscribe(&self) -> Pin<Box<dyn stream::Stream<Item = Response> + Send>> {
+        let addr = self.addr;
+        let mask = self.connect_mask() | SUBSCRIBE;
+        let stream = Self::get_file_socket().await;
+
+        Box::pin(stream::unfold(
+            Some((stream, false)),
+            move |state| async move {
+                let (mut stream, is_stream_initiated) = state?;
+
+                let (code, data) = if is_stream_initiated {
+                    Self::receive_packet_from_daemon(&mut stream).await
+                } else {
+                    Self::_send_packet_to_daemon(&mut stream, Some(addr), mask, EMPTY_BUFFER).await
+                };
+
+                if code != OutputCode::Streaming {
+                    if code != OutputCode::StreamEOF {
+                        error!("Subscription to {} failed: {code:?}", addr_to_str(&addr));
+                    }
+
+                    return None;
+                }
+
+                Some((Response::decode_notification(data), Some((stream, true))))
+            },
+        ))
+    }
+
+    /// Streams the address and connection state of the devices cached by the daemon
+    pub async fn list_daemon_devices(
+    ) -> Pin<Box<dyn stream::Stream<Item = ([u8; ADDR_LEN], bool)> + Send>> {
+        let stream = Self::get_file_socket().await;
+
+        Box::pin(stream::unfold(
+            Some((stream, false)),
+            move |state| async move {
+                let (mut stream, is_stream_initiated) = state?;
+
+                let (code, data) = if is_stream_initiated {
+                    Self::receive_packet_from_daemon(&mut stream).await
+                } else {
+                    Self::_send_packet_to_daemon(&mut stream, None, LIST, EMPTY_BUFFER).await
+                };
+
+                if code != OutputCode::Streaming {
+                    if code != OutputCode::StreamEOF {
+                        error!("Failed to list the daemon devices: {code:?}");
+                    }
+
+                    return None;
+                }
+
+                Some((Self::decode_listed_device(data), Some((stream, true))))
+            },
+        ))
+    }
+
+    pub fn decode_listed_device(data: [u8; OUTPUT_LEN - 1]) -> ([u8; ADDR_LEN], bool) {
+        let mut addr = [0; ADDR_LEN];
+        addr.copy_from_slice(&data[..ADDR_LEN]);
+
+        (addr, data[ADDR_LEN] == 1)
+    }
+
+    /// hue_only filters out the devices whose address isn't from Philips/Signify, see HUE_OUIS
     pub async fn search_by_name(
         name: &String,
+        hue_only: bool,
     ) -> Pin<Box<dyn stream::Stream<Item = FoundDevice> + Send>> {
         let mut buf = EMPTY_BUFFER;
         let bytes = name.as_bytes();
-        let len = usize::min(bytes.len(), buf.len());
+        // The last data byte is kept for the options
+        let len = usize::min(bytes.len(), OPTIONS_IDX);
 
         // 1 for set/get byte offset
         buf[1..len + 1].copy_from_slice(&bytes[..len]);
+        if hue_only {
+            buf[OPTIONS_IDX + 1] = options::HUE_ONLY;
+        }
 
         let get_found_device = |device_buf: [u8; OUTPUT_LEN - 1]| {
             let mut address = [0; ADDR_LEN];
@@ -235,7 +889,7 @@ where
                     return Some((get_found_device(device_buf), Some((stream_guard_ref, true))));
                 }
 
-                let (code, device_buf) = Self::receive_packet_from_daemon(&mut stream_guard).await;
+                let (code, device_buf) = Self::receive_packet_from_daemon(&mut *stream_guard).await;
 
                 // Failure is already handled by the receive_packet fn above
                 if matches!(code, OutputCode::Failure | OutputCode::StreamEOF) {
@@ -251,6 +905,17 @@ where
         Box::pin(stream_iter.filter(|device| future::ready(device.address != [0; ADDR_LEN])))
     }
 
+    /// Asks the daemon to discover the device without its cache and returns how long it took,
+    /// on DeviceNotFound it's the time before giving up
+    pub async fn bench_discovery(&self) -> (OutputCode, Duration) {
+        let (code, data) = self
+            .send_packet_to_daemon(BENCH_DISCOVERY, EMPTY_BUFFER)
+            .await;
+        let millis = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+
+        (code, Duration::from_millis(millis as _))
+    }
+
     pub async fn disconnect_device(&self) -> OutputCode {
         self.send_packet_to_daemon(DISCONNECT, EMPTY_BUFFER).await.0
     }
@@ -261,27 +926,200 @@ where
         self.send_packet_to_daemon(CONNECT, buf).await.0
     }
 
+    /// Asks the daemon to shutdown gracefully. Unlike the other commands it doesn't exit when the
+    /// daemon cannot be reached and it's blocking since the shutdown helpers are, it errors if the
+    /// daemon didn't answer within SHUTDOWN_REQUEST_TIMEOUT_SECS
+    pub fn request_shutdown() -> std::io::Result<()> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        // The thread is left behind on timeout, it ends with the process
+        std::thread::spawn(move || tx.send(Self::send_shutdown_request()));
+
+        rx.recv_timeout(Duration::from_secs(SHUTDOWN_REQUEST_TIMEOUT_SECS))
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "The daemon didn't answer the shutdown request",
+                )
+            })?
+    }
+
+    fn send_shutdown_request() -> std::io::Result<()> {
+        use std::io::{Read as _, Write as _};
+
+        let socket_path = &Config::current().socket_path;
+        let mut stream =
+            SyncStream::connect(socket_path.as_path().to_fs_name::<GenericFilePath>()?)?;
+
+        let request = Self::request_buffer(None, SHUTDOWN, EMPTY_BUFFER);
+        trace_packet("request", &request);
+        stream.write_all(&request)?;
+        stream.flush()?;
+
+        let mut buf = [0; OUTPUT_LEN];
+        stream.read_exact(&mut buf)?;
+        trace_packet("response", &buf);
+
+        match OutputCode::from(buf[0]) {
+            OutputCode::Success => Ok(()),
+            code => Err(std::io::Error::other(format!(
+                "The daemon answered {code:?} to the shutdown request"
+            ))),
+        }
+    }
+
+    /// Whether the daemon is up and answers a ping. Unlike the other requests it doesn't exit when
+    /// the socket cannot be reached
+    pub async fn ping_daemon() -> bool {
+        let socket_path = &Config::current().socket_path;
+        let Ok(fs_name) = socket_path.as_path().to_fs_name::<GenericFilePath>() else {
+            return false;
+        };
+
+        let ping = async {
+            let Ok(mut stream) = TokioStream::connect(fs_name).await else {
+                return false;
+            };
+            Self::ping(&mut stream).await
+        };
+
+        tokio::time::timeout(Duration::from_millis(PING_TIMEOUT_MS), ping)
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Sends a ping on the stream and checks the answer, see masks::PING. A daemon of another
+    /// version answers VersionMismatch, it's up nonetheless
+    pub async fn ping<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> bool {
+        let request = Self::request_buffer(None, PING, EMPTY_BUFFER);
+        trace_packet("request", &request);
+        if stream.write_all(&request).await.is_err() || stream.flush().await.is_err() {
+            return false;
+        }
+
+        let mut buf = [0; OUTPUT_LEN];
+        if stream.read_exact(&mut buf).await.is_err() {
+            return false;
+        }
+        trace_packet("response", &buf);
+
+        [OutputCode::Success, OutputCode::VersionMismatch]
+            .into_iter()
+            .any(|code| buf[0] == u8::from(code))
+    }
+
+    /// The daemon serves the requests of the connection until it's dropped
+    pub async fn connect_daemon() -> TokioStream {
+        Self::get_file_socket().await
+    }
+
     async fn get_file_socket() -> TokioStream {
-        let fs_name = SOCKET_PATH
+        let socket_path = &Config::current().socket_path;
+        let fs_name = socket_path
+            .as_path()
             .to_fs_name::<GenericFilePath>()
             .unwrap_or_else(|error| {
                 error!("Error cannot create filesystem path name: {error}");
                 std::process::exit(2);
             });
         TokioStream::connect(fs_name).await.unwrap_or_else(|error| {
-            error!("Error cannot connect to file socket name: {SOCKET_PATH} => {error}");
+            error!(
+                "Error cannot connect to file socket name: {} => {error}",
+                socket_path.display()
+            );
             std::process::exit(2);
         })
     }
 
     async fn send_packet_to_daemon(&self, flags: MaskT, data: [u8; DATA_LEN + 1]) -> CmdOutput {
-        Self::_send_packet_to_daemon(
+        let output = Self::_send_packet_to_daemon(
             &mut Self::get_file_socket().await,
             Some(self.addr),
             flags,
             data,
         )
-        .await
+        .await;
+
+        if output.0 == OutputCode::NotPaired {
+            error!(
+                "Device {} isn't paired, pair it in the Windows Bluetooth settings and try again",
+                addr_to_str(&self.addr)
+            );
+        }
+
+        if output.0 == OutputCode::NotConnected {
+            error!(
+                "Device {} is offline, check that it's powered and in range",
+                addr_to_str(&self.addr)
+            );
+        }
+
+        if let Some(reason) = failure_reason(&output) {
+            error!("Device {} error: {reason}", addr_to_str(&self.addr));
+        }
+
+        if output.0 == OutputCode::VersionMismatch {
+            error!(
+                "The running daemon uses the protocol version {} but this client uses {PROTOCOL_VERSION}, it must be restarted",
+                output.1[0]
+            );
+            VERSION_MISMATCH.store(true, Ordering::Relaxed);
+        }
+
+        output
+    }
+
+    /// Sends the request for every address, split in requests of MAX_BATCH_LEN devices. See
+    /// masks::BATCH
+    async fn send_batch_to_daemon(
+        addrs: &[[u8; ADDR_LEN]],
+        flags: MaskT,
+        data: [u8; DATA_LEN + 1],
+    ) -> Vec<CmdOutput> {
+        let mut outputs = Vec::with_capacity(addrs.len());
+
+        for chunk in addrs.chunks(MAX_BATCH_LEN) {
+            let mut stream = Self::get_file_socket().await;
+            let request = Self::batch_request(chunk, flags, data);
+
+            if !Self::write_request(&mut stream, &request, dry_run_enabled()).await {
+                let skipped = (OutputCode::Success, [0; OUTPUT_LEN - 1]);
+                outputs.extend(chunk.iter().map(|_| skipped));
+                continue;
+            }
+
+            outputs.extend(Self::receive_batch_outputs(&mut stream, chunk.len()).await);
+        }
+
+        outputs
+    }
+
+    /// Request buffer followed by the devices count and their addresses, at most MAX_BATCH_LEN
+    pub fn batch_request(
+        addrs: &[[u8; ADDR_LEN]],
+        flags: MaskT,
+        data: [u8; DATA_LEN + 1],
+    ) -> Vec<u8> {
+        let mut request = Self::request_buffer(None, flags | BATCH, data).to_vec();
+
+        request.push(addrs.len() as _);
+        for addr in addrs {
+            request.extend_from_slice(addr);
+        }
+
+        request
+    }
+
+    /// Reads one output per device of a batch request
+    pub async fn receive_batch_outputs<S: AsyncRead + Unpin>(
+        stream: &mut S,
+        count: usize,
+    ) -> Vec<CmdOutput> {
+        let mut outputs = Vec::with_capacity(count);
+        for _ in 0..count {
+            outputs.push(Self::receive_packet_from_daemon(stream).await);
+        }
+
+        outputs
     }
 
     /// Data is DATA_LEN + 1 for set/get flag
@@ -291,15 +1129,52 @@ where
         flags: MaskT,
         data: [u8; DATA_LEN + 1],
     ) -> CmdOutput {
+        let chunks = Self::request_buffer(address, flags, data);
+
+        if !Self::write_request(stream, &chunks, dry_run_enabled()).await {
+            return (OutputCode::Success, [0; OUTPUT_LEN - 1]);
+        }
+
+        Self::receive_packet_from_daemon(stream).await
+    }
+
+    /// Writes the request on the stream unless it sets a value in dry run mode, the intended
+    /// action is logged instead and false is returned so the caller doesn't wait for an output
+    pub async fn write_request<S: AsyncWrite + Unpin>(
+        stream: &mut S,
+        request: &[u8],
+        dry_run: bool,
+    ) -> bool {
+        if dry_run {
+            if let Some(action) = set_action(request) {
+                info!("Dry run, would {action}");
+                return false;
+            }
+        }
+
+        trace_packet("request", request);
+        stream.write_all(request).await.unwrap();
+        stream.flush().await.unwrap();
+
+        true
+    }
+
+    fn request_buffer(
+        address: Option<[u8; ADDR_LEN]>,
+        flags: MaskT,
+        data: [u8; DATA_LEN + 1],
+    ) -> [u8; BUFFER_LEN] {
         #[allow(unused_assignments)]
         let mut offset = 0;
         let mut chunks = [0; BUFFER_LEN];
+        chunks[offset] = PROTOCOL_VERSION;
+        offset += 1;
         if let Some(addr) = address {
             for (i, byte) in addr.iter().enumerate() {
-                chunks[i] = *byte;
+                chunks[i + offset] = *byte;
             }
         }
-        offset = ADDR_LEN;
+        offset += ADDR_LEN;
         chunks[offset] = (flags & 0xff) as _;
         offset += 1;
         chunks[offset] = (flags >> 8) as _;
@@ -308,13 +1183,10 @@ where
             chunks[i + offset] = *byte;
         }
 
-        stream.write_all(&chunks[..]).await.unwrap();
-        stream.flush().await.unwrap();
-
-        Self::receive_packet_from_daemon(stream).await
+        chunks
     }
 
-    async fn receive_packet_from_daemon(stream: &mut TokioStream) -> CmdOutput {
+    async fn receive_packet_from_daemon<S: AsyncRead + Unpin>(stream: &mut S) -> CmdOutput {
         // - 1 since the first byte is the output code
         let mut output = [0; OUTPUT_LEN - 1];
 
@@ -325,6 +1197,7 @@ where
             );
             return (OutputCode::Failure, output);
         }
+        trace_packet("response", &buf);
 
         for (i, byte) in buf[1..].iter().enumerate() {
             output[i] = *byte;
@@ -340,14 +1213,19 @@ where
     HueDevice<FFI>: Default + std::fmt::Debug,
 {
     pub fn get_file_socket() -> interprocess::local_socket::Stream {
-        let fs_name = SOCKET_PATH
+        let socket_path = &Config::current().socket_path;
+        let fs_name = socket_path
+            .as_path()
             .to_fs_name::<GenericFilePath>()
             .unwrap_or_else(|error| {
                 error!("Error cannot create filesystem path name: {error}");
                 std::process::exit(2);
             });
         SyncStream::connect(fs_name).unwrap_or_else(|error| {
-            error!("Error cannot connect to file socket name: {SOCKET_PATH} => {error}");
+            error!(
+                "Error cannot connect to file socket name: {} => {error}",
+                socket_path.display()
+            );
             std::process::exit(2);
         })
     }
@@ -363,12 +1241,14 @@ where
         #[allow(unused_assignments)]
         let mut offset = 0;
         let mut chunks = [0; BUFFER_LEN];
+        chunks[offset] = PROTOCOL_VERSION;
+        offset += 1;
         if let Some(addr) = address {
             for (i, byte) in addr.iter().enumerate() {
-                chunks[i] = *byte;
+                chunks[i + offset] = *byte;
             }
         }
-        offset = ADDR_LEN;
+        offset += ADDR_LEN;
         chunks[offset] = (flags & 0xff) as _;
         offset += 1;
         chunks[offset] = (flags >> 8) as _;
@@ -377,6 +1257,7 @@ where
             chunks[i + offset] = *byte;
         }
 
+        trace_packet("request", &chunks);
         stream.write_all(&chunks[..]).unwrap();
         stream.flush().unwrap();
 
@@ -393,6 +1274,7 @@ where
             error!("Error cannot read daemon output, please check `rustbee logs` ({error}) buffer: {buf:?}");
             return (OutputCode::Failure, output);
         }
+        trace_packet("response", &buf);
 
         for (i, byte) in buf[1..].iter().enumerate() {
             output[i] = *byte;
@@ -1,4 +1,6 @@
+pub mod brightness;
 pub mod colors;
+pub mod config;
 pub mod constants;
 pub mod device;
 pub mod logger;
@@ -5,17 +5,56 @@ use std::time::Duration;
 use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral as _};
 use btleplug::platform::Manager;
 use futures::{future, stream, StreamExt};
+use tokio::process::Command;
 use tokio::time;
 
 use crate::device::*;
 
 use crate::constants::ADDR_LEN;
+use crate::utils::is_hue_oui;
 
 const NO_ADAPTER_FOUND: &str = "Failed to get Bluetooth adapter. (maybe your Bluetooth is OFF ?)";
 
+/// Whether a Bluetooth adapter is usable, a new Manager is created each time so it's still
+/// accurate after the adapter was unplugged or its driver reset
+pub async fn is_adapter_available() -> bool {
+    let Ok(manager) = Manager::new().await else {
+        return false;
+    };
+
+    manager
+        .adapters()
+        .await
+        .is_ok_and(|adapters| !adapters.is_empty())
+}
+
+/// Powers the adapter off and on with bluetoothctl to recover a wedged BlueZ stack. It disrupts
+/// every other BLE user of the adapter so it's only done when opted in
+pub async fn reset_adapter() -> btleplug::Result<()> {
+    for state in ["off", "on"] {
+        let status = Command::new("bluetoothctl")
+            .args(["power", state])
+            .status()
+            .await
+            .map_err(|err| btleplug::Error::Other(Box::new(err)))?;
+
+        if !status.success() {
+            return Err(btleplug::Error::Other(Box::new(Error(format!(
+                "bluetoothctl power {state} exited with {status}"
+            )))));
+        }
+    }
+
+    // Gives BlueZ time to bring the adapter back up before discovering
+    time::sleep(Duration::from_secs(1)).await;
+
+    Ok(())
+}
+
 pub async fn search_devices_by_name(
     name: &str,
     timeout_seconds: u64,
+    hue_only: bool,
 ) -> btleplug::Result<Pin<Box<dyn stream::Stream<Item = HueDevice<Server>> + Send>>> {
     let manager = Manager::new().await?;
     let adapters = manager.adapters().await?;
@@ -81,8 +120,8 @@ pub async fn search_devices_by_name(
         },
     );
 
-    Ok(Box::pin(stream.filter(|hue_device| {
-        future::ready(hue_device.device.is_some())
+    Ok(Box::pin(stream.filter(move |hue_device| {
+        future::ready(hue_device.device.is_some() && (!hue_only || is_hue_oui(&hue_device.addr)))
     })))
 }
 
@@ -1,71 +1,85 @@
 use std::fs;
 use std::io;
+use std::path::Path;
 use std::process::{Command, Stdio};
-use std::time::Duration;
 
+use log::*;
 use tokio::process::Command as AsyncCommand;
-use tokio::time;
 
-use crate::constants::SOCKET_PATH;
+use crate::config::Config;
+use crate::constants::DAEMON_BIN;
+use crate::device::{Client, HueDevice};
+use crate::utils::{daemon_flags, spawn_daemon, wait_for_daemon_exit, wait_for_daemon_ready};
 
-fn get_daemon_process_id() -> io::Result<Option<String>> {
-    let cmd = Command::new("ps").arg("-e").output()?;
-    let ps_out = String::from_utf8(cmd.stdout).unwrap();
+/// The kernel truncates process names (comm) to 15 bytes
+const COMM_MAX_LEN: usize = 15;
 
-    let Some(process) = ps_out
-        .lines()
-        .find(|line| line.contains("rustbee-daemon"))
-        .map(str::to_owned)
-    else {
-        return Ok(None);
-    };
+fn get_daemon_process_id() -> io::Result<Option<String>> {
+    find_process_id(Path::new("/proc"), DAEMON_BIN)
+}
 
-    let process = process.trim_start();
+/// Scans a procfs like directory for the first process named `name`
+fn find_process_id(proc_dir: &Path, name: &str) -> io::Result<Option<String>> {
+    let name = &name[..usize::min(name.len(), COMM_MAX_LEN)];
+
+    for entry in fs::read_dir(proc_dir)? {
+        let Ok(entry) = entry else {
+            continue;
+        };
+
+        let file_name = entry.file_name();
+        let Some(pid) = file_name
+            .to_str()
+            .filter(|pid| pid.bytes().all(|c| c.is_ascii_digit()))
+        else {
+            continue;
+        };
+
+        // The process may have exited in the meantime
+        let Ok(comm) = fs::read_to_string(entry.path().join("comm")) else {
+            continue;
+        };
+
+        if comm.trim_end() == name {
+            return Ok(Some(pid.to_owned()));
+        }
+    }
 
-    let Some(offset) = process.bytes().position(|c| c == b' ') else {
-        return Ok(None);
-    };
+    Ok(None)
+}
 
-    Ok(Some(process[..offset].to_owned()))
+pub fn is_daemon_running() -> io::Result<bool> {
+    Ok(get_daemon_process_id()?.is_some())
 }
 
-// get running process rustbee-daemon
-// if running process found:
+// ping rustbee-daemon
+// if it answers or its process is found (it may be too busy to answer):
 // - return
 //
 // spawn rustbee-daemon
 // pipe stderr
-// wait a sec
-// get output status if process exited
-// if status is not 0:
+// ping it until it answers
+// if it exited before with a status that is not 0:
 // - return err and exit 1
 pub async fn launch_daemon() -> io::Result<()> {
-    let pid_found = get_daemon_process_id()?;
-
-    if pid_found.is_some() {
+    if HueDevice::<Client>::ping_daemon().await {
         return Ok(());
     }
 
-    let daemon = AsyncCommand::new("rustbee-daemon")
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    let out = match time::timeout(Duration::from_secs(1), daemon.wait_with_output()).await {
-        Ok(res) => res?,
-        Err(_) => return Ok(()),
-    };
-
-    if !out.status.success() {
-        let stderr = String::from_utf8(out.stderr).unwrap();
-        let stderr = stderr.trim();
-
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("[ERROR] Failed to launch rustbee-daemon:\n{stderr}"),
-        ));
+    // A busy daemon may not answer the ping in time, don't spawn a second one
+    if is_daemon_running()? {
+        debug!("rustbee-daemon didn't answer the ping but its process is running");
+        return Ok(());
     }
 
-    Ok(())
+    let daemon = spawn_daemon(|path| {
+        AsyncCommand::new(path)
+            .args(daemon_flags())
+            .stderr(Stdio::piped())
+            .spawn()
+    })?;
+
+    wait_for_daemon_ready(daemon).await
 }
 
 // get running process rustbee-daemon
@@ -74,33 +88,66 @@ pub async fn launch_daemon() -> io::Result<()> {
 //
 // if -f or --force:
 // - send SIGKILL to the the process
-// - rm SOCKET_FILE
-// - return
+// else:
+// - send a shutdown request through the socket
+// - if it's not answered, send SIGINT to the running process for a graceful shutdown
 //
-// send SIGINT to the running process for a graceful shutdown
+// wait for the process to exit
+// rm SOCKET_FILE
 pub fn shutdown_daemon(force: bool) -> io::Result<()> {
     let pid_found = get_daemon_process_id()?;
     if let Some(pid) = pid_found {
-        if force {
+        let requested = !force
+            && HueDevice::<Client>::request_shutdown()
+                .inspect_err(|err| warn!("{err}, sending SIGINT instead"))
+                .is_ok();
+
+        if !requested {
+            let signal = if force { "KILL" } else { "INT" };
+
             Command::new("kill")
-                .args(["-s", "KILL", &pid])
+                .args(["-s", signal, &pid])
                 .output()
                 .unwrap();
-
-            if fs::exists(SOCKET_PATH)? {
-                fs::remove_file(SOCKET_PATH)?;
-            }
-
-            return Ok(());
         }
 
-        Command::new("kill")
-            .args(["-s", "INT", &pid])
-            .output()
-            .unwrap();
-    } else if fs::exists(SOCKET_PATH)? {
-        fs::remove_file(SOCKET_PATH)?;
+        wait_for_daemon_exit(is_daemon_running)?;
+    }
+
+    let socket_path = &Config::current().socket_path;
+    if fs::exists(socket_path)? {
+        fs::remove_file(socket_path)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod daemon_tests {
+    use super::*;
+
+    #[test]
+    fn process_id_from_proc_dir() {
+        let proc_dir = std::env::temp_dir().join("rustbee_proc_fixture");
+        let _ = fs::remove_dir_all(&proc_dir);
+
+        for (pid, comm) in [
+            ("12", "bash\n"),
+            ("345", "rustbee-daemon\n"),
+            ("self", "rustbee-daemon\n"),
+        ] {
+            fs::create_dir_all(proc_dir.join(pid)).unwrap();
+            fs::write(proc_dir.join(pid).join("comm"), comm).unwrap();
+        }
+        // Not a process dir
+        fs::write(proc_dir.join("uptime"), "").unwrap();
+
+        assert_eq!(
+            find_process_id(&proc_dir, DAEMON_BIN).unwrap(),
+            Some("345".to_owned())
+        );
+        assert_eq!(find_process_id(&proc_dir, "not-running").unwrap(), None);
+
+        let _ = fs::remove_dir_all(&proc_dir);
+    }
+}
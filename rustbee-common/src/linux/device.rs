@@ -2,8 +2,10 @@ use std::ops::Deref;
 use std::time::Duration;
 
 use btleplug::api::WriteType;
+use futures::StreamExt as _;
 use log::*;
-use tokio::time::sleep;
+use tokio::sync::mpsc;
+use tokio::time::{self, sleep};
 use uuid::Uuid;
 
 use crate::constants::*;
@@ -12,6 +14,8 @@ use crate::BluetoothPeripheralImpl as _;
 use crate::InnerDevice;
 
 const ATTEMPTS: u8 = 3;
+/// The notifications don't end when the device disconnects so it's checked periodically
+const SUBSCRIPTION_CHECK_SECS: u64 = 5;
 
 impl HueDevice<Server>
 where
@@ -147,16 +151,44 @@ where
         Ok(())
     }
 
+    /// Mireds of the temperature characteristic, None if the device doesn't support it
+    pub async fn get_color_temp(&self) -> btleplug::Result<Option<u16>> {
+        let read = self
+            .read_gatt_char(&LIGHT_SERVICES_UUID, &TEMPERATURE_UUID)
+            .await?;
+        match read.as_deref() {
+            Some([low, high, ..]) => Ok(Some(u16::from_le_bytes([*low, *high]))),
+            // The bulbs without white ambiance don't expose it
+            None => Ok(None),
+            _ => Err(btleplug::Error::Other(Box::new(Error(
+                format!("[ERROR] Service or Characteristic \"{TEMPERATURE_UUID}\" for \"{LIGHT_SERVICES_UUID}\" not found for device {:?}", self.addr)
+            )))),
+        }
+    }
+
+    /// Mireds range (coolest, warmest) of the temperature characteristic, None if it isn't reported
+    pub async fn get_color_temp_range(&self) -> btleplug::Result<Option<(u16, u16)>> {
+        let read = self
+            .read_gatt_char(&LIGHT_SERVICES_UUID, &TEMPERATURE_UUID)
+            .await?;
+
+        Ok(read.as_deref().and_then(temp_range_from_payload))
+    }
+
+    pub async fn set_color_temp(&self, mireds: u16) -> btleplug::Result<()> {
+        self.write_gatt_char(&LIGHT_SERVICES_UUID, &TEMPERATURE_UUID, &mireds.to_le_bytes())
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn get_color(&self) -> btleplug::Result<[u8; 4]> {
-        let mut buf = [0u8; 4];
         if let Some(bytes) = self
             .read_gatt_char(&LIGHT_SERVICES_UUID, &COLOR_UUID)
             .await?
         {
-            let len = buf.len();
-            buf.copy_from_slice(&bytes[..len]);
-
-            Ok(buf)
+            color_from_payload(&self.addr, &bytes)
+                .map_err(|err| btleplug::Error::Other(Box::new(err)))
         } else {
             Err(btleplug::Error::Other(Box::new(Error(
                 format!("[ERROR] Service or Characteristic \"{COLOR_UUID}\" for \"{LIGHT_SERVICES_UUID}\" not found for device {:?}", self.addr)
@@ -171,6 +203,55 @@ where
         Ok(())
     }
 
+    /// Forwards the power, brightness and color notifications (mask, value) until the device
+    /// disconnects or the receiver is dropped
+    pub async fn subscribe_state(
+        &self,
+        tx: mpsc::Sender<(MaskT, Vec<u8>)>,
+    ) -> btleplug::Result<()> {
+        let characteristics = self
+            .characteristics()
+            .into_iter()
+            .filter(|charac| notification_mask(&charac.uuid).is_some())
+            .collect::<Vec<_>>();
+
+        for charac in &characteristics {
+            self.subscribe(charac).await?;
+        }
+
+        let mut notifications = self.notifications().await?;
+        let mut ticker = time::interval(Duration::from_secs(SUBSCRIPTION_CHECK_SECS));
+
+        loop {
+            tokio::select! {
+                notification = notifications.next() => {
+                    let Some(notification) = notification else {
+                        break;
+                    };
+                    let Some(mask) = notification_mask(&notification.uuid) else {
+                        continue;
+                    };
+
+                    if tx.send((mask, notification.value)).await.is_err() {
+                        break;
+                    }
+                }
+                _ = ticker.tick() => {
+                    if !self.is_connected().await? {
+                        break;
+                    }
+                }
+                _ = tx.closed() => break,
+            }
+        }
+
+        for charac in &characteristics {
+            let _ = self.unsubscribe(charac).await;
+        }
+
+        Ok(())
+    }
+
     pub async fn get_name(&self) -> btleplug::Result<Option<String>> {
         Ok(self
             .properties()
@@ -178,4 +259,37 @@ where
             .map(|properties| properties.local_name)
             .unwrap_or(None))
     }
+
+    pub async fn get_model(&self) -> btleplug::Result<Option<String>> {
+        self.get_info_string(&MODEL_UUID).await
+    }
+
+    pub async fn get_manufacturer(&self) -> btleplug::Result<Option<String>> {
+        self.get_info_string(&MANUFACTURER_UUID).await
+    }
+
+    pub async fn get_firmware(&self) -> btleplug::Result<Option<String>> {
+        self.get_info_string(&FIRMWARE_UUID).await
+    }
+
+    /// String of the device information service, None if the device doesn't expose it
+    async fn get_info_string(&self, charac: &Uuid) -> btleplug::Result<Option<String>> {
+        Ok(self
+            .read_gatt_char(&MISC_SERVICES_UUID, charac)
+            .await?
+            .map(|bytes| info_string(&bytes)))
+    }
+
+    pub async fn set_name(&self, name: &str) -> btleplug::Result<()> {
+        if !self
+            .write_gatt_char(&CONFIG_SERVICES_UUID, &DEVICE_NAME_UUID, name.as_bytes())
+            .await?
+        {
+            return Err(btleplug::Error::Other(Box::new(Error(
+                format!("[ERROR] Service or Characteristic \"{DEVICE_NAME_UUID}\" for \"{CONFIG_SERVICES_UUID}\" not found for device {:?}", self.addr)
+            ))));
+        }
+
+        Ok(())
+    }
 }
@@ -1,20 +1,335 @@
 use std::fs::{self, File};
-use std::io::{Read, SeekFrom, Write};
+use std::io::{self, BufWriter, Read, Seek as _, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use tokio::fs::File as AsyncFile;
 use tokio::io::{AsyncBufReadExt as _, AsyncSeekExt as _, BufReader as AsyncBufReader};
 
-use log::{Level, Log, Metadata, Record};
+use log::{Level, LevelFilter, Log, Metadata, Record};
 
+use crate::config::Config;
 use crate::constants::{LOG_LEVEL, LOG_PATH};
 
 pub use log::{debug, error, info, trace, warn};
 
-const MAX_TAIL_LINES: usize = 50;
+/// Bytes read at once from the end of the log file when looking for the tail lines
+const TAIL_CHUNK_LEN: u64 = 8 * 1024;
+const LOG_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+/// Log target of the packet traces, they're logged even though it's above LOG_LEVEL
+const PACKETS_TARGET: &str = "packets";
+
+/// Read from the config by Logger::init along the level
+static TRACE_PACKETS: AtomicBool = AtomicBool::new(false);
+
+/// Read from the config by Logger::init along the level, plain lines until then
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+
+/// Level filter of a LOG_LEVEL_ENV value, None if it's not a valid filter
+pub fn parse_log_level(value: &str) -> Option<LevelFilter> {
+    value.trim().parse().ok()
+}
+
+/// Line written to the log file for a record, plain by default or a JSON object with the ts,
+/// level, name and msg fields
+pub(crate) fn format_log_line(name: &str, level: Level, msg: &str, json: bool) -> String {
+    let now = chrono::Local::now();
+
+    if json {
+        let line = serde_json::json!({
+            "ts": now.to_rfc3339(),
+            "level": level.as_str(),
+            "name": name,
+            "msg": msg,
+        });
+
+        return format!("{line}\n");
+    }
+
+    format!("[{name}]<{}> {level}: {msg}\n", now.format("%Y-%m-%d %H:%M:%S"))
+}
+
+/// Logs the raw bytes of a request or response as hex if the packets trace is enabled
+pub fn trace_packet(direction: &str, bytes: &[u8]) {
+    if !TRACE_PACKETS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    trace!(target: PACKETS_TARGET, "{direction} [{hex}]");
+}
+
+/// Reads the last lines of the file, backward by chunks so only the tail is kept in memory
+pub(crate) fn read_tail(file: &mut File, lines: usize) -> io::Result<String> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut start = len;
+
+    if lines > 0 && len > 0 {
+        let mut buf = vec![0; TAIL_CHUNK_LEN as _];
+        let mut newlines = 0;
+        let mut end = len;
+        start = 0;
+
+        'search: while end > 0 {
+            let chunk_start = end.saturating_sub(TAIL_CHUNK_LEN);
+            let chunk = &mut buf[..(end - chunk_start) as usize];
+            file.seek(SeekFrom::Start(chunk_start))?;
+            file.read_exact(chunk)?;
+
+            for (i, byte) in chunk.iter().enumerate().rev() {
+                let offset = chunk_start + i as u64;
+                // The newline ending the last line doesn't start another one
+                if *byte != b'\n' || offset == len - 1 {
+                    continue;
+                }
+
+                newlines += 1;
+                if newlines == lines {
+                    start = offset + 1;
+                    break 'search;
+                }
+            }
+
+            end = chunk_start;
+        }
+    }
+
+    let mut tail = String::new();
+    file.seek(SeekFrom::Start(start))?;
+    file.read_to_string(&mut tail)?;
+
+    Ok(tail)
+}
+
+/// Moves the file to path.suffix and recreates it empty. If it can't be renamed (the archive on
+/// another device), it's copied then truncated
+pub(crate) fn archive_log_file(path: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let mut archive = path.as_os_str().to_owned();
+    archive.push(format!(".{suffix}"));
+    let archive = PathBuf::from(archive);
+
+    if fs::rename(path, &archive).is_err() {
+        fs::copy(path, &archive)?;
+    }
+
+    File::create(path)?;
+
+    Ok(archive)
+}
+
+/// If the file at path would exceed max_bytes with len more bytes, renames it to path.1 and the
+/// previous rotations to the next index, the last of the keep rotations being removed. Returns
+/// whether it rotated
+pub(crate) fn rotate_log_file(
+    path: &Path,
+    len: u64,
+    max_bytes: u64,
+    keep: usize,
+) -> io::Result<bool> {
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    if size == 0 || size + len <= max_bytes {
+        return Ok(false);
+    }
+
+    let rotation = |index: usize| {
+        let mut path = path.as_os_str().to_owned();
+        path.push(format!(".{index}"));
+        PathBuf::from(path)
+    };
+
+    for index in (1..keep).rev() {
+        match fs::rename(rotation(index), rotation(index + 1)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+    }
+
+    if keep == 0 {
+        fs::remove_file(path)?;
+    } else {
+        fs::rename(path, rotation(1))?;
+    }
+
+    Ok(true)
+}
+
+/// Whether the file at path isn't the open one anymore
+#[cfg(unix)]
+fn is_replaced(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt as _;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(open), Ok(current)) => (open.dev(), open.ino()) != (current.dev(), current.ino()),
+        _ => true,
+    }
+}
+
+/// The open file can't be renamed or removed on Windows, only a missing one was replaced
+#[cfg(not(unix))]
+fn is_replaced(_file: &File, path: &Path) -> bool {
+    fs::metadata(path).is_err()
+}
+
+enum WriterMessage {
+    Line(String),
+    Flush(mpsc::SyncSender<()>),
+}
+
+/// Appends the lines to the log file from a dedicated thread so logging doesn't block on IO. The
+/// lines are buffered and flushed every LOG_FLUSH_INTERVAL or when asked to
+pub(crate) struct LogWriter {
+    sender: mpsc::Sender<WriterMessage>,
+}
+
+impl LogWriter {
+    pub(crate) fn spawn(path: PathBuf, rotation: Option<(u64, usize)>) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("rustbee-log-writer".into())
+            .spawn(move || Self::run(&path, rotation, receiver))?;
+
+        Ok(Self { sender })
+    }
+
+    /// If sync, waits for the line and the previous ones to be written. Returns the line back if
+    /// the thread stopped
+    pub(crate) fn write(&self, line: String, sync: bool) -> Result<(), String> {
+        self.sender
+            .send(WriterMessage::Line(line))
+            .map_err(|err| match err.0 {
+                WriterMessage::Line(line) => line,
+                WriterMessage::Flush(_) => unreachable!(),
+            })?;
+
+        if sync {
+            self.flush();
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the sent lines to be written
+    pub(crate) fn flush(&self) {
+        let (ack, done) = mpsc::sync_channel(1);
+
+        if self.sender.send(WriterMessage::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+
+    fn run(path: &Path, rotation: Option<(u64, usize)>, receiver: mpsc::Receiver<WriterMessage>) {
+        let open = || {
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(BufWriter::new)
+        };
+        let size = || fs::metadata(path).map_or(0, |metadata| metadata.len());
+        let print_error = |action: &str, err: io::Error| {
+            eprintln!("[ERROR] Failed to {action} {}: {err}", path.display())
+        };
+
+        let mut file = match open() {
+            Ok(file) => file,
+            Err(err) => return print_error("open the log file at", err),
+        };
+        let mut written = size();
+        let mut last_flush = Instant::now();
+
+        loop {
+            let mut ack = None;
+
+            match receiver.recv_timeout(LOG_FLUSH_INTERVAL.saturating_sub(last_flush.elapsed())) {
+                Ok(WriterMessage::Line(line)) => {
+                    let len = line.len() as u64;
+
+                    let exceeds = |(max_bytes, _): &(u64, usize)| written + len > *max_bytes;
+                    if let Some((max_bytes, keep)) = rotation.filter(exceeds) {
+                        // The buffered lines belong to the rotated file
+                        let _ = file.flush();
+
+                        match rotate_log_file(path, len, max_bytes, keep) {
+                            Ok(true) => match open() {
+                                Ok(new_file) => (file, written) = (new_file, 0),
+                                Err(err) => return print_error("open the log file at", err),
+                            },
+                            Ok(false) => written = size(),
+                            Err(err) => print_error("rotate the log file at", err),
+                        }
+                    }
+
+                    if let Err(err) = file.write_all(line.as_bytes()) {
+                        print_error("write to the log file at", err);
+                    }
+                    written += len;
+                }
+                Ok(WriterMessage::Flush(sender)) => ack = Some(sender),
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    let _ = file.flush();
+                    return;
+                }
+            }
+
+            if ack.is_some() || last_flush.elapsed() >= LOG_FLUSH_INTERVAL {
+                // Nothing was written since the last flush otherwise
+                if !file.buffer().is_empty() {
+                    // Other processes write to the same file and may have rotated or archived it,
+                    // the buffered lines go to the new one
+                    if is_replaced(file.get_ref(), path) {
+                        match open() {
+                            Ok(mut new_file) => {
+                                let (_, buffered) = file.into_parts();
+                                let _ = new_file.write_all(&buffered.unwrap_or_default());
+                                file = new_file;
+                            }
+                            Err(err) => print_error("reopen the log file at", err),
+                        }
+                    }
+                    let _ = file.flush();
+                    written = size();
+                }
+                last_flush = Instant::now();
+            }
+
+            if let Some(ack) = ack {
+                let _ = ack.send(());
+            }
+        }
+    }
+}
+
+/// Flushes the lines not written yet, to call before the process exits
+pub fn flush_logs() {
+    log::logger().flush();
+}
 
 pub struct Logger {
     name: &'static str,
     use_stdout_stderr: bool,
+    /// Set by quiet_stdout
+    stdout_quiet: AtomicBool,
+    /// Rotates the log file from this size, keeping the given number of rotations
+    rotation: Option<(u64, usize)>,
+    /// Read from the config by init, LOG_LEVEL until then
+    level: OnceLock<LevelFilter>,
+    /// Spawned by init, the lines are written synchronously until then
+    writer: OnceLock<LogWriter>,
 }
 
 impl Logger {
@@ -22,12 +337,84 @@ impl Logger {
         Self {
             name,
             use_stdout_stderr,
+            stdout_quiet: AtomicBool::new(false),
+            rotation: None,
+            level: OnceLock::new(),
+            writer: OnceLock::new(),
+        }
+    }
+
+    /// Same as new but the log file is rotated when it exceeds max_bytes, see LOG_MAX_BYTES
+    pub const fn with_rotation(
+        name: &'static str,
+        use_stdout_stderr: bool,
+        max_bytes: u64,
+        keep: usize,
+    ) -> Self {
+        Self {
+            name,
+            use_stdout_stderr,
+            stdout_quiet: AtomicBool::new(false),
+            rotation: Some((max_bytes, keep)),
+            level: OnceLock::new(),
+            writer: OnceLock::new(),
         }
     }
 
+    /// Keeps the info and lower levels out of stdout, they're still written to the log file. For
+    /// when stdout is reserved to a machine readable output
+    pub fn quiet_stdout(&self) {
+        self.stdout_quiet.store(true, Ordering::Relaxed);
+    }
+
     pub fn init(&'static self) {
+        match LogWriter::spawn(PathBuf::from(LOG_PATH), self.rotation) {
+            Ok(writer) => {
+                let _ = self.writer.set(writer);
+            }
+            Err(err) => eprintln!("[ERROR] Failed to spawn the log writer thread {err}"),
+        }
+
         log::set_logger(self).expect("Unexpected error: Cannot set logger twice");
         log::set_max_level(log::LevelFilter::Trace);
+
+        // Once the logger is set so the warnings of loading the config are logged
+        self.load_level();
+    }
+
+    /// Used before init or if the writer thread isn't running
+    fn write_sync(&self, log_content: &str) {
+        // Rotated before writing so the line goes to the new file
+        if let Some((max_bytes, keep)) = self.rotation {
+            let len = log_content.len() as _;
+            if let Err(err) = rotate_log_file(Path::new(LOG_PATH), len, max_bytes, keep) {
+                eprintln!("[ERROR] Failed to rotate the log file at {LOG_PATH}: {err}");
+            }
+        }
+
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(LOG_PATH)
+            .unwrap_or_else(|err| {
+                panic!(
+                    "Unexpected error: Cannot get a (write) handle to log file at {LOG_PATH}: {err}"
+                )
+            });
+
+        file.write_all(log_content.as_bytes())
+            .expect("Unexpected error: Failed to write to log file");
+        file.flush().unwrap();
+    }
+
+    /// Reads the level, the packets trace and the format from the config, it warns itself about
+    /// an invalid LOG_LEVEL_ENV
+    fn load_level(&self) {
+        let config = Config::current();
+
+        let _ = self.level.set(config.log_level);
+        TRACE_PACKETS.store(config.trace_packets, Ordering::Relaxed);
+        JSON_FORMAT.store(config.json_logs, Ordering::Relaxed);
     }
 
     /// If tail specified, prints the last x lines too before awaiting the next lines
@@ -79,27 +466,36 @@ impl Logger {
                 })
             };
 
-        let mut content = String::new();
-        file.read_to_string(&mut content)
-            .expect("Failed to read log file");
-
-        if tail.is_some_and(|v| v <= MAX_TAIL_LINES) {
-            content
+        if let Some(lines) = tail {
+            read_tail(&mut file, lines)
+                .expect("Failed to read log file")
                 .lines()
-                .rev()
-                .enumerate()
-                .take_while(|(i, _)| *i < tail.unwrap())
-                .collect::<Vec<_>>()
-                .iter()
-                .rev()
-                .for_each(|(_, line)| println!("{line}"));
+                .for_each(|line| println!("{line}"));
 
             return;
         }
 
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .expect("Failed to read log file");
+
         print!("{content}");
     }
 
+    /// Moves the log file to LOG_PATH.<date> then purges it, returns the archive path
+    pub fn archive_and_purge(&self) -> io::Result<PathBuf> {
+        // The pending lines belong to the archive
+        log::logger().flush();
+
+        let suffix = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+        let archive = archive_log_file(Path::new(LOG_PATH), &suffix)?;
+
+        // Reopens the new log file
+        log::logger().flush();
+
+        Ok(archive)
+    }
+
     pub fn purge(&self) {
         if !fs::exists(LOG_PATH).expect("Lack permissions to check if log file exists") {
             return;
@@ -119,7 +515,10 @@ impl Logger {
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= LOG_LEVEL
+        let level = *self.level.get().unwrap_or(&LOG_LEVEL.to_level_filter());
+
+        metadata.level() <= level
+            || (metadata.target() == PACKETS_TARGET && TRACE_PACKETS.load(Ordering::Relaxed))
     }
 
     fn log(&self, record: &Record) {
@@ -127,36 +526,37 @@ impl Log for Logger {
             return;
         }
 
-        let mut file = File::options()
-            .create(true)
-            .append(true)
-            .open(LOG_PATH)
-            .unwrap_or_else(|err| {
-                panic!(
-                    "Unexpected error: Cannot get a (write) handle to log file at {LOG_PATH}: {err}"
-                )
-            });
-
-        let content = format!("{}\n", record.args());
-        let log_content = format!(
-            "[{}]<{}> {}: {}",
+        let msg = record.args().to_string();
+        let content = format!("{msg}\n");
+        let log_content = format_log_line(
             self.name,
-            chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
             record.level(),
-            content
+            &msg,
+            JSON_FORMAT.load(Ordering::Relaxed),
         );
 
         if self.use_stdout_stderr {
             match record.level() {
                 Level::Error | Level::Warn => eprint!("{content}"),
-                _ => print!("{content}"),
+                _ if !self.stdout_quiet.load(Ordering::Relaxed) => print!("{content}"),
+                _ => (),
             }
         }
 
-        file.write_all(log_content.as_bytes())
-            .expect("Unexpected error: Failed to write to log file");
-        file.flush().unwrap();
+        // Warnings and errors are waited for since they're often followed by an exit
+        let Some(writer) = self.writer.get() else {
+            return self.write_sync(&log_content);
+        };
+
+        // The writer thread stopped, falls back to the synchronous write
+        if let Err(log_content) = writer.write(log_content, record.level() <= Level::Warn) {
+            self.write_sync(&log_content);
+        }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        if let Some(writer) = self.writer.get() {
+            writer.flush();
+        }
+    }
 }
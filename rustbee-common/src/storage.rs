@@ -1,14 +1,25 @@
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use log::*;
 
 use crate::constants::{ADDR_LEN, APP_ID};
+use crate::utils::parse_hex_address;
 
 type Data = HashMap<[u8; ADDR_LEN], SavedDevice>;
 
+/// Version of the storage file format, files written before it was versioned are a bare map of
+/// the devices (version 0)
+pub const STORAGE_VERSION: u32 = 1;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StorageFile {
+    version: u32,
+    devices: HashMap<String, SavedDevice>,
+}
+
 pub struct Storage {
     path: PathBuf,
     data: Data,
@@ -19,6 +30,30 @@ pub struct SavedDevice {
     pub name: String,
     pub current_color: [u8; 3],
     pub brightness: u8,
+    /// Last known power state
+    #[serde(default)]
+    pub power_state: bool,
+    /// Last known color temperature in Kelvin, if it was set
+    #[serde(default)]
+    pub color_temp: Option<u16>,
+    /// State of the device before turning the nightlight on, restored when turning it off
+    #[serde(default)]
+    pub nightlight: Option<LightState>,
+    /// Unix timestamp in seconds of the last time the device was reachable
+    #[serde(default)]
+    pub last_seen: Option<i64>,
+    /// Model number read by `rustbee info`, it tells the color gamut of the device
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LightState {
+    pub power: bool,
+    /// Percentage from 0 to 100
+    pub brightness: u8,
+    /// Raw color characteristic bytes (x and y as little-endian u16)
+    pub color: [u8; 4],
 }
 
 impl Storage {
@@ -61,9 +96,12 @@ impl Storage {
         })
     }
 
+    /// Devices with an all-zero address (a default device from bad input) are skipped so they're
+    /// never written and reloaded forever
     fn serialize_data(&self) -> HashMap<String, SavedDevice> {
         self.data
             .iter()
+            .filter(|(addr, _)| **addr != [0; ADDR_LEN])
             .map(|(addr, device)| {
                 let addr = addr
                     .iter()
@@ -76,20 +114,88 @@ impl Storage {
             .collect()
     }
 
+    /// Addresses differing only by their casing (edited by hand) are the same device, see dedup
     fn deserialize_data(&self, data: HashMap<String, SavedDevice>) -> Data {
-        data.into_iter()
-            .map(|(addr, device)| (parse_hex_address(&addr), device))
-            .collect()
+        let parsed = data
+            .into_iter()
+            .filter_map(|(addr, device)| match parse_hex_address(&addr) {
+                Ok(parsed) => Some((parsed, device)),
+                Err(err) => {
+                    warn!("Skipping the saved device with a malformed address \"{addr}\": {err}");
+                    None
+                }
+            })
+            .filter(|(addr, _)| *addr != [0; ADDR_LEN]);
+
+        Self::dedup(parsed)
     }
 
-    fn load_from_file(&mut self) {
-        let mut file = match File::open(&self.path) {
+    /// Keeps one device per address, the most recently seen one or the last one when they were
+    /// seen at the same time (or never) since it's the most recently written
+    pub fn dedup(devices: impl IntoIterator<Item = ([u8; ADDR_LEN], SavedDevice)>) -> Data {
+        let mut deduped = Data::new();
+
+        for (addr, device) in devices {
+            match deduped.get(&addr) {
+                Some(saved) if saved.last_seen > device.last_seen => {
+                    warn!("Skipping a duplicate of the saved device {addr:?}");
+                }
+                _ => {
+                    deduped.insert(addr, device);
+                }
+            }
+        }
+
+        deduped
+    }
+
+    /// Path of the copy of the previous storage file, kept in case the current one gets corrupted
+    fn backup_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".bak");
+
+        PathBuf::from(path)
+    }
+
+    /// Path of the file written before being renamed over the file at path
+    fn tmp_path(path: &Path) -> PathBuf {
+        let mut path = path.as_os_str().to_owned();
+        path.push(".tmp");
+
+        PathBuf::from(path)
+    }
+
+    /// Parses the storage file content, migrating the previous versions
+    fn parse_file(content: &str) -> Result<HashMap<String, SavedDevice>, String> {
+        let value =
+            serde_json::from_str::<serde_json::Value>(content).map_err(|err| err.to_string())?;
+
+        // Addresses are the only keys of the legacy bare map
+        if value.get("version").is_none() {
+            info!("Migrating the storage file from version 0 to {STORAGE_VERSION}");
+            return serde_json::from_value(value).map_err(|err| err.to_string());
+        }
+
+        let file = serde_json::from_value::<StorageFile>(value).map_err(|err| err.to_string())?;
+        if file.version > STORAGE_VERSION {
+            return Err(format!(
+                "Unsupported storage file version {}, this version of rustbee supports up to {STORAGE_VERSION}",
+                file.version
+            ));
+        }
+
+        Ok(file.devices)
+    }
+
+    /// Returns None if the file doesn't exist
+    fn read_file(path: &Path) -> Result<Option<HashMap<String, SavedDevice>>, String> {
+        let mut file = match File::open(path) {
             Ok(file) => file,
             Err(err) => {
                 if !matches!(err.kind(), std::io::ErrorKind::NotFound) {
                     panic!("Failed to open saved data file in read-only {err}");
                 }
-                return;
+                return Ok(None);
             }
         };
 
@@ -97,9 +203,29 @@ impl Storage {
         file.read_to_string(&mut content)
             .expect("Failed to read from storage file");
 
-        match serde_json::from_str::<HashMap<String, SavedDevice>>(&content) {
-            Ok(data) => self.data = self.deserialize_data(data),
-            Err(err) => error!("Failed to deserialize saved data {err}"),
+        Self::parse_file(&content).map(Some)
+    }
+
+    fn load_from_file(&mut self) {
+        match Self::read_file(&self.path) {
+            Ok(Some(data)) => self.data = self.deserialize_data(data),
+            Ok(None) => (),
+            Err(err) => {
+                error!("Failed to deserialize saved data {err}");
+
+                let backup_path = self.backup_path();
+                match Self::read_file(&backup_path) {
+                    Ok(Some(data)) => {
+                        warn!(
+                            "Storage file is corrupted, loaded the backup from {}",
+                            backup_path.display()
+                        );
+                        self.data = self.deserialize_data(data);
+                    }
+                    Ok(None) => (),
+                    Err(err) => error!("Failed to deserialize saved data backup {err}"),
+                }
+            }
         }
     }
 
@@ -119,62 +245,181 @@ impl Storage {
         &self.data
     }
 
+    /// Same as get_devices but ordered by address so the output is stable across calls
+    pub fn get_sorted_devices(&mut self) -> Vec<(&[u8; ADDR_LEN], &SavedDevice)> {
+        let mut devices = self.get_devices().iter().collect::<Vec<_>>();
+        devices.sort_unstable_by_key(|(addr, _)| **addr);
+
+        devices
+    }
+
+    /// Address of the device at this index of get_sorted_devices
+    pub fn get_device_addr_by_index(&mut self, index: usize) -> Result<[u8; ADDR_LEN], String> {
+        let devices = self.get_sorted_devices();
+
+        match devices.get(index) {
+            Some((addr, _)) => Ok(**addr),
+            None if devices.is_empty() => Err(format!(
+                "Device index {index} is out of range, no device saved"
+            )),
+            None => Err(format!(
+                "Device index {index} is out of range, expected 0 to {}",
+                devices.len() - 1
+            )),
+        }
+    }
+
     pub fn set_device(&mut self, addr: [u8; ADDR_LEN], device: Option<SavedDevice>) {
         self.data.insert(addr, device.unwrap_or_default());
     }
 
+    /// Returns whether the device was saved
+    pub fn remove_device(&mut self, addr: &[u8; ADDR_LEN]) -> bool {
+        if self.data.is_empty() {
+            self.load_from_file();
+        }
+
+        self.data.remove(addr).is_some()
+    }
+
     pub fn set_devices(&mut self, devices: Vec<([u8; ADDR_LEN], Option<SavedDevice>)>) {
-        for (addr, device) in devices {
-            self.data.insert(addr, device.unwrap_or_default());
+        let devices = devices
+            .into_iter()
+            .map(|(addr, device)| (addr, device.unwrap_or_default()));
+
+        self.data.extend(Self::dedup(devices));
+    }
+
+    /// Saves the state of the device prior to the nightlight. If there's already one, it's kept
+    /// and false is returned so turning the nightlight on twice doesn't lose the actual state
+    pub fn save_nightlight_state(&mut self, addr: [u8; ADDR_LEN], state: LightState) -> bool {
+        if self.data.is_empty() {
+            self.load_from_file();
+        }
+
+        let device = self.data.entry(addr).or_default();
+        if device.nightlight.is_some() {
+            return false;
+        }
+
+        device.nightlight = Some(state);
+
+        true
+    }
+
+    pub fn save_color_temp(&mut self, addr: [u8; ADDR_LEN], kelvin: u16) {
+        if self.data.is_empty() {
+            self.load_from_file();
+        }
+
+        self.data.entry(addr).or_default().color_temp = Some(kelvin);
+    }
+
+    pub fn save_name(&mut self, addr: [u8; ADDR_LEN], name: &str) {
+        if self.data.is_empty() {
+            self.load_from_file();
+        }
+
+        self.data.entry(addr).or_default().name = name.into();
+    }
+
+    /// Only the saved devices are updated, reading a device doesn't save it
+    pub fn save_model(&mut self, addr: [u8; ADDR_LEN], model: &str) {
+        if self.data.is_empty() {
+            self.load_from_file();
+        }
+
+        if let Some(device) = self.data.get_mut(&addr) {
+            device.model = Some(model.into());
+        }
+    }
+
+    /// Removes and returns the state of the device prior to the nightlight
+    pub fn take_nightlight_state(&mut self, addr: &[u8; ADDR_LEN]) -> Option<LightState> {
+        if self.data.is_empty() {
+            self.load_from_file();
         }
+
+        self.data
+            .get_mut(addr)
+            .and_then(|device| device.nightlight.take())
     }
 
-    /// Save to disk
+    /// Save to disk, the previous file is kept as a backup if it's valid
     pub fn flush(&self) {
-        let mut file = if !fs::exists(&self.path).unwrap() {
-            File::create(&self.path).expect("Failed to create storage file")
-        } else {
-            File::options()
-                .write(true)
-                .open(&self.path)
-                .expect("Failed to open storage file in write-only")
+        let content = match self.serialize_file() {
+            Ok(content) => content,
+            Err(err) => {
+                error!("Failed to serialize storage data, the storage file is kept as is {err}");
+                return;
+            }
         };
 
-        if let Err(err) = file.write_all(
-            serde_json::to_string(&self.serialize_data())
-                .expect("Cannot parse storage data to String")
-                .as_bytes(),
-        ) {
+        // A corrupted file must not replace a valid backup
+        if let Ok(Some(_)) = Self::read_file(&self.path) {
+            if let Err(err) = fs::copy(&self.path, self.backup_path()) {
+                error!("Failed to backup storage file {err}");
+            }
+        }
+
+        if let Err(err) = Self::write_file(&self.path, &content) {
             error!("Failed to write to storage file data {err}");
-            return;
+        }
+    }
+
+    /// Writes the saved devices to another file, in the same format as the storage file
+    pub fn export_to(&mut self, path: &Path) -> Result<(), String> {
+        if self.data.is_empty() {
+            self.load_from_file();
         }
 
-        file.flush().expect("Failed to write to storage file");
+        let content = self.serialize_file().map_err(|err| err.to_string())?;
+
+        Self::write_file(path, &content).map_err(|err| err.to_string())
     }
-}
 
-fn parse_hex_address(address: &str) -> [u8; ADDR_LEN] {
-    let mut addr = [0; ADDR_LEN];
-    let chars = address.chars().filter(|c| *c != ':');
-    let bytes = chars
-        .clone()
-        .step_by(2)
-        .zip(chars.skip(1).step_by(2))
-        .map(|(a, b)| {
-            u8::from_str_radix(&format!("{a}{b}"), 16)
-                .map_err(|e| panic!("[ERROR] Cannot parse {address} to hex value {e}"))
-                .unwrap()
-        })
-        .collect::<Vec<_>>();
+    /// Reads the devices of an exported file. If merge, they're added to the saved devices (and
+    /// replace the ones with the same address), otherwise they replace all the saved devices. It's
+    /// only kept in memory until flushed. Returns the number of imported devices
+    pub fn import_from(&mut self, path: &Path, merge: bool) -> Result<usize, String> {
+        let devices = match Self::read_file(path)? {
+            Some(devices) => self.deserialize_data(devices),
+            None => return Err(format!("{} doesn't exist", path.display())),
+        };
+        let count = devices.len();
+
+        if merge {
+            if self.data.is_empty() {
+                self.load_from_file();
+            }
+
+            self.data.extend(devices);
+        } else {
+            self.data = devices;
+        }
 
-    assert!(
-        bytes.len() == ADDR_LEN,
-        "[ERROR] Hex address {address} is not right. It must be of length {ADDR_LEN}"
-    );
+        Ok(count)
+    }
 
-    for (i, byte) in bytes.into_iter().enumerate() {
-        addr[i] = byte;
+    fn serialize_file(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&StorageFile {
+            version: STORAGE_VERSION,
+            devices: self.serialize_data(),
+        })
     }
 
-    addr
+    /// Writes to a temporary file renamed over the file so it's never left partially written
+    fn write_file(path: &Path, content: &str) -> std::io::Result<()> {
+        let tmp_path = Self::tmp_path(path);
+        let written = File::create(&tmp_path).and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        });
+
+        written
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp_path);
+            })
+    }
 }
@@ -1,5 +1,28 @@
-use crate::constants::{OutputCode, HUE_BAR_1_ADDR};
-use crate::utils::{addr_to_uint, uint_to_addr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::brightness::{BrightnessAggregate, BrightnessCurve};
+use crate::colors::{GamutClamp, Xy};
+use crate::config::Config;
+use crate::constants::{
+    MaskT, OutputCode, ADDR_LEN, CMD_DELAY_RANGE_MS, DAEMON_TIMEOUT_RANGE_SECS, HUE_BAR_1_ADDR,
+    HUE_BAR_2_ADDR, OUTPUT_LEN,
+};
+use crate::constants::masks::*;
+use crate::constants::regions;
+use crate::device::{
+    color_from_payload, fade_steps, failure_reason, info_string, notification_mask,
+    temp_range_from_payload, CmdOutput, DeviceState, FoundDevice, Response,
+};
+use crate::logger::{
+    archive_log_file, format_log_line, parse_log_level, read_tail, rotate_log_file, LogWriter,
+};
+use crate::storage::{LightState, SavedDevice, Storage, STORAGE_VERSION};
+use crate::utils::{
+    addr_to_str, addr_to_uint, is_hue_oui, parse_hex_address, uint_to_addr, wait_for_daemon_exit,
+    AddressParseError,
+};
 
 #[test]
 fn output_codes_consistency() {
@@ -17,6 +40,18 @@ fn output_codes_consistency() {
 
     assert_eq!(u8::from(OutputCode::StreamEOF), 4);
     assert!(matches!(OutputCode::from(4), OutputCode::StreamEOF));
+
+    assert_eq!(u8::from(OutputCode::RateLimited), 5);
+    assert!(matches!(OutputCode::from(5), OutputCode::RateLimited));
+
+    assert_eq!(u8::from(OutputCode::NotPaired), 6);
+    assert!(matches!(OutputCode::from(6), OutputCode::NotPaired));
+
+    assert_eq!(u8::from(OutputCode::NotConnected), 7);
+    assert!(matches!(OutputCode::from(7), OutputCode::NotConnected));
+
+    assert_eq!(u8::from(OutputCode::VersionMismatch), 8);
+    assert!(matches!(OutputCode::from(8), OutputCode::VersionMismatch));
 }
 
 #[test]
@@ -32,3 +67,852 @@ fn address_conversion() {
     let addr = addr_to_uint(&HUE_BAR_1_ADDR);
     assert_eq!(addr, uint);
 }
+
+#[test]
+fn address_formatting() {
+    assert_eq!(addr_to_str(&HUE_BAR_1_ADDR), "e8:d4:ea:c4:62:00");
+}
+
+#[test]
+fn address_parsing() {
+    assert_eq!(parse_hex_address("e8:d4:ea:c4:62:00"), Ok(HUE_BAR_1_ADDR));
+    assert_eq!(parse_hex_address("E8D4EAC46200"), Ok(HUE_BAR_1_ADDR));
+    let addr = Ok([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+    assert_eq!(parse_hex_address("aabbccddeeff"), addr);
+    assert_eq!(parse_hex_address("AA-BB-CC-DD-EE-FF"), addr);
+    assert_eq!(parse_hex_address("0xAABBCCDDEEFF"), addr);
+    assert_eq!(parse_hex_address("0Xe8d4.eac4.6200"), Ok(HUE_BAR_1_ADDR));
+
+    assert_eq!(
+        parse_hex_address("zz:zz:zz:zz:zz:zz"),
+        Err(AddressParseError::NonHex('z'))
+    );
+    assert_eq!(
+        parse_hex_address("e8_d4_ea_c4_62_00"),
+        Err(AddressParseError::WrongSeparator('_'))
+    );
+    assert_eq!(
+        parse_hex_address("0xe8:d4:ea"),
+        Err(AddressParseError::BadLength(6))
+    );
+    assert_eq!(
+        parse_hex_address("e8:d4:ea"),
+        Err(AddressParseError::BadLength(6))
+    );
+    assert_eq!(
+        parse_hex_address("e8:d4:ea:c4:62:00:01"),
+        Err(AddressParseError::BadLength(14))
+    );
+    assert_eq!(parse_hex_address(""), Err(AddressParseError::BadLength(0)));
+}
+
+#[test]
+fn log_level_parsing() {
+    assert_eq!(parse_log_level("trace"), Some(log::LevelFilter::Trace));
+    assert_eq!(parse_log_level("error"), Some(log::LevelFilter::Error));
+    assert_eq!(parse_log_level(" Debug "), Some(log::LevelFilter::Debug));
+    assert_eq!(parse_log_level("off"), Some(log::LevelFilter::Off));
+    assert_eq!(parse_log_level("verbose"), None);
+    assert_eq!(parse_log_level(""), None);
+}
+
+#[test]
+fn json_log_line() {
+    let line = format_log_line("Rustbee-Test", log::Level::Warn, "Device \"e8\" lost", true);
+    assert!(line.ends_with('\n') && line.matches('\n').count() == 1);
+
+    let value = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+    assert_eq!(value["level"], "WARN");
+    assert_eq!(value["name"], "Rustbee-Test");
+    assert_eq!(value["msg"], "Device \"e8\" lost");
+    assert!(chrono::DateTime::parse_from_rfc3339(value["ts"].as_str().unwrap()).is_ok());
+
+    let line = format_log_line("Rustbee-Test", log::Level::Info, "plain", false);
+    assert!(line.starts_with("[Rustbee-Test]<") && line.ends_with("> INFO: plain\n"));
+}
+
+#[test]
+fn log_writer_order() {
+    let path = std::env::temp_dir().join("rustbee_log_writer.log");
+    let _ = std::fs::remove_file(&path);
+
+    let writer = LogWriter::spawn(path.clone(), None).unwrap();
+    for i in 0..1000 {
+        writer.write(format!("line {i}\n"), i == 500).unwrap();
+    }
+    writer.flush();
+
+    let content = std::fs::read_to_string(&path).unwrap();
+    let lines = content.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 1000);
+    for (i, line) in lines.into_iter().enumerate() {
+        assert_eq!(line, format!("line {i}"));
+    }
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn log_writer_reopen() {
+    let path = std::env::temp_dir().join("rustbee_log_writer_reopen.log");
+    let archive = path.with_extension("log.1");
+    let _ = std::fs::remove_file(&path);
+
+    let writer = LogWriter::spawn(path.clone(), None).unwrap();
+    writer.write("first\n".into(), true).unwrap();
+
+    // Archived by another process, the next lines go to a new file
+    std::fs::rename(&path, &archive).unwrap();
+    writer.write("second\n".into(), true).unwrap();
+
+    assert_eq!(std::fs::read_to_string(&archive).unwrap(), "first\n");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n");
+
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&archive);
+}
+
+#[test]
+fn log_tail() {
+    let path = std::env::temp_dir().join("rustbee_log_tail.log");
+    let content = (0..10_000).map(|i| format!("[Rustbee-Test] line {i}\n")).collect::<String>();
+    std::fs::write(&path, &content).unwrap();
+
+    let mut file = std::fs::File::open(&path).unwrap();
+    let tail = read_tail(&mut file, 500).unwrap();
+    let lines = tail.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 500);
+    assert_eq!(lines[0], "[Rustbee-Test] line 9500");
+    assert_eq!(lines[499], "[Rustbee-Test] line 9999");
+
+    assert_eq!(read_tail(&mut file, 10_000).unwrap(), content);
+    assert_eq!(read_tail(&mut file, 20_000).unwrap(), content);
+    assert_eq!(read_tail(&mut file, 0).unwrap(), "");
+
+    // Without a newline at the end
+    std::fs::write(&path, "a\nb\nc").unwrap();
+    let mut file = std::fs::File::open(&path).unwrap();
+    assert_eq!(read_tail(&mut file, 2).unwrap(), "b\nc");
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn log_archive() {
+    let path = std::env::temp_dir().join("rustbee_log_archive.log");
+    std::fs::write(&path, "[Rustbee-Test] archived\n").unwrap();
+
+    let archive = archive_log_file(&path, "2024-11-18_21-03-12").unwrap();
+    assert_eq!(
+        archive,
+        std::env::temp_dir().join("rustbee_log_archive.log.2024-11-18_21-03-12")
+    );
+    assert_eq!(std::fs::read_to_string(&archive).unwrap(), "[Rustbee-Test] archived\n");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&archive);
+}
+
+#[test]
+fn log_rotation() {
+    let dir = std::env::temp_dir();
+    let path = dir.join("rustbee_log_rotation.log");
+    let rotation = |index: usize| dir.join(format!("rustbee_log_rotation.log.{index}"));
+    for file in [path.clone(), rotation(1), rotation(2), rotation(3)] {
+        let _ = std::fs::remove_file(file);
+    }
+
+    assert!(!rotate_log_file(&path, 10, 64, 2).unwrap());
+
+    for i in 0..3 {
+        std::fs::write(&path, format!("{i}").repeat(60)).unwrap();
+        assert!(!rotate_log_file(&path, 4, 64, 2).unwrap());
+        assert!(rotate_log_file(&path, 10, 64, 2).unwrap());
+        assert!(!path.exists());
+    }
+
+    assert_eq!(std::fs::read_to_string(rotation(1)).unwrap(), "2".repeat(60));
+    assert_eq!(std::fs::read_to_string(rotation(2)).unwrap(), "1".repeat(60));
+    assert!(!rotation(3).exists());
+
+    for file in [rotation(1), rotation(2)] {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+fn output(code: OutputCode, bytes: &[u8]) -> CmdOutput {
+    let mut data = [0; OUTPUT_LEN - 1];
+    data[..bytes.len()].copy_from_slice(bytes);
+    (code, data)
+}
+
+#[test]
+fn response_power() {
+    let res = Response::decode(CONNECT | POWER, output(OutputCode::Success, &[1]));
+    assert_eq!(res, Ok(Response::Power(true)));
+
+    let res = Response::decode(POWER, output(OutputCode::Success, &[0]));
+    assert_eq!(res, Ok(Response::Power(false)));
+}
+
+#[test]
+fn response_brightness() {
+    let res = Response::decode(CONNECT | BRIGHTNESS, output(OutputCode::Success, &[255]));
+    assert_eq!(res, Ok(Response::Brightness(100)));
+
+    let res = Response::decode(BRIGHTNESS, output(OutputCode::Success, &[0]));
+    assert_eq!(res, Ok(Response::Brightness(0)));
+}
+
+#[test]
+fn response_color_xy() {
+    let x = ((0.5 * 0xFFFF as f64) as u16).to_le_bytes();
+    let y = ((0.25 * 0xFFFF as f64) as u16).to_le_bytes();
+
+    for mask in [COLOR_XY, COLOR_RGB, CONNECT | COLOR_HEX] {
+        let res = Response::decode(mask, output(OutputCode::Success, &[x[0], x[1], y[0], y[1]]));
+        let Ok(Response::ColorXy(xy)) = res else {
+            panic!("Unexpected response {res:?}");
+        };
+
+        assert!((xy.x - 0.5).abs() < 1e-4 && (xy.y - 0.25).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn device_state_regions() {
+    let mut data = [0; regions::CONNECTED_IDX + 1];
+    data[regions::POWER_IDX] = 1;
+    data[regions::BRIGHTNESS_IDX] = 255;
+    data[regions::COLOR_IDX..regions::COLOR_IDX + 4].copy_from_slice(&[0xFF, 0xFF, 0, 0]);
+    data[regions::CONNECTED_IDX] = 1;
+
+    let state = DeviceState::decode("Hue Bar".into(), output(OutputCode::Success, &data)).unwrap();
+    assert!(state.connected && state.power);
+    assert_eq!(state.brightness, 100);
+    assert_eq!((state.color.x, state.color.y), (1., 0.));
+    assert_eq!(state.name, "Hue Bar");
+
+    let res = DeviceState::decode(String::new(), output(OutputCode::DeviceNotFound, &[]));
+    assert_eq!(res, Err(OutputCode::DeviceNotFound));
+}
+
+#[test]
+fn notification_decoding() {
+    use crate::constants::{BRIGHTNESS_UUID, COLOR_UUID, MODEL_UUID, POWER_UUID};
+
+    assert_eq!(notification_mask(&POWER_UUID), Some(POWER));
+    assert_eq!(notification_mask(&BRIGHTNESS_UUID), Some(BRIGHTNESS));
+    assert_eq!(notification_mask(&COLOR_UUID), Some(COLOR_XY));
+    assert_eq!(notification_mask(&MODEL_UUID), None);
+
+    let notification = |mask: MaskT, value: &[u8]| {
+        let mut data = [0; OUTPUT_LEN - 1];
+        data[..2].copy_from_slice(&mask.to_le_bytes());
+        data[2..value.len() + 2].copy_from_slice(value);
+        data
+    };
+
+    assert_eq!(
+        Response::decode_notification(notification(POWER, &[1])),
+        Response::Power(true)
+    );
+    assert_eq!(
+        Response::decode_notification(notification(BRIGHTNESS, &[255])),
+        Response::Brightness(100)
+    );
+    let color = Response::decode_notification(notification(COLOR_XY, &[0xFF, 0xFF, 0, 0]));
+    let Response::ColorXy(xy) = color else {
+        panic!("Unexpected response {color:?}");
+    };
+    assert_eq!((xy.x, xy.y), (1., 0.));
+}
+
+#[test]
+fn xy_from_le_bytes() {
+    let xy = Xy::from_le_bytes([0xFF, 0xFF, 0x00, 0x00]);
+    assert_eq!((xy.x, xy.y), (1., 0.));
+
+    // 0x8000 and 0x4000 as little endian
+    let xy = Xy::from_le_bytes([0x00, 0x80, 0x00, 0x40]);
+    assert!((xy.x - 0.5).abs() < 1e-4 && (xy.y - 0.25).abs() < 1e-4);
+    assert!(xy.brightness.is_none());
+}
+
+#[test]
+fn response_name() {
+    let res = Response::decode(NAME, output(OutputCode::Success, b"Hue Bar"));
+    assert_eq!(res, Ok(Response::Name("Hue Bar".into())));
+
+    let res = Response::decode(NAME, output(OutputCode::Success, &[]));
+    assert_eq!(res, Ok(Response::Name(String::new())));
+}
+
+#[test]
+fn response_connected() {
+    let res = Response::decode(CONNECT, output(OutputCode::Success, &[1]));
+    assert_eq!(res, Ok(Response::Connected(true)));
+}
+
+#[test]
+fn response_none_and_errors() {
+    let res = Response::decode(DISCONNECT, output(OutputCode::Success, &[]));
+    assert_eq!(res, Ok(Response::None));
+
+    let res = Response::decode(POWER, output(OutputCode::DeviceNotFound, &[1]));
+    assert_eq!(res, Err(OutputCode::DeviceNotFound));
+
+    let res = Response::decode(NAME, output(OutputCode::Failure, b"Hue"));
+    assert_eq!(res, Err(OutputCode::Failure));
+}
+
+#[test]
+fn failure_reasons() {
+    let res = output(OutputCode::Failure, b"cannot read power");
+    assert_eq!(failure_reason(&res), Some("cannot read power"));
+
+    // Fills the whole data without a null byte
+    let res = output(OutputCode::Failure, &[b'a'; OUTPUT_LEN - 1]);
+    assert_eq!(failure_reason(&res).map(str::len), Some(OUTPUT_LEN - 1));
+
+    assert_eq!(failure_reason(&output(OutputCode::Failure, &[])), None);
+    assert_eq!(failure_reason(&output(OutputCode::Success, b"Hue")), None);
+}
+
+#[test]
+fn color_payload_length() {
+    assert!(color_from_payload(&HUE_BAR_1_ADDR, &[0x01, 0x02]).is_err());
+    assert!(color_from_payload(&HUE_BAR_1_ADDR, &[]).is_err());
+    assert_eq!(
+        color_from_payload(&HUE_BAR_1_ADDR, &[0x01, 0x02, 0x03, 0x04]).unwrap(),
+        [0x01, 0x02, 0x03, 0x04]
+    );
+    // Extra bytes are ignored
+    assert_eq!(
+        color_from_payload(&HUE_BAR_1_ADDR, &[0x01, 0x02, 0x03, 0x04, 0x05]).unwrap(),
+        [0x01, 0x02, 0x03, 0x04]
+    );
+}
+
+#[test]
+fn temp_range_payload() {
+    // 366 mireds with a 153..=454 range
+    assert_eq!(
+        temp_range_from_payload(&[0x6e, 0x01, 0x99, 0x00, 0xc6, 0x01]),
+        Some((153, 454))
+    );
+    // The value only, an unset or a reversed range
+    assert_eq!(temp_range_from_payload(&[0x6e, 0x01]), None);
+    assert_eq!(temp_range_from_payload(&[0x6e, 0x01, 0, 0, 0, 0]), None);
+    assert_eq!(
+        temp_range_from_payload(&[0x6e, 0x01, 0xc6, 0x01, 0x99, 0x00]),
+        None
+    );
+}
+
+#[test]
+fn device_info_strings() {
+    assert_eq!(info_string(b"LCA001"), "LCA001");
+    assert_eq!(
+        info_string(b"Signify Netherlands B.V.\0\0\0"),
+        "Signify Netherlands B.V."
+    );
+    assert_eq!(info_string(b"1.104.2 "), "1.104.2");
+    assert_eq!(info_string(b""), "");
+}
+
+#[test]
+fn fade_interpolation() {
+    let step = Duration::from_millis(100);
+
+    let steps = fade_steps(20., 80., Duration::from_secs(1), step);
+    assert_eq!(steps.len(), 11);
+    assert_eq!((steps[0], steps[10]), (20., 80.));
+    assert!(steps.windows(2).all(|pair| pair[0] < pair[1]));
+
+    assert_eq!(
+        fade_steps(100., 0., Duration::from_millis(250), step),
+        [100., 50., 0.]
+    );
+    // Shorter than a step, it's set at once
+    assert_eq!(fade_steps(0., 42., Duration::ZERO, step), [0., 42.]);
+}
+
+#[test]
+fn hue_oui_filter() {
+    assert!(is_hue_oui(&HUE_BAR_1_ADDR));
+    assert!(is_hue_oui(&HUE_BAR_2_ADDR));
+    assert!(is_hue_oui(&[0x00, 0x17, 0x88, 0x01, 0x02, 0x03]));
+    assert!(!is_hue_oui(&[0x00, 0x17, 0x89, 0x01, 0x02, 0x03]));
+    assert!(!is_hue_oui(&[0; ADDR_LEN]));
+}
+
+#[test]
+fn daemon_exit_polling() {
+    let polls = std::cell::Cell::new(0);
+    let is_running = || {
+        polls.set(polls.get() + 1);
+        Ok(polls.get() < 3)
+    };
+
+    assert!(wait_for_daemon_exit(is_running).is_ok());
+    assert_eq!(polls.get(), 3);
+
+    let is_running = || Err(std::io::Error::other("cannot read processes"));
+    assert!(wait_for_daemon_exit(is_running).is_err());
+}
+
+/// Unique storage path in the temp dir, the file and its .bak and .tmp siblings are removed when
+/// it's dropped so a failing test doesn't leave them behind either
+struct TempStorage {
+    path: PathBuf,
+}
+
+impl TempStorage {
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "rustbee_storage_{}_{count}.json",
+            std::process::id()
+        ));
+        let temp = Self { path };
+        temp.remove_files();
+
+        temp
+    }
+
+    fn storage(&self) -> Storage {
+        Storage::new(self.path.clone())
+    }
+
+    /// Path of the file with the suffix appended, e.g. ".bak"
+    fn with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+
+        PathBuf::from(path)
+    }
+
+    fn remove_files(&self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = std::fs::remove_file(self.with_suffix(".bak"));
+        let _ = std::fs::remove_file(self.with_suffix(".tmp"));
+    }
+}
+
+impl Drop for TempStorage {
+    fn drop(&mut self) {
+        self.remove_files();
+    }
+}
+
+#[test]
+fn nightlight_state_save_restore() {
+    let temp = TempStorage::new();
+
+    let state = LightState {
+        power: true,
+        brightness: 80,
+        color: [0x12, 0x34, 0x56, 0x78],
+    };
+    let mut storage = temp.storage();
+
+    assert!(storage.save_nightlight_state(HUE_BAR_1_ADDR, state));
+    // Turning the nightlight on twice must not overwrite the state prior to the first one
+    assert!(!storage.save_nightlight_state(HUE_BAR_1_ADDR, LightState::default()));
+    storage.flush();
+
+    let mut storage = temp.storage();
+    assert_eq!(storage.take_nightlight_state(&HUE_BAR_1_ADDR), Some(state));
+    assert_eq!(storage.take_nightlight_state(&HUE_BAR_1_ADDR), None);
+    storage.flush();
+
+    let mut storage = temp.storage();
+    assert_eq!(storage.take_nightlight_state(&HUE_BAR_1_ADDR), None);
+}
+
+#[test]
+fn sorted_devices() {
+    let temp = TempStorage::new();
+
+    let mut storage = temp.storage();
+    storage.set_devices(vec![
+        (HUE_BAR_2_ADDR, None),
+        (HUE_BAR_1_ADDR, None),
+        ([0; ADDR_LEN], None),
+    ]);
+
+    let addresses = storage
+        .get_sorted_devices()
+        .into_iter()
+        .map(|(addr, _)| *addr)
+        .collect::<Vec<_>>();
+
+    assert_eq!(addresses, [[0; ADDR_LEN], HUE_BAR_1_ADDR, HUE_BAR_2_ADDR]);
+}
+
+#[test]
+fn device_by_index() {
+    let temp = TempStorage::new();
+
+    let mut storage = temp.storage();
+    storage.set_devices(vec![(HUE_BAR_2_ADDR, None), (HUE_BAR_1_ADDR, None)]);
+
+    assert_eq!(storage.get_device_addr_by_index(0), Ok(HUE_BAR_1_ADDR));
+    assert_eq!(storage.get_device_addr_by_index(1), Ok(HUE_BAR_2_ADDR));
+    assert_eq!(
+        storage.get_device_addr_by_index(2),
+        Err("Device index 2 is out of range, expected 0 to 1".into())
+    );
+}
+
+#[test]
+fn saved_device_state_round_trip() {
+    let temp = TempStorage::new();
+
+    let mut storage = temp.storage();
+    storage.set_device(
+        HUE_BAR_1_ADDR,
+        Some(SavedDevice {
+            power_state: true,
+            color_temp: Some(2700),
+            ..Default::default()
+        }),
+    );
+    storage.set_device(HUE_BAR_2_ADDR, None);
+    storage.save_model(HUE_BAR_1_ADDR, "LCT001");
+    // Not saved so it's left out
+    storage.save_model([0; ADDR_LEN], "LCT001");
+    storage.flush();
+
+    let mut storage = temp.storage();
+    let saved = storage.get_device(&HUE_BAR_1_ADDR).unwrap();
+    assert_eq!((saved.power_state, saved.color_temp), (true, Some(2700)));
+    assert_eq!(saved.model.as_deref(), Some("LCT001"));
+    assert!(storage.get_device(&[0; ADDR_LEN]).is_none());
+    let saved = storage.get_device(&HUE_BAR_2_ADDR).unwrap();
+    assert_eq!((saved.power_state, saved.color_temp), (false, None));
+
+    // Files written before these fields existed
+    let device = serde_json::from_str::<SavedDevice>(
+        r#"{"name":"Bar","current_color":[1,2,3],"brightness":42}"#,
+    )
+    .unwrap();
+    assert_eq!((device.power_state, device.color_temp), (false, None));
+}
+
+#[test]
+fn storage_export_import() {
+    let (source_temp, target_temp, export_temp) =
+        (TempStorage::new(), TempStorage::new(), TempStorage::new());
+
+    let mut source = source_temp.storage();
+    source.set_device(
+        HUE_BAR_1_ADDR,
+        Some(SavedDevice {
+            name: "Exported".into(),
+            ..Default::default()
+        }),
+    );
+    source.export_to(&export_temp.path).unwrap();
+
+    let mut target = target_temp.storage();
+    target.set_device(HUE_BAR_1_ADDR, None);
+    target.set_device(HUE_BAR_2_ADDR, None);
+
+    assert_eq!(target.import_from(&export_temp.path, true), Ok(1));
+    assert_eq!(target.get_device(&HUE_BAR_1_ADDR).unwrap().name, "Exported");
+    assert!(target.get_device(&HUE_BAR_2_ADDR).is_some());
+
+    assert_eq!(target.import_from(&export_temp.path, false), Ok(1));
+    assert!(target.get_device(&HUE_BAR_1_ADDR).is_some());
+    assert!(target.get_device(&HUE_BAR_2_ADDR).is_none());
+
+    // Never written
+    assert!(target.import_from(&TempStorage::new().path, true).is_err());
+}
+
+#[test]
+fn duplicate_addresses() {
+    let temp = TempStorage::new();
+
+    let mut storage = temp.storage();
+    for name in ["First", "Second"] {
+        storage.set_device(
+            HUE_BAR_1_ADDR,
+            Some(SavedDevice {
+                name: name.into(),
+                ..Default::default()
+            }),
+        );
+    }
+    assert_eq!(storage.get_sorted_devices().len(), 1);
+    assert_eq!(storage.get_device(&HUE_BAR_1_ADDR).unwrap().name, "Second");
+
+    // The same address with another casing, the most recently seen is kept
+    std::fs::write(
+        &temp.path,
+        r#"{"version":1,"devices":{
+            "E8:D4:EA:C4:62:00":{"name":"Old","current_color":[0,0,0],"brightness":0,"last_seen":1},
+            "e8:d4:ea:c4:62:00":{"name":"New","current_color":[0,0,0],"brightness":0,"last_seen":2}
+        }}"#,
+    )
+    .unwrap();
+    let mut storage = temp.storage();
+    assert_eq!(storage.get_sorted_devices().len(), 1);
+    assert_eq!(storage.get_device(&HUE_BAR_1_ADDR).unwrap().name, "New");
+}
+
+#[test]
+fn storage_dedup() {
+    let device = |name: &str, last_seen| SavedDevice {
+        name: name.into(),
+        last_seen,
+        ..Default::default()
+    };
+    let upper = parse_hex_address("E8:D4:EA:C4:62:00").unwrap();
+    let lower = parse_hex_address("e8:d4:ea:c4:62:00").unwrap();
+
+    // The most recently seen is kept, then the last one written
+    let devices = Storage::dedup([
+        (upper, device("Seen", Some(2))),
+        (lower, device("Older", Some(1))),
+        (HUE_BAR_2_ADDR, device("First", None)),
+        (HUE_BAR_2_ADDR, device("Second", None)),
+    ]);
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[&HUE_BAR_1_ADDR].name, "Seen");
+    assert_eq!(devices[&HUE_BAR_2_ADDR].name, "Second");
+
+    // The storage is never loaded nor flushed so no file is involved
+    let mut storage = Storage::new(Default::default());
+    storage.set_devices(vec![
+        (upper, Some(device("First", None))),
+        (lower, Some(device("Second", None))),
+    ]);
+    assert_eq!(storage.get_sorted_devices().len(), 1);
+    assert_eq!(storage.get_device(&HUE_BAR_1_ADDR).unwrap().name, "Second");
+}
+
+#[test]
+fn device_removal() {
+    let temp = TempStorage::new();
+
+    let mut storage = temp.storage();
+    storage.set_devices(vec![(HUE_BAR_1_ADDR, None), (HUE_BAR_2_ADDR, None)]);
+    storage.flush();
+
+    let mut storage = temp.storage();
+    assert!(storage.remove_device(&HUE_BAR_1_ADDR));
+    assert!(!storage.remove_device(&HUE_BAR_1_ADDR));
+    storage.flush();
+
+    let mut storage = temp.storage();
+    assert!(storage.get_device(&HUE_BAR_1_ADDR).is_none());
+    assert!(storage.get_device(&HUE_BAR_2_ADDR).is_some());
+}
+
+#[test]
+fn zero_address_not_persisted() {
+    let temp = TempStorage::new();
+
+    let mut storage = temp.storage();
+    storage.set_devices(vec![(HUE_BAR_1_ADDR, None), ([0; ADDR_LEN], None)]);
+    storage.flush();
+
+    let content = std::fs::read_to_string(&temp.path).unwrap();
+    assert!(!content.contains("00:00:00:00:00:00"));
+
+    // Even if it was written by a previous version
+    let legacy = r#"{
+        "00:00:00:00:00:00":{"name":"","current_color":[0,0,0],"brightness":0},
+        "e8:d4:ea:c4:62:00":{"name":"","current_color":[0,0,0],"brightness":0}
+    }"#;
+    std::fs::write(&temp.path, legacy).unwrap();
+    let mut storage = temp.storage();
+    assert!(storage.get_device(&[0; ADDR_LEN]).is_none());
+    assert!(storage.get_device(&HUE_BAR_1_ADDR).is_some());
+}
+
+#[test]
+fn storage_file_versions() {
+    let temp = TempStorage::new();
+    let device = r#"{"name":"Bar","current_color":[1,2,3],"brightness":42}"#;
+
+    // Legacy bare map
+    std::fs::write(&temp.path, format!(r#"{{"e8:d4:ea:c4:62:00":{device}}}"#)).unwrap();
+    let mut storage = temp.storage();
+    let saved = storage.get_device(&HUE_BAR_1_ADDR).unwrap();
+    assert_eq!((saved.name.as_str(), saved.brightness), ("Bar", 42));
+
+    // Flushing upgrades it
+    storage.flush();
+    let content = std::fs::read_to_string(&temp.path).unwrap();
+    assert!(content.starts_with(&format!(r#"{{"version":{STORAGE_VERSION},"devices":"#)));
+
+    std::fs::write(
+        &temp.path,
+        format!(r#"{{"version":1,"devices":{{"e8:d4:ea:c4:62:00":{device}}}}}"#),
+    )
+    .unwrap();
+    let mut storage = temp.storage();
+    assert_eq!(storage.get_device(&HUE_BAR_1_ADDR).unwrap().current_color, [1, 2, 3]);
+
+    // Future versions are rejected, without a backup to fall back to
+    let _ = std::fs::remove_file(temp.with_suffix(".bak"));
+    std::fs::write(
+        &temp.path,
+        format!(r#"{{"version":99,"devices":{{"e8:d4:ea:c4:62:00":{device}}}}}"#),
+    )
+    .unwrap();
+    assert!(temp.storage().get_device(&HUE_BAR_1_ADDR).is_none());
+}
+
+#[test]
+fn storage_interrupted_flush() {
+    let temp = TempStorage::new();
+    let tmp_path = temp.with_suffix(".tmp");
+
+    let mut storage = temp.storage();
+    storage.set_devices(vec![(HUE_BAR_1_ADDR, None)]);
+    storage.flush();
+    assert!(!tmp_path.exists());
+
+    // A flush killed mid-write leaves a truncated temporary file behind, not a truncated storage
+    std::fs::write(&tmp_path, r#"{"e8:d4:ea:c4:62:00":{"na"#).unwrap();
+    let mut storage = temp.storage();
+    assert!(storage.get_device(&HUE_BAR_1_ADDR).is_some());
+
+    // And the next flush replaces it
+    storage.set_devices(vec![(HUE_BAR_2_ADDR, None)]);
+    storage.flush();
+    assert!(!tmp_path.exists());
+    let mut storage = temp.storage();
+    assert!(storage.get_device(&HUE_BAR_2_ADDR).is_some());
+}
+
+#[test]
+fn storage_backup_recovery() {
+    let temp = TempStorage::new();
+
+    let mut storage = temp.storage();
+    storage.set_device(HUE_BAR_1_ADDR, None);
+    storage.flush();
+    // Rotates the first file to the backup
+    storage.set_device(HUE_BAR_2_ADDR, None);
+    storage.flush();
+
+    std::fs::write(&temp.path, "{ not json").unwrap();
+
+    let mut storage = temp.storage();
+    let addresses = storage
+        .get_sorted_devices()
+        .into_iter()
+        .map(|(addr, _)| *addr)
+        .collect::<Vec<_>>();
+    assert_eq!(addresses, [HUE_BAR_1_ADDR]);
+
+    // Flushing over the corrupted file must keep the valid backup
+    storage.flush();
+    std::fs::write(&temp.path, "{ not json").unwrap();
+    assert!(temp.storage().get_device(&HUE_BAR_1_ADDR).is_some());
+}
+
+#[test]
+fn found_device_serialization() {
+    let device = FoundDevice {
+        address: HUE_BAR_1_ADDR,
+        name: "Hue Play".into(),
+    };
+
+    assert_eq!(
+        serde_json::to_value(&device).unwrap(),
+        serde_json::json!({
+            "address": "e8:d4:ea:c4:62:00",
+            "address_bytes": [0xE8, 0xD4, 0xEA, 0xC4, 0x62, 0x00],
+            "name": "Hue Play",
+        })
+    );
+}
+
+#[test]
+fn config_from_vars() {
+    assert_eq!(Config::from_vars(|_| None), Config::default());
+
+    let vars = std::collections::HashMap::from([
+        ("RUSTBEE_DAEMON_BIN", "/opt/rustbee-daemon"),
+        ("RUSTBEE_SOCKET", "/run/user/1000/rustbee.sock"),
+        ("RUSTBEE_MAX_CONNECTIONS", "4"),
+        ("RUSTBEE_DAEMON_TIMEOUT", "3600"),
+        ("RUSTBEE_CMD_DELAY_MS", "50"),
+        ("RUSTBEE_BRIGHTNESS_CURVE", "square"),
+        ("RUSTBEE_BRIGHTNESS_AGGREGATE", "mode"),
+        ("RUSTBEE_GAMUT_CLAMP", "desaturate"),
+        ("RUSTBEE_ALERT_DEVICE", "e8:d4:ea:c4:62:00"),
+        ("RUSTBEE_ADAPTER_RESET", "1"),
+        ("RUSTBEE_TRACE", "packets"),
+        ("RUSTBEE_LOG", "trace"),
+        ("RUSTBEE_LOG_FORMAT", "json"),
+    ]);
+    let config = Config::from_vars(|name| vars.get(name).map(|value| value.to_string()));
+
+    assert_eq!(
+        config,
+        Config {
+            daemon_bin: Some("/opt/rustbee-daemon".into()),
+            socket_path: "/run/user/1000/rustbee.sock".into(),
+            max_connections: 4,
+            daemon_timeout_secs: 3600,
+            cmd_delay_ms: 50,
+            brightness_curve: BrightnessCurve::Square,
+            brightness_aggregate: BrightnessAggregate::Mode,
+            gamut_clamp: GamutClamp::Desaturate,
+            alert_device: Some(HUE_BAR_1_ADDR),
+            adapter_reset: true,
+            trace_packets: true,
+            log_level: log::LevelFilter::Trace,
+            json_logs: true,
+        }
+    );
+
+    // Invalid and empty values fall back to the defaults
+    let vars = std::collections::HashMap::from([
+        ("RUSTBEE_DAEMON_BIN", ""),
+        ("RUSTBEE_SOCKET", ""),
+        ("RUSTBEE_MAX_CONNECTIONS", "0"),
+        ("RUSTBEE_DAEMON_TIMEOUT", "10m"),
+        ("RUSTBEE_CMD_DELAY_MS", "-50"),
+        ("RUSTBEE_BRIGHTNESS_CURVE", "log"),
+        ("RUSTBEE_ALERT_DEVICE", "e8:d4:ea"),
+        ("RUSTBEE_ADAPTER_RESET", "yes"),
+        ("RUSTBEE_LOG", "verbose"),
+    ]);
+    let config = Config::from_vars(|name| vars.get(name).map(|value| value.to_string()));
+    assert_eq!(config, Config::default());
+
+    // Out of range values are clamped
+    let vars = std::collections::HashMap::from([
+        ("RUSTBEE_DAEMON_TIMEOUT", "1"),
+        ("RUSTBEE_CMD_DELAY_MS", "3600000"),
+    ]);
+    let config = Config::from_vars(|name| vars.get(name).map(|value| value.to_string()));
+    assert_eq!(
+        config.daemon_timeout_secs,
+        *DAEMON_TIMEOUT_RANGE_SECS.start()
+    );
+    assert_eq!(config.cmd_delay_ms, *CMD_DELAY_RANGE_MS.end());
+}
+
+#[test]
+fn config_flags() {
+    let args = ["rustbee-daemon", "--adapter-reset", "--trace-packets"].map(String::from);
+    let config = Config::default().with_flags(args);
+    assert!(config.adapter_reset);
+    assert!(config.trace_packets);
+
+    let config = Config::default().with_flags(["rustbee", "power", "on"].map(String::from));
+    assert_eq!(config, Config::default());
+}
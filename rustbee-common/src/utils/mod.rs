@@ -1,7 +1,21 @@
 // Re-exports
 pub use super::daemon::*;
 
-use crate::constants::ADDR_LEN;
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::process::Child;
+use tokio::time;
+
+use crate::config::Config;
+use crate::constants::{
+    ADDR_LEN, DAEMON_BIN, DAEMON_BIN_ENV, DAEMON_READY_TIMEOUT_SECS, DAEMON_SHUTDOWN_TIMEOUT_SECS,
+    HUE_OUIS, MAX_NAME_LEN,
+};
+use crate::device::{Client, HueDevice};
 
 pub fn addr_to_uint(addr: &[u8; ADDR_LEN]) -> u64 {
     let mut res: u64 = 0;
@@ -22,3 +36,209 @@ pub fn uint_to_addr(addr: u64) -> [u8; ADDR_LEN] {
 
     res
 }
+
+/// Formats the address like so: xx:xx:xx:xx:xx:xx
+pub fn addr_to_str(addr: &[u8; ADDR_LEN]) -> String {
+    addr.iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressParseError {
+    /// Number of hex digits found instead of ADDR_LEN * 2
+    BadLength(usize),
+    NonHex(char),
+    WrongSeparator(char),
+}
+
+impl std::fmt::Display for AddressParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadLength(len) => write!(
+                f,
+                "expected {} hex digits but found {len}",
+                ADDR_LEN * 2
+            ),
+            Self::NonHex(c) => write!(f, "'{c}' is not a hex digit"),
+            Self::WrongSeparator(c) => {
+                write!(f, "'{c}' is not a valid separator, use ':', '-' or '.'")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AddressParseError {}
+
+/// Parses an address like so: xx:xx:xx:xx:xx:xx, the separators can also be '-' or '.' or left
+/// out and it can be prefixed by 0x as other tools print them
+pub fn parse_hex_address(address: &str) -> Result<[u8; ADDR_LEN], AddressParseError> {
+    let is_separator = |c: &char| matches!(c, ':' | '-' | '.');
+    let address = address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix("0X"))
+        .unwrap_or(address);
+
+    if let Some(c) = address
+        .chars()
+        .find(|c| !is_separator(c) && !c.is_ascii_hexdigit())
+    {
+        return Err(if matches!(c, '_' | ' ') {
+            AddressParseError::WrongSeparator(c)
+        } else {
+            AddressParseError::NonHex(c)
+        });
+    }
+
+    let digits = address
+        .chars()
+        .filter(|c| !is_separator(c))
+        .collect::<Vec<_>>();
+    if digits.len() != ADDR_LEN * 2 {
+        return Err(AddressParseError::BadLength(digits.len()));
+    }
+
+    let mut addr = [0; ADDR_LEN];
+    for (byte, pair) in addr.iter_mut().zip(digits.chunks(2)) {
+        // Both chars are checked to be hex digits above
+        *byte = (pair[0].to_digit(16).unwrap() << 4 | pair[1].to_digit(16).unwrap()) as _;
+    }
+
+    Ok(addr)
+}
+
+/// Whether the address starts with a known Philips/Signify prefix
+pub fn is_hue_oui(addr: &[u8; ADDR_LEN]) -> bool {
+    HUE_OUIS.iter().any(|oui| addr.starts_with(oui))
+}
+
+/// Truncates the name to MAX_NAME_LEN bytes without splitting a UTF-8 character
+pub fn truncate_name(name: &str) -> &str {
+    let mut end = usize::min(name.len(), MAX_NAME_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &name[..end]
+}
+
+/// Formats a unix timestamp in seconds to the local date time like so: 2024-11-18 21:03:12
+pub fn format_timestamp(secs: i64) -> String {
+    chrono::DateTime::from_timestamp(secs, 0)
+        .map(|date| {
+            date.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Paths the daemon executable is looked up from, in order: RUSTBEE_DAEMON_BIN, the directory of
+/// the current executable (for bundled installs) and the bare name so it's looked up on PATH
+pub fn daemon_bin_candidates() -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+
+    if let Some(path) = &Config::current().daemon_bin {
+        candidates.push(path.clone());
+    }
+
+    if let Some(dir) = env::current_exe().ok().as_deref().and_then(Path::parent) {
+        candidates.push(dir.join(DAEMON_BIN));
+    }
+
+    candidates.push(PathBuf::from(DAEMON_BIN));
+
+    candidates
+}
+
+/// Flags passed on to the spawned daemon so it traces the packets too
+pub(crate) fn daemon_flags() -> Option<&'static str> {
+    Config::current().trace_packets.then_some("--trace-packets")
+}
+
+/// Spawns the daemon from the first candidate path that works
+pub(crate) fn spawn_daemon<F>(spawn: F) -> io::Result<Child>
+where
+    F: Fn(&Path) -> io::Result<Child>,
+{
+    let mut tried = Vec::new();
+
+    for path in daemon_bin_candidates() {
+        match spawn(&path) {
+            Ok(child) => return Ok(child),
+            Err(err) => tried.push(format!("- {} ({err})", path.display())),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "[ERROR] Failed to spawn rustbee-daemon, you can specify its path with {DAEMON_BIN_ENV}. Tried:\n{}",
+            tried.join("\n")
+        ),
+    ))
+}
+
+/// Pings the newly spawned daemon with a backoff until it answers, errors with its stderr (if
+/// piped) when it exits before or if it didn't answer within DAEMON_READY_TIMEOUT_SECS
+pub(crate) async fn wait_for_daemon_ready(daemon: Child) -> io::Result<()> {
+    let ready = async {
+        let mut delay = Duration::from_millis(25);
+        while !HueDevice::<Client>::ping_daemon().await {
+            time::sleep(delay).await;
+            delay = Duration::min(delay * 2, Duration::from_millis(500));
+        }
+    };
+
+    tokio::select! {
+        out = daemon.wait_with_output() => {
+            let out = out?;
+            if !out.status.success() {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                let stderr = stderr.trim();
+
+                return Err(io::Error::other(format!(
+                    "[ERROR] Failed to launch rustbee-daemon:\n{stderr}"
+                )));
+            }
+
+            Ok(())
+        }
+        res = time::timeout(Duration::from_secs(DAEMON_READY_TIMEOUT_SECS), ready) => {
+            res.map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "[ERROR] rustbee-daemon didn't answer within {DAEMON_READY_TIMEOUT_SECS}s after launching it, see `rustbee logs`"
+                    ),
+                )
+            })
+        }
+    }
+}
+
+/// Polls the daemon process until it's gone so a following launch_daemon doesn't find the old
+/// one still shutting down. Errors if it didn't exit within DAEMON_SHUTDOWN_TIMEOUT_SECS
+pub(crate) fn wait_for_daemon_exit<F>(is_running: F) -> io::Result<()>
+where
+    F: Fn() -> io::Result<bool>,
+{
+    let timeout = Duration::from_secs(DAEMON_SHUTDOWN_TIMEOUT_SECS);
+    let start = Instant::now();
+
+    while is_running()? {
+        if start.elapsed() >= timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "[ERROR] rustbee-daemon is still running {DAEMON_SHUTDOWN_TIMEOUT_SECS}s after asking it to shutdown, you can use --force"
+                ),
+            ));
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Ok(())
+}
@@ -7,7 +7,7 @@ use std::time::Duration;
 use bluest::{Adapter, AdvertisingDevice};
 use futures::{future, stream, StreamExt};
 use log::*;
-use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::broadcast::{self, error::RecvError, Sender};
 use tokio::time::timeout;
 use windows::core::{Error as WinError, Result as WinResult, RuntimeType, HSTRING};
 use windows::Devices::Bluetooth::BluetoothLEDevice;
@@ -15,24 +15,39 @@ use windows::Foundation::{AsyncStatus, IAsyncOperation};
 
 use crate::constants::ADDR_LEN;
 use crate::device::{HueDevice, Server};
-use crate::utils::{addr_to_uint, uint_to_addr};
+use crate::utils::{addr_to_uint, is_hue_oui, uint_to_addr};
 
 const NO_ADAPTER_FOUND: &str = "Failed to get Bluetooth adapter. (maybe your Bluetooth is OFF ?)";
+/// Max discovered devices waiting to be consumed. When it's full, the oldest one is dropped so
+/// the scan never waits on a slow consumer. A bigger buffer means less missed devices at the
+/// cost of memory (and a scan yields the same devices over and over)
+const SCAN_BUFFER_LEN: usize = 64;
 
 async fn scan(adapter: Adapter, tx: Sender<AdvertisingDevice>) {
     let mut discovery = adapter.scan(&[]).await.unwrap();
 
     while let Some(dev) = discovery.next().await {
-        // If it errors, channel is probably closed so it's alright to deny the error
-        if tx.send(dev).await.is_err() {
+        // It only errors when there's no receiver left, so the stream has been dropped
+        if tx.send(dev).is_err() {
             break;
         }
     }
 }
 
+/// Whether a Bluetooth adapter is usable, it's fetched each time so it's still accurate after the
+/// adapter was unplugged or its driver reset
+pub async fn is_adapter_available() -> bool {
+    let Some(adapter) = Adapter::default().await else {
+        return false;
+    };
+
+    adapter.is_available().await.unwrap_or(false)
+}
+
 pub async fn search_devices_by_name(
     name: &str,
     timeout_seconds: u64,
+    hue_only: bool,
 ) -> bluest::Result<Pin<Box<dyn stream::Stream<Item = HueDevice<Server>> + Send>>> {
     let Some(adapter) = Adapter::default().await else {
         error!("{NO_ADAPTER_FOUND}");
@@ -41,7 +56,7 @@ pub async fn search_devices_by_name(
 
     adapter.wait_available().await?;
 
-    let (disco_tx, disco_rx) = mpsc::channel(1);
+    let (disco_tx, disco_rx) = broadcast::channel(SCAN_BUFFER_LEN);
 
     tokio::spawn(scan(adapter, disco_tx));
 
@@ -54,7 +69,7 @@ pub async fn search_devices_by_name(
             };
 
             match timeout(Duration::from_secs(timeout_seconds), discovery.recv()).await {
-                Ok(Some(adv_device)) => {
+                Ok(Ok(adv_device)) => {
                     match seen_devices.get(&adv_device.device.id()) {
                         Some(_) => {
                             return Some((
@@ -82,15 +97,18 @@ pub async fn search_devices_by_name(
                         }
                     }
                 }
-                Ok(None) | Err(_) => return None, // No more events or timeout reached
+                Ok(Err(RecvError::Lagged(skipped))) => {
+                    debug!("Discovery is consumed too slowly, {skipped} device(s) dropped");
+                }
+                Ok(Err(RecvError::Closed)) | Err(_) => return None, // No more events or timeout reached
             }
 
             Some((HueDevice::default(), Some((discovery, name, seen_devices))))
         },
     );
 
-    Ok(Box::pin(stream.filter(|hue_device| {
-        future::ready(hue_device.device.is_some())
+    Ok(Box::pin(stream.filter(move |hue_device| {
+        future::ready(hue_device.device.is_some() && (!hue_only || is_hue_oui(&hue_device.addr)))
     })))
 }
 
@@ -2,10 +2,9 @@ use std::ffi::CStr;
 use std::io;
 use std::mem::size_of;
 use std::process::Stdio;
-use std::time::Duration;
 
+use log::*;
 use tokio::process::Command as AsyncCommand;
-use tokio::time;
 use windows::Win32::Foundation::{CloseHandle, BOOL, HANDLE};
 use windows::Win32::System::Diagnostics::ToolHelp::{
     CreateToolhelp32Snapshot, Process32First, Process32Next, PROCESSENTRY32, TH32CS_SNAPPROCESS,
@@ -14,6 +13,10 @@ use windows::Win32::System::Threading::{
     OpenProcess, TerminateProcess, CREATE_NEW_PROCESS_GROUP, DETACHED_PROCESS, PROCESS_TERMINATE,
 };
 
+use crate::constants::DAEMON_BIN;
+use crate::device::{Client, HueDevice};
+use crate::utils::{daemon_flags, spawn_daemon, wait_for_daemon_exit, wait_for_daemon_ready};
+
 /// Maps a windows::core::Error into std::io::Error
 macro_rules! werr {
     ($res:expr) => {
@@ -41,7 +44,7 @@ fn get_daemon_process_id() -> io::Result<Option<u32>> {
                 .to_string_lossy()
                 .into_owned();
 
-            if process_name == "rustbee-daemon.exe" {
+            if process_name == DAEMON_BIN {
                 return Ok(Some(entry.th32ProcessID));
             }
 
@@ -54,43 +57,45 @@ fn get_daemon_process_id() -> io::Result<Option<u32>> {
     Ok(None)
 }
 
-pub async fn launch_daemon() -> io::Result<()> {
-    let pid_opt = get_daemon_process_id()?;
+pub fn is_daemon_running() -> io::Result<bool> {
+    Ok(get_daemon_process_id()?.is_some())
+}
 
-    if pid_opt.is_some() {
+pub async fn launch_daemon() -> io::Result<()> {
+    if HueDevice::<Client>::ping_daemon().await {
         return Ok(());
     }
 
-    let daemon = AsyncCommand::new("rustbee-daemon.exe")
-        .creation_flags(DETACHED_PROCESS.0 | CREATE_NEW_PROCESS_GROUP.0)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?;
-
-    let out = match time::timeout(Duration::from_secs(1), daemon.wait_with_output()).await {
-        Ok(res) => res?,
-        Err(_) => return Ok(()),
-    };
-
-    if !out.status.success() {
-        let stderr = String::from_utf8(out.stderr).unwrap();
-        let stderr = stderr.trim();
-
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("[ERROR] Failed to launch rustbee-daemon:\n{stderr}"),
-        ));
+    // A busy daemon may not answer the ping in time, don't spawn a second one
+    if is_daemon_running()? {
+        debug!("rustbee-daemon didn't answer the ping but its process is running");
+        return Ok(());
     }
 
-    Ok(())
+    let daemon = spawn_daemon(|path| {
+        AsyncCommand::new(path)
+            .args(daemon_flags())
+            .creation_flags(DETACHED_PROCESS.0 | CREATE_NEW_PROCESS_GROUP.0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+    })?;
+
+    wait_for_daemon_ready(daemon).await
 }
 
-pub fn shutdown_daemon(_force: bool) -> io::Result<()> {
+pub fn shutdown_daemon(force: bool) -> io::Result<()> {
     let pid_opt = get_daemon_process_id()?;
 
     if let Some(pid) = pid_opt {
-        // if force {
+        if !force {
+            match HueDevice::<Client>::request_shutdown() {
+                Ok(_) => return wait_for_daemon_exit(is_daemon_running),
+                Err(err) => warn!("{err}, terminating the process instead"),
+            }
+        }
+
         unsafe {
             let process_handle = werr!(OpenProcess(PROCESS_TERMINATE, BOOL(false as _), pid))?;
             if process_handle.0.is_null() {
@@ -104,10 +109,8 @@ pub fn shutdown_daemon(_force: bool) -> io::Result<()> {
             werr!(CloseHandle(process_handle))?;
         }
 
-        return Ok(());
-        // }
-
-        // TODO: Impl a shutdown message on the daemon so it can gracefully kill itself, else, force ^
+        // TerminateProcess is asynchronous
+        return wait_for_daemon_exit(is_daemon_running);
     }
 
     Ok(())
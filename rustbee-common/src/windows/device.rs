@@ -1,6 +1,8 @@
 use std::ops::Deref;
 
+use futures::{stream, StreamExt as _};
 use log::*;
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
 use crate::constants::*;
@@ -61,6 +63,42 @@ where
         Ok(false)
     }
 
+    /// Forwards the power, brightness and color notifications (mask, value) until the device
+    /// disconnects or the receiver is dropped
+    pub async fn subscribe_state(
+        &self,
+        tx: mpsc::Sender<(MaskT, Vec<u8>)>,
+    ) -> bluest::Result<()> {
+        let services = self.services().await?;
+        let Some(service) = services.iter().find(|s| s.uuid() == LIGHT_SERVICES_UUID) else {
+            return Err(bluest::error::ErrorKind::NotFound.into());
+        };
+
+        let characteristics = service
+            .characteristics()
+            .await?
+            .into_iter()
+            .filter(|charac| notification_mask(&charac.uuid()).is_some())
+            .collect::<Vec<_>>();
+
+        let mut streams = Vec::new();
+        for charac in &characteristics {
+            let mask = notification_mask(&charac.uuid()).unwrap();
+            streams.push(charac.notify().await?.map(move |value| (mask, value)));
+        }
+
+        let mut notifications = stream::select_all(streams);
+
+        // Notify streams error out when the device disconnects
+        while let Some((mask, Ok(value))) = notifications.next().await {
+            if tx.send((mask, value)).await.is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
     /// This is no-op, Windows connects automatically when needed
     /// https://docs.rs/bluest/latest/bluest/struct.Adapter.html#method.connect_device
     pub async fn try_connect(&self) -> bluest::Result<()> {
@@ -73,6 +111,27 @@ where
         Ok(())
     }
 
+    /// Pairing is managed by Windows and GATT operations of an unpaired device fail with access
+    /// denied errors, so it's checked first to tell the user to pair it in the Bluetooth settings
+    pub async fn check_paired(&self) -> bluest::Result<bool> {
+        if (*self).is_paired().await? {
+            return Ok(true);
+        }
+
+        // Some devices work without being paired, only an access denied error means it's needed
+        match (*self).services().await {
+            Ok(_) => Ok(true),
+            Err(err) if matches!(err.kind(), bluest::error::ErrorKind::NotAuthorized) => {
+                error!(
+                    "Access denied to the services of device {:?}, it must be paired: {err}",
+                    self.addr
+                );
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
     pub async fn is_device_connected(&self) -> bluest::Result<bool> {
         Ok((*self).is_connected().await)
     }
@@ -115,16 +174,44 @@ where
         Ok(())
     }
 
+    /// Mireds of the temperature characteristic, None if the device doesn't support it
+    pub async fn get_color_temp(&self) -> bluest::Result<Option<u16>> {
+        let read = self
+            .read_gatt_char(&LIGHT_SERVICES_UUID, &TEMPERATURE_UUID)
+            .await?;
+        if let Some([low, high, ..]) = read.as_deref() {
+            Ok(Some(u16::from_le_bytes([*low, *high])))
+        } else if read.is_none() {
+            Ok(None)
+        } else {
+            error!("Service or Characteristic \"{TEMPERATURE_UUID}\" for \"{LIGHT_SERVICES_UUID}\" not found for device {:?}", self.addr);
+            Err(bluest::error::ErrorKind::Other.into())
+        }
+    }
+
+    /// Mireds range (coolest, warmest) of the temperature characteristic, None if it isn't reported
+    pub async fn get_color_temp_range(&self) -> bluest::Result<Option<(u16, u16)>> {
+        let read = self
+            .read_gatt_char(&LIGHT_SERVICES_UUID, &TEMPERATURE_UUID)
+            .await?;
+
+        Ok(read.as_deref().and_then(temp_range_from_payload))
+    }
+
+    pub async fn set_color_temp(&self, mireds: u16) -> bluest::Result<()> {
+        self.write_gatt_char(&LIGHT_SERVICES_UUID, &TEMPERATURE_UUID, &mireds.to_le_bytes())
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn get_color(&self) -> bluest::Result<[u8; 4]> {
-        let mut buf = [0u8; 4];
         if let Some(bytes) = self
             .read_gatt_char(&LIGHT_SERVICES_UUID, &COLOR_UUID)
             .await?
         {
-            let len = buf.len();
-            buf.copy_from_slice(&bytes[..len]);
-
-            Ok(buf)
+            color_from_payload(&self.addr, &bytes)
+                .map_err(|_| bluest::Error::from(bluest::error::ErrorKind::Other))
         } else {
             error!("[ERROR] Service or Characteristic \"{COLOR_UUID}\" for \"{LIGHT_SERVICES_UUID}\" not found for device {:?}", self.addr);
             Err(bluest::error::ErrorKind::Other.into())
@@ -141,4 +228,36 @@ where
     pub async fn get_name(&self) -> bluest::Result<Option<String>> {
         self.name_async().await.map(Some)
     }
+
+    pub async fn get_model(&self) -> bluest::Result<Option<String>> {
+        self.get_info_string(&MODEL_UUID).await
+    }
+
+    pub async fn get_manufacturer(&self) -> bluest::Result<Option<String>> {
+        self.get_info_string(&MANUFACTURER_UUID).await
+    }
+
+    pub async fn get_firmware(&self) -> bluest::Result<Option<String>> {
+        self.get_info_string(&FIRMWARE_UUID).await
+    }
+
+    /// String of the device information service, None if the device doesn't expose it
+    async fn get_info_string(&self, charac: &Uuid) -> bluest::Result<Option<String>> {
+        Ok(self
+            .read_gatt_char(&MISC_SERVICES_UUID, charac)
+            .await?
+            .map(|bytes| info_string(&bytes)))
+    }
+
+    pub async fn set_name(&self, name: &str) -> bluest::Result<()> {
+        if !self
+            .write_gatt_char(&CONFIG_SERVICES_UUID, &DEVICE_NAME_UUID, name.as_bytes())
+            .await?
+        {
+            error!("Service or Characteristic \"{DEVICE_NAME_UUID}\" for \"{CONFIG_SERVICES_UUID}\" not found for device {:?}", self.addr);
+            return Err(bluest::error::ErrorKind::NotFound.into());
+        }
+
+        Ok(())
+    }
 }
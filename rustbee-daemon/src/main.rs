@@ -1,7 +1,11 @@
+use std::future::Future;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
-use std::{collections::HashMap, io::Error};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+};
 
 use futures::stream::StreamExt as _;
 use interprocess::local_socket::{
@@ -9,28 +13,49 @@ use interprocess::local_socket::{
     ListenerOptions, ToFsName as _,
 };
 use tokio::fs;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex, Notify, OwnedMutexGuard, Semaphore};
 use tokio::{
-    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    io::{AsyncRead, AsyncReadExt as _, AsyncWriteExt as _},
     signal,
-    time::{self, sleep},
+    time::{self, sleep, Instant},
 };
 
 use rustbee_common::bluetooth::*;
+use rustbee_common::config::Config;
 use rustbee_common::constants::{
-    MaskT, OutputCode, ADDR_LEN, BUFFER_LEN, OUTPUT_LEN, SET, SOCKET_PATH,
+    masks, options, regions, MaskT, OutputCode, ADDR_LEN, BUFFER_LEN, DATA_LEN, LOG_KEEP,
+    LOG_MAX_BYTES, MAX_NAME_LEN, OPTIONS_IDX, OUTPUT_LEN, PROTOCOL_VERSION, SET,
 };
 use rustbee_common::device::*;
 use rustbee_common::logger::*;
+use rustbee_common::utils::addr_to_str;
 #[cfg(not(target_os = "windows"))]
 use rustbee_common::BluetoothPeripheralImpl as _;
 
-const TIMEOUT_SECS: u64 = 60 * 10;
 const FOUND_DEVICE_TIMEOUT_SECS: u64 = 30;
 
-static LOGGER: Logger = Logger::new("Rustbee-Daemon", false);
+/// Max time to let the requests in progress finish when shutting down, subscriptions and searches
+/// may never end
+const IN_FLIGHT_TIMEOUT_SECS: u64 = 5;
+
+/// Consecutive failing connections to a device before alerting
+const FAILURE_ALERT_THRESHOLD: u32 = 3;
+const ALERT_FLASH_SECS: u64 = 2;
+const ADAPTER_CHECK_SECS: u64 = 10;
+/// Consecutive failed adapter checks before considering it lost
+const ADAPTER_LOST_CHECKS: u32 = 2;
+
+/// Cached devices, the map is only locked to look them up (and during discoveries) while each
+/// device is locked for the whole request so its commands are serialized
+type Devices = Arc<Mutex<HashMap<[u8; ADDR_LEN], Arc<Mutex<HueDevice<Server>>>>>>;
+/// Instant of the last command sent to each device, used for the NO_WAIT option
+type LastCommands = Arc<Mutex<HashMap<[u8; ADDR_LEN], Instant>>>;
+/// Consecutive failing connections per device
+type Failures = Arc<Mutex<HashMap<[u8; ADDR_LEN], u32>>>;
+
+static LOGGER: Logger = Logger::with_rotation("Rustbee-Daemon", false, LOG_MAX_BYTES, LOG_KEEP);
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 enum Command {
     Connect,
     Power,
@@ -41,6 +66,24 @@ enum Command {
     Disconnect,
     Name,
     SearchName,
+    BenchDiscovery,
+    Subscribe,
+    ColorTemp,
+    ListDevices,
+    Shutdown,
+    Ping,
+    /// Not a flag, added by the ENSURE_ON option
+    EnsureOn,
+    /// Not a flag, added by the RESTORE_OFF option
+    RestoreOff,
+    /// Not a flag, replaces Name when reading with the MODEL option
+    Model,
+    /// Not a flag, replaces Name when reading with the MANUFACTURER option
+    Manufacturer,
+    /// Not a flag, replaces Name when reading with the FIRMWARE option
+    Firmware,
+    /// Not a flag, replaces ColorTemp when reading with the TEMP_RANGE option
+    ColorTempRange,
 }
 
 /// converts Result<T, E> into SUCCESS or FAILURE (0 or 1)
@@ -52,20 +95,27 @@ macro_rules! res_to_u8 {
 
 #[tokio::main]
 async fn main() {
-    #[cfg(not(target_os = "windows"))]
-    check_if_path_is_writable().await;
-
+    // Loads the config (along the --trace-packets and --adapter-reset flags) once it can warn
     LOGGER.init();
 
-    if Path::new(SOCKET_PATH).exists() {
+    let socket_path = &Config::current().socket_path;
+
+    #[cfg(not(target_os = "windows"))]
+    check_if_path_is_writable(socket_path).await;
+
+    if socket_path.exists() {
         error!("Error: socket is already in use, an instance might already be running");
         std::process::exit(2);
     }
 
-    let fs_name = SOCKET_PATH
+    let fs_name = socket_path
+        .as_path()
         .to_fs_name::<GenericFilePath>()
         .unwrap_or_else(|error| {
-            error!("Error cannot create filesystem path name: {SOCKET_PATH} => {error}");
+            error!(
+                "Error cannot create filesystem path name: {} => {error}",
+                socket_path.display()
+            );
             std::process::exit(1);
         });
 
@@ -82,8 +132,14 @@ async fn main() {
         }
     };
 
-    let devices: Arc<Mutex<HashMap<[u8; ADDR_LEN], HueDevice<Server>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+    let devices: Devices = Arc::new(Mutex::new(HashMap::new()));
+    let connections = Arc::new(Semaphore::new(Config::current().max_connections));
+    let last_commands: LastCommands = Arc::new(Mutex::new(HashMap::new()));
+    let failures: Failures = Arc::new(Mutex::new(HashMap::new()));
+    let shutdown = Arc::new(Notify::new());
+    let idle_timeout = Duration::from_secs(Config::current().daemon_timeout_secs);
+
+    tokio::spawn(adapter_watchdog(Arc::clone(&devices)));
 
     loop {
         tokio::select! {
@@ -91,303 +147,1203 @@ async fn main() {
                 warn!("SIGINT received, disconnecting...");
                 break;
             },
-            timeout = time::timeout(Duration::from_secs(TIMEOUT_SECS), listener.accept()) => {
+            _ = shutdown.notified() => {
+                info!("Shutdown requested, disconnecting...");
+                break;
+            },
+            timeout = time::timeout(idle_timeout, listener.accept()) => {
                 let Ok(conn) = timeout else {
+                    // Not idle while a connection is still open (e.g. status --watch)
+                    if connections.available_permits() < Config::current().max_connections {
+                        continue;
+                    }
+
                     // Timed out
                     break;
                 };
 
-                tokio::spawn(process_conn(conn, Arc::clone(&devices)));
+                tokio::spawn(limit_concurrency(
+                    Arc::clone(&connections),
+                    process_conn(
+                        conn,
+                        Arc::clone(&devices),
+                        Arc::clone(&last_commands),
+                        Arc::clone(&failures),
+                        Arc::clone(&shutdown),
+                    ),
+                ));
             }
         }
     }
 
-    for (_, device) in devices.lock().await.iter() {
-        let _ = device.try_disconnect().await;
+    let permits = Config::current().max_connections as _;
+    if time::timeout(
+        Duration::from_secs(IN_FLIGHT_TIMEOUT_SECS),
+        connections.acquire_many(permits),
+    )
+    .await
+    .is_err()
+    {
+        warn!("Requests still in progress after {IN_FLIGHT_TIMEOUT_SECS}s, stopping anyway");
+    }
+
+    let devices = devices.lock().await.values().cloned().collect::<Vec<_>>();
+    for device in devices {
+        let _ = device.lock().await.try_disconnect().await;
     }
 
     #[cfg(not(target_os = "windows"))]
-    std::fs::remove_file(SOCKET_PATH).unwrap();
+    std::fs::remove_file(socket_path).unwrap();
+
+    flush_logs();
+}
+
+/// Periodically checks the Bluetooth adapter. When it's lost (USB unplug, driver reset) the cached
+/// devices handles are stale so they're dropped and lazily rediscovered and reconnected by the
+/// next commands once it's back
+async fn adapter_watchdog(devices: Devices) {
+    let mut ticker = time::interval(Duration::from_secs(ADAPTER_CHECK_SECS));
+    let mut failed_checks = 0;
+
+    loop {
+        ticker.tick().await;
+
+        if is_adapter_available().await {
+            if failed_checks >= ADAPTER_LOST_CHECKS {
+                devices.lock().await.clear();
+                info!("Bluetooth adapter is back, BLE session re-initialized");
+            }
+
+            failed_checks = 0;
+            continue;
+        }
+
+        failed_checks += 1;
+        if failed_checks == ADAPTER_LOST_CHECKS {
+            error!("Bluetooth adapter lost, waiting for it to come back");
+            devices.lock().await.clear();
+        }
+    }
+}
+
+/// Recovery measure for BLE stacks that stop discovering until Bluetooth is restarted, see
+/// ADAPTER_RESET_ENV
+#[cfg(not(target_os = "windows"))]
+async fn reset_adapter_if_enabled() {
+    if !Config::current().adapter_reset {
+        return;
+    }
+
+    info!("Resetting the Bluetooth adapter before discovery");
+    match reset_adapter().await {
+        Ok(_) => info!("Bluetooth adapter reset"),
+        Err(error) => error!("Failed to reset the Bluetooth adapter: {error}"),
+    }
+}
+
+/// Waits for a permit before running the future
+async fn limit_concurrency<F: Future>(semaphore: Arc<Semaphore>, future: F) -> Option<F::Output> {
+    // Only fails if the semaphore is closed, which never happens
+    let _permit = semaphore.acquire_owned().await.ok()?;
+
+    Some(future.await)
 }
 
 /*
  * It works as follows:
  * - When setting up a new device, Pair & Trust it, connect and retrieve services to index them by UUID
  * - Respond with [SUCCESS | FAILURE, DATA if any or filled with 0u8]
- * - Multiple commands can be used at the same time like PAIR | CONNECT | POWER for example but do
- * not use multiple commands that returns data, the output could be corrupted
+ * - Multiple commands can be used at the same time like PAIR | CONNECT | POWER for example. When
+ * reading with multiple commands that return data, they're written to their own region of the
+ * output (see constants::regions) along the connection state and the name is fully streamed
  */
 async fn process_conn(
     conn: Result<Stream, Error>,
-    devices: Arc<Mutex<HashMap<[u8; ADDR_LEN], HueDevice<Server>>>>,
+    devices: Devices,
+    last_commands: LastCommands,
+    failures: Failures,
+    shutdown: Arc<Notify>,
 ) {
     match conn {
         Ok(mut stream) => {
-            let mut buf = [0; BUFFER_LEN];
-            if let Err(error) = stream.read_exact(&mut buf).await {
-                error!("Unexpected error on reading chunks: {error}");
-                return;
+            // The connection serves requests until the client closes it, see process_request
+            let mut served = false;
+            loop {
+                let mut buf = [0; BUFFER_LEN];
+                if let Err(error) = stream.read_exact(&mut buf).await {
+                    if !served || error.kind() != ErrorKind::UnexpectedEof {
+                        error!("Unexpected error on reading chunks: {error}");
+                    }
+                    return;
+                }
+                served = true;
+
+                if !process_request(
+                    &mut stream,
+                    buf,
+                    &devices,
+                    &last_commands,
+                    &failures,
+                    &shutdown,
+                )
+                .await
+                {
+                    return;
+                }
             }
-            let mut addr = [0; ADDR_LEN];
-            for (i, byte) in buf[..addr.len()].iter().enumerate() {
-                addr[i] = *byte;
+        }
+        Err(error) => error!("Error on connection: {error}"),
+    }
+}
+
+/// Handles a single request of the connection, returns whether it can serve another one
+async fn process_request(
+    stream: &mut Stream,
+    buf: [u8; BUFFER_LEN],
+    devices: &Devices,
+    last_commands: &LastCommands,
+    failures: &Failures,
+    shutdown: &Notify,
+) -> bool {
+    trace_packet("request", &buf);
+    if buf[0] != PROTOCOL_VERSION {
+        warn!(
+            "Protocol version mismatch, expected {PROTOCOL_VERSION} received {}, the client and the daemon versions differ",
+            buf[0]
+        );
+        let mut output_buf = code_output(OutputCode::VersionMismatch);
+        output_buf[1] = PROTOCOL_VERSION;
+        send_to_stream(stream, output_buf).await;
+        return false;
+    }
+    // The indexes below don't account for the version byte
+    let buf = &buf[1..];
+
+    let mut addr = [0; ADDR_LEN];
+    for (i, byte) in buf[..addr.len()].iter().enumerate() {
+        addr[i] = *byte;
+    }
+    let flags = ((buf[7] as u16) << 8) | buf[6] as u16;
+    let set = buf[8] == SET;
+    let data = &buf[9..];
+
+    let mut output_buf = [0; OUTPUT_LEN];
+    output_buf[0] = u8::MAX;
+
+    let mut commands = get_commands_from_flags(flags);
+    if set {
+        apply_options(&mut commands, data[OPTIONS_IDX]);
+    } else {
+        apply_read_options(&mut commands, data[OPTIONS_IDX]);
+    }
+
+    let no_wait = set && data[OPTIONS_IDX] & options::NO_WAIT != 0;
+
+    if flags & masks::BATCH != 0 {
+        let addrs = match read_batch_addresses(stream).await {
+            Ok(addrs) => addrs,
+            Err(error) => {
+                error!("Unexpected error on reading the batch addresses: {error}");
+                return false;
             }
-            let flags = ((buf[7] as u16) << 8) | buf[6] as u16;
-            let set = buf[8] == SET;
-            let data = &buf[9..];
+        };
+        let request = Request {
+            commands,
+            set,
+            data: buf[9..].try_into().unwrap(),
+            name: None,
+            no_wait,
+        };
 
-            let mut output_buf = [0; OUTPUT_LEN];
-            output_buf[0] = u8::MAX;
+        run_batch(stream, addrs, request, devices, last_commands, failures).await;
+        return true;
+    }
 
-            let mut commands = get_commands_from_flags(flags);
+    let name = if set && commands.contains(&Command::Name) {
+        match read_name(stream, data[0]).await {
+            Ok(name) => Some(name),
+            Err(error) => {
+                error!("Unexpected error on reading the name: {error}");
+                return false;
+            }
+        }
+    } else {
+        None
+    };
 
-            debug!("{buf:?}");
-            debug!(
-                "addr: {:?} flags: {} set {} data: {:?}",
-                addr, flags, set, data
-            );
-            debug!("addr: {addr:?} commands: {commands:?}");
-
-            // Commands that are executed alone and only alone without the need to fetch the device
-            if commands.contains(&Command::SearchName) {
-                let name =
-                    String::from_utf8(data.iter().copied().filter(|c| *c != b'\0').collect())
-                        .unwrap();
-                let mut stream_iter = search_devices_by_name(&name, 10).await.unwrap();
-                let mut device_sent = 0;
-
-                while let Some(device) = stream_iter.next().await {
-                    let mut buf = [0; OUTPUT_LEN];
-                    buf[0] = OutputCode::Streaming.into();
-
-                    let addr = device.addr;
-                    for (i, byte) in addr.iter().enumerate() {
-                        buf[i + 1] = *byte;
-                    }
+    if no_wait {
+        if let Some(output) = rate_limited_output(last_commands, addr).await {
+            return send_to_stream(stream, output).await;
+        }
+    }
 
-                    for (i, byte) in device
-                        .get_name()
-                        .await
-                        .map_err(|_| Some(String::new()))
-                        .unwrap()
-                        .or_else(|| Some(String::new()))
-                        .unwrap()
-                        .as_bytes()
-                        .iter()
-                        .enumerate()
-                    {
-                        let offset = addr.len() + 1 + i;
-                        if offset >= buf.len() {
-                            break;
-                        }
+    debug!(
+        "addr: {:?} flags: {} set {} data: {:?}",
+        addr, flags, set, data
+    );
+    debug!("addr: {addr:?} commands: {commands:?}");
 
-                        buf[offset] = *byte;
-                    }
+    // Commands that are executed alone and only alone without the need to fetch the device
+    if commands.contains(&Command::Ping) {
+        return send_output_code(stream, OutputCode::Success).await;
+    }
 
-                    send_to_stream(&mut stream, buf).await;
-                    device_sent += 1;
-                }
+    if commands.contains(&Command::Shutdown) {
+        info!("Shutdown requested by a client");
+        send_output_code(stream, OutputCode::Success).await;
+        // Stored until the accept loop waits for it
+        shutdown.notify_one();
+        return false;
+    }
 
-                if device_sent == 0 {
-                    send_output_code(&mut stream, OutputCode::DeviceNotFound).await;
-                    return;
+    if commands.contains(&Command::ListDevices) {
+        list_devices(stream, devices).await;
+        return true;
+    }
+
+    if commands.contains(&Command::SearchName) {
+        let name = String::from_utf8(
+            data[..OPTIONS_IDX]
+                .iter()
+                .copied()
+                .filter(|c| *c != b'\0')
+                .collect(),
+        )
+        .unwrap();
+        let hue_only = data[OPTIONS_IDX] & options::HUE_ONLY != 0;
+        let mut stream_iter = search_devices_by_name(&name, 10, hue_only).await.unwrap();
+        let mut device_sent = 0;
+        let mut probe = [0; 1];
+
+        loop {
+            let device = tokio::select! {
+                device = stream_iter.next() => device,
+                // The client doesn't send anything else so it's only resolved when it
+                // closes the socket (e.g. CTRL+C) which cancels the scan
+                _ = stream.read(&mut probe) => {
+                    debug!("Client closed the socket, stopping the search");
+                    return false;
                 }
+            };
+            let Some(device) = device else {
+                break;
+            };
 
-                send_output_code(&mut stream, OutputCode::StreamEOF).await;
-                return;
+            let mut buf = [0; OUTPUT_LEN];
+            buf[0] = OutputCode::Streaming.into();
+
+            let addr = device.addr;
+            for (i, byte) in addr.iter().enumerate() {
+                buf[i + 1] = *byte;
             }
 
-            let mut devices = devices.lock().await;
-            if devices.get(&addr).is_none() {
-                match time::timeout(
-                    Duration::from_secs(FOUND_DEVICE_TIMEOUT_SECS),
-                    get_device(addr),
-                )
+            for (i, byte) in device
+                .get_name()
                 .await
-                {
-                    Err(elapsed) => {
-                        // Timed out
-                        warn!("Timeout: {elapsed} during device discovery, address: {addr:?}");
-                        send_output_code(&mut stream, OutputCode::DeviceNotFound).await;
-                        return;
-                    }
-                    Ok(value) => {
-                        let myb_device = match value {
-                            Ok(myb_device) => myb_device,
-                            Err(err) => {
-                                error!("Cannot get device, address: {addr:?} {err:?}");
-                                send_output_code(&mut stream, OutputCode::Failure).await;
-                                return;
-                            }
-                        };
-
-                        let Some(device) = myb_device else {
-                            warn!("Device not found or not in range, address: {addr:?}");
-                            send_output_code(&mut stream, OutputCode::DeviceNotFound).await;
-                            return;
-                        };
-
-                        devices.insert(addr, device);
-                    }
+                .map_err(|_| Some(String::new()))
+                .unwrap()
+                .or_else(|| Some(String::new()))
+                .unwrap()
+                .as_bytes()
+                .iter()
+                .enumerate()
+            {
+                let offset = addr.len() + 1 + i;
+                if offset >= buf.len() {
+                    break;
                 }
+
+                buf[offset] = *byte;
             }
 
-            let hue_device = devices.get_mut(&addr).unwrap();
+            if !send_to_stream(stream, buf).await {
+                return false;
+            }
+            device_sent += 1;
+        }
 
-            // If we only need to get connect status, avoid connecting to set services
-            if commands.len() == 1 && commands[0] == Command::Connect && !set {
-                if let Ok(state) = hue_device.is_device_connected().await {
-                    output_buf[0] = OutputCode::Success.into();
-                    output_buf[1] = state as _;
-                } else {
-                    output_buf[0] = OutputCode::Failure.into();
-                }
+        if device_sent == 0 {
+            return send_output_code(stream, OutputCode::DeviceNotFound).await;
+        }
 
-                send_to_stream(&mut stream, output_buf).await;
-                return;
+        return send_output_code(stream, OutputCode::StreamEOF).await;
+    }
+
+    if commands.contains(&Command::BenchDiscovery) {
+        let start = Instant::now();
+        let code = match time::timeout(
+            Duration::from_secs(FOUND_DEVICE_TIMEOUT_SECS),
+            get_device(addr),
+        )
+        .await
+        {
+            Ok(Ok(Some(_))) => OutputCode::Success,
+            Ok(Ok(None)) | Err(_) => OutputCode::DeviceNotFound,
+            Ok(Err(err)) => {
+                error!("Cannot get device, address: {addr:?} {err:?}");
+                OutputCode::Failure
             }
+        };
+        let elapsed = start.elapsed();
+        info!("Discovery of {addr:?} took {elapsed:?} ({code:?})");
 
-            #[cfg(not(target_os = "windows"))]
-            if hue_device.services().is_empty() {
-                // if let Err(error) = hue_device.try_pair().await {
-                //     error!(
-                //         "Unexpected error trying to pair with device {}: {error}",
-                //         hue_device.addr
-                //     );
-                //     devices.remove(&addr).unwrap();
-                //     return;
-                // }
-                if let Err(error) = hue_device.try_connect().await {
-                    error!(
-                        "Unexpected error trying to connect with device {:?}: {error}",
-                        hue_device.addr
-                    );
-                    devices.remove(&addr).unwrap();
-                    return;
+        // The elapsed time is also sent when not found, it's the timeout then
+        output_buf[0] = code.into();
+        output_buf[1..5].copy_from_slice(&(elapsed.as_millis() as u32).to_le_bytes());
+        return send_to_stream(stream, output_buf).await;
+    }
+
+    let request = Request {
+        commands,
+        set,
+        data: buf[9..].try_into().unwrap(),
+        name,
+        no_wait,
+    };
+    let hue_device = match prepare_device(addr, request.is_connected_state_only(), devices).await {
+        Prepared::Device(hue_device) => hue_device,
+        Prepared::Done(output) => {
+            if let Some(output) = output {
+                send_to_stream(stream, output).await;
+            }
+            return true;
+        }
+    };
+
+    if request.commands.contains(&Command::Subscribe) {
+        // It lasts until the device disconnects so it doesn't hold the device lock
+        let device = (*hue_device).clone();
+        drop(hue_device);
+
+        subscribe(stream, device).await;
+        return true;
+    }
+
+    let (output_buf, failed) =
+        run_commands(stream, &hue_device, addr, request, last_commands).await;
+
+    if output_buf[0] != u8::MAX {
+        send_to_stream(stream, output_buf).await;
+    }
+
+    record_failure_and_alert(failures, devices, addr, failed).await;
+
+    true
+}
+
+/// Result of getting the device ready for the commands
+enum Prepared {
+    /// Locked until the commands are done
+    Device(OwnedMutexGuard<HueDevice<Server>>),
+    /// Nothing else to run, with the output to send if any
+    Done(Option<[u8; OUTPUT_LEN]>),
+}
+
+/// Commands of a request along their data
+#[derive(Clone)]
+struct Request {
+    commands: Vec<Command>,
+    set: bool,
+    data: [u8; DATA_LEN],
+    /// New name of the device when setting it, read after the request
+    name: Option<String>,
+    no_wait: bool,
+}
+
+impl Request {
+    fn is_connected_state_only(&self) -> bool {
+        self.commands.len() == 1 && self.commands[0] == Command::Connect && !self.set
+    }
+
+    /// Commands sending several outputs, they can't be batched
+    fn is_streamed(&self) -> bool {
+        self.commands.iter().any(|cmd| {
+            matches!(
+                cmd,
+                Command::Name
+                    | Command::Model
+                    | Command::Manufacturer
+                    | Command::Firmware
+                    | Command::SearchName
+                    | Command::BenchDiscovery
+                    | Command::Subscribe
+                    | Command::ListDevices
+            )
+        })
+    }
+}
+
+/// Gets the cached device or discovers it. The map stays locked during the discovery since the
+/// adapter scans for one device at a time
+async fn get_or_discover_device(
+    devices: &Devices,
+    addr: [u8; ADDR_LEN],
+) -> Result<Arc<Mutex<HueDevice<Server>>>, OutputCode> {
+    let mut devices = devices.lock().await;
+    if let Some(device) = devices.get(&addr) {
+        return Ok(Arc::clone(device));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    reset_adapter_if_enabled().await;
+
+    match time::timeout(
+        Duration::from_secs(FOUND_DEVICE_TIMEOUT_SECS),
+        get_device(addr),
+    )
+    .await
+    {
+        Err(elapsed) => {
+            // Timed out
+            warn!("Timeout: {elapsed} during device discovery, address: {addr:?}");
+            Err(OutputCode::DeviceNotFound)
+        }
+        Ok(Err(err)) => {
+            error!("Cannot get device, address: {addr:?} {err:?}");
+            Err(OutputCode::Failure)
+        }
+        Ok(Ok(None)) => {
+            warn!("Device not found or not in range, address: {addr:?}");
+            Err(OutputCode::DeviceNotFound)
+        }
+        Ok(Ok(Some(device))) => {
+            let device = Arc::new(Mutex::new(device));
+            devices.insert(addr, Arc::clone(&device));
+
+            Ok(device)
+        }
+    }
+}
+
+/// Gets the device (discovering it if it's not cached yet) and connects to it to find its
+/// services if needed
+async fn prepare_device(
+    addr: [u8; ADDR_LEN],
+    connected_state_only: bool,
+    devices: &Devices,
+) -> Prepared {
+    let hue_device = match get_or_discover_device(devices, addr).await {
+        Ok(device) => device.lock_owned().await,
+        Err(OutputCode::Failure) => {
+            return Prepared::Done(Some(failure_output("discovery failed")))
+        }
+        Err(code) => return Prepared::Done(Some(code_output(code))),
+    };
+
+    // If we only need to get connect status, avoid connecting to set services
+    if connected_state_only {
+        let mut output_buf = [0; OUTPUT_LEN];
+        if let Ok(state) = hue_device.is_device_connected().await {
+            output_buf[0] = OutputCode::Success.into();
+            output_buf[1] = state as _;
+        } else {
+            output_buf = failure_output("cannot read state");
+        }
+
+        return Prepared::Done(Some(output_buf));
+    }
+
+    #[cfg(target_os = "windows")]
+    if let Ok(false) = hue_device.check_paired().await {
+        warn!("Device {addr:?} isn't paired in the Windows Bluetooth settings");
+        return Prepared::Done(Some(code_output(OutputCode::NotPaired)));
+    }
+
+    // The services are discovered while holding the device lock so concurrent requests to the
+    // same device wait for them
+    #[cfg(not(target_os = "windows"))]
+    if hue_device.services().is_empty() {
+        // if let Err(error) = hue_device.try_pair().await {
+        //     error!(
+        //         "Unexpected error trying to pair with device {}: {error}",
+        //         hue_device.addr
+        //     );
+        //     devices.lock().await.remove(&addr);
+        //     return;
+        // }
+        if let Err(error) = hue_device.try_connect().await {
+            error!(
+                "Unexpected error trying to connect with device {:?}: {error}",
+                hue_device.addr
+            );
+            devices.lock().await.remove(&addr);
+            return Prepared::Done(Some(code_output(OutputCode::NotConnected)));
+        }
+        if let Err(error) = hue_device.discover_services().await {
+            error!("Unexpected error trying get GATT characteristics and services with device {:?}: {error}", hue_device.addr);
+            devices.lock().await.remove(&addr);
+            return Prepared::Done(Some(failure_output("services not found")));
+        }
+    }
+
+    Prepared::Device(hue_device)
+}
+
+/// Runs the commands on the device and returns the output along whether any of them failed. The
+/// output code is u8::MAX when there's nothing to send
+async fn run_commands(
+    stream: &mut Stream,
+    hue_device: &HueDevice<Server>,
+    addr: [u8; ADDR_LEN],
+    request: Request,
+    last_commands: &LastCommands,
+) -> ([u8; OUTPUT_LEN], bool) {
+    let Request {
+        mut commands,
+        set,
+        data,
+        name,
+        no_wait,
+    } = request;
+    let mut output_buf = [0; OUTPUT_LEN];
+    output_buf[0] = u8::MAX;
+
+    let mut failed = false;
+    // Of the first failing command
+    let mut reason = None;
+
+    // Priority command
+    if commands.contains(&Command::Connect) {
+        let value = res_to_u8!(hue_device.try_connect().await);
+        output_buf[0] = u8::min(output_buf[0], value);
+        if value == u8::from(OutputCode::Failure) {
+            failed = true;
+            reason = Some(command_failure_reason(&Command::Connect, set));
+        }
+        commands.retain(|cmd| *cmd != Command::Connect);
+    }
+
+    let mut was_off = false;
+    let disconnects = commands.contains(&Command::Disconnect);
+    let commands_len = commands.len();
+    let use_regions = !set
+        && commands
+            .iter()
+            .filter(|cmd| {
+                matches!(
+                    cmd,
+                    Command::Power
+                        | Command::Brightness
+                        | Command::ColorRgb
+                        | Command::ColorHex
+                        | Command::ColorXy
+                        | Command::ColorTemp
+                        | Command::ColorTempRange
+                        | Command::Name
+                        | Command::Model
+                        | Command::Manufacturer
+                        | Command::Firmware
+                )
+            })
+            .count()
+            > 1;
+    // 1 for the output code
+    let data_idx = |region_idx: usize| 1 + if use_regions { region_idx } else { 0 };
+
+    for (i, command) in commands.into_iter().enumerate() {
+        let value = match command {
+            Command::Connect
+            | Command::SearchName
+            | Command::BenchDiscovery
+            | Command::Subscribe
+            | Command::ListDevices
+            | Command::Shutdown
+            | Command::Ping => continue,
+            Command::EnsureOn => match hue_device.get_power().await {
+                Ok(true) => continue,
+                Ok(false) => {
+                    was_off = true;
+                    res_to_u8!(hue_device.set_power(true as _).await)
                 }
-                if let Err(error) = hue_device.discover_services().await {
-                    error!("Unexpected error trying get GATT characteristics and services with device {:?}: {error}", hue_device.addr);
-                    devices.remove(&addr).unwrap();
-                    return;
+                Err(_) => OutputCode::Failure.into(),
+            },
+            Command::RestoreOff => {
+                if !was_off {
+                    continue;
+                }
+
+                res_to_u8!(hue_device.set_power(false as _).await)
+            }
+            Command::Disconnect => res_to_u8!(hue_device.try_disconnect().await),
+            Command::Power { .. } => {
+                if set {
+                    res_to_u8!(hue_device.set_power(data[0]).await)
+                } else if let Ok(state) = hue_device.get_power().await {
+                    output_buf[data_idx(regions::POWER_IDX)] = state as _;
+                    OutputCode::Success.into()
+                } else {
+                    OutputCode::Failure.into()
                 }
             }
+            Command::Brightness { .. } => {
+                if set {
+                    res_to_u8!(hue_device.set_brightness(data[0]).await)
+                } else if let Ok(v) = hue_device.get_brightness().await {
+                    output_buf[data_idx(regions::BRIGHTNESS_IDX)] = v as _;
+                    OutputCode::Success.into()
+                } else {
+                    OutputCode::Failure.into()
+                }
+            }
+            Command::ColorRgb { .. } | Command::ColorHex { .. } | Command::ColorXy { .. } => {
+                let mut buf = [0u8; 4];
+                buf.copy_from_slice(&data[..4]);
 
-            // Since we're not mutating the device internally, only the hashmap (above), we
-            // can clone the device and free the lock
-            let hue_device = hue_device.clone();
-            drop(devices);
+                if set {
+                    res_to_u8!(hue_device.set_color(buf).await)
+                } else if let Ok(bytes) = hue_device.get_color().await {
+                    for (i, byte) in bytes.iter().enumerate() {
+                        output_buf[data_idx(regions::COLOR_IDX) + i] = *byte;
+                    }
 
-            // Priority command
-            if commands.contains(&Command::Connect) {
-                let value = res_to_u8!(hue_device.try_connect().await);
-                output_buf[0] = u8::min(output_buf[0], value);
-                commands.retain(|cmd| *cmd != Command::Connect);
+                    OutputCode::Success.into()
+                } else {
+                    OutputCode::Failure.into()
+                }
+            }
+            Command::ColorTemp => {
+                if set {
+                    let mireds = u16::from_le_bytes([data[0], data[1]]);
+                    res_to_u8!(hue_device.set_color_temp(mireds).await)
+                } else if let Ok(mireds) = hue_device.get_color_temp().await {
+                    // 0 tells the client the device doesn't support it, see masks::COLOR_TEMP
+                    let idx = data_idx(regions::COLOR_TEMP_IDX);
+                    output_buf[idx..idx + 2].copy_from_slice(&mireds.unwrap_or(0).to_le_bytes());
+                    OutputCode::Success.into()
+                } else {
+                    OutputCode::Failure.into()
+                }
+            }
+            Command::ColorTempRange => {
+                if let Ok(range) = hue_device.get_color_temp_range().await {
+                    // 0s tell the client the device doesn't report it, see options::TEMP_RANGE
+                    let (cool, warm) = range.unwrap_or_default();
+                    let idx = data_idx(regions::COLOR_TEMP_IDX);
+                    output_buf[idx..idx + 2].copy_from_slice(&cool.to_le_bytes());
+                    output_buf[idx + 2..idx + 4].copy_from_slice(&warm.to_le_bytes());
+                    OutputCode::Success.into()
+                } else {
+                    OutputCode::Failure.into()
+                }
             }
+            Command::Name if set => {
+                let name = name.as_deref().unwrap_or_default();
+                res_to_u8!(hue_device.set_name(name).await)
+            }
+            Command::Name | Command::Model | Command::Manufacturer | Command::Firmware => {
+                let res = match command {
+                    Command::Model => hue_device.get_model().await,
+                    Command::Manufacturer => hue_device.get_manufacturer().await,
+                    Command::Firmware => hue_device.get_firmware().await,
+                    _ => hue_device.get_name().await,
+                };
 
-            for command in commands {
-                let value = match command {
-                    Command::Connect | Command::SearchName => continue,
-                    Command::Disconnect => res_to_u8!(hue_device.try_disconnect().await),
-                    Command::Power { .. } => {
-                        if set {
-                            res_to_u8!(hue_device.set_power(data[0]).await)
-                        } else if let Ok(state) = hue_device.get_power().await {
-                            output_buf[1] = state as _;
-                            OutputCode::Success.into()
-                        } else {
-                            OutputCode::Failure.into()
-                        }
-                    }
-                    Command::Brightness { .. } => {
-                        if set {
-                            res_to_u8!(hue_device.set_brightness(data[0]).await)
-                        } else if let Ok(v) = hue_device.get_brightness().await {
-                            output_buf[1] = v as _;
-                            OutputCode::Success.into()
-                        } else {
-                            OutputCode::Failure.into()
-                        }
-                    }
-                    Command::ColorRgb { .. }
-                    | Command::ColorHex { .. }
-                    | Command::ColorXy { .. } => {
-                        let mut buf = [0u8; 4];
-                        buf.copy_from_slice(&data[..4]);
-
-                        if set {
-                            res_to_u8!(hue_device.set_color(buf).await)
-                        } else if let Ok(bytes) = hue_device.get_color().await {
-                            for (i, byte) in bytes.iter().enumerate() {
-                                output_buf[i + 1] = *byte;
-                            }
-
-                            OutputCode::Success.into()
-                        } else {
-                            OutputCode::Failure.into()
+                if let Ok(Some(ref name)) = res {
+                    let mut chunks = name_chunks(name);
+                    // The last chunk is sent along the output code unless the output
+                    // data is shared with other commands
+                    let last = if use_regions {
+                        Default::default()
+                    } else {
+                        chunks.pop().unwrap_or_default()
+                    };
+
+                    for chunk in chunks {
+                        let mut buf = [0; OUTPUT_LEN];
+                        buf[0] = OutputCode::Streaming.into();
+                        buf[1..chunk.len() + 1].copy_from_slice(chunk);
+                        if !send_to_stream(stream, buf).await {
+                            break;
                         }
                     }
-                    Command::Name => {
-                        let res = hue_device.get_name().await;
-
-                        if let Ok(Some(ref name_str)) = res {
-                            let len = name_str.len();
-                            for (i, byte) in name_str.bytes().take(OUTPUT_LEN - 1).enumerate() {
-                                output_buf[i + 1] = byte;
-                            }
-                            if len > (OUTPUT_LEN - 1) {
-                                output_buf[OUTPUT_LEN - 3] = b'.';
-                                output_buf[OUTPUT_LEN - 2] = b'.';
-                                output_buf[OUTPUT_LEN - 1] = b'.';
-                            }
-                        }
 
-                        res_to_u8!(res)
-                    }
-                };
-                output_buf[0] = u8::min(output_buf[0], value);
+                    output_buf[1..last.len() + 1].copy_from_slice(last);
+                }
 
-                // https://developers.meethue.com/develop/get-started-2/core-concepts/#limitations
-                sleep(Duration::from_millis(100)).await;
+                res_to_u8!(res)
             }
+        };
+        output_buf[0] = u8::min(output_buf[0], value);
+        if value == u8::from(OutputCode::Failure) {
+            failed = true;
+            reason.get_or_insert(command_failure_reason(&command, set));
+        }
+
+        last_commands.lock().await.insert(addr, Instant::now());
 
-            if output_buf[0] != u8::MAX {
-                send_to_stream(&mut stream, output_buf).await;
+        // With NO_WAIT, the next command is answered RateLimited if it's too soon
+        if !no_wait || i + 1 < commands_len {
+            sleep(rate_limit()).await;
+        }
+    }
+
+    if use_regions {
+        output_buf[data_idx(regions::CONNECTED_IDX)] =
+            hue_device.is_device_connected().await.unwrap_or(false) as _;
+    }
+
+    // Tells the client the device is unreachable rather than the command erroring
+    if failed && !disconnects && !hue_device.is_device_connected().await.unwrap_or(false) {
+        output_buf[0] = OutputCode::NotConnected.into();
+    } else if let Some(reason) = reason {
+        // Only when the output is a failure, otherwise the other reads kept their data
+        if output_buf[0] == u8::from(OutputCode::Failure) {
+            output_buf = failure_output(reason);
+        }
+    }
+
+    (output_buf, failed)
+}
+
+/// Reason sent along the Failure output when the command fails, at most OUTPUT_LEN - 1 bytes
+fn command_failure_reason(command: &Command, set: bool) -> &'static str {
+    match (command, set) {
+        (Command::Connect, _) => "connection failed",
+        (Command::Disconnect, _) => "disconnect failed",
+        (Command::Power | Command::EnsureOn | Command::RestoreOff, true) => "cannot set power",
+        (Command::Power | Command::EnsureOn | Command::RestoreOff, false) => "cannot read power",
+        (Command::Brightness, _) => "brightness failed",
+        (Command::ColorRgb | Command::ColorHex | Command::ColorXy, _) => "color failed",
+        (Command::ColorTemp | Command::ColorTempRange, _) => "color temp failed",
+        (Command::Name, true) => "cannot set name",
+        (Command::Name, false) => "cannot read name",
+        (Command::Model | Command::Manufacturer | Command::Firmware, _) => "cannot read info",
+        (
+            Command::SearchName
+            | Command::BenchDiscovery
+            | Command::Subscribe
+            | Command::ListDevices
+            | Command::Shutdown
+            | Command::Ping,
+            _,
+        ) => "request failed",
+    }
+}
+
+/// Runs the request on every device one after the other and sends their output in the same order,
+/// see masks::BATCH
+async fn run_batch(
+    stream: &mut Stream,
+    addrs: Vec<[u8; ADDR_LEN]>,
+    request: Request,
+    devices: &Devices,
+    last_commands: &LastCommands,
+    failures: &Failures,
+) {
+    debug!(
+        "batch of {} devices, commands: {:?}",
+        addrs.len(),
+        request.commands
+    );
+
+    for addr in addrs {
+        let output = if request.is_streamed() {
+            failure_output("cannot be batched")
+        } else {
+            batch_output(stream, addr, &request, devices, last_commands, failures).await
+        };
+
+        // Every device must have its output to keep the order
+        if output[0] == u8::MAX {
+            send_output_code(stream, OutputCode::Failure).await;
+        } else {
+            send_to_stream(stream, output).await;
+        }
+    }
+}
+
+async fn batch_output(
+    stream: &mut Stream,
+    addr: [u8; ADDR_LEN],
+    request: &Request,
+    devices: &Devices,
+    last_commands: &LastCommands,
+    failures: &Failures,
+) -> [u8; OUTPUT_LEN] {
+    if request.no_wait {
+        if let Some(output) = rate_limited_output(last_commands, addr).await {
+            return output;
+        }
+    }
+
+    let hue_device = match prepare_device(addr, request.is_connected_state_only(), devices).await {
+        Prepared::Device(hue_device) => hue_device,
+        Prepared::Done(output) => return output.unwrap_or(code_output(OutputCode::Failure)),
+    };
+
+    let (output, failed) =
+        run_commands(stream, &hue_device, addr, request.clone(), last_commands).await;
+    record_failure_and_alert(failures, devices, addr, failed).await;
+
+    output
+}
+
+/// Reads the devices count and addresses following a BATCH request
+async fn read_batch_addresses<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Vec<[u8; ADDR_LEN]>> {
+    let count = reader.read_u8().await?;
+    let mut addrs = vec![[0; ADDR_LEN]; count as usize];
+
+    for addr in &mut addrs {
+        reader.read_exact(addr).await?;
+    }
+
+    Ok(addrs)
+}
+
+/// Reads the name following a NAME set request, invalid UTF-8 is replaced
+async fn read_name<R: AsyncRead + Unpin>(reader: &mut R, len: u8) -> std::io::Result<String> {
+    let mut bytes = vec![0; len as _];
+    reader.read_exact(&mut bytes).await?;
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// RateLimited output with the time to wait (ms, u16 LE) if the last command to the device is too
+/// recent
+async fn rate_limited_output(
+    last_commands: &LastCommands,
+    addr: [u8; ADDR_LEN],
+) -> Option<[u8; OUTPUT_LEN]> {
+    let last_command = last_commands.lock().await.get(&addr).copied();
+    let retry_after = retry_after(last_command)?;
+
+    let mut buf = [0; OUTPUT_LEN];
+    buf[0] = OutputCode::RateLimited.into();
+    buf[1..3].copy_from_slice(&(retry_after.as_millis() as u16).to_le_bytes());
+
+    Some(buf)
+}
+
+/// Records the result of the commands and flashes the alert device when the failures streak
+/// reaches the threshold
+async fn record_failure_and_alert(
+    failures: &Failures,
+    devices: &Devices,
+    addr: [u8; ADDR_LEN],
+    failed: bool,
+) {
+    if !record_failure(failures, addr, failed).await {
+        return;
+    }
+
+    error!(
+        "!!! Commands to device {} failed {FAILURE_ALERT_THRESHOLD} times in a row !!!",
+        addr_to_str(&addr)
+    );
+
+    if let Some(alert_addr) = Config::current().alert_device {
+        if alert_addr != addr {
+            tokio::spawn(flash_alert_device(Arc::clone(devices), alert_addr));
+        }
+    }
+}
+
+/// Streams the cached devices ordered by address, see masks::LIST
+async fn list_devices(stream: &mut Stream, devices: &Devices) {
+    // Collected to free the map lock while checking the connection states
+    let mut devices = devices
+        .lock()
+        .await
+        .iter()
+        .map(|(addr, device)| (*addr, Arc::clone(device)))
+        .collect::<Vec<_>>();
+    devices.sort_by_key(|(addr, _)| *addr);
+
+    for (addr, device) in devices {
+        let connected = device
+            .lock()
+            .await
+            .is_device_connected()
+            .await
+            .unwrap_or(false);
+        if !send_to_stream(stream, listed_device_output(addr, connected)).await {
+            return;
+        }
+    }
+
+    send_output_code(stream, OutputCode::StreamEOF).await;
+}
+
+fn listed_device_output(addr: [u8; ADDR_LEN], connected: bool) -> [u8; OUTPUT_LEN] {
+    let mut buf = code_output(OutputCode::Streaming);
+    buf[1..ADDR_LEN + 1].copy_from_slice(&addr);
+    buf[ADDR_LEN + 1] = connected as _;
+
+    buf
+}
+
+/// Counts the consecutive failing connections to the device, returns true when it reaches the
+/// threshold so it's only alerted once per streak
+async fn record_failure(failures: &Failures, addr: [u8; ADDR_LEN], failed: bool) -> bool {
+    let mut failures = failures.lock().await;
+
+    if !failed {
+        failures.remove(&addr);
+        return false;
+    }
+
+    let count = failures.entry(addr).or_default();
+    *count += 1;
+
+    *count == FAILURE_ALERT_THRESHOLD
+}
+
+/// Streams the state changes of the device as (mask u16 LE, value) until it disconnects (then
+/// StreamEOF is sent) or the client closes the socket
+async fn subscribe(stream: &mut Stream, hue_device: HueDevice<Server>) {
+    let addr = hue_device.addr;
+    let (tx, mut rx) = mpsc::channel(16);
+    let subscription = tokio::spawn(async move { hue_device.subscribe_state(tx).await });
+    let mut probe = [0; 1];
+
+    loop {
+        let change = tokio::select! {
+            change = rx.recv() => change,
+            // The client doesn't send anything else so it's only resolved when it closes the
+            // socket, which ends the subscription
+            _ = stream.read(&mut probe) => {
+                debug!("Client closed the socket, unsubscribing from {addr:?}");
+                subscription.abort();
+                return;
             }
+        };
+        let Some((mask, value)) = change else {
+            break;
+        };
+
+        let mut buf = [0; OUTPUT_LEN];
+        buf[0] = OutputCode::Streaming.into();
+        buf[1..3].copy_from_slice(&MaskT::to_le_bytes(mask));
+        let len = usize::min(value.len(), OUTPUT_LEN - 3);
+        buf[3..len + 3].copy_from_slice(&value[..len]);
+
+        if !send_to_stream(stream, buf).await {
+            subscription.abort();
+            return;
         }
-        Err(error) => error!("Error on connection: {error}"),
     }
+
+    if let Ok(Err(error)) = subscription.await {
+        error!("Failed to subscribe to device {addr:?}: {error}");
+        send_to_stream(stream, failure_output("subscribe failed")).await;
+        return;
+    }
+
+    info!("Device {addr:?} disconnected, subscription ended");
+    send_output_code(stream, OutputCode::StreamEOF).await;
+}
+
+/// Powers on the alert device and turns it red for a while, then restores its state
+async fn flash_alert_device(devices: Devices, addr: [u8; ADDR_LEN]) {
+    let Ok(device) = get_or_discover_device(&devices, addr).await else {
+        warn!("Alert device {} not found", addr_to_str(&addr));
+        return;
+    };
+    let hue_device = device.lock().await;
+
+    if let Err(error) = hue_device.try_connect().await {
+        error!("Cannot connect to the alert device {}: {error}", addr_to_str(&addr));
+        return;
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    if hue_device.services().is_empty() {
+        if let Err(error) = hue_device.discover_services().await {
+            error!("Cannot get the alert device {} services: {error}", addr_to_str(&addr));
+            return;
+        }
+    }
+
+    let was_on = hue_device.get_power().await;
+    let color = hue_device.get_color().await;
+
+    // Red corner of the Hue gamut
+    let x = ((0.6915 * 0xFFFF as f64) as u16).to_le_bytes();
+    let y = ((0.3038 * 0xFFFF as f64) as u16).to_le_bytes();
+
+    let _ = hue_device.set_power(true as _).await;
+    let _ = hue_device.set_color([x[0], x[1], y[0], y[1]]).await;
+
+    sleep(Duration::from_secs(ALERT_FLASH_SECS)).await;
+
+    if let Ok(color) = color {
+        let _ = hue_device.set_color(color).await;
+    }
+    if let Ok(false) = was_on {
+        let _ = hue_device.set_power(false as _).await;
+    }
+}
+
+/// Splits the name (truncated to MAX_NAME_LEN) in chunks fitting an output without splitting a
+/// UTF-8 character
+fn name_chunks(name: &str) -> Vec<&[u8]> {
+    let floor_char_boundary = |s: &str, mut idx: usize| {
+        while !s.is_char_boundary(idx) {
+            idx -= 1;
+        }
+        idx
+    };
+
+    let mut rest = &name[..floor_char_boundary(name, usize::min(name.len(), MAX_NAME_LEN))];
+    let mut chunks = Vec::new();
+
+    while !rest.is_empty() {
+        let end = floor_char_boundary(rest, usize::min(rest.len(), OUTPUT_LEN - 1));
+        let (chunk, tail) = rest.split_at(end);
+
+        chunks.push(chunk.as_bytes());
+        rest = tail;
+    }
+
+    chunks
 }
 
-async fn send_to_stream(stream: &mut Stream, buf: [u8; OUTPUT_LEN]) {
-    stream.write_all(&buf).await.unwrap();
-    stream.flush().await.unwrap();
+/// Delay between two commands to a device, see CMD_DELAY_ENV
+fn rate_limit() -> Duration {
+    Duration::from_millis(Config::current().cmd_delay_ms)
 }
 
-async fn send_output_code(stream: &mut Stream, output_code: OutputCode) {
+/// Remaining time until a new command can be sent to the device
+fn retry_after(last_command: Option<Instant>) -> Option<Duration> {
+    last_command
+        .and_then(|last| rate_limit().checked_sub(last.elapsed()))
+        .filter(|retry_after| !retry_after.is_zero())
+}
+
+/// Returns false when the output cannot be sent, e.g. the client closed the socket before reading
+/// it, so the connection can be dropped without affecting the others
+async fn send_to_stream(stream: &mut Stream, buf: [u8; OUTPUT_LEN]) -> bool {
+    trace_packet("response", &buf);
+
+    let res = match stream.write_all(&buf).await {
+        Ok(_) => stream.flush().await,
+        Err(error) => Err(error),
+    };
+
+    match res {
+        Ok(_) => true,
+        Err(error) if error.kind() == ErrorKind::BrokenPipe => {
+            warn!("Client closed the socket before reading the response, dropping it");
+            false
+        }
+        Err(error) => {
+            error!("Cannot send the response to the client: {error}");
+            false
+        }
+    }
+}
+
+async fn send_output_code(stream: &mut Stream, output_code: OutputCode) -> bool {
+    send_to_stream(stream, code_output(output_code)).await
+}
+
+fn code_output(output_code: OutputCode) -> [u8; OUTPUT_LEN] {
     let mut buf = [0; OUTPUT_LEN];
     buf[0] = output_code.into();
-    send_to_stream(stream, buf).await;
+    buf
+}
+
+/// Failure output with its reason, truncated to the data length
+fn failure_output(reason: &str) -> [u8; OUTPUT_LEN] {
+    let mut buf = code_output(OutputCode::Failure);
+    let len = usize::min(reason.len(), OUTPUT_LEN - 1);
+    buf[1..len + 1].copy_from_slice(&reason.as_bytes()[..len]);
+    buf
 }
 
-async fn check_if_path_is_writable() {
-    if fs::read_dir("/var/run").await.is_err() {
-        error!("Cannot find /var/run directory or lacking permissions to read it");
+/// Checks the directory of the socket since it's created there
+async fn check_if_path_is_writable(socket_path: &Path) {
+    // A relative socket path in the working directory has an empty parent
+    let dir = socket_path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    if fs::read_dir(dir).await.is_err() {
+        error!(
+            "Cannot find {} directory or lacking permissions to read it",
+            dir.display()
+        );
         std::process::exit(2);
     }
 
-    if fs::OpenOptions::new()
+    // create_new never opens an existing file so a user file can't be truncated nor removed
+    let test_file = dir.join(format!(".rustbee-daemon-{}", std::process::id()));
+    match fs::OpenOptions::new()
         .write(true)
-        .create(true)
-        .truncate(false)
-        .open("/var/run/x")
+        .create_new(true)
+        .open(&test_file)
         .await
-        .is_err()
     {
-        error!("Lacking permissions to write to /var/run directory");
-        std::process::exit(2);
+        Ok(_) => {
+            let _ = fs::remove_file(test_file).await;
+        }
+        // Left over by a previous daemon with the same pid, so it's writable
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(_) => {
+            error!(
+                "Lacking permissions to write to {} directory",
+                dir.display()
+            );
+            std::process::exit(2);
+        }
     }
+}
 
-    let _ = fs::remove_file("/var/run/x").await;
+/// Replaces the name read by the device information string selected by the options (see
+/// options::MODEL) and the color temperature read by its range (see options::TEMP_RANGE)
+fn apply_read_options(commands: &mut [Command], opts: u8) {
+    if opts & options::TEMP_RANGE != 0 {
+        if let Some(command) = commands.iter_mut().find(|cmd| **cmd == Command::ColorTemp) {
+            *command = Command::ColorTempRange;
+        }
+    }
+
+    let replacement = if opts & options::MODEL != 0 {
+        Command::Model
+    } else if opts & options::MANUFACTURER != 0 {
+        Command::Manufacturer
+    } else if opts & options::FIRMWARE != 0 {
+        Command::Firmware
+    } else {
+        return;
+    };
+
+    if let Some(command) = commands.iter_mut().find(|cmd| **cmd == Command::Name) {
+        *command = replacement;
+    }
+}
+
+/// Wraps the color/brightness commands with the power commands requested by the options so the
+/// device is on while applying them
+fn apply_options(commands: &mut Vec<Command>, opts: u8) {
+    let sets_values = commands.iter().any(|cmd| {
+        matches!(
+            cmd,
+            Command::ColorRgb
+                | Command::ColorHex
+                | Command::ColorXy
+                | Command::ColorTemp
+                | Command::Brightness
+        )
+    });
+
+    if !sets_values || opts & options::ENSURE_ON != options::ENSURE_ON {
+        return;
+    }
+
+    // Connect is a priority command so it must stay first
+    let idx = commands
+        .iter()
+        .position(|cmd| *cmd != Command::Connect)
+        .unwrap_or(commands.len());
+    commands.insert(idx, Command::EnsureOn);
+
+    if opts & options::RESTORE_OFF == options::RESTORE_OFF {
+        commands.push(Command::RestoreOff);
+    }
 }
 
 fn get_commands_from_flags(flags: MaskT) -> Vec<Command> {
@@ -423,6 +1379,571 @@ fn get_commands_from_flags(flags: MaskT) -> Vec<Command> {
     if (flags >> (SEARCH_NAME - 1)) & 1 == 1 {
         v.push(Command::SearchName)
     }
+    if (flags >> (BENCH_DISCOVERY - 1)) & 1 == 1 {
+        v.push(Command::BenchDiscovery)
+    }
+    if (flags >> (SUBSCRIBE - 1)) & 1 == 1 {
+        v.push(Command::Subscribe)
+    }
+    if (flags >> (COLOR_TEMP - 1)) & 1 == 1 {
+        v.push(Command::ColorTemp)
+    }
+    if (flags >> (LIST - 1)) & 1 == 1 {
+        v.push(Command::ListDevices)
+    }
+    if (flags >> (SHUTDOWN - 1)) & 1 == 1 {
+        v.push(Command::Shutdown)
+    }
+    if (flags >> (PING - 1)) & 1 == 1 {
+        v.push(Command::Ping)
+    }
 
     v
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use interprocess::local_socket::traits::tokio::Stream as _;
+    use rustbee_common::constants::masks::*;
+    use rustbee_common::constants::HUE_BAR_1_ADDR;
+
+    use super::*;
+
+    #[test]
+    fn ensure_on_ordering() {
+        let mut commands = get_commands_from_flags(CONNECT | BRIGHTNESS);
+        apply_options(&mut commands, options::ENSURE_ON | options::RESTORE_OFF);
+        assert_eq!(
+            commands,
+            [
+                Command::Connect,
+                Command::EnsureOn,
+                Command::Brightness,
+                Command::RestoreOff
+            ]
+        );
+
+        let mut commands = get_commands_from_flags(CONNECT | COLOR_XY);
+        apply_options(&mut commands, options::ENSURE_ON);
+        assert_eq!(
+            commands,
+            [Command::Connect, Command::EnsureOn, Command::ColorXy]
+        );
+
+        // Restoring the off state is only done when ensuring it's on
+        let mut commands = get_commands_from_flags(CONNECT | COLOR_XY);
+        apply_options(&mut commands, options::RESTORE_OFF);
+        assert_eq!(commands, [Command::Connect, Command::ColorXy]);
+
+        // Options are ignored for commands that aren't setting colors/brightness
+        let mut commands = get_commands_from_flags(CONNECT | POWER);
+        apply_options(&mut commands, options::ENSURE_ON | options::RESTORE_OFF);
+        assert_eq!(commands, [Command::Connect, Command::Power]);
+    }
+
+    #[test]
+    fn device_info_reads() {
+        let mut commands = get_commands_from_flags(CONNECT | NAME);
+        apply_read_options(&mut commands, options::MODEL);
+        assert_eq!(commands, [Command::Connect, Command::Model]);
+
+        let mut commands = get_commands_from_flags(CONNECT | NAME);
+        apply_read_options(&mut commands, options::FIRMWARE | options::MANUFACTURER);
+        assert_eq!(commands, [Command::Connect, Command::Manufacturer]);
+
+        // Without the options (or without a name read) it's left as is
+        let mut commands = get_commands_from_flags(CONNECT | NAME);
+        apply_read_options(&mut commands, 0);
+        assert_eq!(commands, [Command::Connect, Command::Name]);
+
+        let mut commands = get_commands_from_flags(CONNECT | POWER);
+        apply_read_options(&mut commands, options::MODEL);
+        assert_eq!(commands, [Command::Connect, Command::Power]);
+
+        let mut commands = get_commands_from_flags(CONNECT | COLOR_TEMP);
+        apply_read_options(&mut commands, options::TEMP_RANGE);
+        assert_eq!(commands, [Command::Connect, Command::ColorTempRange]);
+
+        let request = Request {
+            commands: vec![Command::Firmware],
+            set: false,
+            data: [0; DATA_LEN],
+            name: None,
+            no_wait: false,
+        };
+        assert!(request.is_streamed());
+    }
+
+    #[tokio::test]
+    async fn failure_alert_streak() {
+        let failures: Failures = Arc::new(Mutex::new(HashMap::new()));
+        let addr = [1; ADDR_LEN];
+
+        for _ in 1..FAILURE_ALERT_THRESHOLD {
+            assert!(!record_failure(&failures, addr, true).await);
+        }
+        assert!(record_failure(&failures, addr, true).await);
+        // Only alerted once per streak
+        assert!(!record_failure(&failures, addr, true).await);
+
+        assert!(!record_failure(&failures, addr, false).await);
+        assert!(failures.lock().await.is_empty());
+    }
+
+    #[test]
+    fn name_chunking() {
+        assert!(name_chunks("").is_empty());
+        assert_eq!(name_chunks("Hue Bar"), [b"Hue Bar".as_slice()]);
+
+        // Longer than two outputs with multi-byte characters around the boundaries
+        let name = "Hue Play gradient étagère du salon ☀ lightstrip";
+        let chunks = name_chunks(name);
+
+        assert!(chunks.len() > 2);
+        assert_eq!(chunks.concat(), name.as_bytes());
+        for chunk in chunks {
+            assert!(chunk.len() < OUTPUT_LEN);
+            assert!(std::str::from_utf8(chunk).is_ok());
+        }
+
+        let name = "é".repeat(MAX_NAME_LEN);
+        let total: usize = name_chunks(&name).iter().map(|chunk| chunk.len()).sum();
+        assert_eq!(total, MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn rate_limit_retry_after() {
+        assert_eq!(retry_after(None), None);
+
+        let retry = retry_after(Some(Instant::now())).unwrap();
+        assert!(retry <= rate_limit() && !retry.is_zero());
+
+        assert_eq!(retry_after(Some(Instant::now() - rate_limit())), None);
+    }
+
+    #[tokio::test]
+    async fn connections_limit() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        // Holds its permit until the channel is resolved
+        let first = tokio::spawn(limit_concurrency(Arc::clone(&semaphore), async {
+            let _ = rx.await;
+        }));
+        assert_eq!(
+            limit_concurrency(Arc::clone(&semaphore), async { 2 }).await,
+            Some(2)
+        );
+
+        let mut second = tokio::spawn(limit_concurrency(Arc::clone(&semaphore), async {
+            time::sleep(Duration::from_secs(60)).await;
+        }));
+        tokio::task::yield_now().await;
+
+        // Both permits are taken so the third one waits
+        let third = limit_concurrency(Arc::clone(&semaphore), async { 3 });
+        assert!(time::timeout(Duration::from_millis(50), third).await.is_err());
+
+        second.abort();
+        let _ = (&mut second).await;
+        assert_eq!(
+            limit_concurrency(Arc::clone(&semaphore), async { 3 }).await,
+            Some(3)
+        );
+
+        tx.send(()).unwrap();
+        first.await.unwrap();
+    }
+
+    /// Spawns process_conn on a new socket with its own state but the devices and the shutdown
+    /// notification, the request is written before so the daemon may find the client gone. The
+    /// socket file is removed once connected
+    async fn spawn_test_daemon(
+        request: &[u8],
+        devices: Devices,
+        shutdown: Arc<Notify>,
+    ) -> (Stream, tokio::task::JoinHandle<()>) {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("rustbee_test_{}_{count}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let name = || path.as_path().to_fs_name::<GenericFilePath>().unwrap();
+        let listener = ListenerOptions::default()
+            .name(name())
+            .create_tokio()
+            .unwrap();
+
+        let (accepted, client) = tokio::join!(listener.accept(), Stream::connect(name()));
+        let mut client = client.unwrap();
+        client.write_all(request).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let task = tokio::spawn(process_conn(
+            accepted,
+            devices,
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(HashMap::new())),
+            shutdown,
+        ));
+
+        (client, task)
+    }
+
+    /// Request without data for the flags, to any address
+    fn flags_request(flags: MaskT) -> [u8; BUFFER_LEN] {
+        let mut buf = [0; BUFFER_LEN];
+        buf[0] = PROTOCOL_VERSION;
+        buf[1 + ADDR_LEN..1 + ADDR_LEN + 2].copy_from_slice(&flags.to_le_bytes());
+
+        buf
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn per_device_lock() {
+        let devices: Devices = Arc::new(Mutex::new(HashMap::new()));
+        let device = Arc::new(Mutex::new(HueDevice::new(HUE_BAR_1_ADDR)));
+        devices
+            .lock()
+            .await
+            .insert(HUE_BAR_1_ADDR, Arc::clone(&device));
+
+        // Held like by a request running on the device
+        let guard = device.lock().await;
+
+        let mut buf = flags_request(CONNECT | POWER);
+        buf[1..1 + ADDR_LEN].copy_from_slice(&HUE_BAR_1_ADDR);
+        buf[1 + ADDR_LEN + 2] = SET;
+        buf[1 + ADDR_LEN + 3] = 1;
+
+        let mut clients = Vec::new();
+        let mut tasks = Vec::new();
+        for _ in 0..2 {
+            let (client, task) =
+                spawn_test_daemon(&buf, Arc::clone(&devices), Arc::new(Notify::new())).await;
+            clients.push(client);
+            tasks.push(task);
+        }
+
+        // Both SET requests wait for the device, not for the devices map
+        for client in &mut clients {
+            let mut output = [0; OUTPUT_LEN];
+            assert!(
+                time::timeout(Duration::from_millis(100), client.read_exact(&mut output))
+                    .await
+                    .is_err()
+            );
+        }
+        assert!(devices.try_lock().is_ok());
+        assert_eq!(devices.lock().await.len(), 1);
+
+        // The device has no BLE handle to run the commands on
+        for task in tasks {
+            task.abort();
+        }
+        drop(guard);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_connections() {
+        const CLIENTS: usize = 32;
+
+        let path = std::env::temp_dir().join("rustbee_concurrent_connections.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = ListenerOptions::default()
+            .name(path.as_path().to_fs_name::<GenericFilePath>().unwrap())
+            .create_tokio()
+            .unwrap();
+
+        let devices: Devices = Arc::new(Mutex::new(HashMap::new()));
+        let last_commands: LastCommands = Arc::new(Mutex::new(HashMap::new()));
+        let failures: Failures = Arc::new(Mutex::new(HashMap::new()));
+        let shutdown = Arc::new(Notify::new());
+        let server = tokio::spawn(async move {
+            let mut tasks = Vec::new();
+            for _ in 0..CLIENTS {
+                tasks.push(tokio::spawn(process_conn(
+                    listener.accept().await,
+                    Arc::clone(&devices),
+                    Arc::clone(&last_commands),
+                    Arc::clone(&failures),
+                    Arc::clone(&shutdown),
+                )));
+            }
+
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+
+        let clients = (0..CLIENTS)
+            .map(|_| {
+                let path = path.clone();
+
+                tokio::spawn(async move {
+                    let name = path.as_path().to_fs_name::<GenericFilePath>().unwrap();
+                    let mut stream = Stream::connect(name).await.unwrap();
+
+                    let mut buf = [0; BUFFER_LEN];
+                    buf[0] = PROTOCOL_VERSION;
+                    buf[1 + ADDR_LEN..1 + ADDR_LEN + 2].copy_from_slice(&LIST.to_le_bytes());
+                    stream.write_all(&buf).await.unwrap();
+
+                    let mut output = [0; OUTPUT_LEN];
+                    stream.read_exact(&mut output).await.unwrap();
+
+                    OutputCode::from(output[0])
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for client in clients {
+            // No device is cached so the list is only its end
+            assert_eq!(client.await.unwrap(), OutputCode::StreamEOF);
+        }
+        server.await.unwrap();
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn shutdown_request() {
+        let shutdown = Arc::new(Notify::new());
+        let (mut client, task) = spawn_test_daemon(
+            &flags_request(SHUTDOWN),
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::clone(&shutdown),
+        )
+        .await;
+
+        let mut output = [0; OUTPUT_LEN];
+        client.read_exact(&mut output).await.unwrap();
+        assert_eq!(OutputCode::from(output[0]), OutputCode::Success);
+        task.await.unwrap();
+
+        // The notification is kept until the accept loop waits for it
+        assert!(time::timeout(Duration::from_secs(1), shutdown.notified())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn ping_round_trip() {
+        let (mut client, mut daemon) = tokio::io::duplex(64);
+
+        let answer = async {
+            let mut buf = [0; BUFFER_LEN];
+            daemon.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf[0], PROTOCOL_VERSION);
+            let flags = ((buf[8] as u16) << 8) | buf[7] as u16;
+            assert_eq!(get_commands_from_flags(flags), [Command::Ping]);
+
+            daemon
+                .write_all(&code_output(OutputCode::Success))
+                .await
+                .unwrap();
+        };
+        let (pong, _) = tokio::join!(HueDevice::<Client>::ping(&mut client), answer);
+        assert!(pong);
+
+        // The daemon closed the socket without answering
+        let (mut client, daemon) = tokio::io::duplex(64);
+        drop(daemon);
+        assert!(!HueDevice::<Client>::ping(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn dry_run_skips_writes() {
+        let (mut client, mut daemon) = tokio::io::duplex(256);
+
+        let mut data = EMPTY_BUFFER;
+        data[0] = SET;
+        data[1] = 1;
+        let request = HueDevice::<Client>::batch_request(&[HUE_BAR_1_ADDR], CONNECT | POWER, data);
+        assert!(!HueDevice::<Client>::write_request(&mut client, &request, true).await);
+
+        let request = HueDevice::<Client>::name_request(HUE_BAR_1_ADDR, CONNECT, "Desk");
+        assert!(!HueDevice::<Client>::write_request(&mut client, &request, true).await);
+
+        // The reads are still sent
+        let request =
+            HueDevice::<Client>::batch_request(&[HUE_BAR_1_ADDR], CONNECT | POWER, EMPTY_BUFFER);
+        assert!(HueDevice::<Client>::write_request(&mut client, &request, true).await);
+        drop(client);
+
+        let mut sent = Vec::new();
+        daemon.read_to_end(&mut sent).await.unwrap();
+        assert_eq!(sent, request);
+    }
+
+    #[tokio::test]
+    async fn client_gone_before_reading() {
+        let (client, task) = spawn_test_daemon(
+            &flags_request(PING),
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Notify::new()),
+        )
+        .await;
+        // The runtime is single threaded so the task only starts once it's awaited
+        drop(client);
+
+        assert!(task.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn requests_on_one_connection() {
+        let (mut client, task) = spawn_test_daemon(
+            &[],
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Notify::new()),
+        )
+        .await;
+
+        for _ in 0..2 {
+            assert!(HueDevice::<Client>::ping(&mut client).await);
+        }
+
+        // Served until the client closes the connection
+        drop(client);
+        assert!(time::timeout(Duration::from_secs(1), task).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn version_mismatch() {
+        let mut buf = flags_request(LIST);
+        buf[0] = PROTOCOL_VERSION.wrapping_add(1);
+        let (mut client, _task) = spawn_test_daemon(
+            &buf,
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Notify::new()),
+        )
+        .await;
+
+        let mut output = [0; OUTPUT_LEN];
+        client.read_exact(&mut output).await.unwrap();
+        assert_eq!(OutputCode::from(output[0]), OutputCode::VersionMismatch);
+        assert_eq!(output[1], PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn failure_reasons_fit() {
+        let commands = get_commands_from_flags(MaskT::MAX);
+        for command in commands.iter().chain([
+            &Command::EnsureOn,
+            &Command::RestoreOff,
+            &Command::Model,
+            &Command::Manufacturer,
+            &Command::Firmware,
+            &Command::ColorTempRange,
+        ]) {
+            for set in [false, true] {
+                assert!(command_failure_reason(command, set).len() < OUTPUT_LEN);
+            }
+        }
+
+        let output = failure_output("a reason that doesn't fit in the output");
+        assert_eq!(OutputCode::from(output[0]), OutputCode::Failure);
+        assert_eq!(
+            &output[1..],
+            &b"a reason that doesn't fit in the output"[..OUTPUT_LEN - 1]
+        );
+    }
+
+    #[test]
+    fn list_devices_record() {
+        assert_eq!(get_commands_from_flags(LIST), [Command::ListDevices]);
+
+        let buf = listed_device_output(HUE_BAR_1_ADDR, true);
+        assert_eq!(OutputCode::from(buf[0]), OutputCode::Streaming);
+
+        let mut data = [0; OUTPUT_LEN - 1];
+        data.copy_from_slice(&buf[1..]);
+        assert_eq!(
+            HueDevice::<Client>::decode_listed_device(data),
+            (HUE_BAR_1_ADDR, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn name_request_layout() {
+        let (mut client, mut daemon) = tokio::io::duplex(1024);
+        let request = HueDevice::<Client>::name_request(HUE_BAR_1_ADDR, CONNECT, "Desk lamp");
+        client.write_all(&request).await.unwrap();
+
+        let mut buf = [0; BUFFER_LEN];
+        daemon.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[0], PROTOCOL_VERSION);
+        assert_eq!(buf[1..1 + ADDR_LEN], HUE_BAR_1_ADDR);
+        let flags = ((buf[8] as u16) << 8) | buf[7] as u16;
+        assert_eq!(
+            get_commands_from_flags(flags),
+            [Command::Connect, Command::Name]
+        );
+        assert_eq!(buf[9], SET);
+        assert_eq!(read_name(&mut daemon, buf[10]).await.unwrap(), "Desk lamp");
+
+        // Truncated without splitting the last character
+        let name = "€".repeat(MAX_NAME_LEN);
+        let request = HueDevice::<Client>::name_request(HUE_BAR_1_ADDR, CONNECT, &name);
+        assert!(request.len() <= BUFFER_LEN + MAX_NAME_LEN);
+        client.write_all(&request).await.unwrap();
+
+        daemon.read_exact(&mut buf).await.unwrap();
+        assert_eq!(
+            read_name(&mut daemon, buf[10]).await.unwrap(),
+            "€".repeat(MAX_NAME_LEN / 3)
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_request_layout() {
+        let addrs = [[1; ADDR_LEN], [2; ADDR_LEN], [3; ADDR_LEN]];
+        let mut data = EMPTY_BUFFER;
+        data[0] = SET;
+        data[1] = true as _;
+
+        let (mut client, mut daemon) = tokio::io::duplex(1024);
+        let request = HueDevice::<Client>::batch_request(&addrs, CONNECT | POWER, data);
+        client.write_all(&request).await.unwrap();
+
+        let mut buf = [0; BUFFER_LEN];
+        daemon.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[0], PROTOCOL_VERSION);
+        let flags = ((buf[8] as u16) << 8) | buf[7] as u16;
+        assert_eq!(flags, CONNECT | POWER | BATCH);
+        assert_eq!(buf[9], SET);
+        assert_eq!(
+            get_commands_from_flags(flags),
+            [Command::Connect, Command::Power]
+        );
+        assert_eq!(read_batch_addresses(&mut daemon).await.unwrap(), addrs);
+
+        let codes = [
+            OutputCode::Success,
+            OutputCode::DeviceNotFound,
+            OutputCode::Failure,
+        ];
+        for code in codes {
+            daemon.write_all(&code_output(code)).await.unwrap();
+        }
+
+        let outputs = HueDevice::<Client>::receive_batch_outputs(&mut client, addrs.len()).await;
+        assert_eq!(
+            outputs
+                .into_iter()
+                .map(|(code, _)| code)
+                .collect::<Vec<_>>(),
+            codes
+        );
+
+        // Missing addresses, the client closed the socket early
+        let (mut client, mut daemon) = tokio::io::duplex(64);
+        client.write_all(&[2]).await.unwrap();
+        client.write_all(&[1; ADDR_LEN]).await.unwrap();
+        drop(client);
+        assert!(read_batch_addresses(&mut daemon).await.is_err());
+    }
+}
@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use eframe::egui::*;
 use eframe::{CreationContext, NativeOptions};
@@ -10,19 +11,23 @@ use egui_extras::{Size, StripBuilder};
 use futures::{FutureExt, StreamExt as _};
 use tokio::runtime::{self, Runtime};
 use tokio::sync::{
+    oneshot,
     watch::{channel, Receiver},
     RwLock,
 };
+use tokio::task::JoinHandle;
 use tokio::time::{self, Instant};
 
+use rustbee_common::brightness::BrightnessAggregate;
 use rustbee_common::color_space::Rgb;
-use rustbee_common::colors::Xy;
+use rustbee_common::colors::{Gamut, Xy};
 use rustbee_common::constants::{
-    masks, OutputCode, ADDR_LEN, APP_ID, DATA_LEN, GUI_SAVE_INTERVAL_SECS,
+    masks, options, OutputCode, ADDR_LEN, APP_ID, DATA_LEN, GUI_SAVE_INTERVAL_SECS,
+    MAX_COLOR_TEMP_KELVIN, MIN_COLOR_TEMP_KELVIN,
 };
-use rustbee_common::device::{Client, FoundDevice, HueDevice};
+use rustbee_common::device::{Client, FoundDevice, HueDevice, Response as DeviceResponse};
 use rustbee_common::storage::{SavedDevice, Storage};
-use rustbee_common::utils::launch_daemon;
+use rustbee_common::utils::{addr_to_str, launch_daemon, parse_hex_address};
 
 const FONT_NAME: &str = "monaspace";
 // When adding a SVG, add `fill="#FFFFFF"` to the path tag because egui expect svgs to be white by
@@ -32,9 +37,30 @@ const LIGHT_BULB_SVG: ImageSource = include_image!("../assets/lightbulb.svg");
 const BLUETOOTH_SVG: ImageSource = include_image!("../assets/bluetooth.svg");
 const WHITE: Color32 = Color32::from_rgb(0xE7, 0xE7, 0xE4);
 const BACKGROUND: Color32 = Color32::from_rgb(0x0F, 0x0F, 0x10);
+/// Status circle colors
+const STATUS_ON: Color32 = Color32::from_rgb(0, 255, 0);
+const STATUS_IDLE: Color32 = Color32::from_rgb(255, 165, 0);
+const STATUS_OFF: Color32 = Color32::from_rgb(255, 0, 0);
 const SEARCH_MAX_CHARS: usize = DATA_LEN;
+/// Default interval, the user can change it at runtime within the min/max below
 const DEVICE_STATE_UPDATE_SECS: u64 = 60;
+/// Below that, the sync would hog the BLE airtime
+const MIN_DEVICE_STATE_UPDATE_SECS: u64 = 2;
+const MAX_DEVICE_STATE_UPDATE_SECS: u64 = 60 * 10;
+/// eframe storage keys
+const SYNC_INTERVAL_KEY: &str = "device_state_update_secs";
+const AUTOSAVE_KEY: &str = "autosave";
+const WINDOW_SIZE_KEY: &str = "window_size";
+const WINDOW_POSITION_KEY: &str = "window_position";
+/// Used on the first run, fits 3 device widgets
+const DEFAULT_WINDOW_SIZE: [f32; 2] = [1320., 800.];
 const DEBOUNCE_SECS: u64 = 5;
+/// How long commands skip the connection check after connecting to all devices
+const CONNECT_BURST_SECS: u64 = 30;
+/// Submissions of the address input within that delay are ignored
+const ADD_DEVICE_DEBOUNCE_MS: u64 = 500;
+/// Shown until the device reports its color temperature
+const DEFAULT_COLOR_TEMP_KELVIN: u16 = 4000;
 
 /// Keep in mind that this overwrites the current receiver channel,
 /// making the previous future unable to be read (but not cancelled)
@@ -61,11 +87,21 @@ struct HueDeviceWrapper {
     is_found: bool,
     last_update: Instant,
     is_connected: bool,
+    /// The last state update failed because the device is unreachable
+    is_offline: bool,
     power_state: bool,
     brightness: u8,
     /// Don't forget to call .update() after updating the inner value
     current_color: Debounce<[u8; 3]>,
+    /// Kelvin, same as current_color for the .update()
+    color_temp: Debounce<u16>,
+    /// The color temperature was set last so it's in use instead of the color
+    is_color_temp_mode: bool,
     name: String,
+    /// Saved by `rustbee info`, see HueDeviceWrapper::gamut
+    model: Option<String>,
+    /// Set after an explicit connection, until then commands skip the daemon connection check
+    connect_burst_until: Option<Instant>,
     inner: HueDevice<Client>,
 }
 
@@ -77,11 +113,19 @@ impl Default for HueDeviceWrapper {
             power_state: Default::default(),
             brightness: Default::default(),
             name: Default::default(),
+            model: None,
             current_color: Debounce::new([0; 3], Duration::from_secs(DEBOUNCE_SECS)),
+            color_temp: Debounce::new(
+                DEFAULT_COLOR_TEMP_KELVIN,
+                Duration::from_secs(DEBOUNCE_SECS),
+            ),
+            is_color_temp_mode: false,
             is_found: false,
             is_connected: false,
+            is_offline: false,
             is_paired: false,
             is_initiated: false,
+            connect_burst_until: None,
             inner: Default::default(),
         }
     }
@@ -94,6 +138,69 @@ impl HueDeviceWrapper {
             ..Default::default()
         }
     }
+
+    /// Gamut C when the model isn't known
+    fn gamut(&self) -> Gamut {
+        self.model
+            .as_deref()
+            .map_or_else(Gamut::default, Gamut::from_model)
+    }
+
+    fn start_connect_burst(&mut self) {
+        self.inner.skip_connect = true;
+        self.connect_burst_until = Some(Instant::now() + Duration::from_secs(CONNECT_BURST_SECS));
+    }
+
+    fn end_connect_burst(&mut self) {
+        self.inner.skip_connect = false;
+        self.connect_burst_until = None;
+    }
+
+    fn connection_status(&self) -> ConnectionStatus {
+        if self.is_connected {
+            ConnectionStatus::Connected
+        } else if self.is_found && self.is_paired && !self.is_offline {
+            ConnectionStatus::Disconnected
+        } else {
+            ConnectionStatus::NotFound
+        }
+    }
+}
+
+/// What the device widget shows from the is_connected, is_found and is_paired flags
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConnectionStatus {
+    Connected,
+    /// Paired and in range, the next command connects to it
+    Disconnected,
+    /// Out of Bluetooth range, or powered off at the switch, or it stopped answering
+    NotFound,
+}
+
+impl ConnectionStatus {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Connected => "Connected",
+            Self::Disconnected => "Paired, disconnected",
+            Self::NotFound => "Offline / not found",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Connected => "The device is connected and answering",
+            Self::Disconnected => "The device is in range but not connected, the next command connects to it",
+            Self::NotFound => "The device wasn't found or stopped answering, it's out of Bluetooth range or without power",
+        }
+    }
+
+    fn color(self) -> Color32 {
+        match self {
+            Self::Connected => STATUS_ON,
+            Self::Disconnected => STATUS_IDLE,
+            Self::NotFound => STATUS_OFF,
+        }
+    }
 }
 
 impl Deref for HueDeviceWrapper {
@@ -119,6 +226,15 @@ impl From<&HueDeviceWrapper> for SavedDevice {
             name: device.name.clone(),
             current_color: *device.current_color,
             brightness: device.brightness,
+            power_state: device.power_state,
+            color_temp: device.is_color_temp_mode.then_some(*device.color_temp),
+            nightlight: None,
+            last_seen: device.is_found.then(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs() as _)
+            }),
+            model: device.model.clone(),
         }
     }
 }
@@ -192,18 +308,41 @@ impl Text for Ui {
 
 type AppDevices = HashMap<[u8; ADDR_LEN], HueDeviceWrapper>;
 
+/// Background loop syncing the devices state
+struct SyncTask {
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
 struct App {
     devices: Arc<RwLock<AppDevices>>,
     tokio_rt: Runtime,
     devices_color: Debounce<[u8; 3]>,
     devices_brightness: Debounce<u8>,
+    devices_color_temp: Debounce<u16>,
     device_error: Option<String>,
     device_name_search: String,
     devices_found: Arc<RwLock<Vec<FoundDevice>>>,
+    /// Names (or addresses) of the devices that failed the last "all devices" command
+    failed_devices: Arc<RwLock<Vec<String>>>,
     new_device_addr: String,
-    is_new_device_addr_error: bool,
+    new_device_addr_error: Option<String>,
+    /// When the last device was added by address, see ADD_DEVICE_DEBOUNCE_MS
+    new_device_added_at: Option<Instant>,
+    /// Removed once the frame is drawn since the grid holds the devices lock meanwhile
+    removed_device: Option<[u8; ADDR_LEN]>,
     channel: Option<Receiver<bool>>,
     storage: Storage,
+    /// Interval in seconds shared with the sync loop
+    sync_interval: Arc<AtomicU64>,
+    /// When disabled, the devices are only saved with the Save button so a hand-edited storage
+    /// file isn't overwritten
+    autosave: bool,
+    sync_task: Option<SyncTask>,
+    /// Inner size of the window, kept from the last frame since App::save has no context
+    window_size: Option<[f32; 2]>,
+    /// Outer position of the window, some platforms (e.g. Wayland) don't report it
+    window_position: Option<[f32; 2]>,
 }
 
 impl App {
@@ -212,6 +351,8 @@ impl App {
         devices: Arc<RwLock<AppDevices>>,
         tokio_rt: Runtime,
         mut storage: Storage,
+        sync_interval: Arc<AtomicU64>,
+        sync_task: SyncTask,
     ) -> Box<Self> {
         // Customize egui here with cc.egui_ctx.set_fonts and cc.egui_ctx.set_visuals.
         // Restore app state using cc.storage (requires the "persistence" feature).
@@ -263,37 +404,96 @@ impl App {
         for (addr, device) in storage.get_devices() {
             let mut hue_device = HueDeviceWrapper::from_address(*addr);
             hue_device.name = device.name.clone();
+            hue_device.brightness = device.brightness;
+            hue_device.power_state = device.power_state;
+            hue_device.model = device.model.clone();
             hue_device.current_color =
                 Debounce::new(device.current_color, Duration::from_secs(DEBOUNCE_SECS));
+            if let Some(kelvin) = device.color_temp {
+                hue_device.color_temp = Debounce::new(kelvin, Duration::from_secs(DEBOUNCE_SECS));
+            }
 
             devices_guard.insert(*addr, hue_device);
         }
 
-        let lowest_brightness = devices_guard.iter().fold(100u8, |v, (_, device)| {
-            if device.brightness < v {
-                device.brightness
-            } else {
-                v
-            }
-        });
+        let devices_brightness = BrightnessAggregate::current()
+            .aggregate(devices_guard.values().map(|device| device.brightness));
 
         drop(devices_guard);
 
+        if let Some(secs) = cc
+            .storage
+            .and_then(|storage| eframe::get_value::<u64>(storage, SYNC_INTERVAL_KEY))
+        {
+            sync_interval.store(
+                secs.clamp(MIN_DEVICE_STATE_UPDATE_SECS, MAX_DEVICE_STATE_UPDATE_SECS),
+                Ordering::Relaxed,
+            );
+        }
+
+        let autosave = cc
+            .storage
+            .and_then(|storage| eframe::get_value::<bool>(storage, AUTOSAVE_KEY))
+            .unwrap_or(true);
+
         Box::new(Self {
             devices,
             tokio_rt,
             storage,
             devices_color: Debounce::new([0; 3], Duration::from_secs(DEBOUNCE_SECS)),
-            devices_brightness: Debounce::new(lowest_brightness, Duration::from_secs(1)),
+            devices_brightness: Debounce::new(devices_brightness, Duration::from_secs(1)),
+            devices_color_temp: Debounce::new(DEFAULT_COLOR_TEMP_KELVIN, Duration::from_secs(1)),
             device_error: None,
             device_name_search: String::new(),
             devices_found: Arc::new(RwLock::new(Vec::new())),
+            failed_devices: Arc::new(RwLock::new(Vec::new())),
             new_device_addr: String::new(),
-            is_new_device_addr_error: false,
+            new_device_addr_error: None,
+            new_device_added_at: None,
+            removed_device: None,
             channel: None,
+            sync_interval,
+            autosave,
+            sync_task: Some(sync_task),
+            window_size: None,
+            window_position: None,
         })
     }
 
+    /// Persists the devices, keeping what only the CLI handles
+    fn save_devices(&mut self) {
+        let devices_ref = Arc::clone(&self.devices);
+        let devices = self.tokio_rt.block_on(devices_ref.read());
+
+        let saved_devices = devices
+            .iter()
+            // A default device (from bad input) must never be persisted
+            .filter(|(addr, _)| **addr != [0; ADDR_LEN])
+            .map(|(addr, device)| {
+                let mut saved_device = SavedDevice::from(device);
+                let saved = self.storage.get_device(addr);
+                // The nightlight is handled by the CLI so it must be kept as is, so is the color
+                // temperature unless it was set here
+                saved_device.nightlight = saved.and_then(|saved| saved.nightlight);
+                if saved_device.color_temp.is_none() {
+                    saved_device.color_temp = saved.and_then(|saved| saved.color_temp);
+                }
+                if saved_device.last_seen.is_none() {
+                    saved_device.last_seen = saved.and_then(|saved| saved.last_seen);
+                }
+                if saved_device.model.is_none() {
+                    saved_device.model = saved.and_then(|saved| saved.model.clone());
+                }
+
+                (*addr, Some(saved_device))
+            })
+            .collect();
+
+        self.storage.set_devices(saved_devices);
+
+        self.storage.flush();
+    }
+
     fn add_light_bulb_icon(&self, ui: &mut Ui, scale: f32, color: Option<Color32>) -> Response {
         ui.add_sized(
             vec2(14. * scale, 14. * scale),
@@ -308,26 +508,26 @@ impl App {
         )
     }
 
-    fn show_status_circle(&self, ui: &mut Ui, radius: f32, is_on: bool, offset: Option<Vec2>) {
+    /// The response is for a tooltip
+    fn show_status_circle(
+        &self,
+        ui: &mut Ui,
+        radius: f32,
+        color: Color32,
+        offset: Option<Vec2>,
+    ) -> Response {
         // let (Response { rect, .. }, painter) =
         //     ui.allocate_painter(Vec2::splat(radius * 1.5), Sense::hover());
-        let (_, rect) = ui.allocate_space(Vec2::splat(radius));
+        let (rect, response) = ui.allocate_exact_size(Vec2::splat(radius), Sense::hover());
         let pos = if let Some(offset) = offset {
             rect.center() - offset
         } else {
             rect.center()
         };
 
-        ui.painter().circle(
-            pos,
-            radius,
-            if is_on {
-                Color32::from_rgb(0, 255, 0)
-            } else {
-                Color32::from_rgb(255, 0, 0)
-            },
-            Stroke::NONE,
-        );
+        ui.painter().circle(pos, radius, color, Stroke::NONE);
+
+        response
     }
 
     // fn display_device(
@@ -536,7 +736,7 @@ impl App {
                 StripBuilder::new(ui)
                     .cell_layout(Layout::left_to_right(Align::Center))
                     .clip(true)
-                    .sizes(Size::exact(25.), 8)
+                    .sizes(Size::exact(25.), 9)
                     .vertical(|mut strip| {
                         strip.cell(|ui| {
                             ui.label(format!("{size}"));
@@ -565,12 +765,11 @@ impl App {
                             builder.sizes(Size::remainder(), 3).horizontal(|mut strip| {
                                 strip.empty();
                                 strip.cell(|ui| {
-                                    self.show_status_circle(ui, 6., device.is_connected, None);
-                                    ui.text(if device.is_connected {
-                                        "Connected"
-                                    } else {
-                                        "Disconnected"
-                                    });
+                                    let status = device.connection_status();
+                                    let circle =
+                                        self.show_status_circle(ui, 6., status.color(), None);
+                                    let label = ui.text(status.label());
+                                    circle.union(label).on_hover_text(status.description());
                                 });
                                 strip.empty();
                             });
@@ -600,7 +799,11 @@ impl App {
                                                 self.show_status_circle(
                                                     ui,
                                                     6.,
-                                                    device.power_state,
+                                                    if device.power_state {
+                                                        STATUS_ON
+                                                    } else {
+                                                        STATUS_OFF
+                                                    },
                                                     Some(vec2(-5., -3.)),
                                                 );
                                             });
@@ -630,6 +833,44 @@ impl App {
                             }
                         });
 
+                        strip.cell(|ui| {
+                            ui.text("Temperature");
+                            let slider = ui.add(
+                                Slider::new(
+                                    &mut *device.color_temp,
+                                    MIN_COLOR_TEMP_KELVIN..=MAX_COLOR_TEMP_KELVIN,
+                                )
+                                .show_value(false)
+                                .trailing_fill(true),
+                            );
+                            let kelvin = format!("{}K", *device.color_temp);
+                            if device.is_color_temp_mode {
+                                ui.label(kelvin);
+                            } else {
+                                ui.weak(kelvin);
+                            }
+
+                            if (slider.changed() || slider.drag_stopped())
+                                && device.color_temp.update()
+                            {
+                                // It overrides the color
+                                device.is_color_temp_mode = true;
+                                let kelvin = *device.color_temp;
+                                let opts = if device.power_state {
+                                    0
+                                } else {
+                                    options::ENSURE_ON
+                                };
+                                let device = device.clone();
+                                run_async!(self, async move {
+                                    device
+                                        .set_color_temp_with_options(kelvin, opts)
+                                        .await
+                                        .is_success()
+                                });
+                            }
+                        });
+
                         strip.strip(|builder| {
                             builder.sizes(Size::remainder(), 2).horizontal(|mut strip| {
                                 let (r, g, b) = (
@@ -640,7 +881,12 @@ impl App {
 
                                 strip.cell(|ui| {
                                     ui.text("Color");
-                                    ui.label(format!("{:?}", *device.current_color));
+                                    let color = format!("{:?}", *device.current_color);
+                                    if device.is_color_temp_mode {
+                                        ui.weak(color);
+                                    } else {
+                                        ui.label(color);
+                                    }
                                     ui.painter().circle_filled(
                                         ui.next_widget_position(),
                                         7.,
@@ -660,11 +906,26 @@ impl App {
                                             x,
                                             y,
                                             brightness: _,
-                                        } = Xy::from(Rgb::new(r as _, g as _, b as _));
+                                        } = Xy::from_rgb_with_gamut(
+                                            Rgb::new(r as _, g as _, b as _),
+                                            device.gamut(),
+                                        );
+                                        device.is_color_temp_mode = false;
+                                        // Some firmwares ignore the color while being off
+                                        let opts = if device.power_state {
+                                            0
+                                        } else {
+                                            options::ENSURE_ON
+                                        };
                                         let device = device.clone();
                                         run_async!(self, async move {
                                             device
-                                                .set_colors(x as _, y as _, masks::COLOR_RGB)
+                                                .set_colors_with_options(
+                                                    x as _,
+                                                    y as _,
+                                                    masks::COLOR_RGB,
+                                                    opts,
+                                                )
                                                 .await
                                                 .is_success()
                                         });
@@ -705,6 +966,19 @@ impl App {
 
                                             reset_frame = true;
                                         }
+
+                                        // A command in flight could still target the device
+                                        let is_idle = self.channel.is_none();
+                                        let remove = ui
+                                            .add_enabled(is_idle, Button::new("Remove"))
+                                            .on_hover_text("Remove this device from the list")
+                                            .on_disabled_hover_text(
+                                                "Wait for the current command to finish",
+                                            );
+                                        if remove.clicked() {
+                                            self.removed_device = Some(addr);
+                                            reset_frame = true;
+                                        }
                                     });
                                 });
                         });
@@ -847,6 +1121,16 @@ impl eframe::App for App {
     fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
         let devices = Arc::clone(&self.devices);
 
+        ctx.input(|input| {
+            let viewport = input.viewport();
+            if let Some(rect) = viewport.inner_rect {
+                self.window_size = Some([rect.width(), rect.height()]);
+            }
+            if let Some(rect) = viewport.outer_rect {
+                self.window_position = Some([rect.min.x, rect.min.y]);
+            }
+        });
+
         TopBottomPanel::top("banner")
             .show_separator_line(true)
             .show(ctx, |ui| {
@@ -861,65 +1145,6 @@ impl eframe::App for App {
                                 self.add_light_bulb_icon(ui, 2.5, None);
                                 ui.heading(RichText::new("Rustbee").strong().size(24.));
 
-                                /* if !self.new_device_addr.is_empty() && self.is_new_device_addr_error {
-                                    ui.horizontal(|ui| {
-                                        ui.label("Error on parsing Address, please respect the following format: ff:aa:55:ff:aa:55");
-                                    });
-                                }
-
-                                ui.horizontal(|ui| {
-                                    self.add_bluetooth_icon(ui, 2.);
-                                    ui.label("Add a Philips Hue device by uuid");
-                                    let input = ui.text_edit_singleline(&mut self.new_device_addr);
-                                    if input.has_focus() {
-                                        input.show_tooltip_ui(|ui| {
-                                            ui.label(
-                                                "Enter the Hexadecimal UUID and press enter or click elsewhere",
-                                            );
-                                        });
-                                    }
-                                    if input.lost_focus() {
-                                        match parse_address(&self.new_device_addr) {
-                                            Ok(addr) => {
-                                                let devices = Arc::clone(&devices);
-
-                                                run_async!(self, async move {
-                                                    let devices_read = devices.read().await;
-                                                    if devices_read.get(&*addr).is_some() {
-                                                        return false;
-                                                    }
-                                                    drop(devices_read);
-
-                                                    let mut devices = devices.write().await;
-                                                    let mut device = HueDeviceWrapper::from_address(addr);
-
-                                                    match device.pair().await {
-                                                        OutputCode::Success => {
-                                                            device.is_paired = true;
-                                                            device.is_found = true;
-                                                        },
-                                                        _ => {
-                                                            device.is_paired = false;
-                                                            device.is_found = false;
-                                                        },
-                                                    }
-
-                                                    devices.insert(*addr, device);
-                                                    true
-                                                });
-
-                                                self.is_new_device_addr_error = false;
-                                                if let Some(storage) = frame.storage_mut() {
-                                                    self.save(storage);
-                                                }
-                                            },
-                                            Err(_) => {
-                                                self.is_new_device_addr_error = true;
-                                            },
-                                        }
-                                    }
-                                }); */
-
                                 ui.add_space(ui.available_width() - 350.);
                                 Frame::none()
                                     .stroke(Stroke::new(1., Color32::WHITE))
@@ -997,7 +1222,7 @@ impl eframe::App for App {
                                                     run_async!(self, async move {
                                                         let name = name;
                                                         let mut stream =
-                                                            HueDevice::search_by_name(&name).await;
+                                                            HueDevice::search_by_name(&name, false).await;
 
                                                         while let Some(device) = stream.next().await
                                                         {
@@ -1018,6 +1243,82 @@ impl eframe::App for App {
                             });
                         });
 
+                    // Its own row so it doesn't squeeze the search on the right of the top bar
+                    ui.horizontal(|ui| {
+                        ui.add_space(5.);
+                        self.add_bluetooth_icon(ui, 1.5, None);
+                        ui.label("Add a Philips Hue device by address");
+                        let input = ui.add(
+                            TextEdit::singleline(&mut self.new_device_addr)
+                                .hint_text("ff:aa:55:ff:aa:55")
+                                .desired_width(180.),
+                        );
+                        if input.has_focus() {
+                            input.show_tooltip_text(
+                                "Enter the MAC address and press enter or click elsewhere",
+                            );
+                        }
+                        if input.changed() {
+                            self.new_device_addr_error = None;
+                        }
+
+                        // Submitting again right away must not add it twice
+                        let is_debounced = self.new_device_added_at.is_some_and(|added_at| {
+                            added_at.elapsed() < Duration::from_millis(ADD_DEVICE_DEBOUNCE_MS)
+                        });
+
+                        if input.lost_focus() && !self.new_device_addr.is_empty() && !is_debounced {
+                            match parse_hex_address(&self.new_device_addr) {
+                                Ok(addr) => {
+                                    self.new_device_addr.clear();
+                                    self.new_device_added_at = Some(Instant::now());
+
+                                    let mut devices_guard = self.tokio_rt.block_on(devices.write());
+                                    let is_new = !devices_guard.contains_key(&addr);
+                                    if is_new {
+                                        devices_guard
+                                            .insert(addr, HueDeviceWrapper::from_address(addr));
+                                    }
+                                    drop(devices_guard);
+
+                                    if is_new {
+                                        if self.autosave {
+                                            self.save_devices();
+                                        }
+
+                                        let devices = Arc::clone(&devices);
+
+                                        run_async!(self, async move {
+                                            let is_paired = HueDevice::<Client>::new(addr)
+                                                .connect_device()
+                                                .await
+                                                .is_success();
+
+                                            let mut devices = devices.write().await;
+                                            if let Some(device) = devices.get_mut(&addr) {
+                                                device.is_paired = is_paired;
+                                                device.is_found = is_paired;
+                                                if is_paired {
+                                                    update_device_state(device).await;
+                                                }
+                                            }
+
+                                            is_paired
+                                        });
+                                    }
+                                }
+                                Err(err) => self.new_device_addr_error = Some(err.to_string()),
+                            }
+                        }
+
+                        if let Some(ref error) = self.new_device_addr_error {
+                            ui.colored_label(
+                                ui.visuals().error_fg_color,
+                                format!("{error}, expected the format ff:aa:55:ff:aa:55"),
+                            );
+                        }
+                    });
+
                     let devices_found = self.tokio_rt.block_on(self.devices_found.read());
 
                     if !devices_found.is_empty() {
@@ -1088,12 +1389,15 @@ impl eframe::App for App {
                         }
                     }
 
+                    let mut is_last_command_failed = false;
+
                     if let Some(ref mut rx) = self.channel {
                         match rx.has_changed() {
                             Ok(changed) => {
                                 if changed {
                                     if !*rx.borrow_and_update() {
                                         ui.colored_label(ui.visuals().error_fg_color, "Error");
+                                        is_last_command_failed = true;
                                     }
 
                                     self.channel = None;
@@ -1119,40 +1423,119 @@ impl eframe::App for App {
                         return;
                     }
 
+                    // The connection may have been lost so it's checked again on failure
+                    for device in devices_mut.values_mut() {
+                        if device.connect_burst_until.is_some_and(|until| {
+                            is_last_command_failed || until <= Instant::now()
+                        }) {
+                            device.end_connect_burst();
+                        }
+                    }
+
+                    let failed_devices = self.tokio_rt.block_on(self.failed_devices.read());
+
+                    if !failed_devices.is_empty() {
+                        ui.horizontal(|ui| {
+                            ui.colored_label(
+                                ui.visuals().error_fg_color,
+                                format!("Failed to apply to: {}", failed_devices.join(", ")),
+                            );
+                        });
+                    }
+
+                    drop(failed_devices);
+
+                    if ui
+                        .button("Refresh")
+                        .on_hover_text("Reads the state of every device now instead of waiting for the periodic sync")
+                        .clicked()
+                    {
+                        // Takes the same lock as the sync loop and resets the devices last_update
+                        // so they won't be updated twice in a row
+                        run_async!(self, async {
+                            update_all_devices_state(devices).await;
+
+                            true
+                        });
+                        return;
+                    }
+
                     if ui.button("Power OFF all devices").clicked() {
+                        let failed_devices = Arc::clone(&self.failed_devices);
+
                         run_async!(self, async {
                             let devices_read = devices.read().await;
                             let futures = devices_read
                                 .iter()
-                                .map(|(_, device)| device.set_power(false))
+                                .map(|(addr, device)| {
+                                    device
+                                        .set_power(false)
+                                        .map(|output| (*addr, output.is_success()))
+                                })
                                 .collect::<Vec<_>>();
                             let res = futures::future::join_all(futures).await;
+                            let success =
+                                report_batch_results(failed_devices, &devices_read, res).await;
                             drop(devices_read);
 
                             update_all_devices_state(devices).await;
 
-                            !res.into_iter().fold(true, |acc, v| !acc || !v.is_success())
+                            success
                         });
                         return;
                     }
 
                     if ui.button("Power ON all devices").clicked() {
+                        let failed_devices = Arc::clone(&self.failed_devices);
+
                         run_async!(self, async {
                             let devices_read = devices.read().await;
                             let futures = devices_read
                                 .iter()
-                                .map(|(_, device)| device.set_power(true))
+                                .map(|(addr, device)| {
+                                    device
+                                        .set_power(true)
+                                        .map(|output| (*addr, output.is_success()))
+                                })
                                 .collect::<Vec<_>>();
                             let res = futures::future::join_all(futures).await;
+                            let success =
+                                report_batch_results(failed_devices, &devices_read, res).await;
                             drop(devices_read);
 
                             update_all_devices_state(devices).await;
 
-                            !res.into_iter().fold(true, |acc, v| !acc || !v.is_success())
+                            success
                         });
                         return;
                     }
 
+                    ui.horizontal(|ui| {
+                        let mut secs = self.sync_interval.load(Ordering::Relaxed);
+                        ui.text(format!("Devices state sync every {secs}s"));
+                        let slider = ui.add(
+                            Slider::new(
+                                &mut secs,
+                                MIN_DEVICE_STATE_UPDATE_SECS..=MAX_DEVICE_STATE_UPDATE_SECS,
+                            )
+                            .logarithmic(true)
+                            .show_value(false)
+                            .trailing_fill(true),
+                        );
+                        if slider.changed() {
+                            self.sync_interval.store(secs, Ordering::Relaxed);
+                        }
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.autosave, "Autosave devices")
+                            .on_hover_text("When disabled, the devices are only saved with the Save button so your edits of the storage file aren't overwritten");
+
+                        if !self.autosave && ui.button("Save").clicked() {
+                            self.save_devices();
+                        }
+                    });
+
                     ui.horizontal(|ui| {
                         ui.text(format!("Devices brightness {}%", *self.devices_brightness));
                         let slider = ui.add(
@@ -1163,19 +1546,85 @@ impl eframe::App for App {
                         if slider.changed() && self.devices_brightness.update() {
                             let percentage = *self.devices_brightness;
                             let devices_ref = Arc::clone(&devices);
+                            let failed_devices = Arc::clone(&self.failed_devices);
 
                             run_async!(self, async move {
                                 let devices_read = devices_ref.read().await;
                                 let futures = devices_read
                                     .iter()
-                                    .map(|(_, device)| device.set_brightness(percentage))
+                                    .map(|(addr, device)| async move {
+                                        // Backs off instead of queueing behind the previous
+                                        // values while dragging the slider
+                                        let output = match device.try_set_brightness(percentage).await
+                                        {
+                                            Ok(output) => output,
+                                            Err(retry_after) => {
+                                                time::sleep(retry_after).await;
+                                                device.set_brightness(percentage).await
+                                            }
+                                        };
+
+                                        (*addr, output.is_success())
+                                    })
                                     .collect::<Vec<_>>();
                                 let res = futures::future::join_all(futures).await;
+                                let success =
+                                    report_batch_results(failed_devices, &devices_read, res).await;
                                 drop(devices_read);
 
                                 update_all_devices_state(devices_ref).await;
 
-                                !res.into_iter().fold(true, |acc, v| !acc || !v.is_success())
+                                success
+                            });
+                        }
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.text(format!("Devices temperature {}K", *self.devices_color_temp));
+                        let slider = ui.add(
+                            Slider::new(
+                                &mut *self.devices_color_temp,
+                                MIN_COLOR_TEMP_KELVIN..=MAX_COLOR_TEMP_KELVIN,
+                            )
+                            .show_value(false)
+                            .trailing_fill(true),
+                        );
+                        // A batch in flight would lose its receiver channel
+                        if self.channel.is_none()
+                            && slider.changed()
+                            && self.devices_color_temp.update()
+                        {
+                            let kelvin = *self.devices_color_temp;
+                            let devices_ref = Arc::clone(&devices);
+                            let failed_devices = Arc::clone(&self.failed_devices);
+
+                            run_async!(self, async move {
+                                let devices_read = devices_ref.read().await;
+                                let futures = devices_read
+                                    .iter()
+                                    .map(|(addr, device)| async move {
+                                        (*addr, device.set_color_temp(kelvin).await.is_success())
+                                    })
+                                    .collect::<Vec<_>>();
+                                let res = futures::future::join_all(futures).await;
+                                let succeeded = res
+                                    .iter()
+                                    .filter_map(|(addr, success)| success.then_some(*addr))
+                                    .collect::<Vec<_>>();
+                                let success =
+                                    report_batch_results(failed_devices, &devices_read, res).await;
+                                drop(devices_read);
+
+                                // It overrides their color, see is_color_temp_mode
+                                for addr in succeeded {
+                                    if let Some(device) = devices_ref.write().await.get_mut(&addr) {
+                                        device.is_color_temp_mode = true;
+                                    }
+                                }
+
+                                update_all_devices_state(devices_ref).await;
+
+                                success
                             });
                         }
                     });
@@ -1187,22 +1636,29 @@ impl eframe::App for App {
                     {
                         let color = *self.devices_color;
                         let devices_ref = Arc::clone(&devices);
+                        let failed_devices = Arc::clone(&self.failed_devices);
 
                         run_async!(self, async move {
                             let mut res = Vec::new();
+                            let devices_read = devices_ref.read().await;
 
-                            for (_, device) in devices_ref.read().await.iter() {
+                            for (addr, device) in devices_read.iter() {
                                 let (r, g, b) = (color[0], color[1], color[2]);
                                 let Xy {
                                     x,
                                     y,
                                     brightness: _,
-                                } = Xy::from(Rgb::new(r as _, g as _, b as _));
+                                } = Xy::from_rgb_with_gamut(
+                                    Rgb::new(r as _, g as _, b as _),
+                                    device.gamut(),
+                                );
                                 // TODO: Fixme
-                                res.push(device.set_colors(x as _, y as _, masks::COLOR_RGB).await);
+                                let output =
+                                    device.set_colors(x as _, y as _, masks::COLOR_RGB).await;
+                                res.push((*addr, output.is_success()));
                             }
 
-                            !res.into_iter().fold(true, |acc, v| !acc || !v.is_success())
+                            report_batch_results(failed_devices, &devices_read, res).await
                         });
                     }
 
@@ -1229,18 +1685,38 @@ impl eframe::App for App {
                         } */
 
                     if ui.button("Connect to all devices").clicked() {
+                        let failed_devices = Arc::clone(&self.failed_devices);
+
                         run_async!(self, async {
                             let devices_read = devices.read().await;
                             let futures = devices_read
                                 .iter()
-                                .map(|(_, device)| device.connect_device())
+                                .map(|(addr, device)| {
+                                    device
+                                        .connect_device()
+                                        .map(|output| (*addr, output.is_success()))
+                                })
                                 .collect::<Vec<_>>();
                             let res = futures::future::join_all(futures).await;
+                            let connected = res
+                                .iter()
+                                .filter(|(_, success)| *success)
+                                .map(|(addr, _)| *addr)
+                                .collect::<Vec<_>>();
+                            let success =
+                                report_batch_results(failed_devices, &devices_read, res).await;
                             drop(devices_read);
 
+                            // Following commands can skip the connection check for a while
+                            for addr in connected {
+                                if let Some(device) = devices.write().await.get_mut(&addr) {
+                                    device.start_connect_burst();
+                                }
+                            }
+
                             update_all_devices_state(devices).await;
 
-                            !res.into_iter().fold(true, |acc, v| !acc || !v.is_success())
+                            success
                         });
                         return;
                     }
@@ -1266,7 +1742,9 @@ impl eframe::App for App {
                     let height = ui.available_height();
                     static WIDGET_WIDTH: f32 = 420.;
                     static WIDGET_HEIGHT: f32 = 480.;
-                    let widget_count = f32::floor(width / WIDGET_WIDTH);
+                    // At least one even if the window is narrower than a widget
+                    let widget_count = f32::floor(width / WIDGET_WIDTH).max(1.) as usize;
+                    let row_count = devices_mut.len().div_ceil(widget_count);
                     Frame::none()
                         .inner_margin(Margin::same(20.))
                         .show(ui, |ui| {
@@ -1275,56 +1753,101 @@ impl eframe::App for App {
                                 .sizes(
                                     //egui_extras::Size::initial(height / 2.),
                                     egui_extras::Size::initial(WIDGET_HEIGHT),
-                                    (devices_mut.len() as f32 / widget_count).ceil().max(1.) as _,
+                                    row_count,
                                 )
                                 .cell_layout(Layout::top_down(Align::Min))
                                 .vertical(|mut strip| {
-                                    // TODO: Add multiple lines when devices len * widget width >=
-                                    // available width
-                                    strip.strip(|builder| {
-                                        builder
-                                            .sizes(
-                                                egui_extras::Size::initial(width / widget_count),
-                                                devices_mut.len(),
-                                            )
-                                            .cell_layout(Layout::left_to_right(Align::Min))
-                                            .horizontal(|mut strip| {
-                                                let mut reset_frame = false;
-                                                for (addr, device) in devices_mut.iter_mut() {
-                                                    strip.cell(|ui| {
-                                                        reset_frame =
-                                                            self.display_device(ui, *addr, device);
-                                                    });
+                                    let mut reset_frame = false;
+                                    // HashMap order is random so it's sorted to avoid reshuffling
+                                    // the grid
+                                    let mut sorted_devices =
+                                        devices_mut.iter_mut().collect::<Vec<_>>();
+                                    sorted_devices.sort_unstable_by_key(|(addr, _)| **addr);
+
+                                    for row in sorted_devices.chunks_mut(widget_count) {
+                                        strip.strip(|builder| {
+                                            builder
+                                                .sizes(
+                                                    egui_extras::Size::initial(
+                                                        width / widget_count as f32,
+                                                    ),
+                                                    widget_count,
+                                                )
+                                                .cell_layout(Layout::left_to_right(Align::Min))
+                                                .horizontal(|mut strip| {
+                                                    for (addr, device) in row.iter_mut() {
+                                                        strip.cell(|ui| {
+                                                            reset_frame = self
+                                                                .display_device(ui, **addr, device);
+                                                        });
+
+                                                        if reset_frame {
+                                                            return;
+                                                        }
+                                                    }
 
-                                                    if reset_frame {
-                                                        return;
+                                                    // Keeps the widgets of the last row aligned
+                                                    // with the ones above
+                                                    for _ in row.len()..widget_count {
+                                                        strip.empty();
                                                     }
-                                                }
-                                            });
-                                    });
+                                                });
+                                        });
+
+                                        if reset_frame {
+                                            return;
+                                        }
+                                    }
                                 });
                         });
                 });
             });
         });
+
+        // The next frame lays the grid out with the new device count
+        if let Some(addr) = self.removed_device.take() {
+            self.tokio_rt.block_on(self.devices.write()).remove(&addr);
+            self.storage.remove_device(&addr);
+            if self.autosave {
+                self.storage.flush();
+            }
+
+            ctx.request_repaint();
+        }
     }
 
     fn auto_save_interval(&self) -> Duration {
         Duration::from_secs(GUI_SAVE_INTERVAL_SECS)
     }
 
-    fn save(&mut self, _: &mut dyn eframe::Storage) {
-        let devices_ref = Arc::clone(&self.devices);
-        let devices = self.tokio_rt.block_on(devices_ref.read());
-
-        self.storage.set_devices(
-            devices
-                .iter()
-                .map(|(addr, device)| (*addr, Some(SavedDevice::from(device))))
-                .collect(),
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(
+            storage,
+            SYNC_INTERVAL_KEY,
+            &self.sync_interval.load(Ordering::Relaxed),
         );
+        eframe::set_value(storage, AUTOSAVE_KEY, &self.autosave);
+        if let Some(size) = self.window_size {
+            eframe::set_value(storage, WINDOW_SIZE_KEY, &size);
+        }
+        if let Some(position) = self.window_position {
+            eframe::set_value(storage, WINDOW_POSITION_KEY, &position);
+        }
 
-        self.storage.flush();
+        if self.autosave {
+            self.save_devices();
+        }
+    }
+}
+
+impl Drop for App {
+    /// Stops the sync loop before the runtime is dropped so it doesn't talk to the daemon during
+    /// the teardown
+    fn drop(&mut self) {
+        if let Some(SyncTask { shutdown, handle }) = self.sync_task.take() {
+            let _ = shutdown.send(());
+            let _ = self.tokio_rt.block_on(handle);
+        }
     }
 }
 
@@ -1336,14 +1859,27 @@ fn main() -> eframe::Result {
     let state: &'static Arc<RwLock<AppDevices>> =
         Box::leak(Box::new(Arc::new(RwLock::new(HashMap::new()))));
     let state_async = Arc::clone(state);
+    let sync_interval = Arc::new(AtomicU64::new(DEVICE_STATE_UPDATE_SECS));
+    let sync_interval_async = Arc::clone(&sync_interval);
+    let (window_size, window_position) = saved_window_geometry();
+    let mut viewport =
+        ViewportBuilder::default().with_inner_size(window_size.unwrap_or(DEFAULT_WINDOW_SIZE));
+    if let Some(position) = window_position {
+        viewport = viewport.with_position(position);
+    }
     let app_options = NativeOptions {
+        viewport,
+        // Done by saved_window_geometry instead
+        persist_window: false,
         ..Default::default()
     };
 
     rt.block_on(launch_daemon()).unwrap();
 
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+
     // Thread used to init devices state and sync devices state on a timout
-    rt.spawn(async move {
+    let sync_handle = rt.spawn(async move {
         // let mut hue_devices = get_devices(&[HUE_BAR_1_ADDR, HUE_BAR_2_ADDR])
         //     .await
         //     .unwrap()
@@ -1361,18 +1897,27 @@ fn main() -> eframe::Result {
 
         // There must be a loop to update state in case devices state gets updated by a thrird party app
         loop {
+            let sync_interval = Duration::from_secs(sync_interval_async.load(Ordering::Relaxed));
+
             for (_, device) in state_async.write().await.iter_mut() {
                 // if device.is_initiated || device.last_update.elapsed() < Duration::from_secs(DEVICE_STATE_UPDATE_SECS)
-                if device.last_update.elapsed() < Duration::from_secs(DEVICE_STATE_UPDATE_SECS) {
+                if device.last_update.elapsed() < sync_interval {
                     continue;
                 }
 
                 update_device_state(device).await;
             }
 
-            time::sleep(Duration::from_millis(1000)).await;
+            tokio::select! {
+                _ = &mut shutdown_rx => break,
+                _ = time::sleep(Duration::from_millis(1000)) => (),
+            }
         }
     });
+    let sync_task = SyncTask {
+        shutdown: shutdown_tx,
+        handle: sync_handle,
+    };
 
     eframe::run_native(
         APP_ID,
@@ -1384,6 +1929,8 @@ fn main() -> eframe::Result {
                 rt,
                 // TODO: Handle a fallback path
                 Storage::try_default().unwrap(),
+                sync_interval,
+                sync_task,
             ))
         }),
     )?;
@@ -1391,9 +1938,49 @@ fn main() -> eframe::Result {
     Ok(())
 }
 
-// fn parse_address(str: &str) -> Result<BluetoothAddr, String> {
-//     BluetoothAddr::from_str(str).map_err(|e| e.0)
-// }
+/// Inner size and outer position saved by App::save. The eframe storage is only handed to the app
+/// once it runs so the file is read the way eframe writes it, None on the first run
+fn saved_window_geometry() -> (Option<[f32; 2]>, Option<[f32; 2]>) {
+    let values = eframe::storage_dir(APP_ID)
+        .and_then(|dir| std::fs::read_to_string(dir.join("app.ron")).ok())
+        .and_then(|content| ron::from_str::<HashMap<String, String>>(&content).ok())
+        .unwrap_or_default();
+    let value = |key: &str| {
+        values
+            .get(key)
+            .and_then(|value| ron::from_str::<[f32; 2]>(value).ok())
+    };
+
+    (
+        // A collapsed window would be hard to find again
+        value(WINDOW_SIZE_KEY).filter(|[width, height]| *width >= 100. && *height >= 100.),
+        value(WINDOW_POSITION_KEY),
+    )
+}
+
+/// Every device of a batch command is attempted so this keeps track of the ones that failed for
+/// the UI to display them and returns whether or not all of them succeeded
+async fn report_batch_results(
+    failed_devices: Arc<RwLock<Vec<String>>>,
+    devices: &AppDevices,
+    mut results: Vec<([u8; ADDR_LEN], bool)>,
+) -> bool {
+    results.sort_unstable_by_key(|(addr, _)| *addr);
+
+    let failed = results
+        .iter()
+        .filter(|(_, success)| !success)
+        .map(|(addr, _)| match devices.get(addr) {
+            Some(device) if !device.name.is_empty() => device.name.clone(),
+            _ => addr_to_str(addr),
+        })
+        .collect::<Vec<_>>();
+    let success = failed.is_empty();
+
+    *failed_devices.write().await = failed;
+
+    success
+}
 
 async fn update_all_devices_state(devices: Arc<RwLock<AppDevices>>) {
     for (_, device) in devices.write().await.iter_mut() {
@@ -1402,49 +1989,42 @@ async fn update_all_devices_state(devices: Arc<RwLock<AppDevices>>) {
 }
 
 async fn update_device_state(device: &mut HueDeviceWrapper) {
-    let (res_conn, buf_conn) = device.is_connected().await;
-    if res_conn.is_success() {
-        device.is_connected = buf_conn[0] == true as u8;
+    if let Ok(DeviceResponse::Connected(state)) =
+        DeviceResponse::decode(masks::CONNECT, device.is_connected().await)
+    {
+        device.is_connected = state;
     }
 
     if device.is_connected {
-        let (
-            (res_color, buf_color),
-            (res_bright, buf_bright),
-            (res_power, buf_power),
-            (res_name, buf_name),
-        ) = tokio::join!(
-            device.get_colors(masks::COLOR_RGB),
-            device.get_brightness(),
-            device.get_power(),
-            device.get_name()
-        );
-
-        if matches!(res_color, OutputCode::DeviceNotFound)
-            || matches!(res_bright, OutputCode::DeviceNotFound)
-            || matches!(res_power, OutputCode::DeviceNotFound)
-            || matches!(res_name, OutputCode::DeviceNotFound)
-        {
-            device.is_found = false;
-            return;
+        match device.get_all_state().await {
+            Err(OutputCode::DeviceNotFound) => {
+                device.is_found = false;
+                return;
+            }
+            Err(OutputCode::NotConnected) => {
+                device.is_connected = false;
+                device.is_offline = true;
+            }
+            Ok(state) => {
+                // The brightness has its own slider so the color is shown at full brightness
+                let rgb = Rgb::from(state.color);
+
+                *device.current_color = [rgb.r as _, rgb.g as _, rgb.b as _];
+                device.current_color.update();
+                device.brightness = state.brightness;
+                device.power_state = state.power;
+                device.is_connected = state.connected;
+                device.name = state.name;
+                device.is_paired = true;
+                device.is_found = true;
+                device.is_offline = false;
+            }
+            Err(_) => (),
         }
-        if res_color.is_success()
-            && res_bright.is_success()
-            && res_power.is_success()
-            && res_name.is_success()
-        {
-            let x = u16::from_le_bytes([buf_color[0], buf_color[1]]) as f64 / 0xFFFF as f64;
-            let y = u16::from_le_bytes([buf_color[2], buf_color[3]]) as f64 / 0xFFFF as f64;
-            let xy = Xy::new(x, y);
-            let rgb = xy.to_rgb(buf_bright[0] as f64 / 255.);
-
-            *device.current_color = [rgb.r as _, rgb.g as _, rgb.b as _];
-            device.current_color.update();
-            device.brightness = ((buf_bright[0] as f64 / 255.) * 100.) as _;
-            device.power_state = *buf_power.first().unwrap() == 1;
-            device.name = (*String::from_utf8_lossy(&buf_name)).to_owned();
-            device.is_paired = true;
-            device.is_found = true;
+
+        if let Ok(Some(kelvin)) = device.get_color_temp().await {
+            *device.color_temp = kelvin;
+            device.color_temp.update();
         }
     }
     device.is_initiated = true;
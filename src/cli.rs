@@ -1,12 +1,44 @@
 use std::f64;
+use std::io::{self, IsTerminal as _};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use clap::{Parser, Subcommand};
-use color_space::{FromRgb, Rgb, Xyz};
+use clap::{CommandFactory as _, Parser, Subcommand};
+use clap_complete::Shell;
+use color_space::{FromRgb as _, Hsv, Rgb};
+use futures::{future, stream, StreamExt as _};
+use tokio::sync::Mutex;
+use tokio::time;
 
-use rustbee_common::colors::Xy;
-use rustbee_common::constants::{masks::*, MaskT, ADDR_LEN};
-use rustbee_common::device::{Client, HueDevice};
+use rustbee_common::brightness::BrightnessCurve;
+use rustbee_common::colors::{
+    hex_to_rgb, hsv_to_rgb, kelvin_to_mireds, named_color, rgb_to_hex, similar_color_names,
+    ColorSpace, Gamut, Xy,
+};
+use rustbee_common::config::Config;
+use rustbee_common::constants::{
+    masks::*, options, MaskT, OutputCode, ADDR_LEN, MAX_COLOR_TEMP_KELVIN, MIN_COLOR_TEMP_KELVIN,
+};
+use rustbee_common::device::{dry_run_enabled, Client, CmdOutput, HueDevice, Response};
 use rustbee_common::logger::*;
+use rustbee_common::storage::{LightState, SavedDevice, Storage};
+use rustbee_common::utils::{
+    addr_to_str, format_timestamp, launch_daemon, parse_hex_address, shutdown_daemon, truncate_name,
+};
+
+// Around 2000K
+const NIGHTLIGHT_XY: (f64, f64) = (0.5269, 0.4133);
+const NIGHTLIGHT_BRIGHTNESS: u8 = 1;
+/// Max commands sent to the daemon at the same time when applying a command to every device
+const MAX_CONCURRENT_COMMANDS: usize = 4;
+/// Max distance between the color of a light and the one of its temperature for it to be in
+/// temperature mode, the Planckian locus approximation being close to what the lights report
+const TEMP_MODE_XY_TOLERANCE: f64 = 0.01;
+/// Time between two color temperature steps, above the daemon rate limit
+const TEMP_SWEEP_STEP_MS: u64 = 200;
+/// Time between two discoveries of bench-discovery so the adapter settles
+const BENCH_DISCOVERY_PAUSE_MS: u64 = 500;
 
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -21,6 +53,16 @@ pub struct Args {
         help = "If specified, uses device(s) MAC address(es) with this format: xx:xx:xx:xx:xx:xx. It's case-insensitive and space separated if more than one"
     )]
     pub hex_mac_addresses: Option<Vec<String>>,
+    #[arg(
+        short = 'i',
+        long = "index",
+        num_args = 1..,
+        value_delimiter = ' ',
+        global = true,
+        conflicts_with = "hex_mac_addresses",
+        help = "If specified, uses saved device(s) by their index in the `devices` listing. It's space separated if more than one"
+    )]
+    pub indexes: Option<Vec<usize>>,
     #[arg(
         short = '1',
         long = "one-shot",
@@ -38,6 +80,26 @@ pub struct Args {
         help = "If specified, it saves the MAC address(es) so you can use the CLI again without specifying them"
     )]
     pub save: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "If specified, logs the raw bytes sent to and received from the daemon (see `rustbee logs`), the daemon traces them too if it's started by this command"
+    )]
+    pub trace_packets: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "If specified, logs the values that would be set without sending them to the daemon, the values are still read"
+    )]
+    pub dry_run: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        global = true,
+        help = "Output format, json prints one object per device on stdout and keeps the logs out of it. csv only applies to `devices` and `status`"
+    )]
+    pub format: OutputFormat,
 }
 
 #[derive(Debug, PartialEq, Subcommand, Clone)]
@@ -53,22 +115,191 @@ pub enum Command {
         g: Option<u8>,
         #[arg(help = "Positive number from 0 to 255 inclusive")]
         b: Option<u8>,
+        #[arg(
+            long,
+            default_value = "srgb",
+            help = "Color space of the values printed when reading the color: srgb, display-p3 or adobe-rgb"
+        )]
+        color_space: ColorSpace,
+        #[arg(
+            long,
+            value_enum,
+            default_value_t,
+            help = "Notation of the color printed when reading it, the hsv value being the brightness"
+        )]
+        notation: ColorNotation,
+        #[command(flatten)]
+        options: SetOptions,
     },
     ColorHex {
-        #[arg(help = "Case insensitive e.g. ff00FF")]
+        #[arg(
+            value_parser = parse_color_hex,
+            help = "Case insensitive, the leading # is optional e.g. ff00FF"
+        )]
         hex: Option<String>,
+        #[command(flatten)]
+        options: SetOptions,
     },
     ColorXy {
-        #[arg(help = "Positive decimal number from 0.000 to 1.000 inclusive")]
+        #[arg(
+            value_parser = parse_xy_value,
+            help = "Positive decimal number from 0.000 to 1.000 inclusive"
+        )]
         x: Option<f64>,
-        #[arg(help = "Positive decimal number from 0.000 to 1.000 inclusive")]
+        #[arg(
+            value_parser = parse_xy_value,
+            help = "Positive decimal number from 0.000 to 1.000 inclusive"
+        )]
         y: Option<f64>,
+        #[command(flatten)]
+        options: SetOptions,
+    },
+    #[command(about = "Sets a named color like red, warm-white or Hue scene whites like candle")]
+    ColorName {
+        #[arg(
+            value_parser = parse_color_name,
+            help = "CSS color name or candle, relax, read, concentrate, energize"
+        )]
+        name: String,
+        #[command(flatten)]
+        options: SetOptions,
+    },
+    #[command(
+        about = "Sets the color from a hue, saturation and value, out of range values are clamped"
+    )]
+    ColorHsv {
+        #[arg(
+            allow_negative_numbers = true,
+            help = "Hue in degrees from 0 to 360 inclusive"
+        )]
+        h: f64,
+        #[arg(
+            allow_negative_numbers = true,
+            help = "Saturation from 0 to 1 inclusive"
+        )]
+        s: f64,
+        #[arg(
+            allow_negative_numbers = true,
+            help = "Value from 0 to 1 inclusive, the brightness is left as is since it's a separate setting"
+        )]
+        v: f64,
+        #[command(flatten)]
+        options: SetOptions,
     },
     Brightness {
-        #[arg(help = "Positive number (percentage) from 0 to 100 inclusive")]
-        value: Option<u8>,
+        #[arg(
+            value_parser = parse_brightness_value,
+            allow_hyphen_values = true,
+            help = "Percentage from 0 to 100 inclusive, or an offset to the current one like +10 or -10 (clamped)"
+        )]
+        value: Option<BrightnessValue>,
+        #[command(flatten)]
+        options: SetOptions,
+    },
+    #[command(about = "Sets or reads the color temperature of white ambiance lights")]
+    ColorTemp {
+        #[arg(help = "Kelvin from 2000 to 6500 inclusive, values out of range are clamped")]
+        kelvin: Option<u16>,
+        #[command(flatten)]
+        options: SetOptions,
+    },
+    #[command(about = "Sets a neutral (D65) or warm white, optionally along the brightness")]
+    White {
+        #[arg(long, help = "If specified, sets a warm white around 2700K instead")]
+        warm: bool,
+        #[arg(
+            long,
+            value_parser = clap::value_parser!(u8).range(..=100),
+            help = "Percentage from 0 to 100 inclusive, the brightness is left as is if not specified"
+        )]
+        brightness: Option<u8>,
     },
+    #[command(
+        about = "Sets a warm and dim light, turning it off restores the state prior to the nightlight"
+    )]
+    Nightlight {
+        #[command(subcommand)]
+        state: NightlightState,
+    },
+    #[command(
+        about = "Sweeps the color temperature from warm to cool to check the device range, the prior color is restored afterward"
+    )]
+    TempSweep {
+        #[arg(long, default_value_t = 5000, help = "Duration of the sweep in milliseconds")]
+        duration: u64,
+    },
+    #[command(
+        about = "Measures how long the daemon takes to discover the device(s) without its cache"
+    )]
+    BenchDiscovery {
+        #[arg(
+            long,
+            default_value_t = 3,
+            value_parser = clap::value_parser!(u32).range(1..),
+            help = "Number of discoveries"
+        )]
+        runs: u32,
+    },
+    #[command(
+        about = "Prints the power, brightness and color changes of the device(s) as they happen until CTRL+C"
+    )]
+    Subscribe,
+    #[command(
+        about = "Prints the name, model, manufacturer, firmware, power, brightness and color of the device(s)"
+    )]
+    Info,
     Disconnect,
+    #[command(
+        about = "Writes the name to the device and the storage, names over 248 bytes are truncated"
+    )]
+    Rename {
+        name: String,
+    },
+    #[command(
+        visible_alias = "list",
+        about = "Lists the saved devices without communicating with them"
+    )]
+    Devices {
+        #[arg(long, conflicts_with = "format", help = "Shorthand for --format json")]
+        json: bool,
+    },
+    #[command(about = "Lists the devices held by the daemon and whether they're connected")]
+    Status {
+        #[arg(
+            long,
+            value_name = "SECS",
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Rereads the state of the devices every SECS seconds until CTRL+C"
+        )]
+        watch: Option<u64>,
+    },
+    #[command(hide = true, about = "Prints the completion script of the shell")]
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    #[command(about = "Removes saved device(s) without communicating with them")]
+    Forget {
+        #[arg(help = "MAC address of the device to remove, xx:xx:xx:xx:xx:xx")]
+        address: Option<String>,
+        #[arg(long, conflicts_with = "address", help = "Removes every saved device")]
+        all: bool,
+    },
+    #[command(about = "Searches for nearby devices whose name contains the given name")]
+    Search {
+        #[arg(help = "Case insensitive, only the first 9 bytes are used")]
+        name: String,
+        #[arg(
+            long,
+            help = "Only shows devices with a Philips/Signify address prefix, leave it out for non-standard devices"
+        )]
+        hue_only: bool,
+        #[arg(
+            long,
+            help = "Stops the search after this many seconds instead of waiting for CTRL+C"
+        )]
+        timeout: Option<u64>,
+    },
     Shutdown {
         #[arg(
             short = 'f',
@@ -78,6 +309,18 @@ pub enum Command {
         force: bool,
     },
     Gui,
+    #[command(
+        about = "Inspects the configuration read from the RUSTBEE_* env vars, exports or imports the saved devices"
+    )]
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    #[command(about = "Checks for common setup problems and prints how to fix them")]
+    Doctor {
+        #[arg(long, help = "If specified, automatically fixes the problems that are safe to fix")]
+        fix: bool,
+    },
     Logs {
         #[arg(
             short = 'f',
@@ -89,13 +332,173 @@ pub enum Command {
         tail: Option<usize>,
         #[arg(short = 'd', long, help = "If specified, purges the log file")]
         purge: bool,
+        #[arg(
+            long,
+            requires = "purge",
+            help = "If specified, moves the log file to <log file>.<date> before purging it"
+        )]
+        archive: bool,
     },
 }
 
+fn parse_xy_value(value: &str) -> Result<f64, String> {
+    let value = value
+        .parse::<f64>()
+        .map_err(|err| format!("{value} is not a decimal number ({err})"))?;
+
+    if !(0. ..=1.).contains(&value) {
+        return Err(format!("{value} is not between 0.000 and 1.000 inclusive"));
+    }
+
+    Ok(value)
+}
+
+fn parse_color_name(value: &str) -> Result<String, String> {
+    if named_color(value).is_some() {
+        return Ok(value.into());
+    }
+
+    match similar_color_names(value)[..] {
+        [] => Err(format!("{value} is not a known color")),
+        ref names => Err(format!(
+            "{value} is not a known color, did you mean {}?",
+            names.join(", ")
+        )),
+    }
+}
+
+fn parse_color_hex(value: &str) -> Result<String, String> {
+    hex_to_rgb(value).map(|_| value.into())
+}
+
+/// Absolute percentage or an offset to the current brightness
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrightnessValue {
+    Absolute(u8),
+    Relative(i16),
+}
+
+impl BrightnessValue {
+    /// Percentage to set from the current one, clamped from 0 to 100
+    fn apply(self, current: f32) -> u8 {
+        match self {
+            Self::Absolute(percent) => percent,
+            Self::Relative(offset) => (current.round() as i32 + offset as i32).clamp(0, 100) as _,
+        }
+    }
+}
+
+fn parse_brightness_value(value: &str) -> Result<BrightnessValue, String> {
+    if value.starts_with(['+', '-']) {
+        return value
+            .parse::<i16>()
+            .map(BrightnessValue::Relative)
+            .map_err(|err| format!("{value} is not a valid offset ({err})"));
+    }
+
+    match value.parse::<u8>() {
+        Ok(percent) if percent <= 100 => Ok(BrightnessValue::Absolute(percent)),
+        _ => Err(format!("{value} is not between 0 and 100 inclusive")),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum ColorNotation {
+    #[default]
+    Rgb,
+    Hsv,
+}
+
 #[derive(Clone, Debug, PartialEq, Subcommand)]
 pub enum State {
     On,
     Off,
+    #[command(about = "Turns each device off if it's on, else on, independently of the others")]
+    Toggle,
+    #[command(
+        about = "Turns every device off if any of them is on, else turns them all on (see --if-all-on)"
+    )]
+    ToggleAll {
+        #[arg(
+            long,
+            conflicts_with = "if_all_on",
+            help = "Default, if any device is on, turns them all off"
+        )]
+        if_any_on: bool,
+        #[arg(
+            long,
+            help = "If specified, turns every device off only if all of them are on, else turns them all on"
+        )]
+        if_all_on: bool,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Subcommand)]
+pub enum ConfigAction {
+    #[command(about = "Prints the effective value of every setting and whether it's from the env")]
+    Show,
+    #[command(about = "Writes the saved devices to a file, to import them on another machine")]
+    Export { path: PathBuf },
+    #[command(about = "Saves the devices of an exported file, replacing the saved ones")]
+    Import {
+        path: PathBuf,
+        #[arg(
+            long,
+            help = "If specified, keeps the saved devices that aren't in the file instead of replacing all of them"
+        )]
+        merge: bool,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Subcommand)]
+pub enum NightlightState {
+    On,
+    Off,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, clap::Args)]
+pub struct SetOptions {
+    #[arg(
+        long,
+        help = "If specified, powers the device on before setting the value since some devices ignore it while being off"
+    )]
+    pub ensure_on: bool,
+    #[arg(
+        long,
+        requires = "ensure_on",
+        help = "If specified along --ensure-on, powers the device back off afterwards if it was off"
+    )]
+    pub restore_off: bool,
+    #[arg(
+        long,
+        value_name = "MS",
+        conflicts_with = "restore_off",
+        help = "If specified, fades from the current value over the duration in milliseconds"
+    )]
+    pub fade: Option<u64>,
+}
+
+impl From<&SetOptions> for u8 {
+    fn from(value: &SetOptions) -> Self {
+        let mut opts = 0;
+
+        if value.ensure_on {
+            opts |= options::ENSURE_ON;
+        }
+        if value.restore_off {
+            opts |= options::RESTORE_OFF;
+        }
+
+        opts
+    }
 }
 
 impl From<&Command> for MaskT {
@@ -104,10 +507,29 @@ impl From<&Command> for MaskT {
             Command::Power { .. } => POWER,
             Command::ColorRgb { .. } => COLOR_RGB,
             Command::ColorHex { .. } => COLOR_HEX,
-            Command::ColorXy { .. } => COLOR_XY,
+            Command::ColorXy { .. } | Command::ColorName { .. } | Command::ColorHsv { .. } => {
+                COLOR_XY
+            }
+            Command::ColorTemp { .. } => COLOR_TEMP,
             Command::Brightness { .. } => BRIGHTNESS,
             Command::Disconnect => DISCONNECT,
+            Command::Search { .. } => SEARCH_NAME,
+            Command::Status { .. } => LIST,
+            Command::BenchDiscovery { .. } => BENCH_DISCOVERY,
+            Command::Subscribe => SUBSCRIBE,
+            Command::Rename { .. } => NAME,
+            Command::White { .. }
+            | Command::Nightlight { .. }
+            | Command::TempSweep { .. }
+            | Command::Info => {
+                unreachable!("{value:?} is a combination of commands so it doesn't have a mask")
+            }
             command @ Command::Gui
+            | command @ Command::Completions { .. }
+            | command @ Command::Config { .. }
+            | command @ Command::Devices { .. }
+            | command @ Command::Doctor { .. }
+            | command @ Command::Forget { .. }
             | command @ Command::Logs { .. }
             | command @ Command::Shutdown { .. } => {
                 unreachable!("This command {command:?} shouldn't communicate with the daemon")
@@ -117,8 +539,30 @@ impl From<&Command> for MaskT {
 }
 
 impl Command {
-    pub async fn handle(&self, hue_device: HueDevice<Client>) {
-        if matches!(self, Self::Gui | Self::Logs { .. } | Self::Shutdown { .. }) {
+    pub async fn handle(
+        &self,
+        hue_device: HueDevice<Client>,
+        storage: Arc<Mutex<Storage>>,
+        format: OutputFormat,
+    ) {
+        let addr = hue_device.addr;
+        let report = |code: OutputCode, value: Option<(&str, serde_json::Value)>| {
+            print_json(format, &addr, code, value)
+        };
+
+        if matches!(
+            self,
+            Self::Gui
+                | Self::Completions { .. }
+                | Self::Config { .. }
+                | Self::Devices { .. }
+                | Self::Doctor { .. }
+                | Self::Forget { .. }
+                | Self::Logs { .. }
+                | Self::Search { .. }
+                | Self::Shutdown { .. }
+                | Self::Status { .. }
+        ) {
             // Should never occur since it's handled before
             return;
         }
@@ -129,14 +573,50 @@ impl Command {
         // }
 
         match self {
-            Self::Gui | Self::Logs { .. } | Self::Shutdown { .. } => unreachable!(),
+            Self::Gui
+            | Self::Completions { .. }
+            | Self::Config { .. }
+            | Self::Devices { .. }
+            | Self::Doctor { .. }
+            | Self::Forget { .. }
+            | Self::Logs { .. }
+            | Self::Search { .. }
+            | Self::Shutdown { .. }
+            | Self::Status { .. } => {
+                unreachable!()
+            }
             Self::Power { state } => match state {
+                Some(State::ToggleAll { .. }) => {
+                    unreachable!("Toggling all devices needs every device state so it's handled before")
+                }
+                Some(State::Toggle) => {
+                    let state = match toggled_power(hue_device.get_power().await) {
+                        Ok(state) => state,
+                        Err(code) => {
+                            report(code, None);
+                            error!(
+                                "Failed to read power state to hue device address: {:?}",
+                                hue_device.addr
+                            );
+                            return;
+                        }
+                    };
+
+                    let res = hue_device.set_power(state).await;
+                    report(res, res.is_success().then(|| ("power", state.into())));
+
+                    if !res.is_success() {
+                        error!(
+                            "Failed to write power state to hue device address: {:?}",
+                            hue_device.addr
+                        );
+                    }
+                }
                 Some(state) => {
-                    if !hue_device
-                        .set_power(matches!(*state, State::On))
-                        .await
-                        .is_success()
-                    {
+                    let res = hue_device.set_power(matches!(*state, State::On)).await;
+                    report(res, None);
+
+                    if !res.is_success() {
                         error!(
                             "Failed to write power state to hue device address: {:?}",
                             hue_device.addr
@@ -146,6 +626,7 @@ impl Command {
                 None => {
                     let (res, state) = hue_device.get_power().await;
                     let success = res.is_success();
+                    report(res, success.then(|| ("power", (state[0] == 1).into())));
 
                     if !success {
                         error!(
@@ -153,16 +634,13 @@ impl Command {
                             hue_device.addr
                         );
                     } else {
-                        let (code, buf) = hue_device.get_name().await;
-                        let name = if !code.is_success() {
+                        let name = hue_device.get_name().await.unwrap_or_else(|_| {
                             error!(
                                 "Failed to read device name from hue device address: {:?}",
                                 hue_device.addr
                             );
                             String::new()
-                        } else {
-                            String::from_utf8(buf.to_vec()).unwrap()
-                        };
+                        });
 
                         info!(
                             "Device{} {:?} is {}",
@@ -177,14 +655,109 @@ impl Command {
                     }
                 }
             },
-            Self::Brightness { value } => match value {
+            Self::ColorTemp { kelvin, options } => match kelvin {
+                Some(kelvin) => {
+                    let res = match options.fade {
+                        Some(ms) => {
+                            hue_device
+                                .set_color_temp_fade(
+                                    *kelvin,
+                                    Duration::from_millis(ms),
+                                    options.into(),
+                                )
+                                .await
+                        }
+                        None => {
+                            hue_device
+                                .set_color_temp_with_options(*kelvin, options.into())
+                                .await
+                        }
+                    };
+                    report(res, None);
+
+                    if !res.is_success() {
+                        error!(
+                            "Failed to write color temperature to hue device address: {:?}",
+                            hue_device.addr
+                        );
+                        return;
+                    }
+
+                    // The device wasn't written so the saved state is kept
+                    if dry_run_enabled() {
+                        return;
+                    }
+
+                    let kelvin = (*kelvin).clamp(MIN_COLOR_TEMP_KELVIN, MAX_COLOR_TEMP_KELVIN);
+                    let mut storage = storage.lock().await;
+                    storage.save_color_temp(hue_device.addr, kelvin);
+                    storage.flush();
+                }
+                None => match hue_device.get_color_temp().await {
+                    Ok(Some(kelvin)) => {
+                        report(OutputCode::Success, Some(("kelvin", kelvin.into())));
+                        info!(
+                            "Device {:?} color temperature is {kelvin}K",
+                            hue_device.addr
+                        );
+                    }
+                    Ok(None) => {
+                        report(
+                            OutputCode::Success,
+                            Some(("kelvin", serde_json::Value::Null)),
+                        );
+                        info!(
+                            "Device {:?} color temperature is unsupported",
+                            hue_device.addr
+                        );
+                    }
+                    Err(code) => {
+                        report(code, None);
+                        error!(
+                            "Failed to get color temperature from hue device address: {:?}",
+                            hue_device.addr
+                        );
+                    }
+                },
+            },
+            Self::Brightness { value, options } => match value {
                 Some(value) => {
-                    assert!(
-                        (0..=100).contains(value),
-                        "[ERROR] Brightness value must be between 0 and 100 inclusive"
-                    );
+                    let current = if let BrightnessValue::Relative(_) = value {
+                        let (res, brightness) = hue_device.get_brightness().await;
+                        if !res.is_success() {
+                            report(res, None);
+                            error!(
+                                "Failed to get brightness level from hue device address: {:?}",
+                                hue_device.addr
+                            );
+                            return;
+                        }
+
+                        BrightnessCurve::current().to_percent(brightness[0])
+                    } else {
+                        0.
+                    };
+
+                    let value = value.apply(current);
+                    let res = match options.fade {
+                        Some(ms) => {
+                            hue_device
+                                .set_brightness_fade(
+                                    value,
+                                    Duration::from_millis(ms),
+                                    options.into(),
+                                )
+                                .await
+                        }
+                        None => {
+                            hue_device
+                                .set_brightness_with_options(value, options.into())
+                                .await
+                        }
+                    };
+                    report(res, None);
 
-                    if !hue_device.set_brightness(*value).await.is_success() {
+                    if !res.is_success() {
                         error!(
                             "Failed to write brightness state to hue device address: {:?}",
                             hue_device.addr
@@ -194,6 +767,8 @@ impl Command {
                 None => {
                     let (res, brightness) = hue_device.get_brightness().await;
                     let success = res.is_success();
+                    let percent = BrightnessCurve::current().to_percent(brightness[0]);
+                    report(res, success.then(|| ("brightness", percent.round().into())));
 
                     if !success {
                         error!(
@@ -201,16 +776,13 @@ impl Command {
                             hue_device.addr
                         );
                     } else {
-                        let (code, buf) = hue_device.get_name().await;
-                        let name = if !code.is_success() {
+                        let name = hue_device.get_name().await.unwrap_or_else(|_| {
                             error!(
                                 "Failed to read device name from hue device address: {:?}",
                                 hue_device.addr
                             );
                             String::new()
-                        } else {
-                            String::from_utf8(buf.to_vec()).unwrap()
-                        };
+                        });
 
                         info!(
                             "Device{} {:?} brightness level is {}%",
@@ -220,21 +792,38 @@ impl Command {
                                 format!(" {name}")
                             },
                             hue_device.addr,
-                            (brightness[0] as f32 / 255.) * 100.
+                            percent
                         );
                     }
                 }
             },
-            Self::ColorHex { .. } | Self::ColorXy { .. } | Self::ColorRgb { .. } => {
+            Self::ColorHex { .. }
+            | Self::ColorXy { .. }
+            | Self::ColorRgb { .. }
+            | Self::ColorName { .. }
+            | Self::ColorHsv { .. } => {
                 let mut read = false;
                 let (mut x, mut y) = (0., 0.);
+                let opts;
+                let fade;
+                // The model is known once `info` saved it
+                let gamut = storage
+                    .lock()
+                    .await
+                    .get_device(&hue_device.addr)
+                    .and_then(|device| device.model.as_deref())
+                    .map_or_else(Gamut::default, Gamut::from_model);
 
                 match self {
                     Self::ColorRgb {
                         ref r,
                         ref g,
                         ref b,
+                        options,
+                        ..
                     } => {
+                        opts = options.into();
+                        fade = options.fade;
                         if r.is_none() || g.is_none() || b.is_none() {
                             read = true;
                         } else {
@@ -244,114 +833,165 @@ impl Command {
                             //     b.unwrap() as _,
                             // ));
                             // (x, y) = (xyz.x / 100., xyz.y / 100.);
-                            let xy = Xy::from(Rgb::new(
-                                r.unwrap() as _,
-                                g.unwrap() as _,
-                                b.unwrap() as _,
-                            ));
+                            let xy = Xy::from_rgb_with_gamut(
+                                Rgb::new(r.unwrap() as _, g.unwrap() as _, b.unwrap() as _),
+                                gamut,
+                            );
                             x = xy.x;
                             y = xy.y;
                         }
                     }
-                    Self::ColorHex { hex } => {
+                    Self::ColorHex { hex, options } => {
+                        opts = options.into();
+                        fade = options.fade;
                         if hex.is_none() {
                             read = true;
                         } else {
-                            let hex = hex.clone().unwrap();
-                            assert!(
-                                hex.len() == ADDR_LEN,
-                                "Hex length must be {ADDR_LEN} like so: ffFF00"
-                            );
-                            let odd_it = hex.chars().skip(1).step_by(2);
-                            let [r, g, b] = hex
-                                .chars()
-                                .step_by(2)
-                                .zip(odd_it)
-                                .map(|(bit1, bit2)| {
-                                    i32::from_str_radix(&format!("{bit1}{bit2}"), 16).unwrap()
-                                        as f64
-                                })
-                                .collect::<Vec<_>>()[..]
-                            else {
-                                panic!("Unexpected error: cannot get RGB values from HEX {hex}")
+                            let Ok(rgb) = hex_to_rgb(hex.as_ref().unwrap()) else {
+                                unreachable!("The hex is validated when parsing the arguments")
                             };
-                            let xyz = Xyz::from_rgb(&Rgb::new(r, g, b));
-                            (x, y) = (xyz.x / 100., xyz.y / 100.);
+                            let xy = Xy::from_rgb_with_gamut(rgb, gamut);
+                            (x, y) = (xy.x, xy.y);
                         }
                     }
                     Self::ColorXy {
                         x: ref _x,
                         y: ref _y,
+                        options,
                     } => {
+                        opts = options.into();
+                        fade = options.fade;
                         if _x.is_none() || _y.is_none() {
                             read = true;
                         } else {
                             (x, y) = (_x.unwrap(), _y.unwrap());
                         }
                     }
+                    Self::ColorName { name, options } => {
+                        opts = options.into();
+                        fade = options.fade;
+                        let Some(rgb) = named_color(name) else {
+                            unreachable!("The color name is validated when parsing the arguments")
+                        };
+                        let xy = Xy::from_rgb_with_gamut(rgb, gamut);
+                        (x, y) = (xy.x, xy.y);
+                    }
+                    Self::ColorHsv { h, s, v, options } => {
+                        opts = options.into();
+                        fade = options.fade;
+                        let xy = Xy::from_rgb_with_gamut(hsv_to_rgb(*h, *s, *v), gamut);
+                        (x, y) = (xy.x, xy.y);
+                    }
                     _ => unreachable!(),
                 };
 
                 if read {
-                    let (res, data) = hue_device.get_colors(MaskT::from(self)).await;
-                    let success = res.is_success();
+                    let xy = match hue_device.get_color_xy().await {
+                        Ok(xy) => xy,
+                        Err(code) => {
+                            report(code, None);
+                            error!(
+                                "Failed to get color data from hue device address: {:?}",
+                                hue_device.addr
+                            );
+                            return;
+                        }
+                    };
 
-                    if !success {
-                        error!(
-                            "Failed to get color data from hue device address: {:?}",
-                            hue_device.addr
-                        );
-                    } else {
-                        let x = u16::from_le_bytes([data[0], data[1]]) as f64 / 0xFFFF as f64;
-                        let y = u16::from_le_bytes([data[2], data[3]]) as f64 / 0xFFFF as f64;
-                        let xy = Xy::new(x, y);
-                        let xyz = Xyz::new(x, y, 1. - x - y);
-
-                        // TODO: Fix colors display / color processing
-                        match self {
-                            Self::ColorRgb { .. } => {
-                                let (res, brightness) = hue_device.get_brightness().await;
-                                let success = res.is_success();
-
-                                if !success {
-                                    error!("Failed to get brightness to calculate XYZ color");
-                                    return;
-                                }
-
-                                let rgb = xy.to_rgb(brightness[0] as f64 / 255.);
-                                assert!(rgb.r * 100. <= 255.);
-                                assert!(rgb.g * 100. <= 255.);
-                                assert!(rgb.b * 100. <= 255.);
-                                info!(
-                                    "Device color is ({:.0}, {:.0}, {:.0}) ({:?})",
-                                    rgb.r * 100.,
-                                    rgb.g * 100.,
-                                    rgb.b * 100.,
-                                    Rgb::from(xyz)
-                                );
-                            }
-                            Self::ColorHex { .. } => {
-                                let rgb = Rgb::from(xyz);
-                                let hex = [rgb.b as u8, rgb.g as u8, rgb.r as u8]
-                                    .into_iter()
-                                    .fold(String::new(), |_, v| format!("{v:06x}"));
-                                info!("Device color is #{hex}");
+                    // Shown at full brightness since setting a color doesn't change it
+                    match self {
+                        Self::ColorRgb {
+                            color_space,
+                            notation: ColorNotation::Hsv,
+                            ..
+                        } => {
+                            let (res, brightness) = hue_device.get_brightness().await;
+                            if !res.is_success() {
+                                report(res, None);
+                                error!("Failed to get brightness to calculate the HSV value");
+                                return;
                             }
-                            Self::ColorXy { .. } => {
-                                info!("Device color is x: {:.3}, y: {:.3}", xyz.x, xyz.y);
-                            }
-                            _ => unreachable!(),
+
+                            let percentage = BrightnessCurve::current()
+                                .to_percent(brightness[0])
+                                .round() as u8;
+                            let hsv = xy_to_hsv(&xy, percentage, *color_space);
+                            report(
+                                res,
+                                Some((
+                                    "hsv",
+                                    serde_json::json!({ "h": hsv.h, "s": hsv.s, "v": hsv.v }),
+                                )),
+                            );
+                            info!(
+                                "Device color is {} ({color_space:?})",
+                                format_hsv(&hsv)
+                            );
+                        }
+                        Self::ColorRgb { color_space, .. } => {
+                            let rgb = xy.to_rgb_in(1., *color_space);
+                            report(
+                                OutputCode::Success,
+                                Some((
+                                    "rgb",
+                                    serde_json::json!([
+                                        rgb.r.round(),
+                                        rgb.g.round(),
+                                        rgb.b.round()
+                                    ]),
+                                )),
+                            );
+                            info!(
+                                "Device color is ({:.0}, {:.0}, {:.0}) ({color_space:?})",
+                                rgb.r,
+                                rgb.g,
+                                rgb.b
+                            );
+                        }
+                        Self::ColorHex { .. } => {
+                            let hex = rgb_to_hex(Rgb::from(xy));
+                            report(OutputCode::Success, Some(("hex", format!("#{hex}").into())));
+                            info!("Device color is #{hex}");
                         }
+                        Self::ColorXy { .. } => {
+                            report(
+                                OutputCode::Success,
+                                Some(("xy", serde_json::json!({ "x": xy.x, "y": xy.y }))),
+                            );
+                            info!("Device color is x: {:.3}, y: {:.3}", xy.x, xy.y);
+                        }
+                        _ => unreachable!(),
                     }
                 } else {
                     let scaled_x = (x * 0xFFFF as f64) as u16;
                     let scaled_y = (y * 0xFFFF as f64) as u16;
 
-                    if !hue_device
-                        .set_colors(scaled_x, scaled_y, MaskT::from(self))
-                        .await
-                        .is_success()
-                    {
+                    let res = match fade {
+                        Some(ms) => {
+                            hue_device
+                                .set_colors_fade(
+                                    scaled_x,
+                                    scaled_y,
+                                    MaskT::from(self),
+                                    Duration::from_millis(ms),
+                                    opts,
+                                )
+                                .await
+                        }
+                        None => {
+                            hue_device
+                                .set_colors_with_options(
+                                    scaled_x,
+                                    scaled_y,
+                                    MaskT::from(self),
+                                    opts,
+                                )
+                                .await
+                        }
+                    };
+                    report(res, None);
+
+                    if !res.is_success() {
                         error!(
                             "Daemon failed to disconnect from device {:?}",
                             hue_device.addr
@@ -359,14 +999,1234 @@ impl Command {
                     }
                 }
             }
-            Self::Disconnect => {
-                if !hue_device.disconnect_device().await.is_success() {
+            Self::White { warm, brightness } => {
+                let xy = if *warm { Xy::warm_white() } else { Xy::d65() };
+
+                let mut res = hue_device
+                    .set_colors(
+                        (xy.x * 0xFFFF as f64) as u16,
+                        (xy.y * 0xFFFF as f64) as u16,
+                        COLOR_XY,
+                    )
+                    .await;
+                if let (true, Some(brightness)) = (res.is_success(), brightness) {
+                    res = hue_device.set_brightness(*brightness).await;
+                }
+                report(res, None);
+
+                if !res.is_success() {
                     error!(
-                        "Daemon failed to disconnect from device {:?}",
+                        "Failed to set the white for hue device address: {:?}",
                         hue_device.addr
                     );
                 }
             }
-        }
+            Self::TempSweep { duration } => {
+                let prior_kelvin = match hue_device.get_color_temp().await {
+                    Ok(Some(kelvin)) => kelvin,
+                    Ok(None) => {
+                        report(OutputCode::Failure, None);
+                        error!(
+                            "Hue device address {:?} doesn't support color temperature",
+                            hue_device.addr
+                        );
+                        return;
+                    }
+                    Err(code) => {
+                        report(code, None);
+                        error!(
+                            "Failed to get color temperature from hue device address: {:?}",
+                            hue_device.addr
+                        );
+                        return;
+                    }
+                };
+                // The lights without color can't be read so they're always in temperature mode
+                let (res, prior_color) = hue_device.get_colors(COLOR_XY).await;
+                let prior_color = res.is_success().then_some(prior_color).filter(|color| {
+                    let xy = Xy::from_le_bytes([color[0], color[1], color[2], color[3]]);
+                    !is_temp_mode(&xy, prior_kelvin)
+                });
+
+                let (warm, cool) = match hue_device.get_color_temp_range().await {
+                    Ok(range) => temp_sweep_range(range),
+                    Err(code) => {
+                        report(code, None);
+                        error!(
+                            "Failed to get color temperature range from hue device address: {:?}",
+                            hue_device.addr
+                        );
+                        return;
+                    }
+                };
+                let (warm, cool) = (kelvin_to_mireds(warm) as f64, kelvin_to_mireds(cool) as f64);
+                let steps = u64::max(duration / TEMP_SWEEP_STEP_MS, 1);
+                let start = time::Instant::now();
+                let mut sweep_res = OutputCode::Success;
+
+                for step in 0..=steps {
+                    let mireds = warm + (cool - warm) * step as f64 / steps as f64;
+                    let kelvin = (1_000_000. / mireds).round() as u16;
+
+                    sweep_res = hue_device.set_color_temp(kelvin).await;
+                    if !sweep_res.is_success() {
+                        error!(
+                            "Failed to set the color temperature {kelvin}K for hue device address: {:?}",
+                            hue_device.addr
+                        );
+                        break;
+                    }
+
+                    time::sleep_until(start + Duration::from_millis(duration * step / steps))
+                        .await;
+                }
+
+                // Setting the color leaves the temperature mode so only the prior one is written
+                let res = match prior_color {
+                    Some(color) => {
+                        hue_device
+                            .set_colors(
+                                u16::from_le_bytes([color[0], color[1]]),
+                                u16::from_le_bytes([color[2], color[3]]),
+                                COLOR_XY,
+                            )
+                            .await
+                    }
+                    None => hue_device.set_color_temp(prior_kelvin).await,
+                };
+                // The sweep failure prevails over the restore one
+                report(
+                    if sweep_res.is_success() {
+                        res
+                    } else {
+                        sweep_res
+                    },
+                    None,
+                );
+
+                if !res.is_success() {
+                    error!(
+                        "Failed to restore the prior color of hue device address: {:?}",
+                        hue_device.addr
+                    );
+                }
+            }
+            Self::Nightlight { state } => match state {
+                NightlightState::On => {
+                    let (
+                        (res_power, buf_power),
+                        (res_bright, buf_bright),
+                        (res_color, buf_color),
+                    ) = tokio::join!(
+                        hue_device.get_power(),
+                        hue_device.get_brightness(),
+                        hue_device.get_colors(COLOR_XY)
+                    );
+
+                    if !res_power.is_success() || !res_bright.is_success() || !res_color.is_success()
+                    {
+                        let failed = [res_power, res_bright, res_color]
+                            .into_iter()
+                            .find(|res| !res.is_success());
+                        report(failed.unwrap_or(OutputCode::Failure), None);
+                        error!(
+                            "Failed to read the current state of hue device address: {:?}",
+                            hue_device.addr
+                        );
+                        return;
+                    }
+
+                    let prior_state = LightState {
+                        power: buf_power[0] == 1,
+                        brightness: BrightnessCurve::current()
+                            .to_percent(buf_bright[0])
+                            .round() as _,
+                        color: [buf_color[0], buf_color[1], buf_color[2], buf_color[3]],
+                    };
+
+                    // The device isn't written so there's no prior state to save
+                    if !dry_run_enabled() {
+                        let mut storage = storage.lock().await;
+                        if !storage.save_nightlight_state(hue_device.addr, prior_state) {
+                            warn!(
+                                "Nightlight is already on for {:?}, keeping its prior state",
+                                hue_device.addr
+                            );
+                        }
+                        storage.flush();
+                    }
+
+                    let (x, y) = NIGHTLIGHT_XY;
+                    let mut res = hue_device.set_power(true).await;
+                    if res.is_success() {
+                        res = hue_device
+                            .set_colors(
+                                (x * 0xFFFF as f64) as u16,
+                                (y * 0xFFFF as f64) as u16,
+                                COLOR_XY,
+                            )
+                            .await;
+                    }
+                    if res.is_success() {
+                        res = hue_device.set_brightness(NIGHTLIGHT_BRIGHTNESS).await;
+                    }
+                    report(res, None);
+
+                    if !res.is_success() {
+                        error!(
+                            "Failed to turn the nightlight on for hue device address: {:?}",
+                            hue_device.addr
+                        );
+                    }
+                }
+                NightlightState::Off => {
+                    let mut storage_guard = storage.lock().await;
+                    // Only read since nothing is restored
+                    let prior_state = if dry_run_enabled() {
+                        storage_guard
+                            .get_device(&hue_device.addr)
+                            .and_then(|device| device.nightlight)
+                    } else {
+                        storage_guard.take_nightlight_state(&hue_device.addr)
+                    };
+                    let Some(prior_state) = prior_state else {
+                        report(OutputCode::Success, None);
+                        warn!(
+                            "Nightlight isn't on for {:?}, nothing to restore",
+                            hue_device.addr
+                        );
+                        return;
+                    };
+                    drop(storage_guard);
+
+                    let [x0, x1, y0, y1] = prior_state.color;
+                    let mut res = hue_device
+                        .set_colors(
+                            u16::from_le_bytes([x0, x1]),
+                            u16::from_le_bytes([y0, y1]),
+                            COLOR_XY,
+                        )
+                        .await;
+                    if res.is_success() {
+                        res = hue_device.set_brightness(prior_state.brightness).await;
+                    }
+                    if res.is_success() {
+                        res = hue_device.set_power(prior_state.power).await;
+                    }
+                    report(res, None);
+
+                    if !res.is_success() {
+                        error!(
+                            "Failed to restore the state prior to the nightlight for hue device address: {:?}",
+                            hue_device.addr
+                        );
+
+                        // Keep it so the user can try again
+                        let mut storage = storage.lock().await;
+                        storage.save_nightlight_state(hue_device.addr, prior_state);
+                        storage.flush();
+
+                        return;
+                    }
+
+                    if !dry_run_enabled() {
+                        storage.lock().await.flush();
+                    }
+                }
+            },
+            Self::BenchDiscovery { runs } => {
+                let mut durations = Vec::new();
+
+                for run in 1..=*runs {
+                    if run > 1 {
+                        time::sleep(Duration::from_millis(BENCH_DISCOVERY_PAUSE_MS)).await;
+                    }
+
+                    match hue_device.bench_discovery().await {
+                        (OutputCode::Success, duration) => {
+                            info!("Run {run}/{runs}: discovered in {duration:?}");
+                            durations.push(duration);
+                        }
+                        (OutputCode::DeviceNotFound, duration) => {
+                            warn!("Run {run}/{runs}: not found, gave up after {duration:?} (timeout)");
+                        }
+                        (code, _) => error!("Run {run}/{runs}: discovery failed ({code:?})"),
+                    }
+                }
+
+                let (Some(min), Some(max)) = (durations.iter().min(), durations.iter().max())
+                else {
+                    report(OutputCode::DeviceNotFound, None);
+                    error!("Device {:?} was never discovered", hue_device.addr);
+                    return;
+                };
+                let avg = durations.iter().sum::<Duration>() / durations.len() as u32;
+                report(
+                    OutputCode::Success,
+                    Some((
+                        "discovery_ms",
+                        serde_json::json!({
+                            "min": min.as_millis() as u64,
+                            "avg": avg.as_millis() as u64,
+                            "max": max.as_millis() as u64,
+                            "found": durations.len(),
+                            "runs": runs,
+                        }),
+                    )),
+                );
+
+                info!(
+                    "Discovery of {}: min {min:?}, avg {avg:?}, max {max:?} ({}/{runs} found)",
+                    addr_to_str(&hue_device.addr),
+                    durations.len()
+                );
+            }
+            Self::Subscribe => {
+                let addr = addr_to_str(&hue_device.addr);
+                let mut changes = hue_device.subscribe().await;
+                info!("Subscribed to {addr}, press CTRL+C to exit");
+
+                loop {
+                    let change = tokio::select! {
+                        _ = tokio::signal::ctrl_c() => break,
+                        change = changes.next() => change,
+                    };
+                    let Some(change) = change else {
+                        warn!("Subscription to {addr} ended, the device disconnected");
+                        break;
+                    };
+
+                    match change {
+                        Response::Power(state) => {
+                            report(OutputCode::Success, Some(("power", state.into())));
+                            info!("{addr} power {}", if state { "on" } else { "off" })
+                        }
+                        Response::Brightness(percent) => {
+                            report(OutputCode::Success, Some(("brightness", percent.into())));
+                            info!("{addr} brightness {percent}%")
+                        }
+                        Response::ColorXy(xy) => {
+                            report(
+                                OutputCode::Success,
+                                Some(("xy", serde_json::json!({ "x": xy.x, "y": xy.y }))),
+                            );
+                            info!("{addr} color x: {:.3}, y: {:.3}", xy.x, xy.y)
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Self::Info => {
+                let state = match hue_device.get_all_state().await {
+                    Ok(state) => state,
+                    Err(code) => {
+                        report(code, None);
+                        error!(
+                            "Failed to read the state of hue device address: {:?}",
+                            hue_device.addr
+                        );
+                        return;
+                    }
+                };
+
+                // Not every device exposes its device information, it's shown as unknown
+                let known = |value: Result<String, OutputCode>| {
+                    value.ok().filter(|value| !value.is_empty())
+                };
+                let name = known(Ok(state.name));
+                let model = known(hue_device.get_model().await);
+                let manufacturer = known(hue_device.get_manufacturer().await);
+                let firmware = known(hue_device.get_firmware().await);
+                let color_temp = hue_device.get_color_temp().await;
+
+                if let Some(ref model) = model {
+                    let mut storage = storage.lock().await;
+                    storage.save_model(hue_device.addr, model);
+                    storage.flush();
+                }
+
+                report(
+                    OutputCode::Success,
+                    Some((
+                        "info",
+                        serde_json::json!({
+                            "name": name,
+                            "model": model,
+                            "manufacturer": manufacturer,
+                            "firmware": firmware,
+                            "power": state.power,
+                            "brightness": state.brightness,
+                            "xy": { "x": state.color.x, "y": state.color.y },
+                            "kelvin": color_temp.ok().flatten(),
+                        }),
+                    )),
+                );
+
+                let unknown =
+                    |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".into());
+                info!(
+                    "Device {} {}",
+                    addr_to_str(&hue_device.addr),
+                    unknown(&name)
+                );
+                info!(
+                    "  model: {}, manufacturer: {}, firmware: {}",
+                    unknown(&model),
+                    unknown(&manufacturer),
+                    unknown(&firmware)
+                );
+                info!(
+                    "  power: {}, brightness: {}%, color x: {:.3}, y: {:.3}",
+                    if state.power { "on" } else { "off" },
+                    state.brightness,
+                    state.color.x,
+                    state.color.y
+                );
+                info!(
+                    "  color temperature: {}",
+                    match color_temp {
+                        Ok(Some(kelvin)) => format!("{kelvin}K"),
+                        Ok(None) => "unsupported".into(),
+                        Err(_) => "unknown".into(),
+                    }
+                );
+            }
+            Self::Rename { name } => {
+                let res = hue_device.set_name(name).await;
+                report(res, None);
+
+                if !res.is_success() {
+                    error!(
+                        "Failed to write the name to hue device address: {:?}",
+                        hue_device.addr
+                    );
+                    return;
+                }
+
+                if dry_run_enabled() {
+                    return;
+                }
+
+                let mut storage = storage.lock().await;
+                storage.save_name(hue_device.addr, truncate_name(name));
+                storage.flush();
+            }
+            Self::Disconnect => {
+                let res = hue_device.disconnect_device().await;
+                report(res, None);
+
+                if !res.is_success() {
+                    error!(
+                        "Daemon failed to disconnect from device {:?}",
+                        hue_device.addr
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Reads every device power state, turns them all off if any (or all if if_all_on) of them is on,
+/// else turns them all on
+pub async fn toggle_all(hue_devices: &[HueDevice<Client>], if_all_on: bool, format: OutputFormat) {
+    let outputs = future::join_all(hue_devices.iter().map(|device| device.get_power())).await;
+    let states = hue_devices
+        .iter()
+        .zip(outputs)
+        .filter_map(|(device, (res, buf))| {
+            if !res.is_success() {
+                print_json(format, &device.addr, res, None);
+                error!(
+                    "Failed to read power state to hue device address: {:?}",
+                    device.addr
+                );
+                return None;
+            }
+
+            Some(buf[0] == 1)
+        })
+        .collect::<Vec<_>>();
+
+    if states.is_empty() {
+        error!("Cannot toggle devices since no power state could be read");
+        return;
+    }
+
+    let power_state = toggle_all_target(&states, if_all_on);
+
+    stream::iter(hue_devices)
+        .map(|device| async move { (device.addr, device.set_power(power_state).await) })
+        .buffer_unordered(MAX_CONCURRENT_COMMANDS)
+        .for_each(|(addr, res)| async move {
+            print_json(
+                format,
+                &addr,
+                res,
+                res.is_success().then(|| ("power", power_state.into())),
+            );
+
+            if !res.is_success() {
+                error!("Failed to write power state to hue device address: {addr:?}");
+            }
+        })
+        .await;
+}
+
+/// Object printed for each device with --format json: its address, the output code of the command
+/// as status and the value read if any
+fn json_output(
+    addr: &[u8; ADDR_LEN],
+    code: OutputCode,
+    value: Option<(&str, serde_json::Value)>,
+) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    object.insert("address".into(), addr_to_str(addr).into());
+    object.insert("status".into(), status_name(code).into());
+    if let Some((key, value)) = value {
+        object.insert(key.into(), value);
+    }
+
+    object.into()
+}
+
+/// Snake case name of the output code, e.g. not_connected
+fn status_name(code: OutputCode) -> String {
+    format!("{code:?}")
+        .chars()
+        .enumerate()
+        .fold(String::new(), |mut name, (i, c)| {
+            if c.is_ascii_uppercase() && i > 0 {
+                name.push('_');
+            }
+            name.push(c.to_ascii_lowercase());
+            name
+        })
+}
+
+/// Prints json_output on stdout if the format is json, the human text is logged regardless
+fn print_json(
+    format: OutputFormat,
+    addr: &[u8; ADDR_LEN],
+    code: OutputCode,
+    value: Option<(&str, serde_json::Value)>,
+) {
+    if format == OutputFormat::Json {
+        println!("{}", json_output(addr, code, value));
+    }
+}
+
+/// Prints the devices held by the daemon along their saved name
+pub async fn print_daemon_devices(storage: &mut Storage, format: OutputFormat) {
+    let devices = HueDevice::<Client>::list_daemon_devices()
+        .await
+        .collect::<Vec<_>>()
+        .await;
+
+    if format == OutputFormat::Csv {
+        print!("{}", status_to_csv(&devices, storage));
+        return;
+    }
+
+    if devices.is_empty() {
+        info!("The daemon holds no device");
+        return;
+    }
+
+    for (addr, connected) in &devices {
+        let name = storage
+            .get_device(addr)
+            .map(|device| device.name.as_str())
+            .filter(|name| !name.is_empty())
+            .unwrap_or("Unknown name");
+
+        info!(
+            "{} {name} {}",
+            addr_to_str(addr),
+            if *connected {
+                "connected"
+            } else {
+                "disconnected"
+            }
+        );
+    }
+
+    info!("{} device(s) held by the daemon", devices.len());
+}
+
+/// Rereads the state of the devices held by the daemon every interval until CTRL+C. They're listed
+/// once and read on a single connection, a tick is skipped if the reads outlast the interval
+pub async fn watch_daemon_devices(storage: &mut Storage, secs: u64) {
+    let addrs = HueDevice::<Client>::list_daemon_devices()
+        .await
+        .map(|(addr, _)| addr)
+        .collect::<Vec<_>>()
+        .await;
+
+    if addrs.is_empty() {
+        info!("The daemon holds no device");
+        return;
+    }
+
+    let mut stream = HueDevice::<Client>::connect_daemon().await;
+    let mut ticker = time::interval(Duration::from_secs(secs));
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = ticker.tick() => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs() as _);
+                info!("Devices at {}", format_timestamp(now));
+
+                for addr in &addrs {
+                    let name = storage
+                        .get_device(addr)
+                        .map(|device| device.name.as_str())
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or("Unknown name");
+
+                    match HueDevice::<Client>::new(*addr).get_all_state_on(&mut stream).await {
+                        Ok(state) if state.connected => info!(
+                            "{} {name} {} brightness {}% color x: {:.3}, y: {:.3}",
+                            addr_to_str(addr),
+                            if state.power { "on" } else { "off" },
+                            state.brightness,
+                            state.color.x,
+                            state.color.y
+                        ),
+                        Ok(_) => info!("{} {name} disconnected", addr_to_str(addr)),
+                        Err(code) => error!("{} {name} {code:?}", addr_to_str(addr)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Offers to restart the daemon after it rejected the requests for being from another version, the
+/// command has to be run again then
+pub async fn offer_daemon_restart() {
+    if !io::stdin().is_terminal() {
+        info!("Restart the daemon with `rustbee shutdown` and run the command again");
+        return;
+    }
+
+    eprint!("Restart the daemon now? [y/N] ");
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() || !answer.trim().eq_ignore_ascii_case("y") {
+        return;
+    }
+
+    if let Err(err) = shutdown_daemon(false) {
+        error!("{err}");
+        return;
+    }
+
+    if let Err(err) = launch_daemon().await {
+        error!("{err}");
+        return;
+    }
+
+    info!("Daemon restarted, run the command again");
+}
+
+/// Generated at runtime from the arguments definition so the script always matches the installed
+/// version
+pub fn write_completions<W: io::Write>(shell: Shell, out: &mut W) {
+    let mut command = Args::command();
+    let name = command.get_name().to_string();
+
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+/// Prints the saved devices ordered by address
+pub fn print_devices(storage: &mut Storage, format: OutputFormat) {
+    let devices = storage.get_sorted_devices();
+
+    match format {
+        OutputFormat::Json => {
+            let devices = devices
+                .into_iter()
+                .enumerate()
+                .map(|(index, (addr, device))| {
+                    serde_json::json!({
+                        "index": index,
+                        "address": addr_to_str(addr),
+                        "name": device.name,
+                        "color": device.current_color,
+                        "brightness": device.brightness,
+                        "last_seen": device.last_seen,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            println!("{}", serde_json::Value::Array(devices));
+        }
+        OutputFormat::Csv => print!("{}", devices_to_csv(&devices)),
+        OutputFormat::Table => {
+            if devices.is_empty() {
+                info!("No device saved");
+                return;
+            }
+
+            for (index, (addr, device)) in devices.into_iter().enumerate() {
+                let [r, g, b] = device.current_color;
+
+                info!(
+                    "[{index}] {} {} color: #{r:02x}{g:02x}{b:02x} brightness: {}% last seen: {}",
+                    addr_to_str(addr),
+                    if device.name.is_empty() {
+                        "Unknown name"
+                    } else {
+                        device.name.as_str()
+                    },
+                    device.brightness,
+                    device
+                        .last_seen
+                        .map_or_else(|| "never".into(), format_timestamp)
+                );
+            }
+        }
+    }
+}
+
+/// Removes the saved device or all of them, returns false if there was nothing to remove
+pub fn forget_devices(storage: &mut Storage, address: Option<&str>, all: bool) -> bool {
+    let addresses = match address {
+        Some(address) => match parse_hex_address(address) {
+            Ok(addr) => vec![addr],
+            Err(err) => {
+                error!("Invalid MAC address \"{address}\": {err}, expected xx:xx:xx:xx:xx:xx");
+                return false;
+            }
+        },
+        None if all => storage
+            .get_sorted_devices()
+            .into_iter()
+            .map(|(addr, _)| *addr)
+            .collect(),
+        None => {
+            error!("Specify the address of the device to forget or --all");
+            return false;
+        }
+    };
+
+    let removed = addresses
+        .iter()
+        .filter(|addr| storage.remove_device(addr))
+        .map(addr_to_str)
+        .collect::<Vec<_>>();
+
+    if removed.is_empty() {
+        error!("No matching saved device");
+        return false;
+    }
+
+    storage.flush();
+    info!("Forgot {}", removed.join(", "));
+
+    true
+}
+
+pub fn export_devices(storage: &mut Storage, path: &Path) -> bool {
+    if let Err(err) = storage.export_to(path) {
+        error!("Failed to export the saved devices to {}: {err}", path.display());
+        return false;
+    }
+
+    info!("Exported the saved devices to {}", path.display());
+
+    true
+}
+
+pub fn import_devices(storage: &mut Storage, path: &Path, merge: bool) -> bool {
+    match storage.import_from(path, merge) {
+        Ok(count) => {
+            storage.flush();
+            info!("Imported {count} device(s) from {}", path.display());
+
+            true
+        }
+        Err(err) => {
+            error!("Failed to import the devices from {}: {err}", path.display());
+
+            false
+        }
+    }
+}
+
+pub fn print_config() {
+    for (name, value) in Config::current().entries() {
+        let source = if std::env::var_os(name).is_some_and(|value| !value.is_empty()) {
+            "env"
+        } else {
+            "default"
+        };
+
+        info!("{name} = {value} ({source})");
+    }
+}
+
+/// Kelvin range (warmest, coolest) of the temperature sweep, the one reported by the device
+/// clamped to MIN_COLOR_TEMP_KELVIN..=MAX_COLOR_TEMP_KELVIN which is also used when not reported
+fn temp_sweep_range(reported: Option<(u16, u16)>) -> (u16, u16) {
+    let (warm, cool) = reported.unwrap_or((MIN_COLOR_TEMP_KELVIN, MAX_COLOR_TEMP_KELVIN));
+
+    (
+        warm.clamp(MIN_COLOR_TEMP_KELVIN, MAX_COLOR_TEMP_KELVIN),
+        cool.clamp(MIN_COLOR_TEMP_KELVIN, MAX_COLOR_TEMP_KELVIN),
+    )
+}
+
+/// A light in temperature mode reports the color of its temperature, see TEMP_MODE_XY_TOLERANCE
+fn is_temp_mode(xy: &Xy, kelvin: u16) -> bool {
+    let temp_xy = Xy::from_kelvin(kelvin as f64);
+
+    ((xy.x - temp_xy.x).powi(2) + (xy.y - temp_xy.y).powi(2)).sqrt() <= TEMP_MODE_XY_TOLERANCE
+}
+
+/// Header row then one row per device, last_seen being a unix timestamp or empty if never seen
+fn devices_to_csv(devices: &[(&[u8; ADDR_LEN], &SavedDevice)]) -> String {
+    let mut csv = String::from("address,name,power,brightness,r,g,b,last_seen\n");
+
+    for (addr, device) in devices {
+        csv.push_str(&format!(
+            "{},{}\n",
+            addr_to_str(addr),
+            saved_csv_fields(device)
+        ));
+    }
+
+    csv
+}
+
+/// Same as devices_to_csv for the devices held by the daemon along their connection state, the
+/// other fields are the saved ones (empty if the device isn't saved)
+fn status_to_csv(devices: &[([u8; ADDR_LEN], bool)], storage: &mut Storage) -> String {
+    let mut csv = String::from("address,connected,name,power,brightness,r,g,b,last_seen\n");
+
+    for (addr, connected) in devices {
+        let fields = storage
+            .get_device(addr)
+            .map_or_else(|| ",,,,,,".into(), saved_csv_fields);
+
+        csv.push_str(&format!("{},{connected},{fields}\n", addr_to_str(addr)));
+    }
+
+    csv
+}
+
+/// name, power, brightness, r, g, b and last_seen fields of a saved device
+fn saved_csv_fields(device: &SavedDevice) -> String {
+    let [r, g, b] = device.current_color;
+
+    format!(
+        "{},{},{},{r},{g},{b},{}",
+        csv_field(&device.name),
+        device.power_state,
+        device.brightness,
+        device
+            .last_seen
+            .map(|last_seen| last_seen.to_string())
+            .unwrap_or_default()
+    )
+}
+
+/// Quotes the field if needed (RFC 4180)
+fn csv_field(value: &str) -> String {
+    if !value.contains([',', '"', '\n', '\r']) {
+        return value.to_owned();
+    }
+
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// Prints the devices found as they stream in with a progress indicator until the search ends or
+/// CTRL+C is pressed, which closes the socket so the daemon stops scanning. Returns the count
+pub async fn search(
+    name: &String,
+    hue_only: bool,
+    timeout: Option<u64>,
+    format: OutputFormat,
+) -> usize {
+    const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+    let show_progress = io::stderr().is_terminal();
+    let mut devices = HueDevice::<Client>::search_by_name(name, hue_only).await;
+    let mut ticker = time::interval(Duration::from_millis(150));
+    let mut ticks = 0;
+    let mut found = 0;
+
+    let deadline = async {
+        match timeout {
+            Some(secs) => time::sleep(Duration::from_secs(secs)).await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                if show_progress {
+                    eprint!("\r\x1b[2K");
+                }
+                warn!("Search cancelled");
+                break;
+            }
+            _ = &mut deadline => {
+                if show_progress {
+                    eprint!("\r\x1b[2K");
+                }
+                info!("Search stopped after {}s", timeout.unwrap_or_default());
+                break;
+            }
+            device = devices.next() => {
+                if show_progress {
+                    eprint!("\r\x1b[2K");
+                }
+
+                let Some(device) = device else {
+                    break;
+                };
+
+                found += 1;
+                if format == OutputFormat::Json {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "address": addr_to_str(&device.address),
+                            "name": device.name,
+                        })
+                    );
+                }
+                info!("{} {}", addr_to_str(&device.address), device.name);
+            }
+            _ = ticker.tick(), if show_progress => {
+                eprint!("\r{} Searching for \"{name}\"...", SPINNER[ticks % SPINNER.len()]);
+                ticks += 1;
+            }
+        }
+    }
+
+    if found == 0 {
+        error!("No devices found matching \"{name}\"");
+    } else {
+        info!("{found} device(s) found");
+    }
+
+    found
+}
+
+/// Hue and saturation are the ones of the color while the value is the brightness (percentage)
+/// since the device stores them separately. Colors out of the Hue gamut are clamped when they're
+/// set so saturated blues and greens read back with a slightly different hue
+fn xy_to_hsv(xy: &Xy, brightness: u8, color_space: ColorSpace) -> Hsv {
+    let mut hsv = Hsv::from_rgb(&xy.to_rgb_in(1., color_space));
+    hsv.v = brightness as f64 / 100.;
+
+    hsv
+}
+
+/// Formats like so: hsv(210, 50%, 80%)
+fn format_hsv(hsv: &Hsv) -> String {
+    // The hue of grays is undefined
+    let hue = if hsv.h.is_nan() { 0. } else { hsv.h };
+
+    format!(
+        "hsv({:.0}, {:.0}%, {:.0}%)",
+        hue.rem_euclid(360.),
+        hsv.s * 100.,
+        hsv.v * 100.
+    )
+}
+
+/// Power state to write to toggle the device given its power read
+fn toggled_power((code, data): CmdOutput) -> Result<bool, OutputCode> {
+    if !code.is_success() {
+        return Err(code);
+    }
+
+    Ok(data[0] != 1)
+}
+
+/// Returns the power state to apply to every device
+fn toggle_all_target(states: &[bool], if_all_on: bool) -> bool {
+    let turn_off = if if_all_on {
+        states.iter().all(|state| *state)
+    } else {
+        states.iter().any(|state| *state)
+    };
+
+    !turn_off
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use rustbee_common::constants::{HUE_BAR_1_ADDR, OUTPUT_LEN};
+
+    use super::*;
+
+    #[test]
+    fn temp_sweep_bounds() {
+        assert_eq!(temp_sweep_range(Some((2200, 6500))), (2200, 6500));
+        // Clamped to the range the daemon accepts, which is used when the device doesn't report it
+        assert_eq!(temp_sweep_range(Some((1000, 10000))), (2000, 6500));
+        assert_eq!(temp_sweep_range(None), (2000, 6500));
+    }
+
+    #[test]
+    fn temp_mode_detection() {
+        assert!(is_temp_mode(&Xy::from_kelvin(2700.), 2700));
+        assert!(!is_temp_mode(&Xy::from_kelvin(6500.), 2700));
+        assert!(!is_temp_mode(&Xy::new(0.15, 0.06), 2700));
+    }
+
+    #[test]
+    fn toggle_all_rules() {
+        // If any on
+        assert!(!toggle_all_target(&[true, false], false));
+        assert!(toggle_all_target(&[false, false], false));
+        assert!(!toggle_all_target(&[true, true], false));
+
+        // If all on
+        assert!(toggle_all_target(&[true, false], true));
+        assert!(toggle_all_target(&[false, false], true));
+        assert!(!toggle_all_target(&[true, true], true));
+    }
+
+    #[test]
+    fn power_toggle() {
+        let mut data = [0; OUTPUT_LEN - 1];
+        assert_eq!(toggled_power((OutputCode::Success, data)), Ok(true));
+
+        data[0] = 1;
+        assert_eq!(toggled_power((OutputCode::Success, data)), Ok(false));
+
+        assert_eq!(
+            toggled_power((OutputCode::NotConnected, data)),
+            Err(OutputCode::NotConnected)
+        );
+    }
+
+    #[test]
+    fn devices_csv() {
+        let desk = SavedDevice {
+            name: "Desk, \"left\"".into(),
+            current_color: [255, 0, 128],
+            brightness: 50,
+            power_state: true,
+            last_seen: Some(1_700_000_000),
+            ..Default::default()
+        };
+        let unnamed = SavedDevice::default();
+
+        assert_eq!(
+            devices_to_csv(&[(&[0; ADDR_LEN], &unnamed), (&[0xff; ADDR_LEN], &desk)]),
+            "address,name,power,brightness,r,g,b,last_seen\n\
+             00:00:00:00:00:00,,false,0,0,0,0,\n\
+             ff:ff:ff:ff:ff:ff,\"Desk, \"\"left\"\"\",true,50,255,0,128,1700000000\n"
+        );
+        assert_eq!(csv_field("Hue\nPlay"), "\"Hue\nPlay\"");
+    }
+
+    #[test]
+    fn status_csv() {
+        // Never loaded nor flushed since a device is set, so no file is involved
+        let mut storage = Storage::new(Default::default());
+        storage.set_device(
+            [0xff; ADDR_LEN],
+            Some(SavedDevice {
+                name: "Desk, \"left\"".into(),
+                current_color: [255, 0, 128],
+                brightness: 50,
+                power_state: true,
+                last_seen: Some(1_700_000_000),
+                ..Default::default()
+            }),
+        );
+
+        // The unsaved device only has its address and connection state
+        assert_eq!(
+            status_to_csv(
+                &[([0xff; ADDR_LEN], true), ([0; ADDR_LEN], false)],
+                &mut storage
+            ),
+            "address,connected,name,power,brightness,r,g,b,last_seen\n\
+             ff:ff:ff:ff:ff:ff,true,\"Desk, \"\"left\"\"\",true,50,255,0,128,1700000000\n\
+             00:00:00:00:00:00,false,,,,,,,\n"
+        );
+    }
+
+    #[test]
+    fn hsv_read() {
+        assert_eq!(format_hsv(&Hsv::new(210., 0.5, 0.8)), "hsv(210, 50%, 80%)");
+        assert_eq!(format_hsv(&Hsv::new(f64::NAN, 0., 1.)), "hsv(0, 0%, 100%)");
+        assert_eq!(format_hsv(&Hsv::new(360., 1., 0.)), "hsv(0, 100%, 0%)");
+
+        // Written as RGB (what an HSV write goes through) then read back as HSV
+        for rgb in [
+            Rgb::new(255., 0., 0.),
+            Rgb::new(255., 191.25, 127.5),
+            Rgb::new(102., 255., 102.),
+            Rgb::new(153., 221., 255.),
+            Rgb::new(255., 51., 255.),
+        ] {
+            let expected = Hsv::from_rgb(&rgb);
+            let hsv = xy_to_hsv(&Xy::from(rgb), 40, ColorSpace::Srgb);
+
+            assert!((hsv.h - expected.h).abs() < 1., "{hsv:?} {expected:?}");
+            assert!((hsv.s - expected.s).abs() < 0.01, "{hsv:?} {expected:?}");
+            assert_eq!(hsv.v, 0.4);
+        }
+
+        // sRGB blue is out of the Hue gamut so it's clamped to its edge
+        let hsv = xy_to_hsv(&Xy::from(Rgb::new(0., 0., 255.)), 100, ColorSpace::Srgb);
+        assert!((hsv.h - 248.).abs() < 1., "{hsv:?}");
+    }
+
+    #[test]
+    fn json_reads() {
+        let parse = |value: serde_json::Value| {
+            serde_json::from_str::<serde_json::Value>(&value.to_string()).unwrap()
+        };
+
+        let power = parse(json_output(
+            &HUE_BAR_1_ADDR,
+            OutputCode::Success,
+            Some(("power", true.into())),
+        ));
+        assert_eq!(
+            power,
+            serde_json::json!({ "address": "e8:d4:ea:c4:62:00", "status": "success", "power": true })
+        );
+
+        let brightness = parse(json_output(
+            &HUE_BAR_1_ADDR,
+            OutputCode::Success,
+            Some(("brightness", 42.0.into())),
+        ));
+        assert_eq!(brightness["brightness"], 42.0);
+        assert_eq!(brightness["address"], "e8:d4:ea:c4:62:00");
+
+        let failed = parse(json_output(&HUE_BAR_1_ADDR, OutputCode::NotConnected, None));
+        assert_eq!(
+            failed,
+            serde_json::json!({ "address": "e8:d4:ea:c4:62:00", "status": "not_connected" })
+        );
+    }
+
+    #[test]
+    fn bash_completions() {
+        let mut script = Vec::new();
+        write_completions(Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+
+        assert!(!script.is_empty());
+        for subcommand in ["power", "color-rgb", "brightness", "devices", "search"] {
+            assert!(script.contains(subcommand), "{subcommand} isn't completed");
+        }
+    }
+
+    #[test]
+    fn color_names() {
+        assert_eq!(parse_color_name("Warm White"), Ok("Warm White".into()));
+        assert_eq!(
+            parse_color_name("purpel"),
+            Err("purpel is not a known color, did you mean purple?".into())
+        );
+        assert_eq!(
+            parse_color_name("xxxxxxxxxxxx"),
+            Err("xxxxxxxxxxxx is not a known color".into())
+        );
+    }
+
+    #[test]
+    fn brightness_offsets() {
+        let parse = |value| parse_brightness_value(value).unwrap();
+
+        assert_eq!(parse("+10"), BrightnessValue::Relative(10));
+        assert_eq!(parse("+10").apply(42.4), 52);
+        assert_eq!(parse("+10").apply(95.), 100);
+
+        assert_eq!(parse("-200"), BrightnessValue::Relative(-200));
+        assert_eq!(parse("-200").apply(42.), 0);
+        // The largest offsets don't overflow
+        assert_eq!(parse("+32767").apply(50.), 100);
+        assert_eq!(parse("-32768").apply(50.), 0);
+
+        assert_eq!(parse("80"), BrightnessValue::Absolute(80));
+        assert_eq!(parse("80").apply(10.), 80);
+
+        assert!(parse_brightness_value("101").is_err());
+        assert!(parse_brightness_value("-").is_err());
+    }
+
+    #[test]
+    fn args_validation() {
+        let parse = |args: &[&str]| Args::try_parse_from([&["rustbee"], args].concat());
+
+        assert!(parse(&["brightness", "100"]).is_ok());
+        assert!(parse(&["brightness", "101"]).is_err());
+        assert!(parse(&["brightness", "-1"]).is_ok());
+        assert!(parse(&["brightness", "+10", "--ensure-on"]).is_ok());
+        assert!(parse(&["brightness", "+x"]).is_err());
+        assert!(parse(&["brightness", "50", "--fade", "500"]).is_ok());
+        let restored_fade = ["--ensure-on", "--restore-off", "--fade", "500"];
+        assert!(parse(&[&["brightness", "50"][..], &restored_fade].concat()).is_err());
+
+        assert!(parse(&["color-rgb", "255", "0", "255"]).is_ok());
+        assert!(parse(&["color-rgb", "256", "0", "0"]).is_err());
+        assert!(parse(&["color-rgb", "--notation", "hsv"]).is_ok());
+        assert!(parse(&["color-rgb", "--notation", "hsl"]).is_err());
+
+        assert!(parse(&["color-hex", "#ff00FF"]).is_ok());
+        assert!(parse(&["color-hex", "ff00f"]).is_err());
+
+        assert!(parse(&["white", "--warm", "--brightness", "40"]).is_ok());
+        assert!(parse(&["white", "--brightness", "101"]).is_err());
+
+        assert!(parse(&["color-xy", "0.3", "1"]).is_ok());
+        assert!(parse(&["color-xy", "1.2", "0.3"]).is_err());
+        assert!(parse(&["color-xy", "0.3", "x"]).is_err());
+        assert!(parse(&["color-xy", "nan", "0.3"]).is_err());
+
+        assert!(parse(&["color-hsv", "120", "1", "0.5"]).is_ok());
+        // Clamped when converting
+        assert!(parse(&["color-hsv", "-10", "2", "1"]).is_ok());
+        assert!(parse(&["color-hsv", "120", "1"]).is_err());
+
+        assert_eq!(
+            parse(&["power", "--format", "json"]).unwrap().format,
+            OutputFormat::Json
+        );
+        assert_eq!(
+            parse(&["devices", "--format", "csv"]).unwrap().format,
+            OutputFormat::Csv
+        );
+        assert!(parse(&["devices", "--json", "--format", "csv"]).is_err());
+        assert!(matches!(
+            parse(&["list", "--json"]).unwrap().command,
+            Command::Devices { json: true }
+        ));
+
+        assert!(matches!(
+            parse(&["search", "lamp", "--timeout", "5"])
+                .unwrap()
+                .command,
+            Command::Search {
+                timeout: Some(5),
+                ..
+            }
+        ));
+        assert!(parse(&["search", "lamp", "--timeout", "-1"]).is_err());
+
+        assert!(matches!(
+            parse(&["status", "--watch", "2"]).unwrap().command,
+            Command::Status { watch: Some(2) }
+        ));
+        assert!(parse(&["status", "--watch", "0"]).is_err());
     }
 }
@@ -0,0 +1,158 @@
+use std::env;
+use std::fs;
+use std::io;
+
+use rustbee_common::constants::{DAEMON_BIN, DAEMON_BIN_ENV, LOG_PATH};
+#[cfg(not(target_os = "windows"))]
+use rustbee_common::config::Config;
+use rustbee_common::logger::*;
+use rustbee_common::utils::daemon_bin_candidates;
+#[cfg(not(target_os = "windows"))]
+use rustbee_common::utils::is_daemon_running;
+
+struct Problem {
+    description: String,
+    remediation: String,
+    /// Only specified when it's safe to fix it automatically
+    fix: Option<fn() -> io::Result<()>>,
+}
+
+struct Check {
+    name: &'static str,
+    run: fn() -> Option<Problem>,
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        name: "Daemon executable can be found",
+        run: check_daemon_bin,
+    },
+    #[cfg(not(target_os = "windows"))]
+    Check {
+        name: "No stale daemon socket",
+        run: check_stale_socket,
+    },
+    #[cfg(not(target_os = "windows"))]
+    Check {
+        name: "Socket directory is writable",
+        run: check_socket_dir_writable,
+    },
+    Check {
+        name: "Log file is writable",
+        run: check_log_file_writable,
+    },
+];
+
+/// Runs every check, prints a remediation for the failing ones and if fix is true, fixes the
+/// ones that are safe to fix. Returns whether or not every check passed (or got fixed)
+pub fn run(fix: bool) -> bool {
+    let mut healthy = true;
+
+    for check in CHECKS {
+        let Some(problem) = (check.run)() else {
+            info!("[OK] {}", check.name);
+            continue;
+        };
+
+        error!("[FAILED] {}: {}", check.name, problem.description);
+
+        match problem.fix {
+            Some(fix_fn) if fix => match fix_fn() {
+                Ok(()) => {
+                    info!("  => Fixed");
+                    continue;
+                }
+                Err(err) => error!("  => Failed to fix it ({err})"),
+            },
+            Some(_) => info!("  => Can be fixed automatically using `rustbee doctor --fix`"),
+            None => (),
+        }
+
+        info!("  => {}", problem.remediation);
+        healthy = false;
+    }
+
+    healthy
+}
+
+fn check_daemon_bin() -> Option<Problem> {
+    let on_path = || {
+        env::var_os("PATH")
+            .map(|paths| env::split_paths(&paths).any(|dir| dir.join(DAEMON_BIN).is_file()))
+            .unwrap_or(false)
+    };
+
+    // The last candidate is the bare name which is looked up on PATH
+    if daemon_bin_candidates().iter().any(|path| path.is_file()) || on_path() {
+        return None;
+    }
+
+    Some(Problem {
+        description: format!("{DAEMON_BIN} cannot be found so it cannot be launched"),
+        remediation: format!(
+            "Add the directory containing {DAEMON_BIN} to your PATH (`just install` symlinks it to /bin) or specify its full path with {DAEMON_BIN_ENV}"
+        ),
+        fix: None,
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_stale_socket() -> Option<Problem> {
+    let socket_path = &Config::current().socket_path;
+    if !socket_path.exists() || is_daemon_running().unwrap_or(true) {
+        return None;
+    }
+
+    Some(Problem {
+        description: format!(
+            "{} exists but the daemon isn't running so it won't start",
+            socket_path.display()
+        ),
+        remediation: format!("Remove {} or run `rustbee shutdown`", socket_path.display()),
+        fix: Some(|| fs::remove_file(&Config::current().socket_path)),
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_socket_dir_writable() -> Option<Problem> {
+    let dir = Config::current().socket_path.parent()?;
+    // create_new never opens an existing file so a user file can't be truncated nor removed
+    let test_file = dir.join(format!(".rustbee-doctor-{}", std::process::id()));
+
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&test_file)
+    {
+        Ok(_) => {
+            let _ = fs::remove_file(&test_file);
+            return None;
+        }
+        // Left over by a previous run with the same pid, so it's writable
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return None,
+        Err(_) => (),
+    }
+
+    Some(Problem {
+        description: format!("Cannot write to {} so the daemon cannot create its socket", dir.display()),
+        remediation: "Give the capabilities to the executables: `sudo setcap cap_dac_override+ep $(which rustbee-daemon)` and `sudo setcap cap_dac_override,cap_dac_read_search+ep $(which rustbee)`".into(),
+        fix: None,
+    })
+}
+
+fn check_log_file_writable() -> Option<Problem> {
+    let res = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(LOG_PATH);
+
+    if res.is_ok() {
+        return None;
+    }
+
+    Some(Problem {
+        description: format!("Cannot open {LOG_PATH} in write mode"),
+        remediation: format!("Check the permissions of {LOG_PATH} and its directory"),
+        fix: None,
+    })
+}
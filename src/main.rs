@@ -1,30 +1,54 @@
 mod address;
 mod cli;
+mod doctor;
 
 use std::process;
+use std::sync::Arc;
 
 use clap::Parser;
+use tokio::sync::Mutex;
+use rustbee_common::constants::{LOG_KEEP, LOG_MAX_BYTES};
 use rustbee_common::device::*;
 use rustbee_common::logger::*;
 use rustbee_common::storage::Storage;
-use rustbee_common::utils::{launch_daemon, shutdown_daemon};
+use rustbee_common::utils::{launch_daemon, parse_hex_address, shutdown_daemon};
 
 use address::*;
-use cli::Command;
+use cli::{Command, ConfigAction, OutputFormat, State};
 
-static LOGGER: Logger = Logger::new("Rustbee-CLI", true);
+static LOGGER: Logger = Logger::with_rotation("Rustbee-CLI", true, LOG_MAX_BYTES, LOG_KEEP);
 
 #[tokio::main]
 async fn main() {
+    run().await;
+
+    flush_logs();
+}
+
+async fn run() {
     let args = cli::Args::parse();
     let command: &mut Command = Box::leak(Box::new(args.command));
     let mut tasks = Vec::new();
     let mut storage = Storage::try_default()
         .unwrap_or_else(|_| Storage::new(unimplemented!("Fallback path unimplemented")));
 
+    if args.dry_run {
+        enable_dry_run();
+    }
+
     LOGGER.init();
 
+    // The commands print their JSON output on stdout, the logs are still in the log file
+    if args.format == OutputFormat::Json {
+        LOGGER.quiet_stdout();
+    }
+
     match *command {
+        Command::Completions { shell } => {
+            cli::write_completions(shell, &mut std::io::stdout());
+
+            return;
+        }
         Command::Gui => {
             if let Err(err) = process::Command::new("rustbee-gui").spawn() {
                 error!("ERROR: Couldn't launch rustbee-gui ({err})");
@@ -32,6 +56,68 @@ async fn main() {
 
             return;
         }
+        Command::Search {
+            ref name,
+            hue_only,
+            timeout,
+        } => {
+            if let Err(err) = launch_daemon().await {
+                error!("{err}");
+                std::process::exit(1);
+            }
+
+            if cli::search(name, hue_only, timeout, args.format).await == 0 {
+                std::process::exit(1);
+            }
+
+            return;
+        }
+        Command::Status { watch } => {
+            if let Err(err) = launch_daemon().await {
+                error!("{err}");
+                std::process::exit(1);
+            }
+
+            match watch {
+                Some(secs) => cli::watch_daemon_devices(&mut storage, secs).await,
+                None => cli::print_daemon_devices(&mut storage, args.format).await,
+            }
+
+            return;
+        }
+        Command::Devices { json } => {
+            cli::print_devices(
+                &mut storage,
+                if json { OutputFormat::Json } else { args.format },
+            );
+
+            return;
+        }
+        Command::Forget { ref address, all } => {
+            if !cli::forget_devices(&mut storage, address.as_deref(), all) {
+                std::process::exit(1);
+            }
+
+            return;
+        }
+        Command::Config { ref action } => {
+            let success = match action {
+                ConfigAction::Show => {
+                    cli::print_config();
+                    true
+                }
+                ConfigAction::Export { path } => cli::export_devices(&mut storage, path),
+                ConfigAction::Import { path, merge } => {
+                    cli::import_devices(&mut storage, path, *merge)
+                }
+            };
+
+            if !success {
+                std::process::exit(1);
+            }
+
+            return;
+        }
         Command::Shutdown { force } => {
             if let Err(err) = shutdown_daemon(force) {
                 error!("{err}");
@@ -40,11 +126,31 @@ async fn main() {
 
             return;
         }
+        Command::Doctor { fix } => {
+            if !doctor::run(fix) {
+                std::process::exit(1);
+            }
+
+            return;
+        }
         Command::Logs {
             follow,
             tail,
             purge,
+            archive,
         } => {
+            if archive {
+                match LOGGER.archive_and_purge() {
+                    Ok(path) => info!("Log file archived to {}", path.display()),
+                    Err(err) => {
+                        error!("Failed to archive the log file: {err}");
+                        std::process::exit(1);
+                    }
+                }
+
+                return;
+            }
+
             if purge {
                 LOGGER.purge();
 
@@ -64,12 +170,32 @@ async fn main() {
         _ => (),
     }
 
-    let addresses = match &args.hex_mac_addresses {
-        Some(values) => values
+    let addresses = match (&args.hex_mac_addresses, &args.indexes) {
+        (Some(values), _) => values
             .iter()
-            .map(|s| parse_hex_address(s))
+            .map(|s| {
+                parse_hex_address(s).unwrap_or_else(|err| {
+                    error!("Invalid MAC address \"{s}\": {err}, expected xx:xx:xx:xx:xx:xx");
+                    process::exit(1);
+                })
+            })
             .collect::<Vec<_>>(),
-        None => storage.get_devices().keys().copied().collect(),
+        (None, Some(indexes)) => indexes
+            .iter()
+            .map(|index| {
+                storage
+                    .get_device_addr_by_index(*index)
+                    .unwrap_or_else(|err| {
+                        error!("{err}, see `rustbee devices` for the indexes");
+                        std::process::exit(1);
+                    })
+            })
+            .collect(),
+        (None, None) => storage
+            .get_sorted_devices()
+            .into_iter()
+            .map(|(addr, _)| *addr)
+            .collect(),
     };
 
     if addresses.is_empty() {
@@ -89,20 +215,38 @@ async fn main() {
         .map(|addr| HueDevice::new(*addr))
         .collect::<Vec<_>>();
 
-    for hue_device in hue_devices {
-        tasks.push(tokio::spawn(command.handle(hue_device)));
+    let storage = Arc::new(Mutex::new(storage));
+
+    if let Command::Power {
+        state: Some(State::ToggleAll { if_all_on, .. }),
+    } = *command
+    {
+        cli::toggle_all(&hue_devices, if_all_on, args.format).await;
+    } else {
+        for hue_device in hue_devices {
+            tasks.push(tokio::spawn(
+                command.handle(hue_device, Arc::clone(&storage), args.format),
+            ));
+        }
     }
 
     for task in tasks {
         task.await.expect("Failed to spawn async tokio task");
     }
 
+    if daemon_version_mismatched() {
+        cli::offer_daemon_restart().await;
+        std::process::exit(1);
+    }
+
     if args.save {
-        save_addresses(&mut storage, &addresses);
+        save_addresses(&mut *storage.lock().await, &addresses);
     }
 
     if args.one_shot {
-        shutdown_daemon(false).unwrap();
-        return;
+        if let Err(err) = shutdown_daemon(false) {
+            error!("{err}");
+            std::process::exit(1);
+        }
     }
 }